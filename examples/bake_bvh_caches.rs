@@ -0,0 +1,75 @@
+//! Bakes the BVH cache of the `dragon_high` mesh (see the README) next to its glTF file, so that
+//! the apps loading it with `ObvhsBuildSettings::load_baked_caches` read the cache instead of
+//! building it.
+//!
+//! The cache is built with the same settings as the apps: the baked file is ignored if they build
+//! their caches with other settings, or if the mesh changes.
+//!
+//! With the `asset_processor` feature, the asset processor bakes the caches of the meshes named by
+//! `.bvh` source files instead, see `BakeBvhCacheFile`.
+
+use std::{fs, path::Path};
+
+use bevy_app::AppExit;
+use bevy_internal::prelude::*;
+use bevy_internal::DefaultPlugins;
+use bevy_utils::default;
+use bevy_window::ExitCondition;
+
+use bevy_picking_bvh_backend::{
+    storage::file::{baked_bvh_cache_path, BvhCacheFile},
+    PickingBvhBackend,
+};
+
+/// The directory of the assets, as in the `AssetPlugin`.
+const ASSETS_DIR: &str = "assets";
+
+#[derive(Resource)]
+struct MeshToBake(Handle<Mesh>);
+
+fn main() {
+    App::new()
+        .add_plugins(DefaultPlugins.set(WindowPlugin {
+            // No window is needed to bake the caches
+            primary_window: None,
+            exit_condition: ExitCondition::DontExit,
+            ..default()
+        }))
+        .add_systems(Startup, load_mesh)
+        .add_systems(Update, bake_mesh)
+        .run();
+}
+
+fn load_mesh(mut commands: Commands, asset_server: Res<AssetServer>) {
+    let mesh = asset_server.load(
+        GltfAssetLabel::Primitive {
+            mesh: 0,
+            primitive: 0,
+        }
+        .from_asset("models/dragon_high.glb"),
+    );
+    commands.insert_resource(MeshToBake(mesh));
+}
+
+/// Bakes the cache of the mesh once it is loaded, then exits.
+fn bake_mesh(
+    mesh_to_bake: Res<MeshToBake>,
+    meshes: Res<Assets<Mesh>>,
+    asset_server: Res<AssetServer>,
+    mut app_exit: EventWriter<AppExit>,
+) {
+    let Some(mesh) = meshes.get(&mesh_to_bake.0) else {
+        return;
+    };
+    let mesh_path = asset_server.get_path(&mesh_to_bake.0).unwrap();
+
+    // The settings of the apps that load the baked cache
+    let build_settings = PickingBvhBackend::default().build_settings.obvhs;
+    let file = BvhCacheFile::bake_obvhs_bvh2(mesh, &build_settings).expect("Failed to build");
+
+    let path = Path::new(ASSETS_DIR).join(baked_bvh_cache_path(&mesh_path).path());
+    fs::write(&path, file.to_bytes()).expect("Failed to write the baked cache");
+    info!("Baked the BVH cache of {} to {}", mesh_path, path.display());
+
+    app_exit.send(AppExit::Success);
+}
@@ -1,20 +1,29 @@
-use bevy_asset::prelude::*;
-use bevy_ecs::{prelude::*, world::CommandQueue};
-use bevy_log::prelude::*;
-use bevy_tasks::prelude::*;
-
-use bevy_render::{
-    mesh::{Indices, PrimitiveTopology},
-    prelude::*,
+use bevy_render::prelude::*;
+
+use bevy_math::Vec3;
+use bvh::{
+    aabb::Aabb,
+    bounding_hierarchy::BHShape,
+    bvh::{Bvh, BvhNode},
 };
+use nalgebra::Point;
 
-use bvh::bvh::Bvh;
+use std::mem::size_of;
 use triangle::BVHTriangle;
 
 use crate::{
-    common::get_triangles,
-    storage::{AssetBvhCache, AssetsBvhCaches},
-    ComputeBvhCache,
+    common::{
+        attribute::set_triangle_uvs,
+        mesh_triangles,
+        triangle::{Triangle, TriangleFace},
+    },
+    settings::BvhCrateBuildSettings,
+    storage::{
+        file::{BvhCacheFileError, CacheReader, CacheWriter},
+        AssetBvhCache,
+    },
+    compute_triangle_cache_assets, BvhBackend, BvhCacheBuildError, TriangleCacheAssets,
+    TriangleCacheBuildSettings,
 };
 
 pub mod ray_cast;
@@ -25,78 +34,112 @@ pub struct BvhCache {
     pub triangles: Vec<triangle::BVHTriangle>,
 }
 
-impl AssetBvhCache for BvhCache {}
-
-/// Detect new assets and generate BVH tree
-pub fn compute_bvh_cache_assets(
-    mut commands: Commands,
-    mut asset_events: EventReader<AssetEvent<Mesh>>,
-    meshes: Res<Assets<Mesh>>,
-) {
-    let thread_pool = AsyncComputeTaskPool::get();
-
-    for ev in asset_events.read() {
-        match ev {
-            AssetEvent::Added { id } => {
-                let Some(mesh) = meshes.get(*id) else {
-                    warn!("Missing mesh for mesh {}", id);
-                    continue;
-                };
-
-                let task_entity = commands.spawn_empty().id();
-                let task = thread_pool.spawn({
-                    // We need to clone the mesh to be able to process it asynchronously
-                    let mesh = mesh.clone();
-                    let asset_id = id.clone();
-                    async move {
-                        let mut command_queue = CommandQueue::default();
-
-                        let build_bvh_cache_span = info_span!("build_bvh_cache");
-                        let build_bvh_cache_guard = build_bvh_cache_span.enter();
-                        let bvh_cache = build_bvh_cache(&mesh);
-                        drop(build_bvh_cache_guard);
-
-                        if let Some(bvh_cache) = bvh_cache {
-                            command_queue.push(move |world: &mut World| {
-                                let mut bvh_caches =
-                                    world.resource_mut::<AssetsBvhCaches<Mesh, BvhCache>>();
-                                bvh_caches.insert(asset_id, bvh_cache);
-                            })
-                        }
-
-                        command_queue
-                    }
-                });
-                // Spawn new entity and add our new task as a component
-                commands.entity(task_entity).insert(ComputeBvhCache(task));
+impl AssetBvhCache for BvhCache {
+    fn triangle_count(&self) -> usize {
+        self.triangles.len()
+    }
+
+    fn memory_footprint(&self) -> usize {
+        self.bvh.nodes.capacity() * size_of::<BvhNode<f32, 3>>()
+            + self.triangles.capacity() * size_of::<BVHTriangle>()
+    }
+}
+
+impl BvhCache {
+    /// Writes the cache to a baked cache file.
+    pub(crate) fn write(&self, writer: &mut CacheWriter) {
+        writer.slice(&self.bvh.nodes, |writer, node| match node {
+            BvhNode::Leaf {
+                parent_index,
+                shape_index,
+            } => {
+                writer.u8(0);
+                writer.len(*parent_index);
+                writer.len(*shape_index);
+            }
+            BvhNode::Node {
+                parent_index,
+                child_l_index,
+                child_l_aabb,
+                child_r_index,
+                child_r_aabb,
+            } => {
+                writer.u8(1);
+                writer.len(*parent_index);
+                writer.len(*child_l_index);
+                write_aabb(writer, child_l_aabb);
+                writer.len(*child_r_index);
+                write_aabb(writer, child_r_aabb);
             }
-            _ => {}
+        });
+        writer.slice(&self.triangles, |writer, triangle| {
+            writer.len(triangle.bh_node_index());
+            write_triangle(writer, &triangle.0);
+        });
+    }
+
+    /// Reads a cache written by [`BvhCache::write`], and checks that its indices are in bounds.
+    pub(crate) fn read(reader: &mut CacheReader) -> Result<Self, BvhCacheFileError> {
+        let nodes = reader.vec(|reader| match reader.u8()? {
+            0 => Ok(BvhNode::Leaf {
+                parent_index: reader.index()?,
+                shape_index: reader.index()?,
+            }),
+            1 => Ok(BvhNode::Node {
+                parent_index: reader.index()?,
+                child_l_index: reader.index()?,
+                child_l_aabb: read_aabb(reader)?,
+                child_r_index: reader.index()?,
+                child_r_aabb: read_aabb(reader)?,
+            }),
+            _ => Err(BvhCacheFileError::Corrupted),
+        })?;
+        let triangles = reader.vec(|reader| {
+            let node_index = reader.index()?;
+            let mut triangle = BVHTriangle::from_triangle(read_triangle(reader)?);
+            triangle.set_bh_node_index(node_index);
+            Ok(triangle)
+        })?;
+
+        if !is_valid_bvh(&nodes, triangles.len()) {
+            return Err(BvhCacheFileError::Corrupted);
         }
+        Ok(Self {
+            bvh: Bvh { nodes },
+            triangles,
+        })
     }
 }
 
-fn build_bvh_cache(mesh: &Mesh) -> Option<BvhCache> {
-    if mesh.primitive_topology() != PrimitiveTopology::TriangleList {
-        warn!("No triangle list topology");
-        return None; // ray_mesh_intersection assumes vertices are laid out in a triangle list
-    }
+/// Detect new, modified and removed assets and generate, regenerate or drop their BVH tree
+pub fn compute_bvh_cache_assets(params: TriangleCacheAssets<BvhCache>) {
+    compute_triangle_cache_assets(
+        params,
+        BvhBackend::Bvh,
+        |build_settings, _| build_settings.bvh.clone(),
+        build_bvh_cache,
+    );
+}
 
-    // Vertex positions are required
-    let positions = mesh.attribute(Mesh::ATTRIBUTE_POSITION)?.as_float3()?;
+impl TriangleCacheBuildSettings for BvhCrateBuildSettings {
+    fn build_any_mesh(&mut self) {
+        self.min_triangles = 0;
+    }
+}
 
-    // Normals are optional
-    let normals = mesh
-        .attribute(Mesh::ATTRIBUTE_NORMAL)
-        .and_then(|normal_values| normal_values.as_float3());
+pub(crate) fn build_bvh_cache(
+    mesh: &Mesh,
+    build_settings: &BvhCrateBuildSettings,
+) -> Result<BvhCache, BvhCacheBuildError> {
+    let mut triangles = mesh_triangles(mesh)?;
 
-    let triangles = if let Some(indices) = mesh.indices() {
-        match indices {
-            Indices::U16(items) => get_triangles(positions, normals, Some(items)),
-            Indices::U32(items) => get_triangles(positions, normals, Some(items)),
-        }
-    } else {
-        get_triangles::<u16>(positions, normals, None)
-    };
+    // Skip building this cache if not enough triangles
+    if triangles.len() < build_settings.min_triangles {
+        return Err(BvhCacheBuildError::NotEnoughTriangles(triangles.len()));
+    }
+    if build_settings.store_uvs {
+        set_triangle_uvs(mesh, &mut triangles);
+    }
 
     // Convert triangles to the correct type
     let mut triangles = triangles
@@ -106,5 +149,85 @@ fn build_bvh_cache(mesh: &Mesh) -> Option<BvhCache> {
 
     let bvh = Bvh::build(&mut triangles);
 
-    Some(BvhCache { bvh, triangles })
+    Ok(BvhCache { bvh, triangles })
+}
+
+/// Returns `true` if the indices of the `nodes` are in bounds, with `triangle_count` triangles, and
+/// if they form a tree whose children follow their parent and point back to it. Its traversals could
+/// otherwise loop forever.
+fn is_valid_bvh(nodes: &[BvhNode<f32, 3>], triangle_count: usize) -> bool {
+    let parent_index = |node_index: usize| match nodes.get(node_index)? {
+        BvhNode::Leaf { parent_index, .. } | BvhNode::Node { parent_index, .. } => {
+            Some(*parent_index)
+        }
+    };
+    nodes
+        .iter()
+        .enumerate()
+        .all(|(node_index, node)| match node {
+            BvhNode::Leaf { shape_index, .. } => *shape_index < triangle_count,
+            BvhNode::Node {
+                child_l_index,
+                child_r_index,
+                ..
+            } => {
+                child_l_index != child_r_index
+                    && [*child_l_index, *child_r_index]
+                        .into_iter()
+                        .all(|child_index| {
+                            child_index > node_index
+                                && parent_index(child_index) == Some(node_index)
+                        })
+            }
+        })
+}
+
+fn write_aabb(writer: &mut CacheWriter, aabb: &Aabb<f32, 3>) {
+    writer.vec3(Vec3::new(aabb.min.x, aabb.min.y, aabb.min.z));
+    writer.vec3(Vec3::new(aabb.max.x, aabb.max.y, aabb.max.z));
+}
+
+fn read_aabb(reader: &mut CacheReader) -> Result<Aabb<f32, 3>, BvhCacheFileError> {
+    let [min, max] = [reader.vec3()?, reader.vec3()?];
+    Ok(Aabb::with_bounds(
+        Point::<f32, 3>::new(min.x, min.y, min.z),
+        Point::<f32, 3>::new(max.x, max.y, max.z),
+    ))
+}
+
+fn write_triangle(writer: &mut CacheWriter, triangle: &Triangle) {
+    writer.len(triangle.face.triangle_index);
+    for vertex_index in triangle.face.vertex_indices {
+        writer.len(vertex_index);
+    }
+    writer.len(triangle.face.index_offset);
+    for position in triangle.positions {
+        writer.vec3(position);
+    }
+    writer.option(triangle.normals.as_ref(), |writer, normals| {
+        for normal in normals {
+            writer.vec3(*normal);
+        }
+    });
+    writer.option(triangle.uvs.as_ref(), |writer, uvs| {
+        for uv in uvs {
+            writer.vec2(*uv);
+        }
+    });
+}
+
+fn read_triangle(reader: &mut CacheReader) -> Result<Triangle, BvhCacheFileError> {
+    let face = TriangleFace {
+        triangle_index: reader.index()?,
+        vertex_indices: [reader.index()?, reader.index()?, reader.index()?],
+        index_offset: reader.index()?,
+    };
+    let positions = [reader.vec3()?, reader.vec3()?, reader.vec3()?];
+    let mut triangle = Triangle::new(
+        face,
+        positions,
+        reader.option(|reader| Ok([reader.vec3()?, reader.vec3()?, reader.vec3()?]))?,
+    );
+    triangle.uvs = reader.option(|reader| Ok([reader.vec2()?, reader.vec2()?, reader.vec2()?]))?;
+    Ok(triangle)
 }
@@ -1,75 +1,252 @@
-use bevy_math::{Dir3, Mat4, Ray3d};
+use std::{cmp::Reverse, collections::BinaryHeap};
+
+use bevy_math::{FloatOrd, Mat4, Ray3d, Vec3};
 use bevy_picking_more_hitinfo::mesh_picking::ray_cast::{Backfaces, RayMeshHit};
+use bvh::{aabb::Aabb, bvh::BvhNode};
 
-use crate::{bvh::BvhCache, ray_cast::intersections::triangle_intersection};
+use crate::{
+    bvh::{triangle::BVHTriangle, BvhCache},
+    ray_cast::{
+        intersections::{
+            mesh_hit_to_world, mesh_space_distance, mesh_space_ray, ray_triangle_intersection,
+            sort_hits, triangle_intersection,
+        },
+        mesh_caches::TriangleQuery,
+    },
+};
 
-/// Casts a ray on a mesh, and returns the intersection, using bvh cache.
+/// Casts a ray on a mesh, and returns the intersection before `max_distance`, using bvh cache.
+///
+/// The traversal is bounded by `max_distance`, nodes beyond it are never visited.
 pub fn ray_intersection_over_mesh_using_bvh_cache(
     transform: &Mat4,
     ray: Ray3d,
     culling: Backfaces,
     bvh_cache: &BvhCache,
+    max_distance: f32,
 ) -> Option<RayMeshHit> {
-    let world_to_mesh = transform.inverse();
+    let mesh_space_ray = mesh_space_ray(transform, ray)?;
+    let mesh_max_distance = mesh_space_distance(transform, &mesh_space_ray, max_distance);
+
+    // The ray cast can hit the same mesh many times, so we need to track which hit is
+    // closest to the camera, and record that.
+    let mut closest_hit = None;
+
+    traverse_ray_segment(
+        bvh_cache,
+        &mesh_space_ray,
+        mesh_max_distance,
+        |triangle, closest_hit_distance| {
+            let hit = triangle_intersection(
+                &triangle.0.positions,
+                &triangle.0.normals,
+                closest_hit_distance,
+                &mesh_space_ray,
+                culling,
+            )?;
+            let distance = hit.distance;
+            closest_hit = Some(mesh_hit_to_world(
+                transform,
+                &mesh_space_ray,
+                hit,
+                triangle.0.face.triangle_index,
+            ));
+            Some(distance)
+        },
+    );
+
+    closest_hit
+}
 
-    let mesh_space_ray = Ray3d::new(
-        world_to_mesh.transform_point3(ray.origin),
-        Dir3::new(world_to_mesh.transform_vector3(*ray.direction)).ok()?,
+/// Casts a ray on a mesh, and returns all the intersections before `max_distance`, nearest first,
+/// using bvh cache.
+pub fn ray_intersections_over_mesh_using_bvh_cache(
+    transform: &Mat4,
+    ray: Ray3d,
+    culling: Backfaces,
+    bvh_cache: &BvhCache,
+    max_distance: f32,
+) -> Vec<RayMeshHit> {
+    let Some(mesh_space_ray) = mesh_space_ray(transform, ray) else {
+        return Vec::new();
+    };
+    let mesh_max_distance = mesh_space_distance(transform, &mesh_space_ray, max_distance);
+
+    let mut hits = Vec::new();
+    traverse_ray_segment(
+        bvh_cache,
+        &mesh_space_ray,
+        mesh_max_distance,
+        |triangle, max_distance| {
+            if let Some(hit) = triangle_intersection(
+                &triangle.0.positions,
+                &triangle.0.normals,
+                max_distance,
+                &mesh_space_ray,
+                culling,
+            ) {
+                hits.push(mesh_hit_to_world(
+                    transform,
+                    &mesh_space_ray,
+                    hit,
+                    triangle.0.face.triangle_index,
+                ));
+            }
+            // Never shorten the segment, so that every triangle along the ray is visited
+            None
+        },
     );
+    sort_hits(&mut hits);
+    hits
+}
+
+/// Returns `true` if the ray hits any triangle of a mesh before `max_distance`, using bvh cache.
+///
+/// The traversal stops at the first hit found, which is not necessarily the closest.
+pub fn ray_any_hit_over_mesh_using_bvh_cache(
+    transform: &Mat4,
+    ray: Ray3d,
+    culling: Backfaces,
+    bvh_cache: &BvhCache,
+    max_distance: f32,
+) -> bool {
+    let Some(mesh_space_ray) = mesh_space_ray(transform, ray) else {
+        return false;
+    };
+    let mesh_max_distance = mesh_space_distance(transform, &mesh_space_ray, max_distance);
 
-    let ray = bvh::ray::Ray::new(
-        nalgebra::Point3::new(
-            mesh_space_ray.origin.x,
-            mesh_space_ray.origin.y,
-            mesh_space_ray.origin.z,
-        ),
-        nalgebra::SVector::<f32, 3>::new(
-            mesh_space_ray.direction.x,
-            mesh_space_ray.direction.y,
-            mesh_space_ray.direction.z,
-        ),
+    let mut any_hit = false;
+    traverse_ray_segment(
+        bvh_cache,
+        &mesh_space_ray,
+        mesh_max_distance,
+        |triangle, max_distance| {
+            let hit = ray_triangle_intersection(&mesh_space_ray, &triangle.0.positions, culling)?;
+            if hit.distance < 0.0 || hit.distance > max_distance {
+                return None;
+            }
+            any_hit = true;
+            Some(STOP_TRAVERSAL)
+        },
     );
+    any_hit
+}
 
-    let hit_aabbs = bvh_cache.bvh.traverse(&ray, &bvh_cache.triangles);
-    // info!("Got {} hit aabbs", hit_aabbs.len());
+/// Traverses the triangles of a mesh best first with a `query`, using bvh cache.
+///
+/// The nodes are visited by increasing cost, and the traversal stops once the remaining nodes can't
+/// have a better triangle than the best one found.
+pub(crate) fn query_triangles_using_bvh_cache(
+    bvh_cache: &BvhCache,
+    query: &mut impl TriangleQuery,
+) {
+    if bvh_cache.bvh.nodes.is_empty() {
+        return;
+    }
 
-    // The ray cast can hit the same mesh many times, so we need to track which hit is
-    // closest to the camera, and record that.
-    let mut closest_hit_distance = f32::MAX;
-    let mut closest_hit = None;
+    // The AABBs of the nodes are stored in their parent, the root is always visited
+    let mut best_cost = f32::INFINITY;
+    let mut heap = BinaryHeap::from([Reverse((FloatOrd(0.0), 0))]);
+    while let Some(Reverse((FloatOrd(cost), node_index))) = heap.pop() {
+        if cost > best_cost {
+            break;
+        }
+        match &bvh_cache.bvh.nodes[node_index] {
+            BvhNode::Leaf { shape_index, .. } => {
+                let triangle = &bvh_cache.triangles[*shape_index].0;
+                best_cost =
+                    best_cost.min(query.visit(triangle.positions, triangle.face.triangle_index));
+            }
+            BvhNode::Node {
+                child_l_index,
+                child_l_aabb,
+                child_r_index,
+                child_r_aabb,
+                ..
+            } => {
+                for (child_index, child_aabb) in
+                    [(child_l_index, child_l_aabb), (child_r_index, child_r_aabb)]
+                {
+                    let min = Vec3::new(child_aabb.min.x, child_aabb.min.y, child_aabb.min.z);
+                    let max = Vec3::new(child_aabb.max.x, child_aabb.max.y, child_aabb.max.z);
+                    if let Some(cost) = query.node_cost(min, max) {
+                        heap.push(Reverse((FloatOrd(cost), *child_index)));
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Segment length returned to [`traverse_ray_segment`] to stop the traversal.
+const STOP_TRAVERSAL: f32 = f32::NEG_INFINITY;
 
-    for triangle in hit_aabbs.iter() {
-        let tri_vertex_positions = &triangle.0.positions;
-        let tri_normals = &triangle.0.normals;
-
-        let Some(hit) = triangle_intersection(
-            tri_vertex_positions,
-            tri_normals,
-            closest_hit_distance,
-            &mesh_space_ray,
-            culling,
-        ) else {
-            continue;
-        };
-
-        closest_hit = Some(RayMeshHit {
-            point: transform.transform_point3(hit.point),
-            normal: transform.transform_vector3(hit.normal),
-            barycentric_coords: hit.barycentric_coords,
-            distance: transform
-                .transform_vector3(mesh_space_ray.direction * hit.distance)
-                .length(),
-            triangle: hit.triangle.map(|tri| {
-                [
-                    transform.transform_point3(tri[0]),
-                    transform.transform_point3(tri[1]),
-                    transform.transform_point3(tri[2]),
-                ]
-            }),
-            triangle_index: Some(triangle.0.triangle_index),
-        });
-        closest_hit_distance = hit.distance;
+/// Visits the triangles whose AABB is hit by the ray segment from its origin to `max_distance`.
+///
+/// `f` is called with each triangle and the current length of the segment, and returns the new
+/// length of the segment if it hit the triangle, so that farther nodes are skipped. Returning
+/// [`STOP_TRAVERSAL`] ends the traversal.
+fn traverse_ray_segment(
+    bvh_cache: &BvhCache,
+    ray: &Ray3d,
+    max_distance: f32,
+    mut f: impl FnMut(&BVHTriangle, f32) -> Option<f32>,
+) {
+    if bvh_cache.bvh.nodes.is_empty() {
+        return;
     }
 
-    closest_hit
+    let origin = ray.origin;
+    let inv_direction = ray.direction.recip();
+    let mut max_distance = max_distance;
+
+    let mut stack = vec![0];
+    while let Some(node_index) = stack.pop() {
+        match &bvh_cache.bvh.nodes[node_index] {
+            BvhNode::Leaf { shape_index, .. } => {
+                if let Some(distance) = f(&bvh_cache.triangles[*shape_index], max_distance) {
+                    if distance == STOP_TRAVERSAL {
+                        return;
+                    }
+                    max_distance = max_distance.min(distance);
+                }
+            }
+            BvhNode::Node {
+                child_l_index,
+                child_l_aabb,
+                child_r_index,
+                child_r_aabb,
+                ..
+            } => {
+                for (child_index, child_aabb) in
+                    [(child_l_index, child_l_aabb), (child_r_index, child_r_aabb)]
+                {
+                    if ray_segment_aabb_intersection(
+                        origin,
+                        inv_direction,
+                        child_aabb,
+                        max_distance,
+                    ) {
+                        stack.push(*child_index);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Slab test between a ray segment, from its origin to `max_distance`, and an AABB.
+fn ray_segment_aabb_intersection(
+    origin: Vec3,
+    inv_direction: Vec3,
+    aabb: &Aabb<f32, 3>,
+    max_distance: f32,
+) -> bool {
+    let min = Vec3::new(aabb.min.x, aabb.min.y, aabb.min.z);
+    let max = Vec3::new(aabb.max.x, aabb.max.y, aabb.max.z);
+    let t1 = (min - origin) * inv_direction;
+    let t2 = (max - origin) * inv_direction;
+    let t_near = t1.min(t2).max_element().max(0.0);
+    let t_far = t1.max(t2).min_element().min(max_distance);
+    t_near <= t_far
 }
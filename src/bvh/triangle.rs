@@ -7,14 +7,14 @@ use bvh::{
 };
 use nalgebra::Point;
 
-use crate::common::triangle::Triangle;
+use crate::common::triangle::{Triangle, TriangleFace};
 
 #[derive(Debug)]
 pub struct BVHTriangle(pub Triangle, usize);
 
 impl BVHTriangle {
-    pub fn new(triangle_index: usize, positions: [Vec3; 3], normals: Option<[Vec3; 3]>) -> Self {
-        Self(Triangle::new(triangle_index, positions, normals), 0)
+    pub fn new(face: TriangleFace, positions: [Vec3; 3], normals: Option<[Vec3; 3]>) -> Self {
+        Self(Triangle::new(face, positions, normals), 0)
     }
 
     pub fn from_triangle(triangle: Triangle) -> Self {
@@ -0,0 +1,146 @@
+//! Interpolation of the vertex attributes of a mesh at a hit point.
+
+use bevy_math::prelude::*;
+use bevy_render::mesh::{Mesh, MeshVertexAttributeId, VertexAttributeValues};
+
+use super::triangle::{Triangle, TriangleFace};
+
+/// Returns the weights of the three vertices of a triangle, in the order of their
+/// [`TriangleFace::vertex_indices`], from the `barycentric_coords` of a hit on it.
+pub fn barycentric_weights(barycentric_coords: Vec3) -> [f32; 3] {
+    // The barycentric coordinates of the hits weight the vertices 1, 2, then 0
+    [
+        barycentric_coords.z,
+        barycentric_coords.x,
+        barycentric_coords.y,
+    ]
+}
+
+/// Interpolates the `attribute` of the vertices of the `face` of a mesh at the `barycentric_coords`
+/// of a hit.
+///
+/// The components of the attribute are returned in a [`Vec4`], the missing ones are zero. The
+/// attributes of floats and of normalized unsigned integers (as vertex colors) are supported.
+pub fn interpolate_vertex_attribute(
+    mesh: &Mesh,
+    attribute: impl Into<MeshVertexAttributeId>,
+    face: &TriangleFace,
+    barycentric_coords: Vec3,
+) -> Option<Vec4> {
+    let values = mesh.attribute(attribute)?;
+    let weights = barycentric_weights(barycentric_coords);
+    face.vertex_indices
+        .iter()
+        .zip(weights)
+        .try_fold(Vec4::ZERO, |value, (vertex, weight)| {
+            Some(value + vertex_attribute_value(values, *vertex)? * weight)
+        })
+}
+
+/// Interpolates the `uvs` stored in a cached triangle at the `barycentric_coords` of a hit, see
+/// [`set_triangle_uvs`].
+pub fn interpolate_triangle_uv(uvs: [Vec2; 3], barycentric_coords: Vec3) -> Vec2 {
+    let weights = barycentric_weights(barycentric_coords);
+    uvs[0] * weights[0] + uvs[1] * weights[1] + uvs[2] * weights[2]
+}
+
+/// Stores the [`Mesh::ATTRIBUTE_UV_0`] of the vertices of the `triangles` of a mesh in them, so that
+/// the UV of a hit can be read from a cache when the mesh is not available.
+pub fn set_triangle_uvs(mesh: &Mesh, triangles: &mut [Triangle]) {
+    let Some(VertexAttributeValues::Float32x2(uvs)) = mesh.attribute(Mesh::ATTRIBUTE_UV_0) else {
+        return;
+    };
+    for triangle in triangles {
+        let vertices = triangle.face.vertex_indices;
+        triangle.uvs = vertices
+            .iter()
+            .all(|v| *v < uvs.len())
+            .then(|| vertices.map(|v| Vec2::from(uvs[v])));
+    }
+}
+
+/// Returns the value of a vertex attribute as a [`Vec4`], normalizing unsigned integers.
+fn vertex_attribute_value(values: &VertexAttributeValues, vertex: usize) -> Option<Vec4> {
+    let unorm8 = |value: u8| value as f32 / u8::MAX as f32;
+    let unorm16 = |value: u16| value as f32 / u16::MAX as f32;
+    Some(match values {
+        VertexAttributeValues::Float32(values) => Vec4::new(*values.get(vertex)?, 0.0, 0.0, 0.0),
+        VertexAttributeValues::Float32x2(values) => {
+            Vec2::from(*values.get(vertex)?).extend(0.0).extend(0.0)
+        }
+        VertexAttributeValues::Float32x3(values) => Vec3::from(*values.get(vertex)?).extend(0.0),
+        VertexAttributeValues::Float32x4(values) => Vec4::from(*values.get(vertex)?),
+        VertexAttributeValues::Unorm8x2(values) => {
+            let [x, y] = *values.get(vertex)?;
+            Vec4::new(unorm8(x), unorm8(y), 0.0, 0.0)
+        }
+        VertexAttributeValues::Unorm8x4(values) => Vec4::from(values.get(vertex)?.map(unorm8)),
+        VertexAttributeValues::Unorm16x2(values) => {
+            let [x, y] = *values.get(vertex)?;
+            Vec4::new(unorm16(x), unorm16(y), 0.0, 0.0)
+        }
+        VertexAttributeValues::Unorm16x4(values) => Vec4::from(values.get(vertex)?.map(unorm16)),
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy_asset::RenderAssetUsages;
+    use bevy_render::mesh::{Indices, PrimitiveTopology};
+
+    use crate::common::{mesh_triangle_face, mesh_triangles};
+
+    use super::*;
+
+    #[test]
+    fn interpolate_at_hit() {
+        let mut mesh = Mesh::new(PrimitiveTopology::TriangleList, RenderAssetUsages::all());
+        mesh.insert_attribute(
+            Mesh::ATTRIBUTE_POSITION,
+            vec![
+                [0.0, 0.0, 0.0],
+                [1.0, 0.0, 0.0],
+                [0.0, 1.0, 0.0],
+                [1.0, 1.0, 0.0],
+            ],
+        );
+        mesh.insert_attribute(
+            Mesh::ATTRIBUTE_UV_0,
+            vec![[0.0, 0.0], [1.0, 0.0], [0.0, 1.0], [1.0, 1.0]],
+        );
+        mesh.insert_attribute(
+            Mesh::ATTRIBUTE_COLOR,
+            vec![
+                [1.0, 0.0, 0.0, 1.0],
+                [0.0, 1.0, 0.0, 1.0],
+                [0.0, 0.0, 1.0, 1.0],
+                [1.0; 4],
+            ],
+        );
+        mesh.insert_indices(Indices::U16(vec![0, 1, 2, 1, 3, 2]));
+
+        // Halfway between the vertices 3 and 2 of the second triangle
+        let face = mesh_triangle_face(&mesh, 1).unwrap();
+        let barycentric_coords = Vec3::new(0.5, 0.5, 0.0);
+        let uv =
+            interpolate_vertex_attribute(&mesh, Mesh::ATTRIBUTE_UV_0, &face, barycentric_coords);
+        assert_eq!(uv, Some(Vec4::new(0.5, 1.0, 0.0, 0.0)));
+        let color =
+            interpolate_vertex_attribute(&mesh, Mesh::ATTRIBUTE_COLOR, &face, barycentric_coords);
+        assert_eq!(color, Some(Vec4::new(0.5, 0.5, 1.0, 1.0)));
+        assert_eq!(
+            interpolate_vertex_attribute(&mesh, Mesh::ATTRIBUTE_TANGENT, &face, barycentric_coords),
+            None
+        );
+
+        let mut triangles = mesh_triangles(&mesh).unwrap();
+        set_triangle_uvs(&mesh, &mut triangles);
+        assert_eq!(
+            triangles[1]
+                .uvs
+                .map(|uvs| interpolate_triangle_uv(uvs, barycentric_coords)),
+            Some(Vec2::new(0.5, 1.0))
+        );
+    }
+}
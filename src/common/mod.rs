@@ -1,68 +1,474 @@
+use std::iter::Enumerate;
+
 use bevy_math::Vec3;
-use triangle::Triangle;
+use bevy_render::mesh::{Indices, Mesh, PrimitiveTopology};
+use triangle::{Triangle, TriangleFace};
+
+use crate::BvhCacheBuildError;
 
+pub mod attribute;
 pub mod triangle;
 
-pub fn get_triangles<I: TryInto<usize> + Clone + Copy>(
+/// Reads the triangles of a mesh, with their vertex normals if any.
+pub fn mesh_triangles(mesh: &Mesh) -> Result<Vec<Triangle>, BvhCacheBuildError> {
+    Ok(mesh_triangles_iter(mesh)?.collect())
+}
+
+/// Iterates over the triangles of a mesh, with their vertex normals if any, reading each one only
+/// when it is reached.
+pub fn mesh_triangles_iter(
+    mesh: &Mesh,
+) -> Result<impl Iterator<Item = Triangle> + '_, BvhCacheBuildError> {
+    // Vertex positions are required
+    let positions = mesh
+        .attribute(Mesh::ATTRIBUTE_POSITION)
+        .and_then(|position_values| position_values.as_float3())
+        .ok_or(BvhCacheBuildError::MissingPositions)?;
+
+    // Normals are optional
+    let normals = mesh
+        .attribute(Mesh::ATTRIBUTE_NORMAL)
+        .and_then(|normal_values| normal_values.as_float3());
+
+    Ok(mesh_triangle_faces_iter(mesh)?
+        .filter_map(move |face| read_triangle(positions, normals, face)))
+}
+
+/// Reads the triangles of a mesh, using the given vertex positions and normals instead of the
+/// attributes of the mesh, as for a deformed mesh.
+pub fn mesh_triangles_with_vertices(
+    mesh: &Mesh,
     positions: &[[f32; 3]],
-    vertex_normals: Option<&[[f32; 3]]>,
-    indices: Option<&[I]>,
-) -> Vec<Triangle> {
-    if let Some(indices) = indices {
-        indices
-            .chunks_exact(3)
-            .flat_map(|triangle| -> Option<Triangle> {
-                let [a, b, c] = [
-                    triangle[0].try_into().ok()?,
-                    triangle[1].try_into().ok()?,
-                    triangle[2].try_into().ok()?,
-                ];
-
-                let triangle_index = a;
-                let tri_vertex_positions = &[
-                    Vec3::from(positions[a]),
-                    Vec3::from(positions[b]),
-                    Vec3::from(positions[c]),
-                ];
-                let tri_normals = vertex_normals.map(|normals| {
-                    [
-                        Vec3::from(normals[a]),
-                        Vec3::from(normals[b]),
-                        Vec3::from(normals[c]),
-                    ]
-                });
+    normals: Option<&[[f32; 3]]>,
+) -> Result<Vec<Triangle>, BvhCacheBuildError> {
+    Ok(mesh_triangle_faces_iter(mesh)?
+        .filter_map(|face| read_triangle(positions, normals, face))
+        .collect())
+}
 
-                Some(Triangle::new(
-                    triangle_index,
-                    tri_vertex_positions.clone(),
-                    tri_normals,
-                ))
-            })
-            .collect()
-    } else {
-        positions
-            .chunks_exact(3)
-            .enumerate()
-            .flat_map(|(i, triangle)| -> Option<Triangle> {
-                let &[a, b, c] = triangle else {
-                    return None;
+/// Returns the faces of the triangles of a mesh, in the order of its index buffer.
+pub fn mesh_triangle_faces(mesh: &Mesh) -> Result<Vec<TriangleFace>, BvhCacheBuildError> {
+    Ok(mesh_triangle_faces_iter(mesh)?.collect())
+}
+
+/// Iterates over the faces of the triangles of a mesh, in the order of its index buffer.
+pub fn mesh_triangle_faces_iter(
+    mesh: &Mesh,
+) -> Result<Box<dyn Iterator<Item = TriangleFace> + '_>, BvhCacheBuildError> {
+    Ok(match (mesh.primitive_topology(), mesh.indices()) {
+        (PrimitiveTopology::TriangleList, Some(indices)) => {
+            Box::new(TriangleListFaces::new(indices.iter()))
+        }
+        (PrimitiveTopology::TriangleList, None) => {
+            Box::new(TriangleListFaces::new(0..mesh.count_vertices()))
+        }
+        (PrimitiveTopology::TriangleStrip, Some(indices)) => Box::new(TriangleStripFaces::new(
+            indices.iter(),
+            strip_restart_index(Some(indices)),
+        )),
+        (PrimitiveTopology::TriangleStrip, None) => {
+            Box::new(TriangleStripFaces::new(0..mesh.count_vertices(), None))
+        }
+        (topology, _) => return Err(BvhCacheBuildError::UnsupportedTopology(topology)),
+    })
+}
+
+/// Returns the face of the triangle at `triangle_index` in a mesh, as reported by the
+/// `triangle_index` of a hit. Meshes of lines or points have no face.
+///
+/// The face is read from the index buffer at its offset, see [`TriangleFace::triangle_index`]. The
+/// winding of a triangle of a strip depends on its position in the strip, which is found by looking
+/// back for the previous restart index.
+pub fn mesh_triangle_face(mesh: &Mesh, triangle_index: usize) -> Option<TriangleFace> {
+    let indices = mesh.indices();
+    // The vertex at `offset` in the index buffer, or in the vertices if the mesh has no indices
+    let vertex_at = |offset: usize| match indices {
+        Some(Indices::U16(items)) => items.get(offset).map(|i| *i as usize),
+        Some(Indices::U32(items)) => items.get(offset).map(|i| *i as usize),
+        None => Some(offset),
+    };
+
+    let (index_offset, vertex_indices) = match mesh.primitive_topology() {
+        PrimitiveTopology::TriangleList => {
+            let index_offset = triangle_index * 3;
+            let vertex_indices = [
+                vertex_at(index_offset)?,
+                vertex_at(index_offset + 1)?,
+                vertex_at(index_offset + 2)?,
+            ];
+            (index_offset, vertex_indices)
+        }
+        PrimitiveTopology::TriangleStrip => {
+            let index_offset = triangle_index;
+            let [a, b, c] = [
+                vertex_at(index_offset)?,
+                vertex_at(index_offset + 1)?,
+                vertex_at(index_offset + 2)?,
+            ];
+            let restart = strip_restart_index(indices);
+            if [a, b, c].iter().any(|v| Some(*v) == restart) || a == b || b == c || a == c {
+                return None;
+            }
+            let strip_start = match indices {
+                Some(Indices::U16(items)) => items[..index_offset]
+                    .iter()
+                    .rposition(|i| *i == u16::MAX)
+                    .map_or(0, |restart_offset| restart_offset + 1),
+                Some(Indices::U32(items)) => items[..index_offset]
+                    .iter()
+                    .rposition(|i| *i == u32::MAX)
+                    .map_or(0, |restart_offset| restart_offset + 1),
+                None => 0,
+            };
+            let vertex_indices = if (index_offset - strip_start) % 2 == 0 {
+                [a, b, c]
+            } else {
+                [b, a, c]
+            };
+            (index_offset, vertex_indices)
+        }
+        _ => return None,
+    };
+
+    let vertex_count = mesh.count_vertices();
+    vertex_indices
+        .iter()
+        .all(|v| *v < vertex_count)
+        .then_some(TriangleFace {
+            triangle_index,
+            vertex_indices,
+            index_offset,
+        })
+}
+
+/// Returns the index which restarts a triangle strip with the given indices, if any.
+fn strip_restart_index(indices: Option<&Indices>) -> Option<usize> {
+    match indices {
+        Some(Indices::U16(_)) => Some(u16::MAX as usize),
+        Some(Indices::U32(_)) => Some(u32::MAX as usize),
+        None => None,
+    }
+}
+
+/// Returns the faces of a triangle list, given its vertex indices.
+pub fn triangle_list_faces(indices: impl IntoIterator<Item = usize>) -> Vec<TriangleFace> {
+    TriangleListFaces::new(indices).collect()
+}
+
+/// Iterates over the faces of a triangle list, given its vertex indices.
+pub struct TriangleListFaces<I> {
+    indices: I,
+    triangle_index: usize,
+}
+
+impl<I: Iterator<Item = usize>> TriangleListFaces<I> {
+    pub fn new(indices: impl IntoIterator<IntoIter = I>) -> Self {
+        Self {
+            indices: indices.into_iter(),
+            triangle_index: 0,
+        }
+    }
+}
+
+impl<I: Iterator<Item = usize>> Iterator for TriangleListFaces<I> {
+    type Item = TriangleFace;
+
+    fn next(&mut self) -> Option<TriangleFace> {
+        let vertex_indices = [
+            self.indices.next()?,
+            self.indices.next()?,
+            self.indices.next()?,
+        ];
+        let triangle_index = self.triangle_index;
+        self.triangle_index += 1;
+        Some(TriangleFace {
+            triangle_index,
+            vertex_indices,
+            index_offset: triangle_index * 3,
+        })
+    }
+}
+
+/// Returns the faces of a triangle strip, given its vertex indices.
+///
+/// Odd triangles have their first two vertices swapped, so that all the triangles have the same
+/// winding as the first one. Degenerate triangles, used to join strips, are skipped, and a
+/// `restart` index starts a new strip. Each triangle is numbered by the offset of its first index,
+/// so that its face can be found directly, see [`mesh_triangle_face`].
+pub fn triangle_strip_indices(
+    strip: impl IntoIterator<Item = usize>,
+    restart: Option<usize>,
+) -> Vec<TriangleFace> {
+    TriangleStripFaces::new(strip, restart).collect()
+}
+
+/// Iterates over the faces of a triangle strip, see [`triangle_strip_indices`].
+pub struct TriangleStripFaces<I> {
+    strip: Enumerate<I>,
+    restart: Option<usize>,
+    /// Number of vertices since the start of the strip, and its last two vertices
+    strip_len: usize,
+    previous: [usize; 2],
+}
+
+impl<I: Iterator<Item = usize>> TriangleStripFaces<I> {
+    pub fn new(strip: impl IntoIterator<IntoIter = I>, restart: Option<usize>) -> Self {
+        Self {
+            strip: strip.into_iter().enumerate(),
+            restart,
+            strip_len: 0,
+            previous: [0; 2],
+        }
+    }
+}
+
+impl<I: Iterator<Item = usize>> Iterator for TriangleStripFaces<I> {
+    type Item = TriangleFace;
+
+    fn next(&mut self) -> Option<TriangleFace> {
+        for (offset, index) in self.strip.by_ref() {
+            if Some(index) == self.restart {
+                self.strip_len = 0;
+                continue;
+            }
+            let [a, b] = self.previous;
+            self.previous = [b, index];
+            self.strip_len += 1;
+            if self.strip_len < 3 {
+                continue;
+            }
+            let c = index;
+            if a != b && b != c && a != c {
+                let vertex_indices = if self.strip_len % 2 == 1 {
+                    [a, b, c]
+                } else {
+                    [b, a, c]
                 };
-                let triangle_index = i;
-                let tri_vertex_positions = &[Vec3::from(a), Vec3::from(b), Vec3::from(c)];
-                let tri_normals = vertex_normals.map(|normals| {
-                    [
-                        Vec3::from(normals[i]),
-                        Vec3::from(normals[i + 1]),
-                        Vec3::from(normals[i + 2]),
-                    ]
+                return Some(TriangleFace {
+                    triangle_index: offset - 2,
+                    vertex_indices,
+                    index_offset: offset - 2,
                 });
+            }
+        }
+        None
+    }
+}
+
+/// Returns the segments of a mesh of lines, with their index and their vertex indices. The points
+/// of a mesh of points are segments of length zero, and meshes of triangles have no segment.
+///
+/// The segments of a line strip joining a restart index are skipped, but still counted. Line and
+/// point lists have no restart index.
+pub fn mesh_segment_indices(mesh: &Mesh) -> Vec<(usize, [usize; 2])> {
+    let (indices, restart): (Vec<usize>, _) = match mesh.indices() {
+        Some(Indices::U16(indices)) => (
+            indices.iter().map(|i| *i as usize).collect(),
+            Some(u16::MAX as usize),
+        ),
+        Some(Indices::U32(indices)) => (
+            indices.iter().map(|i| *i as usize).collect(),
+            Some(u32::MAX as usize),
+        ),
+        None => ((0..mesh.count_vertices()).collect(), None),
+    };
+
+    let topology = mesh.primitive_topology();
+    let restart = restart.filter(|_| topology == PrimitiveTopology::LineStrip);
+    let segments = match topology {
+        PrimitiveTopology::PointList => indices.iter().map(|i| [*i, *i]).collect::<Vec<_>>(),
+        PrimitiveTopology::LineList => indices.chunks_exact(2).map(|l| [l[0], l[1]]).collect(),
+        PrimitiveTopology::LineStrip => indices.windows(2).map(|l| [l[0], l[1]]).collect(),
+        PrimitiveTopology::TriangleList | PrimitiveTopology::TriangleStrip => Vec::new(),
+    };
+    segments
+        .into_iter()
+        .enumerate()
+        .filter(|(_, segment)| restart.is_none_or(|restart| !segment.contains(&restart)))
+        .collect()
+}
+
+/// Reads the triangles of the `faces` of a mesh. The faces with a vertex out of bounds are skipped.
+pub fn get_triangles(
+    positions: &[[f32; 3]],
+    vertex_normals: Option<&[[f32; 3]]>,
+    faces: Vec<TriangleFace>,
+) -> Vec<Triangle> {
+    faces
+        .into_iter()
+        .filter_map(|face| read_triangle(positions, vertex_normals, face))
+        .collect()
+}
 
-                Some(Triangle::new(
-                    triangle_index,
-                    tri_vertex_positions.clone(),
-                    tri_normals,
-                ))
+/// Reads the triangle of a face, or `None` if one of its vertices is out of bounds.
+fn read_triangle(
+    positions: &[[f32; 3]],
+    vertex_normals: Option<&[[f32; 3]]>,
+    face: TriangleFace,
+) -> Option<Triangle> {
+    let vertices = face.vertex_indices;
+    if vertices.iter().any(|v| *v >= positions.len()) {
+        return None;
+    }
+    Some(Triangle::new(
+        face,
+        vertices.map(|v| Vec3::from(positions[v])),
+        vertex_normals
+            .filter(|normals| vertices.iter().all(|v| *v < normals.len()))
+            .map(|normals| vertices.map(|v| Vec3::from(normals[v]))),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy_asset::RenderAssetUsages;
+
+    use super::*;
+
+    #[test]
+    fn triangle_strip_winding() {
+        // Two strips joined by a restart index, the second one with degenerate triangles
+        let triangles = triangle_strip_indices([0, 1, 2, 3, 9, 4, 5, 6, 6, 7, 8], Some(9))
+            .into_iter()
+            .map(|face| (face.triangle_index, face.vertex_indices, face.index_offset))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            triangles,
+            vec![
+                (0, [0, 1, 2], 0),
+                (1, [2, 1, 3], 1),
+                (5, [4, 5, 6], 5),
+                (8, [7, 6, 8], 8),
+            ]
+        );
+    }
+
+    #[test]
+    fn triangle_faces_match_hits() {
+        // Non-indexed triangle list, the normals of each triangle are read at its own vertices
+        let positions = [[0.0, 0.0, 0.0]; 6];
+        let normals = [
+            [1.0, 0.0, 0.0],
+            [1.0, 0.0, 0.0],
+            [1.0, 0.0, 0.0],
+            [0.0, 1.0, 0.0],
+            [0.0, 1.0, 0.0],
+            [0.0, 1.0, 0.0],
+        ];
+        let triangles = get_triangles(&positions, Some(&normals), triangle_list_faces(0..6));
+        assert_eq!(triangles[1].normals, Some([Vec3::Y; 3]));
+
+        let mut mesh = Mesh::new(PrimitiveTopology::TriangleList, RenderAssetUsages::all());
+        mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, vec![[0.0, 0.0, 0.0]; 5]);
+        mesh.insert_indices(Indices::U16(vec![4, 3, 2, 2, 1, 0]));
+        let face = TriangleFace {
+            triangle_index: 1,
+            vertex_indices: [2, 1, 0],
+            index_offset: 3,
+        };
+        assert_eq!(mesh_triangle_faces(&mesh).unwrap()[1], face);
+        assert_eq!(mesh_triangle_face(&mesh, 1), Some(face));
+        assert_eq!(mesh_triangle_face(&mesh, 2), None);
+
+        // Two ribbons of quads in a strip, joined by a restart index and degenerate triangles
+        let mut mesh = Mesh::new(PrimitiveTopology::TriangleStrip, RenderAssetUsages::all());
+        let positions = (0..24)
+            .map(|v| {
+                let (ribbon, column, row) = (v / 12, (v % 12) / 2, v % 2);
+                [column as f32, 0.0, (ribbon * 2 + row) as f32]
             })
-            .collect()
+            .collect::<Vec<_>>();
+        mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, positions);
+        let mut strip = (0..12).collect::<Vec<u16>>();
+        strip.extend([u16::MAX, 12, 13, 14, 14, 15, 15]);
+        strip.extend(16..24);
+        mesh.insert_indices(Indices::U16(strip));
+        let faces = mesh_triangle_faces(&mesh).unwrap();
+        assert_eq!(faces.len(), 10 + 8);
+        for face in faces {
+            assert_eq!(mesh_triangle_face(&mesh, face.triangle_index), Some(face));
+        }
+
+        // The backends report the same triangles
+        #[cfg(all(feature = "bvh", feature = "obvhs"))]
+        {
+            use bevy_math::{Dir3, Mat4, Ray3d};
+            use bevy_picking_more_hitinfo::mesh_picking::ray_cast::Backfaces;
+
+            use crate::{
+                bvh::{build_bvh_cache, ray_cast::ray_intersection_over_mesh_using_bvh_cache},
+                obvhs::{
+                    build_bvh2_cache, ray_cast::ray_intersection_over_mesh_using_obvhs_bvh2_cache,
+                },
+                ray_cast::intersections::ray_intersection_over_mesh,
+                settings::{BvhCrateBuildSettings, ObvhsBuildSettings},
+            };
+
+            let bvh_cache = build_bvh_cache(
+                &mesh,
+                &BvhCrateBuildSettings {
+                    min_triangles: 0,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+            let obvhs_bvh2_cache = build_bvh2_cache(
+                &mesh,
+                &ObvhsBuildSettings {
+                    min_triangles: 0,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+            let transform = Mat4::from_translation(Vec3::new(0.5, 0.0, -1.0));
+            let mut hit_count = 0;
+            for i in 0..60 {
+                let target = Vec3::new((i % 12) as f32 * 0.45 + 0.6, 0.0, (i / 12) as f32 * 0.8);
+                let ray = Ray3d::new(target + Vec3::new(0.1, 5.0, 0.2), Dir3::NEG_Y);
+                let mesh_hit =
+                    ray_intersection_over_mesh(&mesh, &transform, ray, Backfaces::Include, 10.0);
+                let bvh_hit = ray_intersection_over_mesh_using_bvh_cache(
+                    &transform,
+                    ray,
+                    Backfaces::Include,
+                    &bvh_cache,
+                    10.0,
+                );
+                let obvhs_bvh2_hit = ray_intersection_over_mesh_using_obvhs_bvh2_cache(
+                    &transform,
+                    ray,
+                    Backfaces::Include,
+                    &obvhs_bvh2_cache,
+                    10.0,
+                );
+                let triangle_index = mesh_hit.as_ref().and_then(|hit| hit.triangle_index);
+                assert_eq!(bvh_hit.and_then(|hit| hit.triangle_index), triangle_index);
+                assert_eq!(
+                    obvhs_bvh2_hit.and_then(|hit| hit.triangle_index),
+                    triangle_index
+                );
+                if let Some(triangle_index) = triangle_index {
+                    assert!(mesh_triangle_face(&mesh, triangle_index).is_some());
+                    hit_count += 1;
+                }
+            }
+            assert!(hit_count > 0);
+        }
+    }
+
+    #[test]
+    fn segment_restart_indices() {
+        // The largest `u16` index is a vertex in a line list, and a restart in a line strip
+        let lines = |topology| {
+            let mut mesh = Mesh::new(topology, RenderAssetUsages::all());
+            mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, vec![[0.0, 0.0, 0.0]; 3]);
+            mesh.insert_indices(Indices::U16(vec![0, u16::MAX, 1, 2]));
+            mesh_segment_indices(&mesh)
+        };
+        assert_eq!(
+            lines(PrimitiveTopology::LineList),
+            vec![(0, [0, u16::MAX as usize]), (1, [1, 2])]
+        );
+        assert_eq!(lines(PrimitiveTopology::LineStrip), vec![(2, [1, 2])]);
     }
 }
@@ -1,18 +1,36 @@
 use bevy_math::prelude::*;
 
-#[derive(Debug)]
-pub struct Triangle {
+/// Where a triangle is in its mesh.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TriangleFace {
+    /// Index of the triangle in the mesh: the number of triangles before it in its index buffer, or
+    /// in its vertices if it has no indices. The triangles of a strip are numbered by the offset of
+    /// their first index, which also counts its degenerate triangles and restart indices. This is
+    /// the `triangle_index` of the hits.
     pub triangle_index: usize,
+    /// Indices of the vertices of the triangle, in winding order.
+    pub vertex_indices: [usize; 3],
+    /// Offset of the first index of the triangle in the index buffer, or of its first vertex if the
+    /// mesh has no indices.
+    pub index_offset: usize,
+}
+
+#[derive(Clone, Debug)]
+pub struct Triangle {
+    pub face: TriangleFace,
     pub positions: [Vec3; 3],
     pub normals: Option<[Vec3; 3]>,
+    /// The UVs of the vertices, only stored in the caches built with `store_uvs`.
+    pub uvs: Option<[Vec2; 3]>,
 }
 
 impl Triangle {
-    pub fn new(triangle_index: usize, positions: [Vec3; 3], normals: Option<[Vec3; 3]>) -> Self {
+    pub fn new(face: TriangleFace, positions: [Vec3; 3], normals: Option<[Vec3; 3]>) -> Self {
         Self {
-            triangle_index,
+            face,
             positions,
             normals,
+            uvs: None,
         }
     }
 }
@@ -1,19 +1,65 @@
+#[cfg(any(feature = "obvhs", feature = "bvh"))]
+use core::fmt::Debug;
+use core::time::Duration;
+#[cfg(any(feature = "obvhs", feature = "bvh"))]
+use std::{any::TypeId, sync::Arc};
+
+#[cfg(all(any(feature = "obvhs", feature = "bvh"), not(target_arch = "wasm32")))]
+use std::time::Instant;
+#[cfg(all(any(feature = "obvhs", feature = "bvh"), target_arch = "wasm32"))]
+use web_time::Instant;
+
 use bevy_app::prelude::*;
-use bevy_asset::AssetEvent;
+#[cfg(any(feature = "obvhs", feature = "bvh"))]
+use bevy_asset::{AssetApp, AssetServer, Assets};
+use bevy_asset::{AssetEvent, AssetId};
+#[cfg(any(feature = "obvhs", feature = "bvh"))]
+use bevy_ecs::system::SystemParam;
 use bevy_ecs::{prelude::*, world::CommandQueue};
+#[cfg(any(feature = "obvhs", feature = "bvh"))]
+use bevy_log::prelude::*;
 use bevy_reflect::prelude::*;
-use bevy_render::prelude::*;
+use bevy_render::{mesh::PrimitiveTopology, prelude::*};
 use bevy_tasks::{prelude::*, Task};
+#[cfg(any(feature = "obvhs", feature = "bvh"))]
+use bevy_utils::HashMap;
 #[cfg(feature = "bvh")]
 use bvh::{compute_bvh_cache_assets, BvhCache};
 use futures_lite::future;
+#[cfg(any(feature = "obvhs", feature = "bvh"))]
+use settings::BvhBuildHints;
+use settings::{BvhBuildHint, BvhBuildSettings};
 
 #[cfg(feature = "obvhs")]
-use obvhs::{compute_obvhs_bvh2_cache_assets, ObvhsBvh2Cache};
+use bevy_render::{mesh::inherit_weights, view::VisibilitySystems};
+#[cfg(feature = "obvhs")]
+use bevy_transform::TransformSystem;
+#[cfg(feature = "obvhs")]
+use obvhs::{
+    compute_obvhs_bvh2_cache_assets,
+    lines::{compute_line_cache_assets, LineCache},
+    morph::{update_morphed_mesh_bvhs, MorphedMeshBvhs},
+    point_cloud::{compute_point_cloud_cache_assets, PointCloudCache},
+    skinned::{update_skinned_mesh_bvhs, SkinnedMeshBvhs},
+    tlas::{update_mesh_entities_tlas, MeshEntitiesTlas},
+    ObvhsBvh2Cache,
+};
+#[cfg(all(feature = "asset_processor", feature = "obvhs"))]
+use storage::file::{BakeBvhCacheFile, BvhBakeRequestLoader, BvhCacheFileSaver};
+use storage::BvhCacheAssetStatus;
 #[cfg(any(feature = "obvhs", feature = "bvh"))]
-use storage::AssetsBvhCaches;
+use storage::{
+    cache_dir::PersistentBvhCacheDir,
+    capture_render_world_meshes,
+    file::{
+        baked_bvh_cache_path, mesh_hash, read_baked_bvh_cache, BvhCacheFile, BvhCacheFileLoader,
+        CacheBuildSettings, FileBvhCache,
+    },
+    AssetBvhCache, AssetsBvhCaches, RenderWorldMeshes, SharedBvhCaches,
+};
 
 pub mod mesh_picking;
+pub mod settings;
 pub mod storage;
 
 #[cfg(feature = "bvh")]
@@ -25,6 +71,9 @@ pub mod obvhs;
 pub mod common;
 pub mod ray_cast;
 
+/// Global status of the BVH caches: `Building` while any cache is being built.
+///
+/// See [`AssetsBvhCaches::status`](storage::AssetsBvhCaches::status) for the status of the cache of a given asset.
 #[derive(Clone, Debug, Reflect, Default, PartialEq, Eq)]
 pub enum BvhCacheStatus {
     Building,
@@ -32,7 +81,7 @@ pub enum BvhCacheStatus {
     Ready,
 }
 
-#[derive(Clone, Debug, Reflect)]
+#[derive(Clone, Debug, PartialEq, Reflect)]
 pub enum BvhBackend {
     None,
     #[cfg(feature = "bvh")]
@@ -56,15 +105,52 @@ impl Default for BvhBackend {
     }
 }
 
-#[derive(Clone, Debug, Default, Resource, Reflect)]
+#[derive(Clone, Debug, Resource, Reflect)]
 #[reflect(Resource, Default, Debug)]
 pub struct PickingBvhBackend {
     pub backend: BvhBackend,
+    pub build_settings: BvhBuildSettings,
+    /// World-space distance to the ray under which the lines and points of the meshes with a line
+    /// or point [`PrimitiveTopology`] are hit. The AABBs of the broad phase are grown by it.
+    pub pick_radius: f32,
+    /// Alpha test the hits on the meshes with an [`AlphaMode::Mask`](bevy_render::alpha::AlphaMode::Mask)
+    /// `StandardMaterial`: the hits where its base color is transparent are skipped, and the ray
+    /// continues to the next triangle.
+    pub alpha_test: bool,
+}
+
+impl Default for PickingBvhBackend {
+    fn default() -> Self {
+        Self {
+            backend: BvhBackend::default(),
+            build_settings: BvhBuildSettings::default(),
+            pick_radius: 0.01,
+            alpha_test: false,
+        }
+    }
 }
 
 impl PickingBvhBackend {
     pub fn with_backend(backend: BvhBackend) -> Self {
-        Self { backend }
+        Self {
+            backend,
+            ..Default::default()
+        }
+    }
+
+    pub fn with_build_settings(mut self, build_settings: BvhBuildSettings) -> Self {
+        self.build_settings = build_settings;
+        self
+    }
+
+    pub fn with_pick_radius(mut self, pick_radius: f32) -> Self {
+        self.pick_radius = pick_radius;
+        self
+    }
+
+    pub fn with_alpha_test(mut self, alpha_test: bool) -> Self {
+        self.alpha_test = alpha_test;
+        self
     }
 }
 
@@ -76,12 +162,24 @@ pub struct PickingBvhCache {
 
 impl Plugin for PickingBvhBackend {
     fn build(&self, app: &mut App) {
-        app.init_resource::<PickingBvhCache>();
+        app.init_resource::<PickingBvhCache>()
+            .register_type::<BvhBuildHint>()
+            .add_event::<BvhCacheBuilt>()
+            .add_event::<BvhCacheFailed>();
 
         #[cfg(any(feature = "bvh", feature = "obvhs"))]
         {
             app.add_systems(PreUpdate, detect_meshes);
             app.add_systems(PreUpdate, handle_tasks.after(detect_meshes));
+
+            // The meshes only used in the render world are removed from the main world once extracted
+            app.add_systems(Last, capture_render_world_meshes);
+            app.init_resource::<RenderWorldMeshes>();
+
+            // The baked caches can also be loaded as assets, see `storage::file`
+            app.init_asset::<BvhCacheFile>()
+                .register_asset_loader(BvhCacheFileLoader);
+            app.init_resource::<PersistentBvhCacheDir>();
         }
 
         #[cfg(feature = "bvh")]
@@ -104,14 +202,415 @@ impl Plugin for PickingBvhBackend {
                     .after(detect_meshes),
             );
             app.insert_resource(AssetsBvhCaches::<Mesh, ObvhsBvh2Cache>::default());
+
+            app.add_systems(
+                PreUpdate,
+                compute_point_cloud_cache_assets
+                    .before(handle_tasks)
+                    .after(detect_meshes),
+            );
+            app.insert_resource(AssetsBvhCaches::<Mesh, PointCloudCache>::default());
+
+            app.add_systems(
+                PreUpdate,
+                compute_line_cache_assets
+                    .before(handle_tasks)
+                    .after(detect_meshes),
+            );
+            app.insert_resource(AssetsBvhCaches::<Mesh, LineCache>::default());
+
+            // The TLAS uses the final transforms and AABBs of the frame, and the refitted trees of
+            // the skinned meshes
+            app.add_systems(
+                PostUpdate,
+                update_mesh_entities_tlas
+                    .after(TransformSystem::TransformPropagate)
+                    .after(VisibilitySystems::CalculateBounds)
+                    .after(update_skinned_mesh_bvhs),
+            );
+            app.init_resource::<MeshEntitiesTlas>();
+
+            // Skinned meshes are refitted to the final pose of their joints
+            app.add_systems(
+                PostUpdate,
+                update_skinned_mesh_bvhs
+                    .after(TransformSystem::TransformPropagate)
+                    .after(inherit_weights),
+            );
+            app.init_resource::<SkinnedMeshBvhs>();
+
+            // Morphed meshes are refitted to the weights inherited from their glTF node
+            app.add_systems(PostUpdate, update_morphed_mesh_bvhs.after(inherit_weights));
+            app.init_resource::<MorphedMeshBvhs>();
+        }
+
+        // The `.bvh` source files are baked with the build settings of the plugin, see
+        // `storage::file`
+        #[cfg(all(feature = "asset_processor", feature = "obvhs"))]
+        {
+            app.register_asset_loader(BvhBakeRequestLoader {
+                build_settings: self.build_settings.obvhs.clone(),
+            });
+            app.register_asset_processor(BakeBvhCacheFile::from(BvhCacheFileSaver))
+                .set_default_asset_processor::<BakeBvhCacheFile>("bvh");
         }
 
         app.insert_resource(self.clone());
     }
 }
 
+/// Sent when the BVH cache of a mesh is built, for each mesh waiting for the build.
+///
+/// The meshes that get the cache already built for another mesh with the same content are ready at
+/// once, without this event, see [`SharedBvhCaches`](storage::SharedBvhCaches).
+#[derive(Event, Clone, Debug)]
+pub struct BvhCacheBuilt {
+    pub asset_id: AssetId<Mesh>,
+    pub backend: BvhBackend,
+    pub triangle_count: usize,
+    /// Approximate number of bytes used by the cache on the heap.
+    pub memory_footprint: usize,
+    pub duration: Duration,
+}
+
+/// Sent when the BVH cache of a mesh could not be built.
+#[derive(Event, Clone, Debug)]
+pub struct BvhCacheFailed {
+    pub asset_id: AssetId<Mesh>,
+    pub backend: BvhBackend,
+    pub error: BvhCacheBuildError,
+    pub duration: Duration,
+}
+
+/// Reason why the BVH cache of a mesh was not built.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BvhCacheBuildError {
+    /// The primitive topology of the mesh has no triangles. The triangle caches are only built for
+    /// [`PrimitiveTopology::TriangleList`] and [`PrimitiveTopology::TriangleStrip`]: the meshes of
+    /// lines and points have their own caches with [`BvhBackend::ObvhsBvh2`], and are ray cast
+    /// without acceleration with the other backends.
+    UnsupportedTopology(PrimitiveTopology),
+    /// The mesh has no `Float32x3` [`Mesh::ATTRIBUTE_POSITION`] attribute.
+    MissingPositions,
+    /// The skinned mesh has no `Uint16x4` [`Mesh::ATTRIBUTE_JOINT_INDEX`] or no `Float32x4`
+    /// [`Mesh::ATTRIBUTE_JOINT_WEIGHT`] attribute.
+    MissingJoints,
+    /// The morph targets image of the mesh does not match its vertices.
+    InvalidMorphTargets,
+    /// The mesh has less triangles than the minimum of the build settings. This is not a failure,
+    /// the status of the cache is [`BvhCacheAssetStatus::Skipped`].
+    NotEnoughTriangles(usize),
+}
+
+impl BvhCacheBuildError {
+    /// Returns the status of a cache that could not be built because of this error.
+    pub fn status(&self) -> BvhCacheAssetStatus {
+        match self {
+            BvhCacheBuildError::NotEnoughTriangles(_) => BvhCacheAssetStatus::Skipped,
+            BvhCacheBuildError::UnsupportedTopology(
+                PrimitiveTopology::PointList
+                | PrimitiveTopology::LineList
+                | PrimitiveTopology::LineStrip,
+            ) => BvhCacheAssetStatus::Skipped,
+            _ => BvhCacheAssetStatus::Failed,
+        }
+    }
+}
+
+/// An in-flight BVH cache build for the mesh assets with the same content.
+///
+/// The task is cancelled when this component is dropped, which happens when all the meshes waiting
+/// for it are modified or removed before the build completes.
 #[derive(Component)]
-struct ComputeBvhCache(Task<CommandQueue>);
+struct ComputeBvhCache {
+    task: Task<CommandQueue>,
+    /// The key and the type of the cache being built, to cancel outdated builds, see
+    /// [`cancel_bvh_cache_tasks`].
+    #[cfg(any(feature = "bvh", feature = "obvhs"))]
+    key: u64,
+    #[cfg(any(feature = "bvh", feature = "obvhs"))]
+    cache_type: TypeId,
+}
+
+#[cfg(any(feature = "bvh", feature = "obvhs"))]
+impl ComputeBvhCache {
+    fn new<B: AssetBvhCache>(task: Task<CommandQueue>, key: u64) -> Self {
+        Self {
+            task,
+            key,
+            cache_type: TypeId::of::<B>(),
+        }
+    }
+}
+
+/// Stores the result of the BVH cache build with the `key` for the meshes waiting for it, and
+/// sends the corresponding events.
+#[cfg(any(feature = "bvh", feature = "obvhs"))]
+fn finish_bvh_cache_build<B: AssetBvhCache>(
+    world: &mut World,
+    key: u64,
+    backend: BvhBackend,
+    result: Result<Arc<B>, BvhCacheBuildError>,
+    duration: Duration,
+) {
+    let asset_ids = world
+        .resource_mut::<AssetsBvhCaches<Mesh, B>>()
+        .finish_build(key, &result);
+    for asset_id in asset_ids {
+        match &result {
+            Ok(bvh_cache) => {
+                world.send_event(BvhCacheBuilt {
+                    asset_id,
+                    backend: backend.clone(),
+                    triangle_count: bvh_cache.triangle_count(),
+                    memory_footprint: bvh_cache.memory_footprint(),
+                    duration,
+                });
+            }
+            Err(error) if error.status() == BvhCacheAssetStatus::Failed => {
+                warn!(
+                    "Failed to build {:?} cache of mesh {}: {:?}",
+                    backend, asset_id, error
+                );
+                world.send_event(BvhCacheFailed {
+                    asset_id,
+                    backend: backend.clone(),
+                    error: error.clone(),
+                    duration,
+                });
+            }
+            Err(_) => {}
+        }
+    }
+}
+
+/// What to do with the BVH cache of a mesh asset, given the asset events of a frame.
+#[cfg(any(feature = "bvh", feature = "obvhs"))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum BvhCacheUpdate {
+    /// The mesh was added or modified, (re)build its cache.
+    Build,
+    /// The mesh was removed or is no longer used, drop its cache.
+    Evict,
+}
+
+/// Reads the mesh asset events and keeps only the last relevant update for each asset.
+///
+/// The meshes removed by their extraction to the render world keep their caches, see
+/// [`RenderWorldMeshes`].
+#[cfg(any(feature = "bvh", feature = "obvhs"))]
+fn collect_bvh_cache_updates(
+    asset_events: &mut EventReader<AssetEvent<Mesh>>,
+    render_world_meshes: &RenderWorldMeshes,
+) -> HashMap<AssetId<Mesh>, BvhCacheUpdate> {
+    let mut updates = HashMap::default();
+    for ev in asset_events.read() {
+        match ev {
+            AssetEvent::Added { id } | AssetEvent::Modified { id } => {
+                updates.insert(*id, BvhCacheUpdate::Build);
+            }
+            AssetEvent::Removed { id } if render_world_meshes.is_extracted(*id) => {}
+            AssetEvent::Removed { id } | AssetEvent::Unused { id } => {
+                updates.insert(*id, BvhCacheUpdate::Evict);
+            }
+            AssetEvent::LoadedWithDependencies { .. } => {}
+        }
+    }
+    updates
+}
+
+/// Detaches the given mesh asset from its in-flight build of a `B` cache, so that an outdated build
+/// can never overwrite the cache of a newer version of the mesh. The build is cancelled if no other
+/// mesh waits for it.
+#[cfg(any(feature = "bvh", feature = "obvhs"))]
+fn cancel_bvh_cache_tasks<B: AssetBvhCache>(
+    commands: &mut Commands,
+    compute_tasks: &Query<(Entity, &ComputeBvhCache)>,
+    bvh_caches: &mut AssetsBvhCaches<Mesh, B>,
+    asset_id: AssetId<Mesh>,
+) {
+    let Some(key) = bvh_caches.detach_build(asset_id) else {
+        return;
+    };
+    let cache_type = TypeId::of::<B>();
+    for (task_entity, compute_task) in compute_tasks {
+        if compute_task.key == key && compute_task.cache_type == cache_type {
+            // Dropping the task cancels it
+            commands.entity(task_entity).despawn();
+        }
+    }
+}
+
+/// The resources and queries used to build the triangle caches of the meshes, see
+/// [`compute_bvh_cache_assets`](bvh::compute_bvh_cache_assets) and
+/// [`compute_obvhs_bvh2_cache_assets`](obvhs::compute_obvhs_bvh2_cache_assets).
+#[cfg(any(feature = "bvh", feature = "obvhs"))]
+#[derive(SystemParam)]
+pub struct TriangleCacheAssets<'w, 's, B: AssetBvhCache> {
+    commands: Commands<'w, 's>,
+    asset_events: EventReader<'w, 's, AssetEvent<Mesh>>,
+    meshes: Res<'w, Assets<Mesh>>,
+    bvh_caches: ResMut<'w, AssetsBvhCaches<Mesh, B>>,
+    compute_tasks: Query<'w, 's, (Entity, &'static ComputeBvhCache)>,
+    picking_bvh_backend: Res<'w, PickingBvhBackend>,
+    build_hints: BvhBuildHints<'w, 's>,
+    render_world_meshes: Res<'w, RenderWorldMeshes>,
+    asset_server: Res<'w, AssetServer>,
+    persistent_cache_dir: ResMut<'w, PersistentBvhCacheDir>,
+    /// The hint each cache was last built with, to rebuild the caches whose hint changed.
+    applied_build_hints: Local<'s, HashMap<AssetId<Mesh>, BvhBuildHint>>,
+}
+
+/// The build settings of the caches built by [`compute_triangle_cache_assets`].
+#[cfg(any(feature = "bvh", feature = "obvhs"))]
+trait TriangleCacheBuildSettings: CacheBuildSettings + Debug + Send + Sync + 'static {
+    /// Lets the cache be built whatever the number of triangles of the mesh.
+    fn build_any_mesh(&mut self);
+
+    /// Returns `true` if the baked file of a mesh is read instead of building its cache, see
+    /// [`storage::file`].
+    fn load_baked_caches(&self) -> bool {
+        false
+    }
+}
+
+/// Detects new, modified and removed meshes, and the meshes whose [`BvhBuildHint`] changed, and
+/// generates, regenerates or drops their `B` cache of the `backend`.
+///
+/// The cache of a mesh is built by `build`, with the settings returned by `backend_settings` for
+/// its hint, unless it is read from the baked file of the mesh or from the persistent cache
+/// directory.
+#[cfg(any(feature = "bvh", feature = "obvhs"))]
+fn compute_triangle_cache_assets<B, S>(
+    params: TriangleCacheAssets<B>,
+    backend: BvhBackend,
+    backend_settings: impl Fn(&BvhBuildSettings, Option<&BvhBuildHint>) -> S,
+    build: fn(&Mesh, &S) -> Result<B, BvhCacheBuildError>,
+) where
+    B: AssetBvhCache + FileBvhCache,
+    S: TriangleCacheBuildSettings,
+{
+    let TriangleCacheAssets {
+        mut commands,
+        mut asset_events,
+        meshes,
+        mut bvh_caches,
+        compute_tasks,
+        picking_bvh_backend,
+        mut build_hints,
+        render_world_meshes,
+        asset_server,
+        mut persistent_cache_dir,
+        mut applied_build_hints,
+    } = params;
+    let thread_pool = AsyncComputeTaskPool::get();
+
+    let mut updates = collect_bvh_cache_updates(&mut asset_events, &render_world_meshes);
+    // Rebuild the meshes whose hint changed since their last build
+    let changed_hints = build_hints.changed();
+    let hints = build_hints.by_mesh();
+    for id in changed_hints {
+        if meshes.contains(id) && applied_build_hints.get(&id) != hints.get(&id).copied() {
+            updates.entry(id).or_insert(BvhCacheUpdate::Build);
+        }
+    }
+
+    for (id, update) in updates {
+        // An older build must never overwrite the cache of a newer version of the mesh
+        cancel_bvh_cache_tasks(&mut commands, &compute_tasks, &mut bvh_caches, id);
+
+        // The current cache (if any) is stale, drop it so that picking falls back to the mesh
+        let previous_cache = bvh_caches.remove(id);
+
+        if update == BvhCacheUpdate::Evict {
+            bvh_caches.remove_status(id);
+            applied_build_hints.remove(&id);
+            continue;
+        }
+
+        let build_hint = hints.get(&id).copied();
+        match build_hint {
+            Some(build_hint) => applied_build_hints.insert(id, build_hint.clone()),
+            None => applied_build_hints.remove(&id),
+        };
+        if build_hint.is_some_and(|build_hint| !build_hint.builds(&backend)) {
+            bvh_caches.set_status(id, BvhCacheAssetStatus::Skipped);
+            continue;
+        }
+
+        // The meshes only used in the render world are built from their capture
+        let Some(mesh) = meshes.get(id).or_else(|| render_world_meshes.get(id)) else {
+            warn!("Missing mesh for mesh {}", id);
+            bvh_caches.set_status(id, BvhCacheAssetStatus::Pending);
+            continue;
+        };
+
+        let mut build_settings = backend_settings(&picking_bvh_backend.build_settings, build_hint);
+        // The meshes only used in the render world have no mesh to fall back to
+        if render_world_meshes.is_extracted(id) {
+            build_settings.build_any_mesh();
+        }
+        let content_hash = mesh_hash(mesh);
+        let key = SharedBvhCaches::<B>::key(content_hash, &build_settings);
+        if !bvh_caches.start_build(id, content_hash, key, previous_cache) {
+            continue;
+        }
+
+        let task_entity = commands.spawn_empty().id();
+        let task = thread_pool.spawn({
+            // We need to clone the mesh to be able to process it asynchronously
+            let mesh = mesh.clone();
+            // The meshes loaded from a file may have a baked cache next to it
+            let baked_cache = build_settings
+                .load_baked_caches()
+                .then(|| asset_server.get_path(id))
+                .flatten()
+                .map(|path| (asset_server.clone(), baked_bvh_cache_path(&path)));
+            let shared_caches = bvh_caches.shared().clone();
+            let cache_dir = persistent_cache_dir
+                .get(picking_bvh_backend.build_settings.persistent_cache.as_ref());
+            let backend = backend.clone();
+            async move {
+                let mut command_queue = CommandQueue::default();
+
+                let start = Instant::now();
+                let baked_cache = match &baked_cache {
+                    Some((asset_server, path)) => {
+                        read_baked_bvh_cache(asset_server, path, content_hash, &build_settings)
+                            .await
+                    }
+                    None => None,
+                };
+                let build_bvh_cache = info_span!("build_bvh_cache", ?backend);
+                let build_bvh_cache_guard = build_bvh_cache.enter();
+                let bvh_cache = match baked_cache.and_then(B::from_file) {
+                    Some(cache) => Ok(cache),
+                    None => match &cache_dir {
+                        Some(cache_dir) => {
+                            cache_dir.load_or_build(content_hash, &build_settings, || {
+                                build(&mesh, &build_settings)
+                            })
+                        }
+                        None => build(&mesh, &build_settings),
+                    },
+                }
+                .map(|bvh_cache| shared_caches.insert(key, bvh_cache));
+                let duration = start.elapsed();
+                drop(build_bvh_cache_guard);
+
+                command_queue.push(move |world: &mut World| {
+                    finish_bvh_cache_build(world, key, backend, bvh_cache, duration);
+                });
+
+                command_queue
+            }
+        });
+        // Spawn new entity and add our new task as a component
+        commands
+            .entity(task_entity)
+            .insert(ComputeBvhCache::new::<B>(task, key));
+    }
+}
 
 fn detect_meshes(
     mut asset_events: EventReader<AssetEvent<Mesh>>,
@@ -119,7 +618,7 @@ fn detect_meshes(
 ) {
     'iter: for ev in asset_events.read() {
         match ev {
-            AssetEvent::Added { id: _ } => {
+            AssetEvent::Added { id: _ } | AssetEvent::Modified { id: _ } => {
                 bvh_cache.status = BvhCacheStatus::Building;
                 break 'iter;
             }
@@ -139,7 +638,7 @@ fn handle_tasks(
 ) {
     let mut remaining_tasks: usize = 0;
     for (task_entity, mut task) in &mut transform_tasks {
-        if let Some(mut commands_queue) = block_on(future::poll_once(&mut task.0)) {
+        if let Some(mut commands_queue) = block_on(future::poll_once(&mut task.task)) {
             // append the returned command queue to have it execute later
             commands.append(&mut commands_queue);
             // remove the task entity to prevent polling it again
@@ -155,6 +654,10 @@ fn handle_tasks(
     }
 }
 
+/// Run condition that is `true` when no BVH cache is being built.
+///
+/// Ray casts fall back to the meshes while their cache is not built, so this is only needed to wait
+/// for all the caches, as in benchmarks. Use [`AssetsBvhCaches::status`](storage::AssetsBvhCaches::status) to check a given mesh.
 pub fn run_if_bvh_cache_ready(bvh_cache: Res<PickingBvhCache>) -> bool {
     bvh_cache.status == BvhCacheStatus::Ready
 }
@@ -0,0 +1,322 @@
+//! BVH caches of the meshes of lines, with a [`PrimitiveTopology::LineList`] or
+//! [`PrimitiveTopology::LineStrip`] topology.
+//!
+//! The lines have no surface to hit, so they are picked by their distance to the ray, see
+//! [`BvhMeshRayCast::pick_line`](crate::ray_cast::BvhMeshRayCast::pick_line).
+
+use bevy_math::{Mat4, Ray3d, Vec3};
+use bevy_picking_more_hitinfo::mesh_picking::ray_cast::RayMeshHit;
+use bevy_render::{mesh::PrimitiveTopology, prelude::*};
+use obvhs::{
+    aabb::Aabb,
+    bvh2::{builder::build_bvh2, Bvh2},
+};
+
+use std::mem::size_of;
+
+#[cfg(not(target_arch = "wasm32"))]
+use core::time::Duration;
+#[cfg(target_arch = "wasm32")]
+use web_time::Duration;
+
+use crate::{
+    common::mesh_segment_indices,
+    ray_cast::intersections::{
+        ray_segment_hit, ray_segment_mesh_hit, sort_hits, PickTolerance, RaySegmentHit,
+    },
+    settings::ObvhsBuildSettings,
+    storage::AssetBvhCache,
+    BvhCacheBuildError,
+};
+
+use super::{
+    bvh2_memory_footprint, compute_primitive_cache_assets, traverse_nearest_to_ray,
+    PrimitiveCacheAssets,
+};
+
+/// A BVH over the segments of a mesh of lines, in mesh space.
+pub struct LineCache {
+    pub bvh: Bvh2,
+    pub segments: Vec<[Vec3; 2]>,
+    /// Index of each segment in the mesh, see [`RaySegmentHit::segment_index`].
+    pub segment_indices: Vec<u32>,
+    /// Indices of the vertices at the start and the end of each segment.
+    pub vertex_indices: Vec<[u32; 2]>,
+}
+
+impl LineCache {
+    /// Builds the tree over the `segments` at `segment_indices` in the mesh, between the vertices
+    /// at `vertex_indices`.
+    pub fn build(
+        segments: Vec<[Vec3; 2]>,
+        segment_indices: Vec<u32>,
+        vertex_indices: Vec<[u32; 2]>,
+        build_settings: &ObvhsBuildSettings,
+    ) -> Self {
+        let aabbs = segments
+            .iter()
+            .map(|[start, end]| Aabb {
+                min: start.min(*end).into(),
+                max: start.max(*end).into(),
+            })
+            .collect::<Vec<_>>();
+
+        let bvh = build_bvh2(
+            &aabbs,
+            build_settings.quality.build_params(),
+            &mut Duration::default(),
+        );
+
+        Self {
+            bvh,
+            segments,
+            segment_indices,
+            vertex_indices,
+        }
+    }
+}
+
+impl AssetBvhCache for LineCache {
+    fn triangle_count(&self) -> usize {
+        self.segments.len()
+    }
+
+    fn memory_footprint(&self) -> usize {
+        bvh2_memory_footprint(&self.bvh)
+            + self.segments.capacity() * size_of::<[Vec3; 2]>()
+            + self.segment_indices.capacity() * size_of::<u32>()
+            + self.vertex_indices.capacity() * size_of::<[u32; 2]>()
+    }
+}
+
+/// Detect new, modified and removed meshes of lines and generate, regenerate or drop their BVH tree.
+///
+/// The other meshes are ignored, they have no status in the
+/// [`AssetsBvhCaches`](crate::storage::AssetsBvhCaches) of the lines.
+pub fn compute_line_cache_assets(params: PrimitiveCacheAssets<LineCache>) {
+    compute_primitive_cache_assets(
+        params,
+        |topology| {
+            matches!(
+                topology,
+                PrimitiveTopology::LineList | PrimitiveTopology::LineStrip
+            )
+        },
+        build_line_cache,
+    );
+}
+
+fn build_line_cache(
+    mesh: &Mesh,
+    build_settings: &ObvhsBuildSettings,
+) -> Result<LineCache, BvhCacheBuildError> {
+    let positions = mesh
+        .attribute(Mesh::ATTRIBUTE_POSITION)
+        .and_then(|position_values| position_values.as_float3())
+        .ok_or(BvhCacheBuildError::MissingPositions)?;
+
+    let segment_indices = mesh_segment_indices(mesh)
+        .into_iter()
+        .filter(|(_, [a, b])| *a < positions.len() && *b < positions.len())
+        .collect::<Vec<_>>();
+
+    // Skip building this cache if not enough segments
+    if segment_indices.len() < build_settings.min_triangles {
+        return Err(BvhCacheBuildError::NotEnoughTriangles(
+            segment_indices.len(),
+        ));
+    }
+
+    let segments = segment_indices
+        .iter()
+        .map(|(_, [a, b])| [Vec3::from(positions[*a]), Vec3::from(positions[*b])])
+        .collect();
+    let vertex_indices = segment_indices
+        .iter()
+        .map(|(_, [a, b])| [*a as u32, *b as u32])
+        .collect();
+    let segment_indices = segment_indices
+        .iter()
+        .map(|(segment_index, _)| *segment_index as u32)
+        .collect();
+    Ok(LineCache::build(
+        segments,
+        segment_indices,
+        vertex_indices,
+        build_settings,
+    ))
+}
+
+/// Returns the segment of a mesh of lines nearest to the ray, within the `tolerance` and before
+/// `max_distance`, using its cache.
+pub fn ray_nearest_segment_using_line_cache(
+    transform: &Mat4,
+    ray: Ray3d,
+    tolerance: &PickTolerance,
+    cache: &LineCache,
+    max_distance: f32,
+) -> Option<RaySegmentHit> {
+    let mut nearest_hit: Option<RaySegmentHit> = None;
+    traverse_nearest_to_ray(
+        &cache.bvh,
+        transform,
+        &ray,
+        tolerance,
+        max_distance,
+        |primitive| {
+            let [start, end] = cache.segments[primitive];
+            let segment = [
+                transform.transform_point3(start),
+                transform.transform_point3(end),
+            ];
+            let [a, b] = cache.vertex_indices[primitive];
+            if let Some(hit) = ray_segment_hit(
+                &ray,
+                tolerance,
+                max_distance,
+                cache.segment_indices[primitive] as usize,
+                [a as usize, b as usize],
+                &segment,
+            ) {
+                if nearest_hit
+                    .as_ref()
+                    .is_none_or(|nearest_hit| hit.is_nearer_than(nearest_hit))
+                {
+                    nearest_hit = Some(hit);
+                }
+            }
+            nearest_hit
+                .as_ref()
+                .map_or(f32::INFINITY, |nearest_hit| nearest_hit.metric)
+        },
+    );
+    nearest_hit
+}
+
+/// Casts a ray on a mesh of lines, and returns all the segments closer than `pick_radius` to the
+/// ray before `max_distance`, nearest first, using its cache. The hits are the same as
+/// [`ray_intersections_over_lines_and_points`](crate::ray_cast::intersections::ray_intersections_over_lines_and_points).
+pub fn ray_intersections_using_line_cache(
+    transform: &Mat4,
+    ray: Ray3d,
+    pick_radius: f32,
+    cache: &LineCache,
+    max_distance: f32,
+) -> Vec<RayMeshHit> {
+    let mut hits = Vec::new();
+    traverse_nearest_to_ray(
+        &cache.bvh,
+        transform,
+        &ray,
+        &PickTolerance::World(pick_radius),
+        max_distance,
+        |primitive| {
+            let [start, end] = cache.segments[primitive];
+            let segment = [
+                transform.transform_point3(start),
+                transform.transform_point3(end),
+            ];
+            let segment_index = cache.segment_indices[primitive] as usize;
+            hits.extend(ray_segment_mesh_hit(
+                &ray,
+                pick_radius,
+                max_distance,
+                segment_index,
+                &segment,
+            ));
+            // Every segment within the radius is hit, none of the nodes is skipped
+            f32::INFINITY
+        },
+    );
+    sort_hits(&mut hits);
+    hits
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy_asset::RenderAssetUsages;
+    use bevy_math::prelude::*;
+
+    use crate::ray_cast::intersections::{
+        ray_intersections_over_lines_and_points, ray_nearest_segment_over_mesh,
+    };
+
+    use super::*;
+
+    #[test]
+    fn cache_matches_mesh() {
+        // A helix, as a strip and as a list of lines
+        let positions = (0..500)
+            .map(|i| {
+                let i = i as f32;
+                [(i * 0.1).cos() * 2.0, i * 0.008 - 2.0, (i * 0.1).sin()]
+            })
+            .collect::<Vec<_>>();
+        let transform = Mat4::from_scale_rotation_translation(
+            Vec3::new(1.0, 2.0, 1.5),
+            Quat::from_rotation_y(0.3),
+            Vec3::new(0.5, -0.2, 0.0),
+        );
+
+        for topology in [PrimitiveTopology::LineStrip, PrimitiveTopology::LineList] {
+            let mut mesh = Mesh::new(topology, RenderAssetUsages::all());
+            mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, positions.clone());
+            let cache = build_line_cache(&mesh, &ObvhsBuildSettings::default()).unwrap();
+
+            for tolerance in [PickTolerance::World(0.05), PickTolerance::Angular(0.01)] {
+                let mut hit_count = 0;
+                for i in 0..64 {
+                    let target =
+                        Vec3::new((i % 8) as f32 * 0.5 - 2.0, (i / 8) as f32 * 0.5 - 2.0, 0.0);
+                    let origin = Vec3::new(0.0, 0.0, 5.0);
+                    let ray = Ray3d::new(origin, Dir3::new(target - origin).unwrap());
+                    let mesh_hit = ray_nearest_segment_over_mesh(
+                        &mesh,
+                        &transform,
+                        ray,
+                        &tolerance,
+                        f32::INFINITY,
+                    );
+                    let cache_hit = ray_nearest_segment_using_line_cache(
+                        &transform,
+                        ray,
+                        &tolerance,
+                        &cache,
+                        f32::INFINITY,
+                    );
+                    assert_eq!(
+                        mesh_hit.as_ref().map(|hit| hit.segment_index),
+                        cache_hit.map(|hit| hit.segment_index)
+                    );
+                    hit_count += mesh_hit.is_some() as usize;
+
+                    let mesh_hits = ray_intersections_over_lines_and_points(
+                        &mesh,
+                        &transform,
+                        ray,
+                        0.05,
+                        f32::INFINITY,
+                    );
+                    let cache_hits = ray_intersections_using_line_cache(
+                        &transform,
+                        ray,
+                        0.05,
+                        &cache,
+                        f32::INFINITY,
+                    );
+                    assert_eq!(
+                        mesh_hits
+                            .iter()
+                            .map(|hit| hit.triangle_index)
+                            .collect::<Vec<_>>(),
+                        cache_hits
+                            .iter()
+                            .map(|hit| hit.triangle_index)
+                            .collect::<Vec<_>>()
+                    );
+                }
+                assert!(hit_count > 0);
+            }
+        }
+    }
+}
@@ -1,128 +1,665 @@
 use bevy_asset::prelude::*;
-use bevy_ecs::{prelude::*, world::CommandQueue};
+use bevy_ecs::{prelude::*, system::SystemParam, world::CommandQueue};
 use bevy_log::prelude::*;
-use bevy_render::{
-    mesh::{Indices, PrimitiveTopology},
-    prelude::*,
-};
+use bevy_math::{FloatOrd, Mat4, Ray3d, Vec3, Vec3A};
+use bevy_render::{mesh::PrimitiveTopology, prelude::*};
 use bevy_tasks::prelude::*;
+use bevy_utils::{HashMap, HashSet};
 use obvhs::{
-    bvh2::{builder::build_bvh2_from_tris, Bvh2},
+    aabb::Aabb,
+    bvh2::{builder::build_bvh2_from_tris, Bvh2, Bvh2Node},
     triangle::Triangle as ObvhTriangle,
-    BvhBuildParams,
 };
 
+use std::{cmp::Reverse, collections::BinaryHeap, mem::size_of};
+
 #[cfg(target_arch = "wasm32")]
-use web_time::Duration;
+use web_time::{Duration, Instant};
 #[cfg(not(target_arch = "wasm32"))]
 use core::time::Duration;
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::Instant;
 
 use crate::{
-    common::{get_triangles, triangle::Triangle},
-    storage::{AssetBvhCache, AssetsBvhCaches},
-    ComputeBvhCache,
+    common::{attribute::set_triangle_uvs, mesh_triangles, triangle::Triangle},
+    obvhs::triangles::{quantize_positions, CacheTriangles},
+    ray_cast::intersections::{ray_sphere_point_metric, PickTolerance},
+    settings::{
+        BvhBuildHint, BvhBuildHints, ObvhsBuildQuality, ObvhsBuildSettings, ObvhsCacheLayout,
+        PositionPrecision,
+    },
+    storage::{
+        file::{mesh_hash, BvhCacheFileError, CacheReader, CacheWriter},
+        AssetBvhCache, AssetsBvhCaches, BvhCacheAssetStatus, RenderWorldMeshes, SharedBvhCaches,
+    },
+    cancel_bvh_cache_tasks, collect_bvh_cache_updates, compute_triangle_cache_assets,
+    finish_bvh_cache_build, BvhBackend, BvhCacheBuildError, BvhCacheUpdate, ComputeBvhCache,
+    PickingBvhBackend, TriangleCacheAssets, TriangleCacheBuildSettings,
 };
 
+pub mod lines;
+pub mod morph;
+pub mod packet;
+pub mod point_cloud;
 pub mod ray_cast;
+pub mod skinned;
+pub mod tlas;
+pub mod triangles;
 
 pub struct ObvhsBvh2Cache {
     pub bvh: Bvh2,
-    pub triangles: Vec<Triangle>,
+    /// The triangles, in the order of the leaves of the tree.
+    pub triangles: CacheTriangles,
+    layout: ObvhsCacheLayout,
+}
+
+impl ObvhsBvh2Cache {
+    /// Builds the tree over the `triangles`, stored with the default layout.
+    pub fn build(triangles: Vec<Triangle>, quality: &ObvhsBuildQuality) -> Self {
+        Self::build_with_layout(triangles, quality, &ObvhsCacheLayout::default())
+    }
+
+    /// Builds the tree over the `triangles`, stored with the `layout`.
+    pub fn build_with_layout(
+        mut triangles: Vec<Triangle>,
+        quality: &ObvhsBuildQuality,
+        layout: &ObvhsCacheLayout,
+    ) -> Self {
+        let quantization = quantize_positions(&mut triangles, layout);
+        let obvhs_triangles = triangles
+            .iter()
+            .map(|t| ObvhTriangle {
+                v0: t.positions[0].into(),
+                v1: t.positions[1].into(),
+                v2: t.positions[2].into(),
+            })
+            .collect::<Vec<_>>();
+
+        let mut bvh = build_bvh2_from_tris(
+            &obvhs_triangles,
+            quality.build_params(),
+            &mut Duration::default(),
+        );
+        order_bvh2_nodes(&mut bvh);
+
+        // Store the triangles in the order of the leaves, so that the primitive indices of the tree
+        // are the slots of the triangles. Split triangles are in several leaves, but stored once.
+        let mut slots = vec![u32::MAX; triangles.len()];
+        let mut order = Vec::with_capacity(triangles.len());
+        for primitive in &mut bvh.primitive_indices {
+            let slot = &mut slots[*primitive as usize];
+            if *slot == u32::MAX {
+                *slot = order.len() as u32;
+                order.push(*primitive);
+            }
+            *primitive = *slot;
+        }
+        let triangles = CacheTriangles::new(&triangles, &order, layout, quantization);
+
+        Self {
+            bvh,
+            triangles,
+            layout: layout.clone(),
+        }
+    }
+
+    /// Replaces the positions and normals of the triangles in place by their deformed version, and
+    /// refits the tree to them.
+    ///
+    /// The `triangles` must be the ones the tree was built with, in the order of the mesh. Returns
+    /// `false` if they are not, or if the positions of the cache are quantized: the cache must then be
+    /// rebuilt.
+    pub fn refit(&mut self, triangles: &[Triangle]) -> bool {
+        if !self.triangles.update_vertices(triangles) {
+            return false;
+        }
+        refit_bvh2(&mut self.bvh, |primitive| {
+            self.triangles
+                .positions(primitive)
+                .map_or(Aabb::empty(), triangle_aabb)
+        });
+        true
+    }
+
+    /// Writes the cache to a baked cache file.
+    pub(crate) fn write(&self, writer: &mut CacheWriter) {
+        writer.bool(self.layout.indexed);
+        writer.u8(match self.layout.precision {
+            PositionPrecision::Full => 0,
+            PositionPrecision::Quantized => 1,
+        });
+        writer.bool(self.layout.normals);
+        write_bvh2(writer, &self.bvh);
+        self.triangles.write(writer);
+    }
+
+    /// Reads a cache written by [`ObvhsBvh2Cache::write`].
+    pub(crate) fn read(reader: &mut CacheReader) -> Result<Self, BvhCacheFileError> {
+        let layout = ObvhsCacheLayout {
+            indexed: reader.bool()?,
+            precision: match reader.u8()? {
+                0 => PositionPrecision::Full,
+                1 => PositionPrecision::Quantized,
+                _ => return Err(BvhCacheFileError::Corrupted),
+            },
+            normals: reader.bool()?,
+        };
+        let bvh = read_bvh2(reader)?;
+        let triangles = CacheTriangles::read(reader)?;
+        if !is_valid_bvh2(&bvh, triangles.len()) {
+            return Err(BvhCacheFileError::Corrupted);
+        }
+        Ok(Self {
+            bvh,
+            triangles,
+            layout,
+        })
+    }
+}
+
+impl AssetBvhCache for ObvhsBvh2Cache {
+    fn triangle_count(&self) -> usize {
+        self.triangles.len()
+    }
+
+    fn memory_footprint(&self) -> usize {
+        bvh2_memory_footprint(&self.bvh) + self.triangles.memory_footprint()
+    }
+}
+
+/// Detect new, modified and removed assets and generate, regenerate or drop their BVH tree
+pub fn compute_obvhs_bvh2_cache_assets(params: TriangleCacheAssets<ObvhsBvh2Cache>) {
+    compute_triangle_cache_assets(
+        params,
+        BvhBackend::ObvhsBvh2,
+        |build_settings, build_hint| {
+            let mut build_settings = build_settings.obvhs.clone();
+            if let Some(quality) =
+                build_hint.and_then(|build_hint| build_hint.obvhs_quality.clone())
+            {
+                build_settings.quality = quality;
+            }
+            build_settings
+        },
+        build_bvh2_cache,
+    );
 }
 
-impl AssetBvhCache for ObvhsBvh2Cache {}
+impl TriangleCacheBuildSettings for ObvhsBuildSettings {
+    fn build_any_mesh(&mut self) {
+        self.min_triangles = 0;
+    }
+
+    fn load_baked_caches(&self) -> bool {
+        self.load_baked_caches
+    }
+}
+
+pub(crate) fn build_bvh2_cache(
+    mesh: &Mesh,
+    build_settings: &ObvhsBuildSettings,
+) -> Result<ObvhsBvh2Cache, BvhCacheBuildError> {
+    let mut triangles = mesh_triangles(mesh)?;
+
+    // Skip building this cache if not enough triangles
+    if triangles.len() < build_settings.min_triangles {
+        return Err(BvhCacheBuildError::NotEnoughTriangles(triangles.len()));
+    }
+    if build_settings.store_uvs {
+        set_triangle_uvs(mesh, &mut triangles);
+    }
+
+    Ok(ObvhsBvh2Cache::build_with_layout(
+        triangles,
+        &build_settings.quality,
+        &build_settings.layout,
+    ))
+}
+
+/// The resources and queries used to build the caches of the meshes of lines or points.
+#[derive(SystemParam)]
+pub struct PrimitiveCacheAssets<'w, 's, B: AssetBvhCache> {
+    commands: Commands<'w, 's>,
+    asset_events: EventReader<'w, 's, AssetEvent<Mesh>>,
+    meshes: Res<'w, Assets<Mesh>>,
+    bvh_caches: ResMut<'w, AssetsBvhCaches<Mesh, B>>,
+    compute_tasks: Query<'w, 's, (Entity, &'static ComputeBvhCache)>,
+    picking_bvh_backend: Res<'w, PickingBvhBackend>,
+    build_hints: BvhBuildHints<'w, 's>,
+    render_world_meshes: Res<'w, RenderWorldMeshes>,
+    applied_build_hints: Local<'s, HashMap<AssetId<Mesh>, BvhBuildHint>>,
+}
 
-/// Detect new assets and generate BVH tree
-pub fn compute_obvhs_bvh2_cache_assets(
-    mut commands: Commands,
-    mut asset_events: EventReader<AssetEvent<Mesh>>,
-    meshes: Res<Assets<Mesh>>,
+/// Detect new, modified and removed meshes whose topology is accepted by `has_primitives`, and
+/// generate, regenerate or drop their `B` cache with `build`.
+fn compute_primitive_cache_assets<B: AssetBvhCache>(
+    params: PrimitiveCacheAssets<B>,
+    has_primitives: impl Fn(PrimitiveTopology) -> bool,
+    build: fn(&Mesh, &ObvhsBuildSettings) -> Result<B, BvhCacheBuildError>,
 ) {
+    let PrimitiveCacheAssets {
+        mut commands,
+        mut asset_events,
+        meshes,
+        mut bvh_caches,
+        compute_tasks,
+        picking_bvh_backend,
+        mut build_hints,
+        render_world_meshes,
+        mut applied_build_hints,
+    } = params;
     let thread_pool = AsyncComputeTaskPool::get();
 
-    for ev in asset_events.read() {
-        match ev {
-            AssetEvent::Added { id } => {
-                let Some(mesh) = meshes.get(*id) else {
-                    warn!("Missing mesh for mesh {}", id);
-                    continue;
-                };
-
-                let task_entity = commands.spawn_empty().id();
-                let task = thread_pool.spawn({
-                    // We need to clone the mesh to be able to process it asynchronously
-                    let mesh = mesh.clone();
-                    let asset_id = id.clone();
-                    async move {
-                        let mut command_queue = CommandQueue::default();
-
-                        let build_obvhs_bvh2_cache = info_span!("build_obvhs_bvh2_cache");
-                        let build_obvhs_bvh2_cache_guard = build_obvhs_bvh2_cache.enter();
-                        let bvh_cache = build_bvh2_cache(&mesh);
-                        drop(build_obvhs_bvh2_cache_guard);
-
-                        if let Some(bvh_cache) = bvh_cache {
-                            command_queue.push(move |world: &mut World| {
-                                let mut bvh_caches =
-                                    world.resource_mut::<AssetsBvhCaches<Mesh, ObvhsBvh2Cache>>();
-                                bvh_caches.insert(asset_id, bvh_cache);
-                            })
-                        }
-
-                        command_queue
-                    }
+    let mut updates = collect_bvh_cache_updates(&mut asset_events, &render_world_meshes);
+    // Rebuild the meshes whose hint changed since their last build
+    let changed_hints = build_hints.changed();
+    let hints = build_hints.by_mesh();
+    for id in changed_hints {
+        if meshes.contains(id) && applied_build_hints.get(&id) != hints.get(&id).copied() {
+            updates.entry(id).or_insert(BvhCacheUpdate::Build);
+        }
+    }
+
+    for (id, update) in updates {
+        // An older build must never overwrite the cache of a newer version of the mesh
+        cancel_bvh_cache_tasks(&mut commands, &compute_tasks, &mut bvh_caches, id);
+
+        // The current cache (if any) is stale, drop it so that picking falls back to the mesh
+        let previous_cache = bvh_caches.remove(id);
+        bvh_caches.remove_status(id);
+
+        if update == BvhCacheUpdate::Evict {
+            applied_build_hints.remove(&id);
+            continue;
+        }
+        let Some(mesh) = meshes.get(id).or_else(|| render_world_meshes.get(id)) else {
+            continue;
+        };
+        if !has_primitives(mesh.primitive_topology()) {
+            continue;
+        }
+        let build_hint = hints.get(&id).copied();
+        match build_hint {
+            Some(build_hint) => applied_build_hints.insert(id, build_hint.clone()),
+            None => applied_build_hints.remove(&id),
+        };
+        if build_hint.is_some_and(|build_hint| !build_hint.builds(&BvhBackend::ObvhsBvh2)) {
+            bvh_caches.set_status(id, BvhCacheAssetStatus::Skipped);
+            continue;
+        }
+
+        let mut build_settings = picking_bvh_backend.build_settings.obvhs.clone();
+        // The meshes only used in the render world have no mesh to fall back to
+        if render_world_meshes.is_extracted(id) {
+            build_settings.min_triangles = 0;
+        }
+        let content_hash = mesh_hash(mesh);
+        let key = SharedBvhCaches::<B>::key(content_hash, &build_settings);
+        if !bvh_caches.start_build(id, content_hash, key, previous_cache) {
+            continue;
+        }
+
+        let task_entity = commands.spawn_empty().id();
+        let task = thread_pool.spawn({
+            // We need to clone the mesh to be able to process it asynchronously
+            let mesh = mesh.clone();
+            let shared_caches = bvh_caches.shared().clone();
+            async move {
+                let mut command_queue = CommandQueue::default();
+
+                let build_primitive_cache = info_span!("build_primitive_cache");
+                let build_primitive_cache_guard = build_primitive_cache.enter();
+                let start = Instant::now();
+                let bvh_cache = build(&mesh, &build_settings)
+                    .map(|bvh_cache| shared_caches.insert(key, bvh_cache));
+                let duration = start.elapsed();
+                drop(build_primitive_cache_guard);
+
+                command_queue.push(move |world: &mut World| {
+                    finish_bvh_cache_build(world, key, BvhBackend::ObvhsBvh2, bvh_cache, duration);
                 });
-                // Spawn new entity and add our new task as a component
-                commands.entity(task_entity).insert(ComputeBvhCache(task));
+
+                command_queue
             }
-            _ => {}
+        });
+        // Spawn new entity and add our new task as a component
+        commands
+            .entity(task_entity)
+            .insert(ComputeBvhCache::new::<B>(task, key));
+    }
+}
+
+/// Visits the primitives of `bvh`, in mesh space, that may be within the `tolerance` of the world
+/// space `ray` before `max_distance`.
+///
+/// The nodes are visited nearest to the ray first. `visit` is called with each primitive of the
+/// visited leaves, and returns the [`PickTolerance::metric`] of the nearest primitive found so far,
+/// so that the nodes whose primitives can't be nearer are skipped.
+pub(crate) fn traverse_nearest_to_ray(
+    bvh: &Bvh2,
+    transform: &Mat4,
+    ray: &Ray3d,
+    tolerance: &PickTolerance,
+    max_distance: f32,
+    mut visit: impl FnMut(usize) -> f32,
+) {
+    if bvh.nodes.is_empty() {
+        return;
+    }
+
+    // The node bounds are tested in world space, with the sphere enclosing their AABB
+    let max_scale = transform
+        .x_axis
+        .truncate()
+        .length()
+        .max(transform.y_axis.truncate().length())
+        .max(transform.z_axis.truncate().length());
+    // Lower bound of the metric of the primitives of a node, or `None` if none can be picked
+    let node_metric = |aabb: &Aabb| {
+        let center = transform.transform_point3(Vec3::from((aabb.min + aabb.max) * 0.5));
+        let radius = Vec3::from((aabb.max - aabb.min) * 0.5).length() * max_scale;
+        ray_sphere_point_metric(ray, tolerance, max_distance, center, radius)
+    };
+
+    let mut nearest_metric = f32::INFINITY;
+    let mut stack = vec![(0, 0.0)];
+    while let Some((node_index, metric)) = stack.pop() {
+        if metric > nearest_metric {
+            continue;
+        }
+
+        let node = &bvh.nodes[node_index];
+        let first_index = node.first_index as usize;
+        if !node.is_leaf() {
+            // Visit the nearest child first, so that farther nodes are skipped
+            let mut children = [first_index, first_index + 1]
+                .into_iter()
+                .filter_map(|child| Some((child, node_metric(&bvh.nodes[child].aabb)?)))
+                .collect::<Vec<_>>();
+            children.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+            stack.extend(children);
+            continue;
+        }
+
+        for primitive in &bvh.primitive_indices[first_index..first_index + node.prim_count as usize]
+        {
+            nearest_metric = visit(*primitive as usize);
+        }
+    }
+}
+
+/// Visits the primitives of `bvh` best first.
+///
+/// The nodes are visited by increasing `node_cost`, a lower bound of the cost of their primitives,
+/// or skipped if it is `None`. `visit` is called with each primitive of the visited leaves, and
+/// returns the cost of the best primitive found so far, so that the traversal stops once the
+/// remaining nodes can't have a better one.
+pub(crate) fn traverse_best_first(
+    bvh: &Bvh2,
+    node_cost: impl Fn(&Aabb) -> Option<f32>,
+    mut visit: impl FnMut(usize) -> f32,
+) {
+    let Some(root_cost) = bvh.nodes.first().and_then(|root| node_cost(&root.aabb)) else {
+        return;
+    };
+
+    let mut best_cost = f32::INFINITY;
+    let mut heap = BinaryHeap::from([Reverse((FloatOrd(root_cost), 0))]);
+    while let Some(Reverse((FloatOrd(cost), node_index))) = heap.pop() {
+        if cost > best_cost {
+            break;
+        }
+
+        let node = &bvh.nodes[node_index];
+        let first_index = node.first_index as usize;
+        if !node.is_leaf() {
+            for child in [first_index, first_index + 1] {
+                if let Some(cost) = node_cost(&bvh.nodes[child].aabb) {
+                    heap.push(Reverse((FloatOrd(cost), child)));
+                }
+            }
+            continue;
+        }
+
+        for primitive in &bvh.primitive_indices[first_index..first_index + node.prim_count as usize]
+        {
+            best_cost = best_cost.min(visit(*primitive as usize));
         }
     }
 }
 
-fn build_bvh2_cache(mesh: &Mesh) -> Option<ObvhsBvh2Cache> {
-    if mesh.primitive_topology() != PrimitiveTopology::TriangleList {
-        warn!("No triangle list topology");
-        return None; // ray_mesh_intersection assumes vertices are laid out in a triangle list
+/// Returns the index of the triangle at `triangle_index` in the mesh among `triangles`, which are in
+/// the order of the mesh.
+fn find_triangle(triangles: &[Triangle], triangle_index: usize) -> Option<usize> {
+    // Only the faces with a vertex out of bounds are skipped, the triangles are usually all there
+    if triangles
+        .get(triangle_index)
+        .is_some_and(|triangle| triangle.face.triangle_index == triangle_index)
+    {
+        return Some(triangle_index);
     }
+    triangles
+        .binary_search_by_key(&triangle_index, |triangle| triangle.face.triangle_index)
+        .ok()
+}
+
+/// Returns the AABB of a triangle.
+fn triangle_aabb(positions: [Vec3; 3]) -> Aabb {
+    let [a, b, c] = positions.map(Vec3A::from);
+    Aabb {
+        min: a.min(b).min(c),
+        max: a.max(b).max(c),
+    }
+}
 
-    // Vertex positions are required
-    let positions = mesh.attribute(Mesh::ATTRIBUTE_POSITION)?.as_float3()?;
+/// Returns the approximate number of bytes used by the nodes and the primitive indices of `bvh`.
+pub(crate) fn bvh2_memory_footprint(bvh: &Bvh2) -> usize {
+    bvh.nodes.capacity() * size_of::<Bvh2Node>()
+        + bvh.primitive_indices.capacity() * size_of::<u32>()
+}
+
+/// Writes the nodes and the primitive indices of `bvh` to a baked cache file.
+pub(crate) fn write_bvh2(writer: &mut CacheWriter, bvh: &Bvh2) {
+    writer.slice(&bvh.nodes, |writer, node| {
+        writer.vec3(node.aabb.min.into());
+        writer.vec3(node.aabb.max.into());
+        writer.u32(node.prim_count);
+        writer.u32(node.first_index);
+    });
+    writer.slice(&bvh.primitive_indices, |writer, primitive| {
+        writer.u32(*primitive);
+    });
+}
 
-    // Normals are optional
-    let normals = mesh
-        .attribute(Mesh::ATTRIBUTE_NORMAL)
-        .and_then(|normal_values| normal_values.as_float3());
+/// Reads a tree written by [`write_bvh2`]. Check it with [`is_valid_bvh2`] before using it.
+pub(crate) fn read_bvh2(reader: &mut CacheReader) -> Result<Bvh2, BvhCacheFileError> {
+    let nodes = reader.vec(|reader| {
+        Ok(Bvh2Node {
+            aabb: Aabb {
+                min: reader.vec3()?.into(),
+                max: reader.vec3()?.into(),
+            },
+            prim_count: reader.u32()?,
+            first_index: reader.u32()?,
+        })
+    })?;
+    let primitive_indices = reader.vec(|reader| reader.u32())?;
+    Ok(Bvh2 {
+        nodes,
+        primitive_indices,
+        ..Default::default()
+    })
+}
 
-    let triangles = if let Some(indices) = mesh.indices() {
-        match indices {
-            Indices::U16(items) => get_triangles(positions, normals, Some(items)),
-            Indices::U32(items) => get_triangles(positions, normals, Some(items)),
+/// Returns `true` if the indices of `bvh` are in bounds, with less than `primitive_count`
+/// primitives, and if it is a tree whose children follow their parent, as laid out by
+/// [`order_bvh2_nodes`]. Its traversals and refits could otherwise loop forever.
+pub(crate) fn is_valid_bvh2(bvh: &Bvh2, primitive_count: usize) -> bool {
+    let mut has_parent = vec![false; bvh.nodes.len()];
+    let valid_nodes = bvh.nodes.iter().enumerate().all(|(node_index, node)| {
+        let first_index = node.first_index as usize;
+        if node.is_leaf() {
+            return first_index
+                .checked_add(node.prim_count as usize)
+                .is_some_and(|end| end <= bvh.primitive_indices.len());
+        }
+        if first_index <= node_index || first_index + 1 >= bvh.nodes.len() {
+            return false;
         }
+        // A node with two parents would be visited twice, as would all its descendants
+        let children = &mut has_parent[first_index..first_index + 2];
+        if children.contains(&true) {
+            return false;
+        }
+        children.fill(true);
+        true
+    });
+    valid_nodes
+        && bvh
+            .primitive_indices
+            .iter()
+            .all(|primitive| (*primitive as usize) < primitive_count)
+}
+
+/// Lays out the nodes of `bvh` breadth first, so that the children of each node follow it.
+///
+/// The other fields of the tree, which are not used by the caches, are reset.
+pub(crate) fn order_bvh2_nodes(bvh: &mut Bvh2) {
+    let Some(root) = bvh.nodes.first() else {
+        return;
+    };
+    let mut nodes = Vec::with_capacity(bvh.nodes.len());
+    nodes.push(*root);
+    let mut node_index = 0;
+    while node_index < nodes.len() {
+        let node = nodes[node_index];
+        if !node.is_leaf() {
+            let first_index = node.first_index as usize;
+            nodes[node_index].first_index = nodes.len() as u32;
+            nodes.push(bvh.nodes[first_index]);
+            nodes.push(bvh.nodes[first_index + 1]);
+        }
+        node_index += 1;
+    }
+    *bvh = Bvh2 {
+        nodes,
+        primitive_indices: std::mem::take(&mut bvh.primitive_indices),
+        ..Default::default()
+    };
+}
+
+/// Refits the nodes of `bvh` after its primitives moved, keeping its topology.
+///
+/// This is much faster than a rebuild, but the quality of the tree degrades if the primitives move
+/// a lot relative to each other. The children of each node must follow it, see
+/// [`order_bvh2_nodes`]: the nodes are refitted in reverse order, without recursion.
+pub(crate) fn refit_bvh2(bvh: &mut Bvh2, primitive_aabb: impl Fn(usize) -> Aabb) {
+    for node_index in (0..bvh.nodes.len()).rev() {
+        refit_bvh2_node(bvh, node_index, &primitive_aabb);
+    }
+}
+
+/// Refits the `nodes` of `bvh` and their ancestors only, after the primitives of these nodes moved
+/// or after they were split by [`insert_bvh2_primitive`]. `parents` is the parent of each node, see
+/// [`bvh2_parents`].
+pub(crate) fn refit_bvh2_nodes(
+    bvh: &mut Bvh2,
+    parents: &[u32],
+    nodes: impl IntoIterator<Item = usize>,
+    primitive_aabb: impl Fn(usize) -> Aabb,
+) {
+    // Each ancestor is refitted once, after its children as they follow it
+    let mut refitted = HashSet::default();
+    for mut node_index in nodes {
+        while refitted.insert(node_index) {
+            match parents[node_index] {
+                NO_PARENT => break,
+                parent => node_index = parent as usize,
+            }
+        }
+    }
+    let mut refitted = refitted.into_iter().collect::<Vec<_>>();
+    refitted.sort_unstable_by(|a, b| b.cmp(a));
+    for node_index in refitted {
+        refit_bvh2_node(bvh, node_index, &primitive_aabb);
+    }
+}
+
+/// Sets the AABB of a node of `bvh` to the union of its children or of its primitives.
+fn refit_bvh2_node(bvh: &mut Bvh2, node_index: usize, primitive_aabb: &impl Fn(usize) -> Aabb) {
+    let node = &bvh.nodes[node_index];
+    let first_index = node.first_index as usize;
+    let aabb = if node.is_leaf() {
+        bvh.primitive_indices[first_index..first_index + node.prim_count as usize]
+            .iter()
+            .fold(Aabb::empty(), |aabb, primitive| {
+                aabb.union(&primitive_aabb(*primitive as usize))
+            })
     } else {
-        get_triangles::<u16>(positions, normals, None)
+        bvh.nodes[first_index]
+            .aabb
+            .union(&bvh.nodes[first_index + 1].aabb)
     };
+    bvh.nodes[node_index].aabb = aabb;
+}
 
-    // Skip building this cache if not enough triangles
-    if triangles.len() < 64 {
-        return None;
+/// The parent of the root in [`bvh2_parents`].
+pub(crate) const NO_PARENT: u32 = u32::MAX;
+
+/// Returns the parent of each node of `bvh`, [`NO_PARENT`] for the root.
+pub(crate) fn bvh2_parents(bvh: &Bvh2) -> Vec<u32> {
+    let mut parents = vec![NO_PARENT; bvh.nodes.len()];
+    for (node_index, node) in bvh.nodes.iter().enumerate() {
+        if !node.is_leaf() {
+            let first_index = node.first_index as usize;
+            parents[first_index] = node_index as u32;
+            parents[first_index + 1] = node_index as u32;
+        }
     }
+    parents
+}
 
-    let obvhs_triangles = triangles
-        .iter()
-        .map(|t| ObvhTriangle {
-            v0: t.positions[0].into(),
-            v1: t.positions[1].into(),
-            v2: t.positions[2].into(),
-        })
-        .collect::<Vec<_>>();
+/// Inserts the `primitive` with the `aabb` in `bvh` without rebuilding it: the leaf whose AABB grows
+/// the least is split into the previous leaf and a new one, appended to the nodes.
+///
+/// Returns the split node, now the parent of the previous leaf and of the new one, or `None` if the
+/// tree was empty. Its ancestors are not grown, refit them afterwards with [`refit_bvh2_nodes`].
+pub(crate) fn insert_bvh2_primitive(bvh: &mut Bvh2, primitive: u32, aabb: &Aabb) -> Option<usize> {
+    let leaf = Bvh2Node {
+        aabb: *aabb,
+        prim_count: 1,
+        first_index: bvh.primitive_indices.len() as u32,
+    };
+    bvh.primitive_indices.push(primitive);
+    if bvh.nodes.is_empty() {
+        bvh.nodes.push(leaf);
+        return None;
+    }
 
-    // TODO: make build params configurable at plugin level
-    let bvh = build_bvh2_from_tris(
-        &obvhs_triangles,
-        BvhBuildParams::medium_build(),
-        &mut Duration::default(),
-    );
+    let half_area = |aabb: &Aabb| {
+        let extent = (aabb.max - aabb.min).max(Vec3A::ZERO);
+        extent.x * extent.y + extent.y * extent.z + extent.z * extent.x
+    };
+    let growth = |node: &Bvh2Node| half_area(&node.aabb.union(aabb)) - half_area(&node.aabb);
+    let mut node_index = 0;
+    while !bvh.nodes[node_index].is_leaf() {
+        let first_index = bvh.nodes[node_index].first_index as usize;
+        node_index = if growth(&bvh.nodes[first_index]) <= growth(&bvh.nodes[first_index + 1]) {
+            first_index
+        } else {
+            first_index + 1
+        };
+    }
 
-    Some(ObvhsBvh2Cache { bvh, triangles })
+    // The children of a node are next to each other, the split leaf is moved with the new one
+    let split_leaf = bvh.nodes[node_index];
+    let first_index = bvh.nodes.len() as u32;
+    bvh.nodes.push(split_leaf);
+    bvh.nodes.push(leaf);
+    bvh.nodes[node_index] = Bvh2Node {
+        aabb: split_leaf.aabb.union(aabb),
+        prim_count: 0,
+        first_index,
+    };
+    Some(node_index)
 }
@@ -0,0 +1,274 @@
+//! BVH instances of the mesh entities with morph targets.
+//!
+//! The cache of a mesh asset is built from its base shape, which does not match the current shape
+//! of an entity with [`MeshMorphWeights`]. Each of these entities gets its own tree, built once
+//! from its morphed triangles, then refitted when its weights change.
+
+use bevy_asset::prelude::*;
+use bevy_ecs::prelude::*;
+use bevy_image::Image;
+use bevy_math::Vec3;
+use bevy_render::{
+    mesh::{
+        morph::{MeshMorphWeights, MorphAttributes},
+        skinning::SkinnedMesh,
+    },
+    prelude::*,
+};
+use bevy_utils::{tracing::*, HashMap, HashSet};
+
+use crate::{
+    common::mesh_triangles_with_vertices,
+    settings::{BvhBuildHint, DeformedBvhUpdate},
+    BvhBackend, BvhCacheBuildError, PickingBvhBackend,
+};
+
+use super::ObvhsBvh2Cache;
+
+/// The tree of a mesh entity with morph targets, in mesh space.
+pub struct MorphedMeshBvh {
+    /// The triangles are morphed in mesh space, the transform of the entity still applies.
+    pub cache: ObvhsBvh2Cache,
+    mesh_id: AssetId<Mesh>,
+}
+
+/// The trees of the mesh entities with morph targets, used by
+/// [`BvhMeshRayCast`](crate::ray_cast::BvhMeshRayCast) instead of the cache of their mesh.
+///
+/// Skinned mesh entities are morphed before being skinned, in their
+/// [`SkinnedMeshBvhs`](super::skinned::SkinnedMeshBvhs) tree.
+#[derive(Resource, Default)]
+pub struct MorphedMeshBvhs {
+    bvhs: HashMap<Entity, MorphedMeshBvh>,
+    refit_requests: HashSet<Entity>,
+}
+
+impl MorphedMeshBvhs {
+    /// Returns the tree of the morphed mesh `entity`, if it is built.
+    pub fn get(&self, entity: Entity) -> Option<&MorphedMeshBvh> {
+        self.bvhs.get(&entity)
+    }
+
+    /// Refits the tree of the morphed mesh `entity` to its current weights at the end of the frame,
+    /// when the trees are updated [`DeformedBvhUpdate::OnDemand`].
+    pub fn request_refit(&mut self, entity: Entity) {
+        self.refit_requests.insert(entity);
+    }
+}
+
+/// The vertices of a mesh with its morph targets applied.
+pub struct MorphedVertices {
+    pub positions: Vec<[f32; 3]>,
+    pub normals: Option<Vec<[f32; 3]>>,
+}
+
+/// Builds the trees of the mesh entities with morph targets, and refits them when their weights change.
+#[allow(clippy::too_many_arguments)]
+pub fn update_morphed_mesh_bvhs(
+    mut morphed_mesh_bvhs: ResMut<MorphedMeshBvhs>,
+    mut asset_events: EventReader<AssetEvent<Mesh>>,
+    meshes: Res<Assets<Mesh>>,
+    images: Res<Assets<Image>>,
+    picking_bvh_backend: Res<PickingBvhBackend>,
+    morphed_meshes: Query<
+        (
+            Entity,
+            &Mesh3d,
+            Ref<MeshMorphWeights>,
+            Option<&BvhBuildHint>,
+        ),
+        Without<SkinnedMesh>,
+    >,
+) {
+    let update_morphed_mesh_bvhs = info_span!("update_morphed_mesh_bvhs");
+    let _update_morphed_mesh_bvhs_guard = update_morphed_mesh_bvhs.enter();
+
+    let morphed_mesh_bvhs = morphed_mesh_bvhs.as_mut();
+    let build_settings = &picking_bvh_backend.build_settings.obvhs;
+    if build_settings.morphed_meshes == DeformedBvhUpdate::Disabled {
+        morphed_mesh_bvhs.bvhs.clear();
+        morphed_mesh_bvhs.refit_requests.clear();
+        return;
+    }
+
+    // The trees of modified meshes are rebuilt, as their topology may have changed
+    let modified_meshes = asset_events
+        .read()
+        .filter_map(|ev| match ev {
+            AssetEvent::Modified { id } => Some(*id),
+            _ => None,
+        })
+        .collect::<HashSet<_>>();
+
+    morphed_mesh_bvhs
+        .bvhs
+        .retain(|entity, _| morphed_meshes.contains(*entity));
+
+    for (entity, mesh3d, morph_weights, build_hint) in &morphed_meshes {
+        if build_hint.is_some_and(|build_hint| !build_hint.builds(&BvhBackend::ObvhsBvh2)) {
+            morphed_mesh_bvhs.bvhs.remove(&entity);
+            continue;
+        }
+
+        let mesh_id = mesh3d.0.id();
+        let rebuild = morphed_mesh_bvhs
+            .bvhs
+            .get(&entity)
+            .is_none_or(|bvh| bvh.mesh_id != mesh_id || modified_meshes.contains(&mesh_id));
+        let refit = match build_settings.morphed_meshes {
+            DeformedBvhUpdate::OnDemand => morphed_mesh_bvhs.refit_requests.contains(&entity),
+            _ => morph_weights.is_changed(),
+        };
+        if !rebuild && !refit {
+            continue;
+        }
+
+        let Some(mesh) = meshes.get(mesh_id) else {
+            continue;
+        };
+        let Some(morph_targets) = mesh.morph_targets().and_then(|handle| images.get(handle)) else {
+            // The mesh has no morph targets, or they are not loaded yet
+            continue;
+        };
+
+        let triangles =
+            morphed_vertices(mesh, morph_targets, morph_weights.weights()).and_then(|vertices| {
+                mesh_triangles_with_vertices(mesh, &vertices.positions, vertices.normals.as_deref())
+            });
+        let triangles = match triangles {
+            Ok(triangles) => triangles,
+            Err(error) => {
+                warn!(
+                    "Failed to morph mesh {} of {}: {:?}",
+                    mesh_id, entity, error
+                );
+                morphed_mesh_bvhs.bvhs.remove(&entity);
+                continue;
+            }
+        };
+
+        // The tree is rebuilt if the triangles can't be refitted in place
+        let refitted = !rebuild
+            && morphed_mesh_bvhs
+                .bvhs
+                .get_mut(&entity)
+                .is_some_and(|bvh| bvh.cache.refit(&triangles));
+        if !refitted {
+            morphed_mesh_bvhs.bvhs.insert(
+                entity,
+                MorphedMeshBvh {
+                    cache: ObvhsBvh2Cache::build(triangles, &build_settings.quality),
+                    mesh_id,
+                },
+            );
+        }
+    }
+
+    morphed_mesh_bvhs.refit_requests.clear();
+}
+
+/// Applies the displacements of the `morph_targets` of the `mesh`, scaled by the `weights`, to its
+/// vertex positions and normals.
+///
+/// The `morph_targets` image is laid out as built by `MorphTargetImage`: one layer per target,
+/// with the [`MorphAttributes`] of each vertex.
+pub fn morphed_vertices(
+    mesh: &Mesh,
+    morph_targets: &Image,
+    weights: &[f32],
+) -> Result<MorphedVertices, BvhCacheBuildError> {
+    let positions = mesh
+        .attribute(Mesh::ATTRIBUTE_POSITION)
+        .and_then(|position_values| position_values.as_float3())
+        .ok_or(BvhCacheBuildError::MissingPositions)?;
+    let normals = mesh
+        .attribute(Mesh::ATTRIBUTE_NORMAL)
+        .and_then(|normal_values| normal_values.as_float3());
+
+    let size = morph_targets.texture_descriptor.size;
+    let layer_len = (size.width * size.height) as usize * size_of::<f32>();
+    let target_len = positions.len() * MorphAttributes::COMPONENT_COUNT * size_of::<f32>();
+    if layer_len == 0
+        || target_len > layer_len
+        || morph_targets.data.len() < layer_len * size.depth_or_array_layers as usize
+    {
+        return Err(BvhCacheBuildError::InvalidMorphTargets);
+    }
+
+    let mut morphed_positions = positions
+        .iter()
+        .copied()
+        .map(Vec3::from)
+        .collect::<Vec<_>>();
+    let mut morphed_normals =
+        normals.map(|normals| normals.iter().copied().map(Vec3::from).collect::<Vec<_>>());
+
+    for (layer, weight) in morph_targets
+        .data
+        .chunks_exact(layer_len)
+        .zip(weights)
+        .filter(|(_, weight)| **weight != 0.0)
+    {
+        let components = layer[..target_len]
+            .chunks_exact(size_of::<f32>())
+            // The image data is the native-endian bytes of the `f32` components
+            .map(|bytes| f32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+            .collect::<Vec<_>>();
+        for (vertex, attributes) in components
+            .chunks_exact(MorphAttributes::COMPONENT_COUNT)
+            .enumerate()
+        {
+            morphed_positions[vertex] += Vec3::from_slice(&attributes[0..3]) * *weight;
+            if let Some(morphed_normals) = &mut morphed_normals {
+                morphed_normals[vertex] += Vec3::from_slice(&attributes[3..6]) * *weight;
+            }
+        }
+    }
+
+    Ok(MorphedVertices {
+        positions: morphed_positions
+            .into_iter()
+            .map(|position| position.to_array())
+            .collect(),
+        normals: morphed_normals.map(|normals| {
+            normals
+                .into_iter()
+                .map(|normal| normal.normalize_or_zero().to_array())
+                .collect()
+        }),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy_asset::RenderAssetUsages;
+    use bevy_math::prelude::*;
+    use bevy_render::mesh::morph::MorphTargetImage;
+
+    use super::*;
+
+    #[test]
+    fn morphed_vertices_apply_weights() {
+        let mesh = Mesh::from(Cuboid::default());
+        let vertex_count = mesh.count_vertices();
+        let targets = [Vec3::X, Vec3::Y].map(|displacement| {
+            (0..vertex_count)
+                .map(move |_| MorphAttributes::new(displacement, Vec3::ZERO, Vec3::ZERO))
+        });
+        let morph_targets =
+            MorphTargetImage::new(targets.into_iter(), vertex_count, RenderAssetUsages::all())
+                .unwrap()
+                .0;
+
+        let vertices = morphed_vertices(&mesh, &morph_targets, &[0.5, 2.0]).unwrap();
+
+        let base_positions = mesh
+            .attribute(Mesh::ATTRIBUTE_POSITION)
+            .and_then(|position_values| position_values.as_float3())
+            .unwrap();
+        for (base, morphed) in base_positions.iter().zip(&vertices.positions) {
+            let displacement = Vec3::from(*morphed) - Vec3::from(*base);
+            assert!(displacement.distance(Vec3::new(0.5, 2.0, 0.0)) < 1e-6);
+        }
+    }
+}
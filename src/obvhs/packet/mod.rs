@@ -0,0 +1,208 @@
+//! Packet traversal of the obvhs `Bvh2`, for coherent rays.
+//!
+//! Neighbouring rays of a camera-aligned grid visit mostly the same nodes, so a packet of rays is
+//! traversed together: each node is fetched once for the whole packet, and visited if any ray of
+//! the packet hits it.
+
+use bevy_math::{Mat4, Ray3d, Vec3A};
+use bevy_picking_more_hitinfo::mesh_picking::ray_cast::{Backfaces, RayMeshHit};
+use obvhs::aabb::Aabb;
+
+use crate::ray_cast::intersections::{
+    mesh_hit_to_world, mesh_space_distance, mesh_space_ray, triangle_intersection,
+};
+
+use super::ObvhsBvh2Cache;
+
+/// Number of rays in the packets used by [`BvhMeshRayCast::cast_coherent_rays`](crate::ray_cast::BvhMeshRayCast::cast_coherent_rays).
+pub const RAY_PACKET_SIZE: usize = 8;
+
+/// Up to `N` mesh space rays traversed together.
+struct RayPacket<const N: usize> {
+    rays: [Option<Ray3d>; N],
+    origins: [Vec3A; N],
+    inv_directions: [Vec3A; N],
+    /// The closest hit distance of each ray, the segment of the ray beyond is ignored.
+    max_distances: [f32; N],
+}
+
+impl<const N: usize> RayPacket<N> {
+    /// Returns the mask of the rays of the packet hitting the `aabb`, and the minimum entry distance.
+    fn intersect_aabb(&self, aabb: &Aabb) -> (u32, f32) {
+        let mut mask = 0;
+        let mut min_distance = f32::INFINITY;
+        for i in 0..N {
+            if self.rays[i].is_none() {
+                continue;
+            }
+            let t1 = (aabb.min - self.origins[i]) * self.inv_directions[i];
+            let t2 = (aabb.max - self.origins[i]) * self.inv_directions[i];
+            let t_near = t1.min(t2).max_element().max(0.0);
+            let t_far = t1.max(t2).min_element().min(self.max_distances[i]);
+            if t_near <= t_far {
+                mask |= 1 << i;
+                min_distance = min_distance.min(t_near);
+            }
+        }
+        (mask, min_distance)
+    }
+}
+
+/// Casts a packet of rays on a mesh, and returns the intersection of each ray before
+/// `max_distance`, using bvh cache.
+///
+/// The result is the same as casting each ray with
+/// [`ray_intersection_over_mesh_using_obvhs_bvh2_cache`](super::ray_cast::ray_intersection_over_mesh_using_obvhs_bvh2_cache),
+/// but faster when the rays are coherent. `rays` can have less than `N` rays.
+pub fn ray_packet_intersections_over_mesh_using_obvhs_bvh2_cache<const N: usize>(
+    transform: &Mat4,
+    rays: &[Ray3d],
+    culling: Backfaces,
+    cache: &ObvhsBvh2Cache,
+    max_distance: f32,
+) -> [Option<RayMeshHit>; N] {
+    assert!(
+        rays.len() <= N && N <= 32,
+        "a ray packet has at most 32 rays"
+    );
+
+    let mut packet = RayPacket::<N> {
+        rays: [None; N],
+        origins: [Vec3A::ZERO; N],
+        inv_directions: [Vec3A::ZERO; N],
+        max_distances: [0.0; N],
+    };
+    for (i, ray) in rays.iter().enumerate() {
+        let Some(mesh_space_ray) = mesh_space_ray(transform, *ray) else {
+            continue;
+        };
+        packet.origins[i] = mesh_space_ray.origin.into();
+        packet.inv_directions[i] = Vec3A::from(*mesh_space_ray.direction).recip();
+        packet.max_distances[i] = mesh_space_distance(transform, &mesh_space_ray, max_distance);
+        packet.rays[i] = Some(mesh_space_ray);
+    }
+
+    let mut closest_hits = [const { None }; N];
+    if cache.bvh.nodes.is_empty() {
+        return closest_hits;
+    }
+
+    let mut stack = vec![0];
+    while let Some(node_index) = stack.pop() {
+        let node = &cache.bvh.nodes[node_index];
+        let (mask, _) = packet.intersect_aabb(&node.aabb);
+        if mask == 0 {
+            continue;
+        }
+
+        let first_index = node.first_index as usize;
+        if !node.is_leaf() {
+            // Visit the nearest child first, so that the packet shortens its rays early
+            let (_, left_distance) = packet.intersect_aabb(&cache.bvh.nodes[first_index].aabb);
+            let (_, right_distance) = packet.intersect_aabb(&cache.bvh.nodes[first_index + 1].aabb);
+            if left_distance <= right_distance {
+                stack.push(first_index + 1);
+                stack.push(first_index);
+            } else {
+                stack.push(first_index);
+                stack.push(first_index + 1);
+            }
+            continue;
+        }
+
+        for primitive in
+            &cache.bvh.primitive_indices[first_index..first_index + node.prim_count as usize]
+        {
+            let Some(triangle) = cache.triangles.get(*primitive as usize) else {
+                continue;
+            };
+            for i in (0..N).filter(|i| mask & (1 << i) != 0) {
+                let Some(mesh_space_ray) = &packet.rays[i] else {
+                    continue;
+                };
+                let Some(hit) = triangle_intersection(
+                    &triangle.positions,
+                    &triangle.normals,
+                    packet.max_distances[i],
+                    mesh_space_ray,
+                    culling,
+                ) else {
+                    continue;
+                };
+                packet.max_distances[i] = hit.distance;
+                closest_hits[i] = Some(mesh_hit_to_world(
+                    transform,
+                    mesh_space_ray,
+                    hit,
+                    triangle.triangle_index,
+                ));
+            }
+        }
+    }
+
+    closest_hits
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy_math::prelude::*;
+    use bevy_render::mesh::Mesh;
+
+    use crate::{
+        obvhs::{build_bvh2_cache, ray_cast::ray_intersection_over_mesh_using_obvhs_bvh2_cache},
+        settings::ObvhsBuildSettings,
+    };
+
+    use super::*;
+
+    #[test]
+    fn packet_matches_single_rays() {
+        let mesh = Mesh::from(Sphere::new(1.0));
+        let cache = build_bvh2_cache(&mesh, &ObvhsBuildSettings::default()).unwrap();
+        let transform = Mat4::from_scale_rotation_translation(
+            Vec3::new(1.0, 2.0, 1.5),
+            Quat::from_rotation_y(0.3),
+            Vec3::new(0.5, -0.2, 0.0),
+        );
+
+        // A camera-aligned grid of rays, some of them missing the sphere
+        let rays = (0..16)
+            .flat_map(|y| (0..16).map(move |x| (x, y)))
+            .map(|(x, y)| {
+                let target = Vec3::new(x as f32 / 4.0 - 2.0, y as f32 / 4.0 - 2.0, 0.0);
+                let origin = Vec3::new(0.0, 0.0, 5.0);
+                Ray3d::new(origin, Dir3::new(target - origin).unwrap())
+            })
+            .collect::<Vec<_>>();
+
+        let mut hit_count = 0;
+        for rays in rays.chunks(RAY_PACKET_SIZE) {
+            let packet_hits = ray_packet_intersections_over_mesh_using_obvhs_bvh2_cache::<
+                RAY_PACKET_SIZE,
+            >(
+                &transform, rays, Backfaces::Cull, &cache, f32::INFINITY
+            );
+            for (ray, packet_hit) in rays.iter().zip(packet_hits) {
+                let single_hit = ray_intersection_over_mesh_using_obvhs_bvh2_cache(
+                    &transform,
+                    *ray,
+                    Backfaces::Cull,
+                    &cache,
+                    f32::INFINITY,
+                );
+                match (single_hit, packet_hit) {
+                    (None, None) => {}
+                    (Some(single_hit), Some(packet_hit)) => {
+                        hit_count += 1;
+                        assert!((single_hit.distance - packet_hit.distance).abs() < 1e-5);
+                        assert!(single_hit.point.distance(packet_hit.point) < 1e-5);
+                    }
+                    (single_hit, packet_hit) => {
+                        panic!("single ray hit {single_hit:?}, packet hit {packet_hit:?}")
+                    }
+                }
+            }
+        }
+        assert!(hit_count > 0);
+    }
+}
@@ -0,0 +1,294 @@
+//! BVH caches of the point clouds, the meshes with a [`PrimitiveTopology::PointList`] topology.
+//!
+//! The points have no surface to hit, so they are picked by their distance to the ray, see
+//! [`BvhMeshRayCast::pick_point`](crate::ray_cast::BvhMeshRayCast::pick_point).
+
+use bevy_math::{Mat4, Ray3d, Vec3};
+use bevy_picking_more_hitinfo::mesh_picking::ray_cast::RayMeshHit;
+use bevy_render::{mesh::PrimitiveTopology, prelude::*};
+use obvhs::{
+    aabb::Aabb,
+    bvh2::{builder::build_bvh2, Bvh2},
+};
+
+use std::mem::size_of;
+
+#[cfg(not(target_arch = "wasm32"))]
+use core::time::Duration;
+#[cfg(target_arch = "wasm32")]
+use web_time::Duration;
+
+use crate::{
+    ray_cast::intersections::{
+        ray_point_hit, ray_segment_mesh_hit, sort_hits, PickTolerance, RayPointHit,
+    },
+    settings::ObvhsBuildSettings,
+    storage::AssetBvhCache,
+    BvhCacheBuildError,
+};
+
+use super::{
+    bvh2_memory_footprint, compute_primitive_cache_assets, traverse_nearest_to_ray,
+    PrimitiveCacheAssets,
+};
+
+/// A BVH over the points of a point cloud, in mesh space.
+pub struct PointCloudCache {
+    pub bvh: Bvh2,
+    pub positions: Vec<Vec3>,
+    /// Index of each point in the mesh, its offset in the index buffer if the mesh has indices.
+    pub point_indices: Vec<u32>,
+    /// Index of the vertex of each point in the mesh.
+    pub vertex_indices: Vec<u32>,
+}
+
+impl PointCloudCache {
+    /// Builds the tree over the points at `point_indices` in the mesh, at the `positions` of the
+    /// vertices at `vertex_indices`.
+    pub fn build(
+        positions: Vec<Vec3>,
+        point_indices: Vec<u32>,
+        vertex_indices: Vec<u32>,
+        build_settings: &ObvhsBuildSettings,
+    ) -> Self {
+        let aabbs = positions
+            .iter()
+            .map(|position| Aabb {
+                min: (*position).into(),
+                max: (*position).into(),
+            })
+            .collect::<Vec<_>>();
+
+        let bvh = build_bvh2(
+            &aabbs,
+            build_settings.quality.build_params(),
+            &mut Duration::default(),
+        );
+
+        Self {
+            bvh,
+            positions,
+            point_indices,
+            vertex_indices,
+        }
+    }
+}
+
+impl AssetBvhCache for PointCloudCache {
+    fn triangle_count(&self) -> usize {
+        self.positions.len()
+    }
+
+    fn memory_footprint(&self) -> usize {
+        bvh2_memory_footprint(&self.bvh)
+            + self.positions.capacity() * size_of::<Vec3>()
+            + self.point_indices.capacity() * size_of::<u32>()
+            + self.vertex_indices.capacity() * size_of::<u32>()
+    }
+}
+
+/// Detect new, modified and removed point clouds and generate, regenerate or drop their BVH tree.
+///
+/// The other meshes are ignored, they have no status in the
+/// [`AssetsBvhCaches`](crate::storage::AssetsBvhCaches) of the point clouds.
+pub fn compute_point_cloud_cache_assets(params: PrimitiveCacheAssets<PointCloudCache>) {
+    compute_primitive_cache_assets(
+        params,
+        |topology| topology == PrimitiveTopology::PointList,
+        build_point_cloud_cache,
+    );
+}
+
+fn build_point_cloud_cache(
+    mesh: &Mesh,
+    build_settings: &ObvhsBuildSettings,
+) -> Result<PointCloudCache, BvhCacheBuildError> {
+    let positions = mesh
+        .attribute(Mesh::ATTRIBUTE_POSITION)
+        .and_then(|position_values| position_values.as_float3())
+        .ok_or(BvhCacheBuildError::MissingPositions)?;
+
+    let (point_indices, vertex_indices): (Vec<u32>, Vec<u32>) = match mesh.indices() {
+        Some(indices) => indices
+            .iter()
+            .enumerate()
+            .filter(|(_, index)| *index < positions.len())
+            .map(|(point_index, index)| (point_index as u32, index as u32))
+            .unzip(),
+        None => (0..positions.len() as u32)
+            .map(|index| (index, index))
+            .unzip(),
+    };
+
+    // Skip building this cache if not enough points
+    if vertex_indices.len() < build_settings.min_triangles {
+        return Err(BvhCacheBuildError::NotEnoughTriangles(vertex_indices.len()));
+    }
+
+    let points = vertex_indices
+        .iter()
+        .map(|index| Vec3::from(positions[*index as usize]))
+        .collect();
+    Ok(PointCloudCache::build(
+        points,
+        point_indices,
+        vertex_indices,
+        build_settings,
+    ))
+}
+
+/// Returns the point of a point cloud nearest to the ray, within the `tolerance` and before
+/// `max_distance`, using its cache.
+///
+/// The nodes are visited nearest first, and skipped when none of their points can be nearer than
+/// the nearest point found so far.
+pub fn ray_nearest_point_using_point_cloud_cache(
+    transform: &Mat4,
+    ray: Ray3d,
+    tolerance: &PickTolerance,
+    cache: &PointCloudCache,
+    max_distance: f32,
+) -> Option<RayPointHit> {
+    let mut nearest_hit: Option<RayPointHit> = None;
+    traverse_nearest_to_ray(
+        &cache.bvh,
+        transform,
+        &ray,
+        tolerance,
+        max_distance,
+        |primitive| {
+            let point = transform.transform_point3(cache.positions[primitive]);
+            let vertex_index = cache.vertex_indices[primitive] as usize;
+            if let Some(hit) = ray_point_hit(&ray, tolerance, max_distance, vertex_index, point) {
+                if nearest_hit
+                    .as_ref()
+                    .is_none_or(|nearest_hit| hit.is_nearer_than(nearest_hit))
+                {
+                    nearest_hit = Some(hit);
+                }
+            }
+            nearest_hit
+                .as_ref()
+                .map_or(f32::INFINITY, |nearest_hit| nearest_hit.metric)
+        },
+    );
+    nearest_hit
+}
+
+/// Casts a ray on a point cloud, and returns all the points closer than `pick_radius` to the ray
+/// before `max_distance`, nearest first, using its cache. The hits are the same as
+/// [`ray_intersections_over_lines_and_points`](crate::ray_cast::intersections::ray_intersections_over_lines_and_points).
+pub fn ray_intersections_using_point_cloud_cache(
+    transform: &Mat4,
+    ray: Ray3d,
+    pick_radius: f32,
+    cache: &PointCloudCache,
+    max_distance: f32,
+) -> Vec<RayMeshHit> {
+    let mut hits = Vec::new();
+    traverse_nearest_to_ray(
+        &cache.bvh,
+        transform,
+        &ray,
+        &PickTolerance::World(pick_radius),
+        max_distance,
+        |primitive| {
+            let point = transform.transform_point3(cache.positions[primitive]);
+            let point_index = cache.point_indices[primitive] as usize;
+            hits.extend(ray_segment_mesh_hit(
+                &ray,
+                pick_radius,
+                max_distance,
+                point_index,
+                &[point, point],
+            ));
+            // Every point within the radius is hit, none of the nodes is skipped
+            f32::INFINITY
+        },
+    );
+    sort_hits(&mut hits);
+    hits
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy_asset::RenderAssetUsages;
+    use bevy_math::prelude::*;
+
+    use crate::ray_cast::intersections::{
+        ray_intersections_over_lines_and_points, ray_nearest_point_over_mesh,
+    };
+
+    use super::*;
+
+    #[test]
+    fn cache_matches_mesh() {
+        let positions = (0..1000)
+            .map(|i| {
+                let i = i as f32;
+                [
+                    (i * 0.37).sin() * 2.0,
+                    (i * 0.11).cos() * 2.0,
+                    (i * 0.23).sin(),
+                ]
+            })
+            .collect::<Vec<_>>();
+        let mut mesh = Mesh::new(PrimitiveTopology::PointList, RenderAssetUsages::all());
+        mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, positions);
+        let cache = build_point_cloud_cache(&mesh, &ObvhsBuildSettings::default()).unwrap();
+        let transform = Mat4::from_scale_rotation_translation(
+            Vec3::new(1.0, 2.0, 1.5),
+            Quat::from_rotation_y(0.3),
+            Vec3::new(0.5, -0.2, 0.0),
+        );
+
+        for tolerance in [PickTolerance::World(0.05), PickTolerance::Angular(0.01)] {
+            let mut hit_count = 0;
+            for i in 0..64 {
+                let target = Vec3::new((i % 8) as f32 * 0.5 - 2.0, (i / 8) as f32 * 0.5 - 2.0, 0.0);
+                let origin = Vec3::new(0.0, 0.0, 5.0);
+                let ray = Ray3d::new(origin, Dir3::new(target - origin).unwrap());
+                let mesh_hit =
+                    ray_nearest_point_over_mesh(&mesh, &transform, ray, &tolerance, f32::INFINITY);
+                let cache_hit = ray_nearest_point_using_point_cloud_cache(
+                    &transform,
+                    ray,
+                    &tolerance,
+                    &cache,
+                    f32::INFINITY,
+                );
+                assert_eq!(
+                    mesh_hit.as_ref().map(|hit| hit.vertex_index),
+                    cache_hit.map(|hit| hit.vertex_index)
+                );
+                hit_count += mesh_hit.is_some() as usize;
+
+                let mesh_hits = ray_intersections_over_lines_and_points(
+                    &mesh,
+                    &transform,
+                    ray,
+                    0.05,
+                    f32::INFINITY,
+                );
+                let cache_hits = ray_intersections_using_point_cloud_cache(
+                    &transform,
+                    ray,
+                    0.05,
+                    &cache,
+                    f32::INFINITY,
+                );
+                assert_eq!(
+                    mesh_hits
+                        .iter()
+                        .map(|hit| hit.triangle_index)
+                        .collect::<Vec<_>>(),
+                    cache_hits
+                        .iter()
+                        .map(|hit| hit.triangle_index)
+                        .collect::<Vec<_>>()
+                );
+            }
+            assert!(hit_count > 0);
+        }
+    }
+}
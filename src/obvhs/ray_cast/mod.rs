@@ -1,32 +1,34 @@
-use bevy_math::{Dir3, Mat4, Ray3d, Vec3A};
+use bevy_math::{Mat4, Ray3d, Vec3A};
 use bevy_picking_more_hitinfo::mesh_picking::ray_cast::{Backfaces, RayMeshHit};
 use obvhs::ray::RayHit;
-use std::f32;
+use std::{cell::RefCell, f32};
 
-use crate::ray_cast::intersections::triangle_intersection;
+use crate::ray_cast::{
+    intersections::{
+        mesh_hit_to_world, mesh_space_distance, mesh_space_ray, ray_triangle_intersection,
+        sort_hits, triangle_intersection,
+    },
+    mesh_caches::TriangleQuery,
+};
 
-use super::ObvhsBvh2Cache;
+use super::{traverse_best_first, ObvhsBvh2Cache};
 
-/// Casts a ray on a mesh, and returns the intersection, using bvh cache.
+/// Casts a ray on a mesh, and returns the intersection before `max_distance`, using bvh cache.
+///
+/// The traversal is bounded by `max_distance`, nodes beyond it are never visited.
 pub fn ray_intersection_over_mesh_using_obvhs_bvh2_cache(
     transform: &Mat4,
     ray: Ray3d,
     culling: Backfaces,
     cache: &ObvhsBvh2Cache,
+    max_distance: f32,
 ) -> Option<RayMeshHit> {
-    let world_to_mesh = transform.inverse();
+    let mesh_space_ray = mesh_space_ray(transform, ray)?;
+    let mesh_max_distance = mesh_space_distance(transform, &mesh_space_ray, max_distance);
 
-    let mesh_space_ray = Ray3d::new(
-        world_to_mesh.transform_point3(ray.origin),
-        Dir3::new(world_to_mesh.transform_vector3(*ray.direction)).ok()?,
-    );
-
-    let ray = obvhs::ray::Ray::new_inf(
-        mesh_space_ray.origin.into(),
-        Vec3A::from_array(mesh_space_ray.direction.to_array()),
-    );
+    let ray = obvhs_ray(&mesh_space_ray, mesh_max_distance);
 
-    let mut closest_hit_distance = f32::MAX;
+    let mut closest_hit_distance = mesh_max_distance;
     let mut closest_hit = None;
 
     let mut ray_hit = RayHit::none();
@@ -52,27 +54,155 @@ pub fn ray_intersection_over_mesh_using_obvhs_bvh2_cache(
                 return f32::INFINITY;
             };
 
-            closest_hit = Some(RayMeshHit {
-                point: transform.transform_point3(hit.point),
-                normal: transform.transform_vector3(hit.normal),
-                barycentric_coords: hit.barycentric_coords,
-                distance: transform
-                    .transform_vector3(mesh_space_ray.direction * hit.distance)
-                    .length(),
-                triangle: hit.triangle.map(|tri| {
-                    [
-                        transform.transform_point3(tri[0]),
-                        transform.transform_point3(tri[1]),
-                        transform.transform_point3(tri[2]),
-                    ]
-                }),
-                triangle_index: Some(triangle.triangle_index),
-            });
             closest_hit_distance = hit.distance;
+            closest_hit = Some(mesh_hit_to_world(
+                transform,
+                &mesh_space_ray,
+                hit,
+                triangle.triangle_index,
+            ));
 
-            hit.distance
+            closest_hit_distance
         })
     {}
 
     closest_hit
 }
+
+/// Casts a ray on a mesh, and returns all the intersections before `max_distance`, nearest first,
+/// using bvh cache.
+pub fn ray_intersections_over_mesh_using_obvhs_bvh2_cache(
+    transform: &Mat4,
+    ray: Ray3d,
+    culling: Backfaces,
+    cache: &ObvhsBvh2Cache,
+    max_distance: f32,
+) -> Vec<RayMeshHit> {
+    let Some(mesh_space_ray) = mesh_space_ray(transform, ray) else {
+        return Vec::new();
+    };
+    let mesh_max_distance = mesh_space_distance(transform, &mesh_space_ray, max_distance);
+
+    let ray = obvhs_ray(&mesh_space_ray, mesh_max_distance);
+
+    let mut hits = Vec::new();
+    let mut ray_hit = RayHit::none();
+
+    let mut ray_traversal = cache.bvh.new_ray_traversal(ray);
+    while cache
+        .bvh
+        .ray_traverse_dynamic(&mut ray_traversal, &mut ray_hit, |_ray, id| {
+            let Some(triangle) = cache
+                .triangles
+                .get(cache.bvh.primitive_indices[id] as usize)
+            else {
+                return f32::INFINITY;
+            };
+
+            if let Some(hit) = triangle_intersection(
+                &triangle.positions,
+                &triangle.normals,
+                mesh_max_distance,
+                &mesh_space_ray,
+                culling,
+            ) {
+                hits.push(mesh_hit_to_world(
+                    transform,
+                    &mesh_space_ray,
+                    hit,
+                    triangle.triangle_index,
+                ));
+            }
+
+            // Never report a hit, so that every triangle along the ray is visited
+            f32::INFINITY
+        })
+    {}
+
+    sort_hits(&mut hits);
+    hits
+}
+
+/// Returns `true` if the ray hits any triangle of a mesh before `max_distance`, using bvh cache.
+///
+/// The traversal stops at the first hit found, which is not necessarily the closest.
+pub fn ray_any_hit_over_mesh_using_obvhs_bvh2_cache(
+    transform: &Mat4,
+    ray: Ray3d,
+    culling: Backfaces,
+    cache: &ObvhsBvh2Cache,
+    max_distance: f32,
+) -> bool {
+    let Some(mesh_space_ray) = mesh_space_ray(transform, ray) else {
+        return false;
+    };
+    let mesh_max_distance = mesh_space_distance(transform, &mesh_space_ray, max_distance);
+
+    let ray = obvhs_ray(&mesh_space_ray, mesh_max_distance);
+
+    let mut any_hit = false;
+    let mut ray_hit = RayHit::none();
+
+    let mut ray_traversal = cache.bvh.new_ray_traversal(ray);
+    while !any_hit
+        && cache
+            .bvh
+            .ray_traverse_dynamic(&mut ray_traversal, &mut ray_hit, |_ray, id| {
+                // Skip the remaining triangles of the leaf
+                if any_hit {
+                    return f32::INFINITY;
+                }
+                let Some(positions) = cache
+                    .triangles
+                    .positions(cache.bvh.primitive_indices[id] as usize)
+                else {
+                    return f32::INFINITY;
+                };
+
+                let Some(hit) = ray_triangle_intersection(&mesh_space_ray, &positions, culling)
+                else {
+                    return f32::INFINITY;
+                };
+                if hit.distance < 0.0 || hit.distance > mesh_max_distance {
+                    return f32::INFINITY;
+                }
+
+                any_hit = true;
+                hit.distance
+            })
+    {}
+
+    any_hit
+}
+
+/// Converts a mesh space ray to an obvhs ray, bounded by `max_distance`.
+fn obvhs_ray(mesh_space_ray: &Ray3d, max_distance: f32) -> obvhs::ray::Ray {
+    obvhs::ray::Ray::new(
+        mesh_space_ray.origin.into(),
+        Vec3A::from_array(mesh_space_ray.direction.to_array()),
+        0.0,
+        max_distance,
+    )
+}
+
+/// Traverses the triangles of a mesh best first with a `query`, using bvh cache.
+pub(crate) fn query_triangles_using_obvhs_bvh2_cache(
+    cache: &ObvhsBvh2Cache,
+    query: &mut impl TriangleQuery,
+) {
+    let query = RefCell::new(query);
+    traverse_best_first(
+        &cache.bvh,
+        |aabb| query.borrow().node_cost(aabb.min.into(), aabb.max.into()),
+        |slot| {
+            let mut query = query.borrow_mut();
+            match (
+                cache.triangles.positions(slot),
+                cache.triangles.triangle_index(slot),
+            ) {
+                (Some(positions), Some(triangle_index)) => query.visit(positions, triangle_index),
+                _ => f32::INFINITY,
+            }
+        },
+    );
+}
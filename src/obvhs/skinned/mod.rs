@@ -0,0 +1,372 @@
+//! BVH instances of the skinned mesh entities.
+//!
+//! The cache of a mesh asset is built from its bind pose, which does not match the current pose of
+//! an animated entity. Each skinned mesh entity gets its own tree, built once from its CPU-skinned
+//! triangles, then refitted to the current pose of its joints.
+
+use bevy_asset::prelude::*;
+use bevy_ecs::prelude::*;
+use bevy_image::Image;
+use bevy_math::{Mat3, Mat3A, Mat4, Vec3};
+use bevy_render::{
+    mesh::{
+        morph::MeshMorphWeights,
+        skinning::{SkinnedMesh, SkinnedMeshInverseBindposes},
+        VertexAttributeValues,
+    },
+    prelude::*,
+    primitives::Aabb,
+};
+use bevy_transform::components::GlobalTransform;
+use bevy_utils::{tracing::*, HashMap, HashSet};
+
+use crate::{
+    common::{mesh_triangles_with_vertices, triangle::Triangle},
+    settings::{BvhBuildHint, DeformedBvhUpdate},
+    BvhBackend, BvhCacheBuildError, PickingBvhBackend,
+};
+
+use super::{
+    morph::{morphed_vertices, MorphedVertices},
+    ObvhsBvh2Cache,
+};
+
+/// The tree of a skinned mesh entity, in world space.
+pub struct SkinnedMeshBvh {
+    /// The triangles are skinned in world space, as the skinned mesh ignores the transform of the
+    /// entity.
+    pub cache: ObvhsBvh2Cache,
+    mesh_id: AssetId<Mesh>,
+}
+
+/// The trees of the skinned mesh entities, used by
+/// [`BvhMeshRayCast`](crate::ray_cast::BvhMeshRayCast) instead of the cache of their mesh.
+///
+/// The broad phase and the [`MeshEntitiesTlas`](crate::obvhs::tlas::MeshEntitiesTlas) grow the
+/// [`Aabb`] of the entities, which encloses their bind pose, to enclose their tree in its current
+/// pose, see [`SkinnedMeshBvhs::world_aabb`].
+#[derive(Resource, Default)]
+pub struct SkinnedMeshBvhs {
+    bvhs: HashMap<Entity, SkinnedMeshBvh>,
+    refit_requests: HashSet<Entity>,
+}
+
+impl SkinnedMeshBvhs {
+    /// Returns the tree of the skinned mesh `entity`, if it is built.
+    pub fn get(&self, entity: Entity) -> Option<&SkinnedMeshBvh> {
+        self.bvhs.get(&entity)
+    }
+
+    /// Refits the tree of the skinned mesh `entity` to its current pose at the end of the frame,
+    /// when the trees are updated [`DeformedBvhUpdate::OnDemand`].
+    pub fn request_refit(&mut self, entity: Entity) {
+        self.refit_requests.insert(entity);
+    }
+
+    /// Returns the skinned mesh entities whose tree is built.
+    pub fn entities(&self) -> impl Iterator<Item = Entity> + '_ {
+        self.bvhs.keys().copied()
+    }
+
+    /// Returns the world-space AABB enclosing both the local `aabb` of the skinned mesh `entity`
+    /// with its `transform`, and its tree in its current pose, if it is built.
+    pub fn world_aabb(&self, entity: Entity, aabb: &Aabb, transform: &Mat4) -> Option<Aabb> {
+        let root = &self.bvhs.get(&entity)?.cache.bvh.nodes.first()?.aabb;
+        let center = transform.transform_point3a(aabb.center);
+        let half_extents = Mat3A::from_mat4(*transform).abs() * aabb.half_extents;
+        Some(Aabb::from_min_max(
+            (center - half_extents).min(root.min).into(),
+            (center + half_extents).max(root.max).into(),
+        ))
+    }
+}
+
+/// Builds the trees of the skinned mesh entities, and refits them to the current pose of their joints.
+///
+/// The morph targets of the entities with [`MeshMorphWeights`] are applied before skinning.
+#[allow(clippy::too_many_arguments)]
+pub fn update_skinned_mesh_bvhs(
+    mut skinned_mesh_bvhs: ResMut<SkinnedMeshBvhs>,
+    mut asset_events: EventReader<AssetEvent<Mesh>>,
+    meshes: Res<Assets<Mesh>>,
+    images: Res<Assets<Image>>,
+    inverse_bindposes: Res<Assets<SkinnedMeshInverseBindposes>>,
+    picking_bvh_backend: Res<PickingBvhBackend>,
+    skinned_meshes: Query<(
+        Entity,
+        &Mesh3d,
+        &SkinnedMesh,
+        Option<&MeshMorphWeights>,
+        Option<&BvhBuildHint>,
+    )>,
+    joints: Query<&GlobalTransform>,
+) {
+    let update_skinned_mesh_bvhs = info_span!("update_skinned_mesh_bvhs");
+    let _update_skinned_mesh_bvhs_guard = update_skinned_mesh_bvhs.enter();
+
+    let skinned_mesh_bvhs = skinned_mesh_bvhs.as_mut();
+    let build_settings = &picking_bvh_backend.build_settings.obvhs;
+    if build_settings.skinned_meshes == DeformedBvhUpdate::Disabled {
+        skinned_mesh_bvhs.bvhs.clear();
+        skinned_mesh_bvhs.refit_requests.clear();
+        return;
+    }
+
+    // The trees of modified meshes are rebuilt, as their topology may have changed
+    let modified_meshes = asset_events
+        .read()
+        .filter_map(|ev| match ev {
+            AssetEvent::Modified { id } => Some(*id),
+            _ => None,
+        })
+        .collect::<HashSet<_>>();
+
+    skinned_mesh_bvhs
+        .bvhs
+        .retain(|entity, _| skinned_meshes.contains(*entity));
+
+    for (entity, mesh3d, skinned_mesh, morph_weights, build_hint) in &skinned_meshes {
+        if build_hint.is_some_and(|build_hint| !build_hint.builds(&BvhBackend::ObvhsBvh2)) {
+            skinned_mesh_bvhs.bvhs.remove(&entity);
+            continue;
+        }
+
+        let mesh_id = mesh3d.0.id();
+        let rebuild = skinned_mesh_bvhs
+            .bvhs
+            .get(&entity)
+            .is_none_or(|bvh| bvh.mesh_id != mesh_id || modified_meshes.contains(&mesh_id));
+        if !rebuild
+            && build_settings.skinned_meshes == DeformedBvhUpdate::OnDemand
+            && !skinned_mesh_bvhs.refit_requests.contains(&entity)
+        {
+            continue;
+        }
+
+        let (Some(mesh), Some(inverse_bindposes)) = (
+            meshes.get(mesh_id),
+            inverse_bindposes.get(&skinned_mesh.inverse_bindposes),
+        ) else {
+            continue;
+        };
+        let Some(joint_matrices) = skinned_mesh
+            .joints
+            .iter()
+            .zip(inverse_bindposes.iter())
+            .map(|(joint, inverse_bindpose)| {
+                let joint_transform = joints.get(*joint).ok()?;
+                Some(joint_transform.compute_matrix() * *inverse_bindpose)
+            })
+            .collect::<Option<Vec<_>>>()
+        else {
+            continue;
+        };
+
+        // Morph targets that are not loaded yet are ignored
+        let morphed = match (
+            morph_weights,
+            mesh.morph_targets().and_then(|handle| images.get(handle)),
+        ) {
+            (Some(morph_weights), Some(morph_targets))
+                if build_settings.morphed_meshes != DeformedBvhUpdate::Disabled =>
+            {
+                Some(morphed_vertices(
+                    mesh,
+                    morph_targets,
+                    morph_weights.weights(),
+                ))
+            }
+            _ => None,
+        }
+        .transpose();
+
+        let triangles = match morphed
+            .and_then(|morphed| skinned_mesh_triangles(mesh, morphed.as_ref(), &joint_matrices))
+        {
+            Ok(triangles) => triangles,
+            Err(error) => {
+                warn!("Failed to skin mesh {} of {}: {:?}", mesh_id, entity, error);
+                skinned_mesh_bvhs.bvhs.remove(&entity);
+                continue;
+            }
+        };
+
+        // The tree is rebuilt if the triangles can't be refitted in place
+        let refitted = !rebuild
+            && skinned_mesh_bvhs
+                .bvhs
+                .get_mut(&entity)
+                .is_some_and(|bvh| bvh.cache.refit(&triangles));
+        if !refitted {
+            skinned_mesh_bvhs.bvhs.insert(
+                entity,
+                SkinnedMeshBvh {
+                    cache: ObvhsBvh2Cache::build(triangles, &build_settings.quality),
+                    mesh_id,
+                },
+            );
+        }
+    }
+
+    skinned_mesh_bvhs.refit_requests.clear();
+}
+
+/// Skins the vertices of the `mesh` with the `joint_matrices` (joint transform times inverse
+/// bindpose), and returns its triangles in world space.
+///
+/// The `morphed` vertices are skinned instead of the ones of the mesh, if any.
+pub fn skinned_mesh_triangles(
+    mesh: &Mesh,
+    morphed: Option<&MorphedVertices>,
+    joint_matrices: &[Mat4],
+) -> Result<Vec<Triangle>, BvhCacheBuildError> {
+    let (positions, normals) = match morphed {
+        Some(morphed) => (morphed.positions.as_slice(), morphed.normals.as_deref()),
+        None => (
+            mesh.attribute(Mesh::ATTRIBUTE_POSITION)
+                .and_then(|position_values| position_values.as_float3())
+                .ok_or(BvhCacheBuildError::MissingPositions)?,
+            mesh.attribute(Mesh::ATTRIBUTE_NORMAL)
+                .and_then(|normal_values| normal_values.as_float3()),
+        ),
+    };
+    let (
+        Some(VertexAttributeValues::Uint16x4(joint_indices)),
+        Some(VertexAttributeValues::Float32x4(joint_weights)),
+    ) = (
+        mesh.attribute(Mesh::ATTRIBUTE_JOINT_INDEX),
+        mesh.attribute(Mesh::ATTRIBUTE_JOINT_WEIGHT),
+    )
+    else {
+        return Err(BvhCacheBuildError::MissingJoints);
+    };
+    if joint_indices.len() != positions.len() || joint_weights.len() != positions.len() {
+        return Err(BvhCacheBuildError::MissingJoints);
+    }
+
+    // Same as the `skin_model` function of the skinning shader
+    let skin_matrices = joint_indices
+        .iter()
+        .zip(joint_weights)
+        .map(|(indices, weights)| {
+            indices
+                .iter()
+                .zip(weights)
+                .fold(Mat4::ZERO, |matrix, (index, weight)| {
+                    let joint_matrix = joint_matrices
+                        .get(*index as usize)
+                        .copied()
+                        .unwrap_or(Mat4::IDENTITY);
+                    matrix + joint_matrix * *weight
+                })
+        })
+        .collect::<Vec<_>>();
+
+    let skinned_positions = positions
+        .iter()
+        .zip(&skin_matrices)
+        .map(|(position, matrix)| matrix.transform_point3(Vec3::from(*position)).to_array())
+        .collect::<Vec<_>>();
+    let skinned_normals = normals.map(|normals| {
+        normals
+            .iter()
+            .zip(&skin_matrices)
+            .map(|(normal, matrix)| {
+                let normal_matrix = Mat3::from_mat4(*matrix).inverse().transpose();
+                (normal_matrix * Vec3::from(*normal))
+                    .normalize_or_zero()
+                    .to_array()
+            })
+            .collect::<Vec<_>>()
+    });
+
+    mesh_triangles_with_vertices(mesh, &skinned_positions, skinned_normals.as_deref())
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy_math::{prelude::*, Ray3d};
+    use bevy_picking_more_hitinfo::mesh_picking::ray_cast::Backfaces;
+
+    use super::*;
+    use crate::{
+        obvhs::ray_cast::ray_intersection_over_mesh_using_obvhs_bvh2_cache,
+        settings::ObvhsBuildQuality,
+    };
+
+    /// A plane whose vertices are all bound to a single joint.
+    fn single_joint_plane() -> Mesh {
+        let mut mesh: Mesh = Plane3d::default().mesh().size(2.0, 2.0).into();
+        let vertex_count = mesh.count_vertices();
+        mesh.insert_attribute(
+            Mesh::ATTRIBUTE_JOINT_INDEX,
+            VertexAttributeValues::Uint16x4(vec![[0; 4]; vertex_count]),
+        );
+        mesh.insert_attribute(
+            Mesh::ATTRIBUTE_JOINT_WEIGHT,
+            vec![[1.0_f32, 0.0, 0.0, 0.0]; vertex_count],
+        );
+        mesh
+    }
+
+    #[test]
+    fn skinned_hits_follow_joints() {
+        let mesh = single_joint_plane();
+
+        let ray = Ray3d::new(Vec3::new(0.5, 5.0, 0.0), Dir3::NEG_Y);
+        let cast = |cache: &ObvhsBvh2Cache| {
+            ray_intersection_over_mesh_using_obvhs_bvh2_cache(
+                &Mat4::IDENTITY,
+                ray,
+                Backfaces::Cull,
+                cache,
+                f32::INFINITY,
+            )
+            .unwrap()
+        };
+
+        let bind_pose = skinned_mesh_triangles(&mesh, None, &[Mat4::IDENTITY]).unwrap();
+        let mut cache = ObvhsBvh2Cache::build(bind_pose, &ObvhsBuildQuality::default());
+        let hit = cast(&cache);
+        assert!(hit.point.distance(Vec3::new(0.5, 0.0, 0.0)) < 1e-5);
+
+        // The joint moves up and turns the plane around X, the tree is refitted to the new pose
+        let joint = Mat4::from_translation(Vec3::Y * 2.0) * Mat4::from_rotation_x(0.5);
+        let pose = skinned_mesh_triangles(&mesh, None, &[joint]).unwrap();
+        assert!(!cache.refit(&pose[1..]));
+        assert!(cache.refit(&pose));
+        let hit = cast(&cache);
+        assert!(hit.point.distance(Vec3::new(0.5, 2.0, 0.0)) < 1e-5);
+        assert!((hit.distance - 3.0).abs() < 1e-5);
+        assert!(hit.normal.distance(joint.transform_vector3(Vec3::Y)) < 1e-5);
+    }
+    #[test]
+    fn world_aabb_encloses_pose() {
+        let mesh = single_joint_plane();
+        let joint = Mat4::from_translation(Vec3::Y * 5.0);
+        let pose = skinned_mesh_triangles(&mesh, None, &[joint]).unwrap();
+        let entity = Entity::from_raw(0);
+        let mut skinned_mesh_bvhs = SkinnedMeshBvhs::default();
+        skinned_mesh_bvhs.bvhs.insert(
+            entity,
+            SkinnedMeshBvh {
+                cache: ObvhsBvh2Cache::build(pose, &ObvhsBuildQuality::default()),
+                mesh_id: AssetId::default(),
+            },
+        );
+
+        // The bind pose AABB of the entity, moved along X, is grown to the pose raised along Y
+        let bind_pose_aabb =
+            Aabb::from_min_max(Vec3::new(-1.0, 0.0, -1.0), Vec3::new(1.0, 0.0, 1.0));
+        let transform = Mat4::from_translation(Vec3::X);
+        let world_aabb = skinned_mesh_bvhs
+            .world_aabb(entity, &bind_pose_aabb, &transform)
+            .unwrap();
+        assert!(Vec3::from(world_aabb.min()).distance(Vec3::new(-1.0, 0.0, -1.0)) < 1e-5);
+        assert!(Vec3::from(world_aabb.max()).distance(Vec3::new(2.0, 5.0, 1.0)) < 1e-5);
+
+        assert!(skinned_mesh_bvhs
+            .world_aabb(Entity::from_raw(1), &bind_pose_aabb, &transform)
+            .is_none());
+    }
+}
@@ -0,0 +1,511 @@
+//! Top-level acceleration structure (TLAS) over the pickable mesh entities.
+//!
+//! The broad phase of [`BvhMeshRayCast`](crate::ray_cast::BvhMeshRayCast) uses it to find the
+//! entities whose AABB may be hit by a ray, instead of testing the AABB of every entity.
+
+use bevy_ecs::prelude::*;
+use bevy_math::{Affine3A, Ray3d, Vec3A};
+use bevy_picking_more_hitinfo::mesh_picking::ray_cast::SimplifiedMesh;
+use bevy_render::{prelude::*, primitives::Aabb};
+use bevy_transform::components::GlobalTransform;
+use bevy_utils::{tracing::*, HashMap, HashSet};
+use obvhs::{
+    aabb::Aabb as ObvhsAabb,
+    bvh2::{builder::build_bvh2, Bvh2},
+    ray::RayHit,
+    BvhBuildParams,
+};
+
+#[cfg(not(target_arch = "wasm32"))]
+use core::time::Duration;
+#[cfg(target_arch = "wasm32")]
+use web_time::Duration;
+
+use crate::{ray_cast::MeshFilter, PickingBvhBackend};
+
+use super::{
+    bvh2_parents, insert_bvh2_primitive, order_bvh2_nodes, refit_bvh2, refit_bvh2_nodes,
+    skinned::SkinnedMeshBvhs, traverse_best_first, NO_PARENT,
+};
+
+/// The tree is rebuilt once it is deeper than twice its depth when built, and at least this deep.
+const MIN_REBUILD_DEPTH: usize = 16;
+
+/// A BVH over the world-space AABBs of the pickable mesh entities.
+///
+/// The ancestors of the entities whose [`GlobalTransform`] or [`Aabb`] change are refitted. The
+/// entities added or removed are inserted in or removed from the tree, whose ancestors are refitted
+/// too, and it is only rebuilt once as many entities were inserted or removed as there are in the
+/// tree, or once the insertions made it too deep. The AABBs are grown by the
+/// [`PickingBvhBackend::pick_radius`], and the AABBs of the skinned mesh entities enclose their tree
+/// once it is refitted, see [`SkinnedMeshBvhs::world_aabb`].
+#[derive(Resource, Default)]
+pub struct MeshEntitiesTlas {
+    bvh: Option<Bvh2>,
+    /// The parent of each node of the tree, and the leaf of each primitive.
+    parents: Vec<u32>,
+    leaves: Vec<u32>,
+    /// The depth of the tree when it was built, and its current depth.
+    build_depth: usize,
+    depth: usize,
+    /// The entity of each primitive, [`Entity::PLACEHOLDER`] for the free primitives.
+    entities: Vec<Entity>,
+    aabbs: Vec<ObvhsAabb>,
+    /// The primitives whose AABB changed since the last refit.
+    changed_primitives: Vec<usize>,
+    primitives: HashMap<Entity, usize>,
+    /// The primitives of the removed entities, still in the tree with an empty AABB, reused for the
+    /// next inserted entities.
+    free_primitives: Vec<usize>,
+    /// The number of entities inserted or removed since the last build of the tree.
+    updates_since_build: usize,
+    pick_radius: f32,
+    /// The skinned mesh entities whose AABB encloses their tree.
+    skinned_entities: HashSet<Entity>,
+}
+
+impl MeshEntitiesTlas {
+    /// Returns `true` if the TLAS contains at least one entity.
+    pub fn is_ready(&self) -> bool {
+        self.bvh.is_some()
+    }
+
+    /// Returns the number of entities in the TLAS.
+    pub fn len(&self) -> usize {
+        self.primitives.len()
+    }
+
+    /// Returns `true` if there is no entity in the TLAS.
+    pub fn is_empty(&self) -> bool {
+        self.primitives.is_empty()
+    }
+
+    /// Calls `f` with each entity whose world-space AABB may be hit by the ray before `max_distance`.
+    pub fn traverse_ray(&self, ray: Ray3d, max_distance: f32, mut f: impl FnMut(Entity)) {
+        let Some(bvh) = &self.bvh else {
+            return;
+        };
+
+        let ray = obvhs::ray::Ray::new(
+            ray.origin.into(),
+            Vec3A::from_array(ray.direction.to_array()),
+            0.0,
+            max_distance,
+        );
+
+        let mut ray_hit = RayHit::none();
+        let mut ray_traversal = bvh.new_ray_traversal(ray);
+        while bvh.ray_traverse_dynamic(&mut ray_traversal, &mut ray_hit, |_ray, id| {
+            self.visit(bvh.primitive_indices[id] as usize, &mut f);
+            // Never report a hit, so that every node along the ray is visited
+            f32::INFINITY
+        }) {}
+    }
+
+    /// Calls `f` with each entity whose world-space AABB may be touched by a sphere of `radius`
+    /// swept along the ray before `max_distance`.
+    pub fn traverse_swept_sphere(
+        &self,
+        ray: Ray3d,
+        radius: f32,
+        max_distance: f32,
+        mut f: impl FnMut(Entity),
+    ) {
+        let Some(bvh) = &self.bvh else {
+            return;
+        };
+
+        let origin = Vec3A::from(ray.origin);
+        let inv_direction = Vec3A::from_array(ray.direction.to_array()).recip();
+        traverse_best_first(
+            bvh,
+            |aabb| {
+                // Slab test of the ray against the AABB grown by the radius
+                let t1 = (aabb.min - radius - origin) * inv_direction;
+                let t2 = (aabb.max + radius - origin) * inv_direction;
+                let t_near = t1.min(t2).max_element().max(0.0);
+                let t_far = t1.max(t2).min_element().min(max_distance);
+                (t_near <= t_far).then_some(t_near)
+            },
+            |primitive| {
+                self.visit(primitive, &mut f);
+                // Never report a hit, so that every node along the ray is visited
+                f32::INFINITY
+            },
+        );
+    }
+
+    /// Calls `f` with each entity whose world-space AABB may be within `radius` of `center`, nearest
+    /// first.
+    pub fn traverse_sphere(&self, center: Vec3A, radius: f32, mut f: impl FnMut(Entity)) {
+        let Some(bvh) = &self.bvh else {
+            return;
+        };
+
+        traverse_best_first(
+            bvh,
+            |aabb| {
+                let distance = (aabb.min - center).max(center - aabb.max).max(Vec3A::ZERO);
+                let distance = distance.length();
+                (distance <= radius).then_some(distance)
+            },
+            |primitive| {
+                self.visit(primitive, &mut f);
+                // Never report a hit, so that every node within the radius is visited
+                f32::INFINITY
+            },
+        );
+    }
+
+    /// Calls `f` with the entity of the `primitive`, unless it is free.
+    fn visit(&self, primitive: usize, f: &mut impl FnMut(Entity)) {
+        let entity = self.entities[primitive];
+        if entity != Entity::PLACEHOLDER {
+            f(entity);
+        }
+    }
+
+    /// Inserts the `entity` with its world-space `aabb`. The tree must be refitted afterwards.
+    fn insert(&mut self, entity: Entity, aabb: ObvhsAabb) {
+        self.updates_since_build += 1;
+        // A free primitive is still in the tree, where it is refitted to its new AABB
+        if let Some(primitive) = self.free_primitives.pop() {
+            self.entities[primitive] = entity;
+            self.primitives.insert(entity, primitive);
+            self.set_aabb(primitive, aabb);
+            return;
+        }
+
+        let primitive = self.entities.len();
+        self.primitives.insert(entity, primitive);
+        self.entities.push(entity);
+        self.aabbs.push(aabb);
+        let Some(bvh) = &mut self.bvh else {
+            return;
+        };
+        let Some(split_node) = insert_bvh2_primitive(bvh, primitive as u32, &aabb) else {
+            return;
+        };
+
+        // The split leaf is moved to the first child of the split node, the new leaf is the second
+        let moved_leaf = bvh.nodes[split_node].first_index as usize;
+        self.parents.extend([split_node as u32; 2]);
+        let leaf = &bvh.nodes[moved_leaf];
+        let first_index = leaf.first_index as usize;
+        for moved in &bvh.primitive_indices[first_index..first_index + leaf.prim_count as usize] {
+            self.leaves[*moved as usize] = moved_leaf as u32;
+        }
+        self.leaves.push(moved_leaf as u32 + 1);
+
+        let mut depth = 1;
+        let mut node_index = split_node;
+        while self.parents[node_index] != NO_PARENT {
+            node_index = self.parents[node_index] as usize;
+            depth += 1;
+        }
+        self.depth = self.depth.max(depth);
+        self.changed_primitives.push(primitive);
+    }
+
+    /// Removes the `entity`, whose primitive is freed. The tree must be refitted afterwards.
+    fn remove(&mut self, entity: Entity) {
+        if let Some(primitive) = self.primitives.remove(&entity) {
+            self.updates_since_build += 1;
+            self.entities[primitive] = Entity::PLACEHOLDER;
+            self.set_aabb(primitive, ObvhsAabb::empty());
+            self.free_primitives.push(primitive);
+        }
+    }
+
+    /// Sets the world-space `aabb` of the `primitive`. The tree must be refitted afterwards.
+    fn set_aabb(&mut self, primitive: usize, aabb: ObvhsAabb) {
+        self.aabbs[primitive] = aabb;
+        self.changed_primitives.push(primitive);
+    }
+
+    /// Refits the ancestors of the primitives whose AABB changed, or the whole tree if most of them
+    /// changed.
+    fn refit(&mut self) {
+        let Some(bvh) = &mut self.bvh else {
+            return;
+        };
+        let aabbs = &self.aabbs;
+        if self.changed_primitives.len() > self.entities.len() / 2 {
+            refit_bvh2(bvh, |primitive| aabbs[primitive]);
+        } else {
+            let leaves = self
+                .changed_primitives
+                .iter()
+                .map(|primitive| self.leaves[*primitive] as usize);
+            refit_bvh2_nodes(bvh, &self.parents, leaves, |primitive| aabbs[primitive]);
+        }
+        self.changed_primitives.clear();
+    }
+
+    /// Returns `true` if the tree should be rebuilt rather than refitted, because it was never
+    /// built or because its quality degraded after too many insertions and removals.
+    fn needs_rebuild(&self) -> bool {
+        self.bvh.is_none()
+            || self.updates_since_build > self.primitives.len()
+            || self.depth > (2 * self.build_depth).max(MIN_REBUILD_DEPTH)
+    }
+
+    fn rebuild(&mut self) {
+        // The free primitives are dropped
+        let (entities, aabbs): (Vec<_>, Vec<_>) = self
+            .entities
+            .iter()
+            .zip(&self.aabbs)
+            .filter(|(entity, _)| **entity != Entity::PLACEHOLDER)
+            .unzip();
+        self.entities = entities;
+        self.aabbs = aabbs;
+        self.free_primitives.clear();
+        self.changed_primitives.clear();
+        self.updates_since_build = 0;
+        self.primitives = self
+            .entities
+            .iter()
+            .enumerate()
+            .map(|(primitive, entity)| (*entity, primitive))
+            .collect();
+
+        self.bvh = (!self.aabbs.is_empty()).then(|| {
+            let mut bvh = build_bvh2(
+                &self.aabbs,
+                BvhBuildParams::fastest_build(),
+                &mut Duration::default(),
+            );
+            // The refits need the children after their parent
+            order_bvh2_nodes(&mut bvh);
+            bvh
+        });
+
+        let Some(bvh) = &self.bvh else {
+            return;
+        };
+        self.parents = bvh2_parents(bvh);
+        self.leaves = vec![0; self.entities.len()];
+        let mut depths = vec![0; bvh.nodes.len()];
+        for (node_index, node) in bvh.nodes.iter().enumerate() {
+            if let Some(parent) = self.parents.get(node_index).filter(|p| **p != NO_PARENT) {
+                depths[node_index] = depths[*parent as usize] + 1;
+            }
+            if node.is_leaf() {
+                let first_index = node.first_index as usize;
+                let primitives = first_index..first_index + node.prim_count as usize;
+                for primitive in &bvh.primitive_indices[primitives] {
+                    self.leaves[*primitive as usize] = node_index as u32;
+                }
+            }
+        }
+        self.build_depth = depths.into_iter().max().unwrap_or_default();
+        self.depth = self.build_depth;
+    }
+}
+
+/// Keeps the [`MeshEntitiesTlas`] in sync with the pickable mesh entities.
+#[allow(clippy::too_many_arguments)]
+#[allow(clippy::type_complexity)]
+pub fn update_mesh_entities_tlas(
+    mut tlas: ResMut<MeshEntitiesTlas>,
+    picking_bvh_backend: Res<PickingBvhBackend>,
+    changed_entities: Query<
+        (Entity, &Aabb, &GlobalTransform),
+        (
+            MeshFilter,
+            Or<(
+                Changed<GlobalTransform>,
+                Changed<Aabb>,
+                Added<Mesh2d>,
+                Added<Mesh3d>,
+                Added<SimplifiedMesh>,
+            )>,
+        ),
+    >,
+    pickable_entities: Query<(Entity, &Aabb, &GlobalTransform), MeshFilter>,
+    skinned_mesh_bvhs: Res<SkinnedMeshBvhs>,
+    mut removed_aabbs: RemovedComponents<Aabb>,
+    mut removed_mesh2ds: RemovedComponents<Mesh2d>,
+    mut removed_mesh3ds: RemovedComponents<Mesh3d>,
+    mut removed_simplified_meshes: RemovedComponents<SimplifiedMesh>,
+) {
+    let update_tlas = info_span!("update_mesh_entities_tlas");
+    let _update_tlas_guard = update_tlas.enter();
+
+    let tlas = tlas.as_mut();
+    let mut refit = false;
+
+    // Entities that are no longer pickable (or despawned) are removed from the TLAS
+    let removed_entities = removed_aabbs
+        .read()
+        .chain(removed_mesh2ds.read())
+        .chain(removed_mesh3ds.read())
+        .chain(removed_simplified_meshes.read())
+        .filter(|entity| {
+            tlas.primitives.contains_key(entity) && !pickable_entities.contains(*entity)
+        })
+        .collect::<Vec<_>>();
+    for entity in removed_entities {
+        tlas.remove(entity);
+        refit = true;
+    }
+
+    // The skinned mesh entities follow the refits of their tree, and get back their own AABB once
+    // their tree is dropped
+    let mut skinned_entities = Vec::new();
+    if skinned_mesh_bvhs.is_changed() {
+        let refitted_entities = skinned_mesh_bvhs.entities().collect::<HashSet<_>>();
+        skinned_entities.extend(tlas.skinned_entities.union(&refitted_entities));
+        tlas.skinned_entities = refitted_entities;
+    }
+
+    // All the AABBs are grown again when the pick radius changes
+    let pick_radius = picking_bvh_backend.pick_radius;
+    let changed_entities = if tlas.pick_radius != pick_radius {
+        tlas.pick_radius = pick_radius;
+        pickable_entities.iter().collect::<Vec<_>>()
+    } else {
+        changed_entities
+            .iter()
+            .chain(pickable_entities.iter_many(skinned_entities))
+            .collect()
+    };
+
+    for (entity, aabb, transform) in changed_entities {
+        let world_aabb =
+            match skinned_mesh_bvhs.world_aabb(entity, aabb, &transform.compute_matrix()) {
+                Some(aabb) => world_space_aabb(&aabb, &Affine3A::IDENTITY, pick_radius),
+                None => world_space_aabb(aabb, &transform.affine(), pick_radius),
+            };
+        match tlas.primitives.get(&entity) {
+            Some(&primitive) => tlas.set_aabb(primitive, world_aabb),
+            None => tlas.insert(entity, world_aabb),
+        }
+        refit = true;
+    }
+
+    if !refit {
+        return;
+    }
+    if tlas.needs_rebuild() {
+        tlas.rebuild();
+    } else {
+        tlas.refit();
+    }
+}
+
+/// Computes the world-space AABB enclosing the given local AABB once transformed, grown by `margin`.
+fn world_space_aabb(aabb: &Aabb, transform: &Affine3A, margin: f32) -> ObvhsAabb {
+    let center = transform.transform_point3a(aabb.center);
+    let half_extents = transform.matrix3.abs() * aabb.half_extents + margin;
+    ObvhsAabb {
+        min: center - half_extents,
+        max: center + half_extents,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy_math::{Dir3, Vec3};
+
+    use super::*;
+
+    fn unit_aabb(x: f32) -> ObvhsAabb {
+        ObvhsAabb {
+            min: Vec3A::new(x - 0.5, -0.5, -0.5),
+            max: Vec3A::new(x + 0.5, 0.5, 0.5),
+        }
+    }
+
+    fn hit_entities(tlas: &MeshEntitiesTlas) -> Vec<Entity> {
+        let mut entities = Vec::new();
+        let ray = Ray3d::new(Vec3::new(-10.0, 0.0, 0.0), Dir3::X);
+        tlas.traverse_ray(ray, f32::INFINITY, |entity| entities.push(entity));
+        entities.sort();
+        entities
+    }
+
+    #[test]
+    fn incremental_updates() {
+        let mut tlas = MeshEntitiesTlas::default();
+        let entities = (0..8).map(Entity::from_raw).collect::<Vec<_>>();
+        for (i, entity) in entities.iter().enumerate() {
+            tlas.insert(*entity, unit_aabb(i as f32 * 2.0));
+        }
+        tlas.rebuild();
+        assert_eq!(hit_entities(&tlas), entities);
+
+        // Inserted and removed without a rebuild
+        let inserted = Entity::from_raw(100);
+        tlas.insert(inserted, unit_aabb(20.0));
+        tlas.remove(entities[3]);
+        assert!(!tlas.needs_rebuild());
+        tlas.refit();
+        let mut expected = entities.clone();
+        expected.remove(3);
+        expected.push(inserted);
+        assert_eq!(hit_entities(&tlas), expected);
+        assert_eq!(tlas.len(), 8);
+
+        // The primitive of the removed entity is reused
+        let reinserted = Entity::from_raw(101);
+        tlas.insert(reinserted, unit_aabb(-4.0));
+        tlas.refit();
+        expected.push(reinserted);
+        assert_eq!(hit_entities(&tlas), expected);
+        assert_eq!(tlas.entities.len(), 9);
+    }
+
+    /// Returns the bounds of the nodes of the tree.
+    fn node_bounds(tlas: &MeshEntitiesTlas) -> Vec<(Vec3A, Vec3A)> {
+        let bvh = tlas.bvh.as_ref().unwrap();
+        bvh.nodes
+            .iter()
+            .map(|node| (node.aabb.min, node.aabb.max))
+            .collect()
+    }
+
+    #[test]
+    fn refits_the_ancestors_of_the_changes() {
+        let mut tlas = MeshEntitiesTlas::default();
+        let entities = (0..16).map(Entity::from_raw).collect::<Vec<_>>();
+        for (i, entity) in entities.iter().enumerate() {
+            tlas.insert(*entity, unit_aabb(i as f32 * 2.0));
+        }
+        tlas.rebuild();
+
+        // One entity moved, another inserted and another removed
+        let primitive = tlas.primitives[&entities[5]];
+        tlas.set_aabb(primitive, unit_aabb(50.0));
+        tlas.insert(Entity::from_raw(100), unit_aabb(-20.0));
+        tlas.remove(entities[9]);
+        assert!(tlas.changed_primitives.len() <= tlas.entities.len() / 2);
+        tlas.refit();
+        let refitted = node_bounds(&tlas);
+
+        // The same as refitting the whole tree
+        let aabbs = tlas.aabbs.clone();
+        refit_bvh2(tlas.bvh.as_mut().unwrap(), |primitive| aabbs[primitive]);
+        assert_eq!(refitted, node_bounds(&tlas));
+    }
+
+    #[test]
+    fn deep_insertions_rebuild_the_tree() {
+        let mut tlas = MeshEntitiesTlas::default();
+        for i in 0..8 {
+            tlas.insert(Entity::from_raw(i), unit_aabb(i as f32 * 2.0));
+        }
+        tlas.rebuild();
+
+        // Entities spawned along a line are inserted in a chain
+        for i in 8..32 {
+            tlas.insert(Entity::from_raw(i), unit_aabb(i as f32 * 2.0));
+        }
+        assert!(tlas.updates_since_build <= tlas.primitives.len());
+        assert!(tlas.needs_rebuild());
+        tlas.rebuild();
+        assert!(!tlas.needs_rebuild());
+        assert!(tlas.depth < MIN_REBUILD_DEPTH);
+    }
+}
@@ -0,0 +1,513 @@
+//! Compact storage of the triangles of the [`ObvhsBvh2Cache`](super::ObvhsBvh2Cache) trees.
+//!
+//! The triangles are stored in the order of the leaves of their tree, so that the triangles of a
+//! leaf are next to each other in memory. Their vertices can be shared between triangles and their
+//! positions quantized, see [`ObvhsCacheLayout`].
+
+use std::mem::size_of;
+
+use bevy_math::prelude::*;
+use bevy_utils::HashMap;
+
+use crate::{
+    common::triangle::Triangle,
+    settings::{ObvhsCacheLayout, PositionPrecision},
+    storage::file::{BvhCacheFileError, CacheReader, CacheWriter},
+};
+
+use super::find_triangle;
+
+/// The triangles of a tree, in the order of its leaves: the primitive indices of the tree are the
+/// slots of the triangles.
+pub struct CacheTriangles {
+    /// Index of the triangle of each slot in the mesh, see
+    /// [`TriangleFace::triangle_index`](crate::common::triangle::TriangleFace::triangle_index).
+    triangle_indices: Vec<u32>,
+    /// Vertices of the triangle of each slot, if the vertices are shared. Otherwise, the triangle of
+    /// each slot has three vertices of its own.
+    indices: Option<Vec<[u32; 3]>>,
+    positions: CachePositions,
+    normals: Option<Vec<Vec3>>,
+    uvs: Option<Vec<Vec2>>,
+    /// The slots sorted by triangle index, to find a triangle by its index in the mesh. Only kept
+    /// with the UVs, which are the only data read by triangle index.
+    sorted_slots: Option<Vec<u32>>,
+}
+
+enum CachePositions {
+    Full(Vec<Vec3>),
+    Quantized {
+        quantization: Quantization,
+        positions: Vec<[u16; 3]>,
+    },
+}
+
+impl CacheTriangles {
+    /// Stores the `triangles` in the `order` of the leaves of their tree, with the `layout`.
+    ///
+    /// The positions of the triangles must already be rounded with the `quantization` of the
+    /// layout, see [`quantize_positions`].
+    pub(crate) fn new(
+        triangles: &[Triangle],
+        order: &[u32],
+        layout: &ObvhsCacheLayout,
+        quantization: Option<Quantization>,
+    ) -> Self {
+        let ordered_triangles = || order.iter().map(|index| &triangles[*index as usize]);
+        let vertex_count = order.len() * 3;
+
+        let mut indices = layout.indexed.then(|| Vec::with_capacity(order.len()));
+        let mut shared_vertices = HashMap::new();
+        let mut positions = Vec::with_capacity(vertex_count);
+        let mut normals = (layout.normals && triangles.iter().all(|t| t.normals.is_some()))
+            .then(|| Vec::with_capacity(vertex_count));
+        let mut uvs = (!triangles.is_empty() && triangles.iter().all(|t| t.uvs.is_some()))
+            .then(|| Vec::with_capacity(vertex_count));
+
+        for triangle in ordered_triangles() {
+            let mut vertices = [0; 3];
+            for (corner, vertex) in triangle.face.vertex_indices.into_iter().enumerate() {
+                if indices.is_some() {
+                    if let Some(shared_vertex) = shared_vertices.get(&vertex) {
+                        vertices[corner] = *shared_vertex;
+                        continue;
+                    }
+                    shared_vertices.insert(vertex, positions.len() as u32);
+                }
+                vertices[corner] = positions.len() as u32;
+                positions.push(triangle.positions[corner]);
+                if let Some(normals) = &mut normals {
+                    normals.push(triangle.normals.map_or(Vec3::ZERO, |n| n[corner]));
+                }
+                if let Some(uvs) = &mut uvs {
+                    uvs.push(triangle.uvs.map_or(Vec2::ZERO, |uvs| uvs[corner]));
+                }
+            }
+            if let Some(indices) = &mut indices {
+                indices.push(vertices);
+            }
+        }
+        // The shared vertices are fewer than the reserved ones
+        positions.shrink_to_fit();
+        if let Some(normals) = &mut normals {
+            normals.shrink_to_fit();
+        }
+        if let Some(uvs) = &mut uvs {
+            uvs.shrink_to_fit();
+        }
+
+        let triangle_indices = ordered_triangles()
+            .map(|triangle| triangle.face.triangle_index as u32)
+            .collect::<Vec<_>>();
+        let sorted_slots = uvs.is_some().then(|| {
+            let mut sorted_slots = (0..order.len() as u32).collect::<Vec<_>>();
+            sorted_slots.sort_unstable_by_key(|slot| triangle_indices[*slot as usize]);
+            sorted_slots
+        });
+
+        let positions = match quantization {
+            Some(quantization) => CachePositions::Quantized {
+                quantization,
+                positions: positions
+                    .iter()
+                    .map(|position| quantization.quantize(*position))
+                    .collect(),
+            },
+            None => CachePositions::Full(positions),
+        };
+
+        Self {
+            triangle_indices,
+            indices,
+            positions,
+            normals,
+            uvs,
+            sorted_slots,
+        }
+    }
+
+    /// Returns the number of triangles.
+    pub fn len(&self) -> usize {
+        self.triangle_indices.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.triangle_indices.is_empty()
+    }
+
+    /// Returns the index in the mesh of the triangle at `slot`.
+    pub fn triangle_index(&self, slot: usize) -> Option<usize> {
+        self.triangle_indices
+            .get(slot)
+            .map(|triangle_index| *triangle_index as usize)
+    }
+
+    /// Returns the positions of the vertices of the triangle at `slot`.
+    pub fn positions(&self, slot: usize) -> Option<[Vec3; 3]> {
+        let vertices = self.vertices(slot)?;
+        Some(match &self.positions {
+            CachePositions::Full(positions) => vertices.map(|vertex| positions[vertex]),
+            CachePositions::Quantized {
+                quantization,
+                positions,
+            } => vertices.map(|vertex| quantization.dequantize(positions[vertex])),
+        })
+    }
+
+    /// Returns the triangle at `slot`.
+    pub fn get(&self, slot: usize) -> Option<CachedTriangle> {
+        let vertices = self.vertices(slot)?;
+        Some(CachedTriangle {
+            triangle_index: self.triangle_indices[slot] as usize,
+            positions: self.positions(slot)?,
+            normals: self
+                .normals
+                .as_ref()
+                .map(|normals| vertices.map(|vertex| normals[vertex])),
+            uvs: self
+                .uvs
+                .as_ref()
+                .map(|uvs| vertices.map(|vertex| uvs[vertex])),
+        })
+    }
+
+    /// Returns the triangle at `triangle_index` in the mesh, if it is in the cache.
+    ///
+    /// Only the caches with UVs find their triangles by index, see
+    /// [`ObvhsBuildSettings::store_uvs`](crate::settings::ObvhsBuildSettings::store_uvs).
+    pub fn find(&self, triangle_index: usize) -> Option<CachedTriangle> {
+        let sorted_slots = self.sorted_slots.as_ref()?;
+        let index = sorted_slots
+            .binary_search_by_key(&triangle_index, |slot| {
+                self.triangle_indices[*slot as usize] as usize
+            })
+            .ok()?;
+        self.get(sorted_slots[index] as usize)
+    }
+
+    /// Replaces the positions and normals of the stored triangles in place by the ones of the
+    /// `triangles` with the same index in the mesh, as for a deformed mesh.
+    ///
+    /// Returns `false` if one of the stored triangles is missing from the `triangles`, or if the
+    /// positions are quantized: the triangles must then be stored again.
+    pub(crate) fn update_vertices(&mut self, triangles: &[Triangle]) -> bool {
+        let CachePositions::Full(positions) = &mut self.positions else {
+            return false;
+        };
+        for (slot, triangle_index) in self.triangle_indices.iter().enumerate() {
+            let Some(index) = find_triangle(triangles, *triangle_index as usize) else {
+                return false;
+            };
+            let triangle = &triangles[index];
+            let vertices = match &self.indices {
+                Some(indices) => indices[slot].map(|v| v as usize),
+                None => [slot * 3, slot * 3 + 1, slot * 3 + 2],
+            };
+            for (corner, vertex) in vertices.into_iter().enumerate() {
+                positions[vertex] = triangle.positions[corner];
+                if let Some(normals) = &mut self.normals {
+                    normals[vertex] = triangle.normals.map_or(Vec3::ZERO, |n| n[corner]);
+                }
+            }
+        }
+        true
+    }
+
+    /// Returns the approximate number of bytes used by the triangles on the heap.
+    pub fn memory_footprint(&self) -> usize {
+        let positions = match &self.positions {
+            CachePositions::Full(positions) => positions.capacity() * size_of::<Vec3>(),
+            CachePositions::Quantized { positions, .. } => {
+                positions.capacity() * size_of::<[u16; 3]>()
+            }
+        };
+        self.triangle_indices.capacity() * size_of::<u32>()
+            + self
+                .indices
+                .as_ref()
+                .map_or(0, |indices| indices.capacity() * size_of::<[u32; 3]>())
+            + positions
+            + self
+                .normals
+                .as_ref()
+                .map_or(0, |normals| normals.capacity() * size_of::<Vec3>())
+            + self
+                .uvs
+                .as_ref()
+                .map_or(0, |uvs| uvs.capacity() * size_of::<Vec2>())
+            + self
+                .sorted_slots
+                .as_ref()
+                .map_or(0, |sorted_slots| sorted_slots.capacity() * size_of::<u32>())
+    }
+
+    /// Writes the triangles to a baked cache file.
+    pub(crate) fn write(&self, writer: &mut CacheWriter) {
+        writer.slice(&self.triangle_indices, |writer, index| writer.u32(*index));
+        writer.option(self.indices.as_ref(), |writer, indices| {
+            writer.slice(indices, |writer, vertices| {
+                for vertex in vertices {
+                    writer.u32(*vertex);
+                }
+            });
+        });
+        match &self.positions {
+            CachePositions::Full(positions) => {
+                writer.u8(0);
+                writer.slice(positions, |writer, position| writer.vec3(*position));
+            }
+            CachePositions::Quantized {
+                quantization,
+                positions,
+            } => {
+                writer.u8(1);
+                writer.vec3(quantization.min);
+                writer.vec3(quantization.step);
+                writer.slice(positions, |writer, position| {
+                    for coordinate in position {
+                        writer.bytes(&coordinate.to_le_bytes());
+                    }
+                });
+            }
+        }
+        writer.option(self.normals.as_ref(), |writer, normals| {
+            writer.slice(normals, |writer, normal| writer.vec3(*normal));
+        });
+        writer.option(self.uvs.as_ref(), |writer, uvs| {
+            writer.slice(uvs, |writer, uv| writer.vec2(*uv));
+        });
+        writer.option(self.sorted_slots.as_ref(), |writer, sorted_slots| {
+            writer.slice(sorted_slots, |writer, slot| writer.u32(*slot));
+        });
+    }
+
+    /// Reads the triangles written by [`CacheTriangles::write`], and checks that their indices are
+    /// in bounds.
+    pub(crate) fn read(reader: &mut CacheReader) -> Result<Self, BvhCacheFileError> {
+        let triangle_indices = reader.vec(|reader| reader.u32())?;
+        let indices = reader.option(|reader| {
+            reader.vec(|reader| Ok([reader.u32()?, reader.u32()?, reader.u32()?]))
+        })?;
+        let positions = match reader.u8()? {
+            0 => CachePositions::Full(reader.vec(|reader| reader.vec3())?),
+            1 => CachePositions::Quantized {
+                quantization: Quantization {
+                    min: reader.vec3()?,
+                    step: reader.vec3()?,
+                },
+                positions: reader.vec(|reader| {
+                    let [x0, x1, y0, y1, z0, z1] = reader.array()?;
+                    Ok([
+                        u16::from_le_bytes([x0, x1]),
+                        u16::from_le_bytes([y0, y1]),
+                        u16::from_le_bytes([z0, z1]),
+                    ])
+                })?,
+            },
+            _ => return Err(BvhCacheFileError::Corrupted),
+        };
+        let normals = reader.option(|reader| reader.vec(|reader| reader.vec3()))?;
+        let uvs = reader.option(|reader| reader.vec(|reader| reader.vec2()))?;
+        let sorted_slots = reader.option(|reader| reader.vec(|reader| reader.u32()))?;
+
+        let triangle_count = triangle_indices.len();
+        let vertex_count = match &positions {
+            CachePositions::Full(positions) => positions.len(),
+            CachePositions::Quantized { positions, .. } => positions.len(),
+        };
+        let valid_indices = match &indices {
+            Some(indices) => {
+                indices.len() == triangle_count
+                    && indices
+                        .iter()
+                        .flatten()
+                        .all(|v| (*v as usize) < vertex_count)
+            }
+            None => vertex_count == triangle_count * 3,
+        };
+        let valid_attributes = normals.as_ref().is_none_or(|n| n.len() == vertex_count)
+            && uvs.as_ref().is_none_or(|uvs| uvs.len() == vertex_count);
+        let valid_sorted_slots = sorted_slots.as_ref().is_none_or(|sorted_slots| {
+            sorted_slots.len() == triangle_count
+                && sorted_slots
+                    .iter()
+                    .all(|slot| (*slot as usize) < triangle_count)
+        });
+        if !valid_indices || !valid_attributes || !valid_sorted_slots {
+            return Err(BvhCacheFileError::Corrupted);
+        }
+
+        Ok(Self {
+            triangle_indices,
+            indices,
+            positions,
+            normals,
+            uvs,
+            sorted_slots,
+        })
+    }
+
+    /// Returns the indices of the vertices of the triangle at `slot`.
+    fn vertices(&self, slot: usize) -> Option<[usize; 3]> {
+        match &self.indices {
+            Some(indices) => indices
+                .get(slot)
+                .map(|vertices| vertices.map(|v| v as usize)),
+            None => (slot < self.len()).then(|| [slot * 3, slot * 3 + 1, slot * 3 + 2]),
+        }
+    }
+}
+
+/// A triangle stored in [`CacheTriangles`]. Only the index of its face in the mesh is stored, its
+/// vertices are read with [`mesh_triangle_face`](crate::common::mesh_triangle_face).
+#[derive(Clone, Debug)]
+pub struct CachedTriangle {
+    /// See [`TriangleFace::triangle_index`](crate::common::triangle::TriangleFace::triangle_index).
+    pub triangle_index: usize,
+    pub positions: [Vec3; 3],
+    pub normals: Option<[Vec3; 3]>,
+    pub uvs: Option<[Vec2; 3]>,
+}
+
+/// Quantization of the positions of a mesh to 16 bits per axis, within its bounds.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Quantization {
+    min: Vec3,
+    step: Vec3,
+}
+
+impl Quantization {
+    fn new(triangles: &[Triangle]) -> Self {
+        let (min, max) = triangles
+            .iter()
+            .flat_map(|triangle| triangle.positions)
+            .fold((Vec3::INFINITY, Vec3::NEG_INFINITY), |(min, max), p| {
+                (min.min(p), max.max(p))
+            });
+        if !min.is_finite() || !max.is_finite() {
+            return Self {
+                min: Vec3::ZERO,
+                step: Vec3::ONE,
+            };
+        }
+        Self {
+            min,
+            // Flat meshes have an empty axis, which must not be divided by zero
+            step: ((max - min) / u16::MAX as f32).max(Vec3::splat(f32::MIN_POSITIVE)),
+        }
+    }
+
+    fn quantize(&self, position: Vec3) -> [u16; 3] {
+        ((position - self.min) / self.step)
+            .round()
+            .clamp(Vec3::ZERO, Vec3::splat(u16::MAX as f32))
+            .to_array()
+            .map(|coordinate| coordinate as u16)
+    }
+
+    fn dequantize(&self, position: [u16; 3]) -> Vec3 {
+        self.min + Vec3::from(position.map(f32::from)) * self.step
+    }
+}
+
+/// Rounds the positions of the `triangles` to the precision of the `layout`, so that the tree is
+/// built over the positions that are stored. Returns the quantization of the positions, if any.
+pub(crate) fn quantize_positions(
+    triangles: &mut [Triangle],
+    layout: &ObvhsCacheLayout,
+) -> Option<Quantization> {
+    if layout.precision != PositionPrecision::Quantized {
+        return None;
+    }
+    let quantization = Quantization::new(triangles);
+    for triangle in triangles {
+        triangle.positions = triangle
+            .positions
+            .map(|position| quantization.dequantize(quantization.quantize(position)));
+    }
+    Some(quantization)
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy_asset::RenderAssetUsages;
+    use bevy_render::mesh::{Indices, Mesh, PrimitiveTopology};
+
+    use crate::common::{attribute::set_triangle_uvs, mesh_triangles};
+
+    use super::*;
+
+    #[test]
+    fn layouts_store_the_same_triangles() {
+        // A grid of 16x16 quads, whose inner vertices are shared by six triangles
+        let mut positions = Vec::new();
+        let mut normals = Vec::new();
+        let mut uvs = Vec::new();
+        let mut indices = Vec::new();
+        for y in 0..17 {
+            for x in 0..17 {
+                positions.push([x as f32 * 0.5, (x * y) as f32 * 0.01, y as f32 * 0.5]);
+                normals.push(
+                    Vec3::new(-(y as f32), 50.0, -(x as f32))
+                        .normalize()
+                        .to_array(),
+                );
+                uvs.push([x as f32 / 16.0, y as f32 / 16.0]);
+            }
+        }
+        for y in 0..16 {
+            for x in 0..16 {
+                let i = y * 17 + x;
+                indices.extend([i, i + 17, i + 1, i + 1, i + 17, i + 18]);
+            }
+        }
+        let mut mesh = Mesh::new(PrimitiveTopology::TriangleList, RenderAssetUsages::all());
+        mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, positions);
+        mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, normals);
+        mesh.insert_attribute(Mesh::ATTRIBUTE_UV_0, uvs);
+        mesh.insert_indices(Indices::U32(indices));
+        let mut triangles = mesh_triangles(&mesh).unwrap();
+        set_triangle_uvs(&mesh, &mut triangles);
+        // Reversed, as the leaves of a tree would reorder them
+        let order = (0..triangles.len() as u32).rev().collect::<Vec<_>>();
+
+        let full = CacheTriangles::new(&triangles, &order, &ObvhsCacheLayout::default(), None);
+        let compact_layout = ObvhsCacheLayout {
+            indexed: true,
+            precision: PositionPrecision::Quantized,
+            normals: false,
+        };
+        let mut quantized_triangles = mesh_triangles(&mesh).unwrap();
+        set_triangle_uvs(&mesh, &mut quantized_triangles);
+        let quantization = quantize_positions(&mut quantized_triangles, &compact_layout);
+        let compact =
+            CacheTriangles::new(&quantized_triangles, &order, &compact_layout, quantization);
+
+        assert_eq!(full.len(), triangles.len());
+        assert_eq!(compact.len(), triangles.len());
+        for (slot, index) in order.iter().enumerate() {
+            let triangle = &triangles[*index as usize];
+            let stored = full.get(slot).unwrap();
+            assert_eq!(stored.triangle_index, triangle.face.triangle_index);
+            assert_eq!(stored.positions, triangle.positions);
+            assert_eq!(stored.normals, triangle.normals);
+
+            let compact_stored = compact.get(slot).unwrap();
+            assert_eq!(compact_stored.normals, None);
+            assert_eq!(compact_stored.uvs, triangle.uvs);
+            for (a, b) in compact_stored.positions.iter().zip(triangle.positions) {
+                assert!(a.distance(b) < 1e-3);
+            }
+            // The positions are rounded once, the stored ones are the ones of the tree
+            assert_eq!(
+                compact_stored.positions,
+                quantized_triangles[*index as usize].positions
+            );
+        }
+        assert_eq!(
+            compact.find(100).map(|triangle| triangle.triangle_index),
+            Some(100)
+        );
+        assert!(compact.memory_footprint() * 3 < full.memory_footprint());
+    }
+}
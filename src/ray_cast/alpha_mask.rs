@@ -0,0 +1,165 @@
+//! Alpha test of the hits on the meshes with an alpha masked [`StandardMaterial`], so that the
+//! transparent parts of their textures, like the gaps between the leaves of foliage, are not hit.
+
+use bevy_asset::Assets;
+use bevy_color::Alpha;
+use bevy_image::{Image, ImageAddressMode, ImageSampler, ImageSamplerBorderColor};
+use bevy_math::{Affine2, UVec2, Vec2};
+use bevy_pbr::{StandardMaterial, UvChannel};
+use bevy_render::{alpha::AlphaMode, mesh::Mesh, mesh::MeshVertexAttribute};
+
+/// The alpha mask of the material of a mesh entity.
+pub struct AlphaMask<'a> {
+    /// The hits where the alpha is lower than this are rejected.
+    pub cutoff: f32,
+    /// The alpha of the base color of the material, multiplied with the one of its texture.
+    pub base_alpha: f32,
+    /// The base color texture, if it is loaded and readable on the CPU.
+    pub texture: Option<&'a Image>,
+    /// The UV attribute of the mesh used by the texture.
+    pub uv_attribute: MeshVertexAttribute,
+    pub uv_transform: Affine2,
+}
+
+impl<'a> AlphaMask<'a> {
+    /// Returns the alpha mask of a `material`, or `None` if it is not [`AlphaMode::Mask`].
+    pub fn from_material(material: &StandardMaterial, images: &'a Assets<Image>) -> Option<Self> {
+        let AlphaMode::Mask(cutoff) = material.alpha_mode else {
+            return None;
+        };
+        Some(Self {
+            cutoff,
+            base_alpha: material.base_color.alpha(),
+            texture: material
+                .base_color_texture
+                .as_ref()
+                .and_then(|texture| images.get(texture)),
+            uv_attribute: match material.base_color_channel {
+                UvChannel::Uv0 => Mesh::ATTRIBUTE_UV_0,
+                UvChannel::Uv1 => Mesh::ATTRIBUTE_UV_1,
+            },
+            uv_transform: material.uv_transform,
+        })
+    }
+
+    /// Returns `true` if the material is opaque enough at the `uv` of a hit to be hit.
+    pub fn is_opaque_at(&self, uv: Vec2) -> bool {
+        let texture_alpha = self
+            .texture
+            .and_then(|texture| texture_alpha(texture, self.uv_transform.transform_point2(uv)))
+            .unwrap_or(1.0);
+        self.base_alpha * texture_alpha >= self.cutoff
+    }
+}
+
+/// Returns the alpha of the texel of the `image` at `uv`, addressed outside of `0.0..1.0` with the
+/// address modes of its sampler. The images with the default sampler are clamped to their edges,
+/// as with the default sampler of the `ImagePlugin`. Returns `None` if the format of the image
+/// can't be read.
+pub fn texture_alpha(image: &Image, uv: Vec2) -> Option<f32> {
+    let size = image.size();
+    if size.x == 0 || size.y == 0 {
+        return None;
+    }
+    let (address_modes, border_color) = match &image.sampler {
+        ImageSampler::Descriptor(descriptor) => (
+            [descriptor.address_mode_u, descriptor.address_mode_v],
+            descriptor.border_color,
+        ),
+        ImageSampler::Default => ([ImageAddressMode::ClampToEdge; 2], None),
+    };
+
+    let mut texel = UVec2::ZERO;
+    for axis in 0..2 {
+        let Some(coordinate) = address_coordinate(uv[axis], address_modes[axis]) else {
+            // Outside of an image clamped to its border
+            return Some(match border_color {
+                Some(
+                    ImageSamplerBorderColor::OpaqueBlack | ImageSamplerBorderColor::OpaqueWhite,
+                ) => 1.0,
+                _ => 0.0,
+            });
+        };
+        texel[axis] = ((coordinate * size[axis] as f32) as u32).min(size[axis] - 1);
+    }
+    image
+        .get_color_at(texel.x, texel.y)
+        .ok()
+        .map(|color| color.alpha())
+}
+
+/// Maps a texture coordinate in `0.0..=1.0` with an address mode, or returns `None` if it is on the
+/// border of an image clamped with [`ImageAddressMode::ClampToBorder`].
+fn address_coordinate(coordinate: f32, address_mode: ImageAddressMode) -> Option<f32> {
+    match address_mode {
+        ImageAddressMode::ClampToEdge => Some(coordinate.clamp(0.0, 1.0)),
+        ImageAddressMode::Repeat => Some(coordinate.rem_euclid(1.0)),
+        ImageAddressMode::MirrorRepeat => {
+            let coordinate = coordinate.rem_euclid(2.0);
+            Some(if coordinate > 1.0 {
+                2.0 - coordinate
+            } else {
+                coordinate
+            })
+        }
+        ImageAddressMode::ClampToBorder => (0.0..=1.0).contains(&coordinate).then_some(coordinate),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy_asset::RenderAssetUsages;
+    use bevy_color::Color;
+    use bevy_image::ImageSamplerDescriptor;
+    use bevy_render::render_resource::{Extent3d, TextureDimension, TextureFormat};
+
+    use super::*;
+
+    #[test]
+    fn alpha_test() {
+        // The left half of the texture is transparent
+        let image = Image::new(
+            Extent3d {
+                width: 2,
+                height: 1,
+                depth_or_array_layers: 1,
+            },
+            TextureDimension::D2,
+            vec![255, 255, 255, 0, 255, 255, 255, 255],
+            TextureFormat::Rgba8Unorm,
+            RenderAssetUsages::all(),
+        );
+        let mut images = Assets::<Image>::default();
+        let material = StandardMaterial {
+            base_color: Color::srgba(1.0, 1.0, 1.0, 0.8),
+            base_color_texture: Some(images.add(image)),
+            alpha_mode: AlphaMode::Mask(0.5),
+            ..Default::default()
+        };
+
+        let alpha_mask = AlphaMask::from_material(&material, &images).unwrap();
+        assert!(!alpha_mask.is_opaque_at(Vec2::new(0.25, 0.5)));
+        assert!(alpha_mask.is_opaque_at(Vec2::new(0.75, 0.5)));
+        // The texture is clamped to its edges with the default sampler
+        assert!(alpha_mask.is_opaque_at(Vec2::new(1.25, 0.5)));
+        assert!(!alpha_mask.is_opaque_at(Vec2::new(-0.5, 0.5)));
+
+        // The texture repeats with a repeating sampler
+        images
+            .get_mut(material.base_color_texture.as_ref().unwrap())
+            .unwrap()
+            .sampler = ImageSampler::Descriptor(ImageSamplerDescriptor {
+            address_mode_u: ImageAddressMode::Repeat,
+            ..Default::default()
+        });
+        let alpha_mask = AlphaMask::from_material(&material, &images).unwrap();
+        assert!(!alpha_mask.is_opaque_at(Vec2::new(1.25, 0.5)));
+        assert!(alpha_mask.is_opaque_at(Vec2::new(-0.25, 0.5)));
+
+        let opaque = StandardMaterial {
+            alpha_mode: AlphaMode::Opaque,
+            ..material
+        };
+        assert!(AlphaMask::from_material(&opaque, &images).is_none());
+    }
+}
@@ -0,0 +1,122 @@
+//! Closest point queries against the triangles of the meshes, see
+//! [`BvhMeshRayCast::closest_point`](super::BvhMeshRayCast::closest_point).
+//!
+//! The entities are visited by increasing distance to their AABB, and the BVH cache of each mesh is
+//! traversed best first, by increasing distance to its nodes, so that only the triangles which can
+//! be nearer than the nearest one found so far are tested.
+
+use bevy_math::{Mat4, Vec3};
+
+use super::{
+    mesh_caches::TriangleQuery,
+    shape_cast::{closest_point_on_triangle, max_stretch},
+};
+
+/// The point of a mesh nearest to a query point.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClosestPointHit {
+    /// The nearest point on the mesh, in world space.
+    pub point: Vec3,
+    /// The normal of the triangle of the point, from its winding, in world space.
+    pub normal: Vec3,
+    /// The barycentric coordinates of the point in its triangle, as in the hits of the ray casts.
+    pub barycentric_coords: Vec3,
+    /// The distance from the query point to the nearest point.
+    pub distance: f32,
+    /// The index of the triangle of the point in its mesh.
+    pub triangle_index: usize,
+}
+
+/// Searches the triangle of a mesh nearest to a point, within a maximum distance.
+pub(crate) struct ClosestPointQuery {
+    point: Vec3,
+    max_distance: f32,
+    /// The transform from the space of the triangles to world space.
+    transform: Mat4,
+    /// The point in the space of the triangles, and how much the distances can be stretched from
+    /// world space to it.
+    mesh_point: Vec3,
+    stretch: f32,
+    pub nearest_hit: Option<ClosestPointHit>,
+}
+
+impl ClosestPointQuery {
+    pub fn new(point: Vec3, max_distance: f32, transform: &Mat4) -> Self {
+        let world_to_mesh = transform.inverse();
+        Self {
+            point,
+            max_distance,
+            transform: *transform,
+            mesh_point: world_to_mesh.transform_point3(point),
+            stretch: max_stretch(&world_to_mesh),
+            nearest_hit: None,
+        }
+    }
+
+    /// Returns the distance to the nearest point found so far.
+    fn nearest_distance(&self) -> f32 {
+        self.nearest_hit
+            .as_ref()
+            .map_or(self.max_distance, |hit| hit.distance)
+    }
+}
+
+impl TriangleQuery for ClosestPointQuery {
+    fn node_cost(&self, min: Vec3, max: Vec3) -> Option<f32> {
+        // A lower bound of the world space distance to the AABB
+        let offset = (min - self.mesh_point)
+            .max(self.mesh_point - max)
+            .max(Vec3::ZERO);
+        let distance = offset.length() / self.stretch;
+        (distance <= self.nearest_distance()).then_some(distance)
+    }
+
+    fn visit(&mut self, positions: [Vec3; 3], triangle_index: usize) -> f32 {
+        let triangle = positions.map(|position| self.transform.transform_point3(position));
+        let (point, weights) = closest_point_on_triangle(self.point, &triangle);
+        let distance = self.point.distance(point);
+        let nearest_distance = self.nearest_distance();
+        if distance <= nearest_distance
+            && (self.nearest_hit.is_none() || distance < nearest_distance)
+        {
+            self.nearest_hit = Some(ClosestPointHit {
+                point,
+                normal: (triangle[1] - triangle[0])
+                    .cross(triangle[2] - triangle[0])
+                    .normalize_or_zero(),
+                barycentric_coords: Vec3::new(weights[1], weights[2], weights[0]),
+                distance,
+                triangle_index,
+            });
+        }
+        self.nearest_distance()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ray_cast::shape_cast::tests::TRIANGLE;
+
+    #[test]
+    fn closest_point() {
+        // The triangle is stretched along X, so its corner is at x = 2
+        let transform = Mat4::from_scale(Vec3::new(2.0, 1.0, 1.0));
+        let mut query = ClosestPointQuery::new(Vec3::new(3.0, 1.0, -2.0), 5.0, &transform);
+        let [min, max] = [Vec3::new(-1.0, 0.0, -1.0), Vec3::new(1.0, 0.0, 1.0)];
+        let cost = query.node_cost(min, max).unwrap();
+        assert!(cost <= 3.0_f32.sqrt());
+
+        query.visit(TRIANGLE, 7);
+        let hit = query.nearest_hit.as_ref().unwrap();
+        assert!(hit.point.distance(Vec3::new(2.0, 0.0, -1.0)) < 1e-5);
+        assert!((hit.distance - 3.0_f32.sqrt()).abs() < 1e-5);
+        assert!(hit.barycentric_coords.distance(Vec3::X) < 1e-5);
+        assert_eq!(hit.triangle_index, 7);
+
+        // The nodes farther than the nearest point are skipped
+        assert!(query
+            .node_cost(Vec3::splat(10.0), Vec3::splat(11.0))
+            .is_none());
+    }
+}
@@ -1,6 +1,11 @@
-use bevy_math::{Mat4, Ray3d, Vec3};
-use bevy_picking_more_hitinfo::mesh_picking::ray_cast::{ray_mesh_intersection, Backfaces, RayMeshHit};
-use bevy_render::mesh::{Indices, Mesh, PrimitiveTopology};
+use bevy_math::{Dir3, FloatOrd, Mat4, Ray3d, Vec3};
+use bevy_picking_more_hitinfo::mesh_picking::ray_cast::{Backfaces, RayMeshHit};
+use bevy_render::{
+    camera::Projection,
+    mesh::{Mesh, PrimitiveTopology},
+};
+
+use crate::common::{mesh_segment_indices, mesh_triangles_iter, triangle::Triangle};
 
 /// Hit data for an intersection between a ray and a triangle.
 #[derive(Default, Debug)]
@@ -9,33 +14,487 @@ pub struct RayTriangleHit {
     pub barycentric_coords: (f32, f32),
 }
 
-/// Casts a ray on a mesh, and returns the intersection.
+/// Closest approach between a ray and a segment.
+#[derive(Default, Debug)]
+pub struct RaySegmentApproach {
+    /// Distance along the ray to the point closest to the segment.
+    pub distance: f32,
+    /// Parameter along the segment of the point closest to the ray, from `0.0` at its start to
+    /// `1.0` at its end.
+    pub parameter: f32,
+    /// Distance between the ray and the segment.
+    pub separation: f32,
+}
+
+/// How far from a ray a point can be picked.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PickTolerance {
+    /// A distance to the ray, in world units.
+    World(f32),
+    /// An angle around the ray in radians, as seen from its origin: the tolerance grows with the
+    /// distance along the ray, as a number of pixels on the screen.
+    Angular(f32),
+}
+
+impl PickTolerance {
+    /// Returns the tolerance covered by `pixels` on the screen of a camera with the given
+    /// `projection` and `viewport_height`, in logical pixels.
+    pub fn from_pixels(pixels: f32, projection: &Projection, viewport_height: f32) -> Self {
+        match projection {
+            Projection::Perspective(perspective) => {
+                PickTolerance::Angular(pixels * perspective.fov / viewport_height)
+            }
+            Projection::Orthographic(orthographic) => {
+                PickTolerance::World(pixels * orthographic.area.height() / viewport_height)
+            }
+        }
+    }
+
+    /// Returns how far from the ray is a point at `separation` from it and `distance` along it, in
+    /// the unit of the tolerance. The point can be picked if it is at most [`Self::limit`].
+    pub fn metric(&self, separation: f32, distance: f32) -> f32 {
+        match self {
+            PickTolerance::World(_) => separation,
+            PickTolerance::Angular(_) if distance > 0.0 => separation / distance,
+            PickTolerance::Angular(_) if separation <= 0.0 => 0.0,
+            PickTolerance::Angular(_) => f32::INFINITY,
+        }
+    }
+
+    /// Returns the maximum [`Self::metric`] of a point that can be picked.
+    pub fn limit(&self) -> f32 {
+        match self {
+            PickTolerance::World(distance) => *distance,
+            PickTolerance::Angular(angle) => angle.tan(),
+        }
+    }
+}
+
+/// A point of a mesh picked near a ray.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RayPointHit {
+    /// Index of the vertex of the point in the mesh.
+    pub vertex_index: usize,
+    /// Position of the point, in world space.
+    pub point: Vec3,
+    /// Distance along the ray to the point closest to the picked point.
+    pub distance: f32,
+    /// Distance between the ray and the picked point.
+    pub separation: f32,
+    /// The [`PickTolerance::metric`] of the point, the nearest point to the ray has the lowest.
+    pub metric: f32,
+}
+
+impl RayPointHit {
+    /// Returns `true` if this point is nearer to the ray than `other`, or as near but closer to the
+    /// ray origin.
+    pub fn is_nearer_than(&self, other: &RayPointHit) -> bool {
+        (FloatOrd(self.metric), FloatOrd(self.distance))
+            < (FloatOrd(other.metric), FloatOrd(other.distance))
+    }
+}
+
+/// Returns the hit of a point of a mesh, in world space, if it is within the `tolerance` of the
+/// ray before `max_distance`.
+pub fn ray_point_hit(
+    ray: &Ray3d,
+    tolerance: &PickTolerance,
+    max_distance: f32,
+    vertex_index: usize,
+    point: Vec3,
+) -> Option<RayPointHit> {
+    let approach = ray_segment_closest_approach(ray, &[point, point]);
+    let metric = tolerance.metric(approach.separation, approach.distance);
+    if approach.distance > max_distance || metric > tolerance.limit() {
+        return None;
+    }
+    Some(RayPointHit {
+        vertex_index,
+        point,
+        distance: approach.distance,
+        separation: approach.separation,
+        metric,
+    })
+}
+
+/// Returns a lower bound of the [`PickTolerance::metric`] of the points inside a world space
+/// sphere, or `None` if none of them can be picked within the `tolerance` before `max_distance`.
+pub fn ray_sphere_point_metric(
+    ray: &Ray3d,
+    tolerance: &PickTolerance,
+    max_distance: f32,
+    center: Vec3,
+    radius: f32,
+) -> Option<f32> {
+    let approach = ray_segment_closest_approach(ray, &[center, center]);
+    if approach.distance - radius > max_distance {
+        return None;
+    }
+    let metric = tolerance.metric(
+        (approach.separation - radius).max(0.0),
+        approach.distance + radius,
+    );
+    (metric <= tolerance.limit()).then_some(metric)
+}
+
+/// A segment of a mesh of lines picked near a ray.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RaySegmentHit {
+    /// Index of the segment in the mesh: the line of a line list, or the segment of a line strip.
+    pub segment_index: usize,
+    /// Indices of the vertices at the start and the end of the segment.
+    pub vertex_indices: [usize; 2],
+    /// Point of the segment closest to the ray, in world space.
+    pub point: Vec3,
+    /// Parameter along the segment of the picked point, from `0.0` at its start to `1.0` at its end.
+    pub parameter: f32,
+    /// Distance along the ray to the point closest to the segment.
+    pub distance: f32,
+    /// Distance between the ray and the segment.
+    pub separation: f32,
+    /// The [`PickTolerance::metric`] of the segment, the nearest segment to the ray has the lowest.
+    pub metric: f32,
+}
+
+impl RaySegmentHit {
+    /// Returns `true` if this segment is nearer to the ray than `other`, or as near but closer to
+    /// the ray origin.
+    pub fn is_nearer_than(&self, other: &RaySegmentHit) -> bool {
+        (FloatOrd(self.metric), FloatOrd(self.distance))
+            < (FloatOrd(other.metric), FloatOrd(other.distance))
+    }
+}
+
+/// Returns the hit of a segment of a mesh, in world space, if it is within the `tolerance` of the
+/// ray before `max_distance`.
+pub fn ray_segment_hit(
+    ray: &Ray3d,
+    tolerance: &PickTolerance,
+    max_distance: f32,
+    segment_index: usize,
+    vertex_indices: [usize; 2],
+    segment: &[Vec3; 2],
+) -> Option<RaySegmentHit> {
+    let approach = ray_segment_closest_approach(ray, segment);
+    let metric = tolerance.metric(approach.separation, approach.distance);
+    if approach.distance > max_distance || metric > tolerance.limit() {
+        return None;
+    }
+    Some(RaySegmentHit {
+        segment_index,
+        vertex_indices,
+        point: segment[0].lerp(segment[1], approach.parameter),
+        parameter: approach.parameter,
+        distance: approach.distance,
+        separation: approach.separation,
+        metric,
+    })
+}
+
+/// Returns the segment of a mesh with a [`PrimitiveTopology::LineList`] or
+/// [`PrimitiveTopology::LineStrip`] topology nearest to the ray, within the `tolerance` and before
+/// `max_distance`.
+pub fn ray_nearest_segment_over_mesh(
+    mesh: &Mesh,
+    transform: &Mat4,
+    ray: Ray3d,
+    tolerance: &PickTolerance,
+    max_distance: f32,
+) -> Option<RaySegmentHit> {
+    if !matches!(
+        mesh.primitive_topology(),
+        PrimitiveTopology::LineList | PrimitiveTopology::LineStrip
+    ) {
+        return None;
+    }
+    let positions = mesh.attribute(Mesh::ATTRIBUTE_POSITION)?.as_float3()?;
+    let world_position = |index: usize| {
+        positions
+            .get(index)
+            .map(|position| transform.transform_point3(Vec3::from(*position)))
+    };
+
+    mesh_segment_indices(mesh)
+        .into_iter()
+        .filter_map(|(segment_index, [a, b])| {
+            let segment = [world_position(a)?, world_position(b)?];
+            ray_segment_hit(
+                &ray,
+                tolerance,
+                max_distance,
+                segment_index,
+                [a, b],
+                &segment,
+            )
+        })
+        .reduce(|nearest, hit| {
+            if hit.is_nearer_than(&nearest) {
+                hit
+            } else {
+                nearest
+            }
+        })
+}
+
+/// Returns the point of a mesh with a [`PrimitiveTopology::PointList`] topology nearest to the ray,
+/// within the `tolerance` and before `max_distance`.
+pub fn ray_nearest_point_over_mesh(
+    mesh: &Mesh,
+    transform: &Mat4,
+    ray: Ray3d,
+    tolerance: &PickTolerance,
+    max_distance: f32,
+) -> Option<RayPointHit> {
+    if mesh.primitive_topology() != PrimitiveTopology::PointList {
+        return None;
+    }
+    let positions = mesh.attribute(Mesh::ATTRIBUTE_POSITION)?.as_float3()?;
+    let vertex_indices: Box<dyn Iterator<Item = usize>> = match mesh.indices() {
+        Some(indices) => Box::new(indices.iter()),
+        None => Box::new(0..positions.len()),
+    };
+
+    vertex_indices
+        .filter_map(|vertex_index| {
+            let point = transform.transform_point3(Vec3::from(*positions.get(vertex_index)?));
+            ray_point_hit(&ray, tolerance, max_distance, vertex_index, point)
+        })
+        .reduce(|nearest, hit| {
+            if hit.is_nearer_than(&nearest) {
+                hit
+            } else {
+                nearest
+            }
+        })
+}
+
+/// Casts a ray on a mesh, and returns the intersection before `max_distance`.
 pub fn ray_intersection_over_mesh(
     mesh: &Mesh,
     transform: &Mat4,
     ray: Ray3d,
     culling: Backfaces,
+    max_distance: f32,
 ) -> Option<RayMeshHit> {
-    if mesh.primitive_topology() != PrimitiveTopology::TriangleList {
-        return None; // ray_mesh_intersection assumes vertices are laid out in a triangle list
+    // The hits report the same triangle index as the caches
+    ray_intersection_over_triangles(
+        mesh_triangles_iter(mesh).ok()?,
+        transform,
+        ray,
+        culling,
+        max_distance,
+    )
+}
+
+/// Casts a ray on a mesh, and returns all the intersections before `max_distance`, nearest first.
+pub fn ray_intersections_over_mesh(
+    mesh: &Mesh,
+    transform: &Mat4,
+    ray: Ray3d,
+    culling: Backfaces,
+    max_distance: f32,
+) -> Vec<RayMeshHit> {
+    let Ok(triangles) = mesh_triangles_iter(mesh) else {
+        return Vec::new();
+    };
+    let Some(mesh_space_ray) = mesh_space_ray(transform, ray) else {
+        return Vec::new();
+    };
+    let mesh_max_distance = mesh_space_distance(transform, &mesh_space_ray, max_distance);
+
+    let mut hits = triangles
+        .filter_map(|triangle| {
+            let hit = triangle_intersection(
+                &triangle.positions,
+                &triangle.normals,
+                mesh_max_distance,
+                &mesh_space_ray,
+                culling,
+            )?;
+            Some(mesh_hit_to_world(
+                transform,
+                &mesh_space_ray,
+                hit,
+                triangle.face.triangle_index,
+            ))
+        })
+        .collect::<Vec<_>>();
+    sort_hits(&mut hits);
+    hits
+}
+
+/// Casts a ray on the triangles of a mesh, and returns the closest intersection before `max_distance`.
+fn ray_intersection_over_triangles(
+    triangles: impl IntoIterator<Item = Triangle>,
+    transform: &Mat4,
+    ray: Ray3d,
+    culling: Backfaces,
+    max_distance: f32,
+) -> Option<RayMeshHit> {
+    let mesh_space_ray = mesh_space_ray(transform, ray)?;
+    let mut closest_hit_distance = mesh_space_distance(transform, &mesh_space_ray, max_distance);
+    let mut closest_hit = None;
+    for triangle in triangles {
+        let Some(hit) = triangle_intersection(
+            &triangle.positions,
+            &triangle.normals,
+            closest_hit_distance,
+            &mesh_space_ray,
+            culling,
+        ) else {
+            continue;
+        };
+        closest_hit_distance = hit.distance;
+        closest_hit = Some(mesh_hit_to_world(
+            transform,
+            &mesh_space_ray,
+            hit,
+            triangle.face.triangle_index,
+        ));
     }
-    // Vertex positions are required
-    let positions = mesh.attribute(Mesh::ATTRIBUTE_POSITION)?.as_float3()?;
+    closest_hit
+}
 
-    // Normals are optional
-    let normals = mesh
-        .attribute(Mesh::ATTRIBUTE_NORMAL)
-        .and_then(|normal_values| normal_values.as_float3());
+/// Casts a ray on a mesh of lines or points, and returns all the lines or points closer than
+/// `pick_radius` to the ray before `max_distance`, nearest first.
+///
+/// The distances are computed in world space. The hit point is on the line or at the point, its
+/// normal faces the ray, and its barycentric coordinates are the weights of the two vertices of
+/// the line. Meshes of triangles are never hit.
+pub fn ray_intersections_over_lines_and_points(
+    mesh: &Mesh,
+    transform: &Mat4,
+    ray: Ray3d,
+    pick_radius: f32,
+    max_distance: f32,
+) -> Vec<RayMeshHit> {
+    let Some(positions) = mesh
+        .attribute(Mesh::ATTRIBUTE_POSITION)
+        .and_then(|position_values| position_values.as_float3())
+    else {
+        return Vec::new();
+    };
+    let world_position = |index: usize| {
+        positions
+            .get(index)
+            .map(|position| transform.transform_point3(Vec3::from(*position)))
+    };
 
-    match mesh.indices() {
-        Some(Indices::U16(indices)) => {
-            ray_mesh_intersection(ray, transform, positions, normals, Some(indices), culling)
-        }
-        Some(Indices::U32(indices)) => {
-            ray_mesh_intersection(ray, transform, positions, normals, Some(indices), culling)
-        }
-        None => ray_mesh_intersection::<usize>(ray, transform, positions, normals, None, culling),
+    let mut hits = mesh_segment_indices(mesh)
+        .into_iter()
+        .filter_map(|(index, [a, b])| {
+            let segment = [world_position(a)?, world_position(b)?];
+            ray_segment_mesh_hit(&ray, pick_radius, max_distance, index, &segment)
+        })
+        .collect::<Vec<_>>();
+    sort_hits(&mut hits);
+    hits
+}
+
+/// Returns the hit of a world space segment, or point if both its ends are the same, at `index` in
+/// a mesh of lines or points, if it is closer than `pick_radius` to the ray before `max_distance`.
+/// See [`ray_intersections_over_lines_and_points`].
+pub fn ray_segment_mesh_hit(
+    ray: &Ray3d,
+    pick_radius: f32,
+    max_distance: f32,
+    index: usize,
+    segment: &[Vec3; 2],
+) -> Option<RayMeshHit> {
+    let approach = ray_segment_closest_approach(ray, segment);
+    if approach.separation > pick_radius || approach.distance > max_distance {
+        return None;
+    }
+    Some(RayMeshHit {
+        point: segment[0].lerp(segment[1], approach.parameter),
+        normal: -*ray.direction,
+        barycentric_coords: Vec3::new(1.0 - approach.parameter, approach.parameter, 0.0),
+        distance: approach.distance,
+        triangle: None,
+        triangle_index: Some(index),
+    })
+}
+
+/// Computes the closest approach between a ray and a segment. The segment can be a point.
+pub fn ray_segment_closest_approach(ray: &Ray3d, segment: &[Vec3; 2]) -> RaySegmentApproach {
+    let direction = *ray.direction;
+    let edge = segment[1] - segment[0];
+    let offset = ray.origin - segment[0];
+    let edge_length_squared = edge.length_squared();
+    let direction_dot_edge = direction.dot(edge);
+    let direction_dot_offset = direction.dot(offset);
+
+    // Closest points of the two lines, clamped to the ray and the segment
+    let denominator = edge_length_squared - direction_dot_edge * direction_dot_edge;
+    let mut parameter = if denominator > f32::EPSILON {
+        ((edge.dot(offset) - direction_dot_edge * direction_dot_offset) / denominator)
+            .clamp(0.0, 1.0)
+    } else {
+        0.0
+    };
+    let distance = (parameter * direction_dot_edge - direction_dot_offset).max(0.0);
+    if edge_length_squared > f32::EPSILON {
+        parameter = (edge.dot(offset + direction * distance) / edge_length_squared).clamp(0.0, 1.0);
     }
+
+    RaySegmentApproach {
+        distance,
+        parameter,
+        separation: ray
+            .get_point(distance)
+            .distance(segment[0] + edge * parameter),
+    }
+}
+
+/// Transforms a world space ray in the space of a mesh.
+pub fn mesh_space_ray(transform: &Mat4, ray: Ray3d) -> Option<Ray3d> {
+    let world_to_mesh = transform.inverse();
+
+    Some(Ray3d::new(
+        world_to_mesh.transform_point3(ray.origin),
+        Dir3::new(world_to_mesh.transform_vector3(*ray.direction)).ok()?,
+    ))
+}
+
+/// Converts a world space distance along the ray into a distance along the mesh space ray.
+pub fn mesh_space_distance(transform: &Mat4, mesh_space_ray: &Ray3d, distance: f32) -> f32 {
+    if distance == f32::MAX || distance.is_infinite() {
+        return distance;
+    }
+    distance
+        / transform
+            .transform_vector3(*mesh_space_ray.direction)
+            .length()
+}
+
+/// Converts a hit on a triangle of a mesh, computed with the mesh space ray, into a world space hit.
+pub fn mesh_hit_to_world(
+    transform: &Mat4,
+    mesh_space_ray: &Ray3d,
+    hit: RayMeshHit,
+    triangle_index: usize,
+) -> RayMeshHit {
+    RayMeshHit {
+        point: transform.transform_point3(hit.point),
+        normal: transform.transform_vector3(hit.normal),
+        barycentric_coords: hit.barycentric_coords,
+        distance: transform
+            .transform_vector3(mesh_space_ray.direction * hit.distance)
+            .length(),
+        triangle: hit.triangle.map(|tri| {
+            [
+                transform.transform_point3(tri[0]),
+                transform.transform_point3(tri[1]),
+                transform.transform_point3(tri[2]),
+            ]
+        }),
+        triangle_index: Some(triangle_index),
+    }
+}
+
+/// Sorts hits by distance, nearest first.
+pub fn sort_hits(hits: &mut [RayMeshHit]) {
+    hits.sort_by_key(|hit| FloatOrd(hit.distance));
 }
 
 pub fn triangle_intersection(
@@ -129,8 +588,6 @@ pub fn ray_triangle_intersection(
 
 #[cfg(test)]
 mod tests {
-    use bevy_math::{Dir3, Vec3};
-
     use super::*;
 
     // Triangle vertices to be used in a left-hand coordinate system
@@ -146,6 +603,34 @@ mod tests {
         assert!(result.unwrap().distance - 1.0 <= f32::EPSILON);
     }
 
+    #[test]
+    fn mesh_space_distance_with_scale() {
+        let transform = Mat4::from_scale(Vec3::splat(2.0));
+        let ray = Ray3d::new(Vec3::new(0.0, 0.0, -10.0), Dir3::Z);
+        let mesh_space_ray = mesh_space_ray(&transform, ray).unwrap();
+        assert!((mesh_space_distance(&transform, &mesh_space_ray, 4.0) - 2.0).abs() < 1e-6);
+        assert_eq!(
+            mesh_space_distance(&transform, &mesh_space_ray, f32::INFINITY),
+            f32::INFINITY
+        );
+    }
+
+    #[test]
+    fn ray_segment_closest_approach_crossing() {
+        let ray = Ray3d::new(Vec3::new(0.0, 0.0, -5.0), Dir3::Z);
+        let segment = [Vec3::new(-1.0, 0.5, 2.0), Vec3::new(3.0, 0.5, 2.0)];
+        let approach = ray_segment_closest_approach(&ray, &segment);
+        assert!((approach.distance - 7.0).abs() < 1e-5);
+        assert!((approach.parameter - 0.25).abs() < 1e-5);
+        assert!((approach.separation - 0.5).abs() < 1e-5);
+
+        // A point behind the ray origin is approached at the origin
+        let point = Vec3::new(0.0, 1.0, -6.0);
+        let approach = ray_segment_closest_approach(&ray, &[point, point]);
+        assert_eq!(approach.distance, 0.0);
+        assert!((approach.separation - 2.0_f32.sqrt()).abs() < 1e-5);
+    }
+
     #[test]
     fn ray_cast_triangle_mt_culling() {
         let triangle = [V2.into(), V1.into(), V0.into()];
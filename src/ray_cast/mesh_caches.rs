@@ -0,0 +1,732 @@
+//! Access to the meshes and their BVH caches, used by the ray casts of each backend.
+
+use bevy_asset::Assets;
+use bevy_ecs::{prelude::*, system::SystemParam};
+use bevy_image::Image;
+use bevy_math::{Mat4, Ray3d, Vec2, Vec3};
+use bevy_pbr::StandardMaterial;
+use bevy_picking_more_hitinfo::mesh_picking::ray_cast::{Backfaces, RayMeshHit};
+use bevy_render::{
+    mesh::{Mesh, MeshVertexAttribute, PrimitiveTopology},
+    primitives::Aabb,
+};
+
+#[cfg(feature = "bvh")]
+use crate::bvh::{
+    ray_cast::{
+        query_triangles_using_bvh_cache, ray_any_hit_over_mesh_using_bvh_cache,
+        ray_intersection_over_mesh_using_bvh_cache, ray_intersections_over_mesh_using_bvh_cache,
+    },
+    BvhCache,
+};
+
+#[cfg(feature = "obvhs")]
+use crate::obvhs::{
+    lines::{ray_intersections_using_line_cache, ray_nearest_segment_using_line_cache, LineCache},
+    morph::MorphedMeshBvhs,
+    packet::{ray_packet_intersections_over_mesh_using_obvhs_bvh2_cache, RAY_PACKET_SIZE},
+    point_cloud::{
+        ray_intersections_using_point_cloud_cache, ray_nearest_point_using_point_cloud_cache,
+        PointCloudCache,
+    },
+    ray_cast::{
+        query_triangles_using_obvhs_bvh2_cache, ray_any_hit_over_mesh_using_obvhs_bvh2_cache,
+        ray_intersection_over_mesh_using_obvhs_bvh2_cache,
+        ray_intersections_over_mesh_using_obvhs_bvh2_cache,
+    },
+    skinned::SkinnedMeshBvhs,
+    ObvhsBvh2Cache,
+};
+
+#[cfg(any(feature = "obvhs", feature = "bvh"))]
+use crate::storage::AssetsBvhCaches;
+
+use crate::{
+    common::{
+        attribute::{interpolate_triangle_uv, interpolate_vertex_attribute},
+        mesh_triangle_face, mesh_triangles,
+    },
+    BvhBackend,
+};
+
+use super::{
+    alpha_mask::AlphaMask,
+    intersections::{
+        ray_intersection_over_mesh, ray_intersections_over_lines_and_points,
+        ray_intersections_over_mesh, ray_nearest_point_over_mesh, ray_nearest_segment_over_mesh,
+        PickTolerance, RayPointHit, RaySegmentHit,
+    },
+    MeshTarget,
+};
+
+/// A query over the triangles of a mesh, which traverses its BVH cache best first, see
+/// [`MeshBvhCaches::query_triangles`].
+pub(crate) trait TriangleQuery {
+    /// Returns a lower bound of the cost of the triangles in the AABB from `min` to `max`, in the
+    /// space of the triangles, or `None` if none of them can match the query.
+    fn node_cost(&self, min: Vec3, max: Vec3) -> Option<f32>;
+
+    /// Tests a triangle, with its `positions` and its index in the mesh, and returns the cost of
+    /// the best triangle found so far, so that the nodes which can't have a better one are skipped.
+    fn visit(&mut self, positions: [Vec3; 3], triangle_index: usize) -> f32;
+}
+
+/// The meshes and their BVH caches.
+///
+/// Each query uses the cache of the backend of the target, and falls back to the mesh if the cache
+/// is not built yet. Skinned and morphed mesh entities use their own tree instead, see
+/// [`SkinnedMeshBvhs`] and [`MorphedMeshBvhs`].
+#[derive(SystemParam)]
+pub struct MeshBvhCaches<'w> {
+    #[doc(hidden)]
+    pub meshes: Res<'w, Assets<Mesh>>,
+    #[cfg(feature = "bvh")]
+    #[doc(hidden)]
+    pub bvh_caches: Res<'w, AssetsBvhCaches<Mesh, BvhCache>>,
+    #[cfg(feature = "obvhs")]
+    #[doc(hidden)]
+    pub obvhs_bvh2_caches: Res<'w, AssetsBvhCaches<Mesh, ObvhsBvh2Cache>>,
+    #[cfg(feature = "obvhs")]
+    #[doc(hidden)]
+    pub skinned_mesh_bvhs: Res<'w, SkinnedMeshBvhs>,
+    #[cfg(feature = "obvhs")]
+    #[doc(hidden)]
+    pub morphed_mesh_bvhs: Res<'w, MorphedMeshBvhs>,
+    #[cfg(feature = "obvhs")]
+    #[doc(hidden)]
+    pub point_cloud_caches: Res<'w, AssetsBvhCaches<Mesh, PointCloudCache>>,
+    #[cfg(feature = "obvhs")]
+    #[doc(hidden)]
+    pub line_caches: Res<'w, AssetsBvhCaches<Mesh, LineCache>>,
+    #[doc(hidden)]
+    pub materials: Option<Res<'w, Assets<StandardMaterial>>>,
+    #[doc(hidden)]
+    pub images: Option<Res<'w, Assets<Image>>>,
+}
+
+impl MeshBvhCaches<'_> {
+    /// Returns the tree of the target if it is a skinned or morphed mesh entity, with the transform
+    /// of its triangles: skinned triangles are in world space, morphed ones in mesh space.
+    #[cfg(feature = "obvhs")]
+    fn deformed_mesh_bvh(&self, target: &MeshTarget) -> Option<(&ObvhsBvh2Cache, Mat4)> {
+        if target.backend != BvhBackend::ObvhsBvh2 {
+            return None;
+        }
+        if let Some(skinned_mesh_bvh) = self.skinned_mesh_bvhs.get(target.entity) {
+            return Some((&skinned_mesh_bvh.cache, Mat4::IDENTITY));
+        }
+        self.morphed_mesh_bvhs
+            .get(target.entity)
+            .map(|morphed_mesh_bvh| (&morphed_mesh_bvh.cache, target.transform))
+    }
+
+    /// Returns the AABB of the `entity` tested by the broad phase, with its transform. The trees of
+    /// the skinned mesh entities are in world space, so their local `aabb` is grown to enclose
+    /// their tree in world space, with the identity transform.
+    pub(crate) fn broad_phase_bounds(
+        &self,
+        entity: Entity,
+        aabb: &Aabb,
+        transform: Mat4,
+    ) -> (Aabb, Mat4) {
+        #[cfg(feature = "obvhs")]
+        if let Some(aabb) = self.skinned_mesh_bvhs.world_aabb(entity, aabb, &transform) {
+            return (aabb, Mat4::IDENTITY);
+        }
+        #[cfg(not(feature = "obvhs"))]
+        let _ = entity;
+        (*aabb, transform)
+    }
+
+    /// Returns the UVs stored for the triangle at `triangle_index` in the cache of the backend of the
+    /// `target`, if it is built. Skinned and morphed mesh entities use their own tree.
+    pub(crate) fn cached_triangle_uvs(
+        &self,
+        target: &MeshTarget,
+        triangle_index: usize,
+    ) -> Option<[Vec2; 3]> {
+        match target.backend {
+            BvhBackend::None => None,
+            #[cfg(feature = "bvh")]
+            BvhBackend::Bvh => {
+                // The cache keeps the triangles in the order of the mesh, without the skipped ones
+                let bvh_cache = self.bvh_caches.get(target.mesh_handle)?;
+                let index = bvh_cache
+                    .triangles
+                    .binary_search_by_key(&triangle_index, |triangle| {
+                        triangle.0.face.triangle_index
+                    })
+                    .ok()?;
+                bvh_cache.triangles[index].0.uvs
+            }
+            #[cfg(feature = "obvhs")]
+            BvhBackend::ObvhsBvh2 => {
+                let obvhs_bvh2_cache = match self.deformed_mesh_bvh(target) {
+                    Some((cache, _)) => cache,
+                    None => self.obvhs_bvh2_caches.get(target.mesh_handle)?,
+                };
+                obvhs_bvh2_cache.triangles.find(triangle_index)?.uvs
+            }
+        }
+    }
+
+    /// Returns the UV of a `hit` on the mesh of the `target`, interpolated from its `uv_attribute`, or
+    /// from the UVs stored in its cache if the mesh is not available.
+    pub(crate) fn hit_uv(
+        &self,
+        target: &MeshTarget,
+        hit: &RayMeshHit,
+        uv_attribute: MeshVertexAttribute,
+    ) -> Option<Vec2> {
+        let triangle_index = hit.triangle_index?;
+        if let Some(mesh) = self.meshes.get(target.mesh_handle) {
+            let face = mesh_triangle_face(mesh, triangle_index)?;
+            let uv =
+                interpolate_vertex_attribute(mesh, uv_attribute, &face, hit.barycentric_coords)?;
+            return Some(Vec2::new(uv.x, uv.y));
+        }
+        if uv_attribute.id != Mesh::ATTRIBUTE_UV_0.id {
+            return None;
+        }
+        let uvs = self.cached_triangle_uvs(target, triangle_index)?;
+        Some(interpolate_triangle_uv(uvs, hit.barycentric_coords))
+    }
+
+    /// Returns the alpha mask of the material of the `target`, if its hits are alpha tested.
+    fn alpha_mask(&self, target: &MeshTarget) -> Option<AlphaMask> {
+        let material = self.materials.as_ref()?.get(target.material?)?;
+        AlphaMask::from_material(material, self.images.as_ref()?)
+    }
+
+    /// Returns `true` if the `hit` on the `target` passes the `alpha_mask`. The hits without a UV
+    /// are kept.
+    fn is_hit_opaque(&self, target: &MeshTarget, alpha_mask: &AlphaMask, hit: &RayMeshHit) -> bool {
+        self.hit_uv(target, hit, alpha_mask.uv_attribute)
+            .is_none_or(|uv| alpha_mask.is_opaque_at(uv))
+    }
+
+    /// Casts the `ray` on the mesh of the `target` if it is made of lines or points, and returns all
+    /// the lines or points within its pick radius before `max_distance`, nearest first. Returns
+    /// `None` for the meshes of triangles.
+    ///
+    /// The `ObvhsBvh2` backend uses the line or point cloud cache of the mesh, if it is built, as do
+    /// all the backends for the meshes only used in the render world.
+    fn line_and_point_intersections(
+        &self,
+        target: &MeshTarget,
+        ray: Ray3d,
+        max_distance: f32,
+    ) -> Option<Vec<RayMeshHit>> {
+        let mesh = self.meshes.get(target.mesh_handle);
+        let transform = &target.transform;
+        let pick_radius = target.pick_radius;
+
+        #[cfg(feature = "obvhs")]
+        if target.backend == BvhBackend::ObvhsBvh2 || mesh.is_none() {
+            if let Some(cache) = self.line_caches.get(target.mesh_handle) {
+                return Some(ray_intersections_using_line_cache(
+                    transform,
+                    ray,
+                    pick_radius,
+                    cache,
+                    max_distance,
+                ));
+            }
+            if let Some(cache) = self.point_cloud_caches.get(target.mesh_handle) {
+                return Some(ray_intersections_using_point_cloud_cache(
+                    transform,
+                    ray,
+                    pick_radius,
+                    cache,
+                    max_distance,
+                ));
+            }
+        }
+
+        let mesh = mesh.filter(|mesh| is_line_or_point_mesh(mesh))?;
+        Some(ray_intersections_over_lines_and_points(
+            mesh,
+            transform,
+            ray,
+            pick_radius,
+            max_distance,
+        ))
+    }
+
+    /// Casts the `ray` on the mesh of the `target`, and returns the intersection before `max_distance`.
+    pub(crate) fn ray_intersection(
+        &self,
+        target: &MeshTarget,
+        ray: Ray3d,
+        max_distance: f32,
+    ) -> Option<RayMeshHit> {
+        if let Some(alpha_mask) = self.alpha_mask(target) {
+            // The transparent hits let the ray through to the next triangle
+            return self
+                .ray_intersections(target, ray, target.backfaces, max_distance)
+                .into_iter()
+                .find(|hit| self.is_hit_opaque(target, &alpha_mask, hit));
+        }
+
+        #[cfg(feature = "obvhs")]
+        if let Some((cache, transform)) = self.deformed_mesh_bvh(target) {
+            return ray_intersection_over_mesh_using_obvhs_bvh2_cache(
+                &transform,
+                ray,
+                target.backfaces,
+                cache,
+                max_distance,
+            );
+        }
+
+        // The meshes only used in the render world are cast with their cache alone
+        let mesh = self.meshes.get(target.mesh_handle);
+        let transform = &target.transform;
+        let backfaces = target.backfaces;
+
+        if let Some(hits) = self.line_and_point_intersections(target, ray, max_distance) {
+            return hits.into_iter().next();
+        }
+
+        match target.backend {
+            BvhBackend::None => {
+                ray_intersection_over_mesh(mesh?, transform, ray, backfaces, max_distance)
+            }
+            #[cfg(feature = "bvh")]
+            BvhBackend::Bvh => {
+                let bvh_cache = self.bvh_caches.get(target.mesh_handle);
+                if let Some(bvh_cache) = bvh_cache {
+                    ray_intersection_over_mesh_using_bvh_cache(
+                        transform,
+                        ray,
+                        backfaces,
+                        bvh_cache,
+                        max_distance,
+                    )
+                } else {
+                    ray_intersection_over_mesh(mesh?, transform, ray, backfaces, max_distance)
+                }
+            }
+            #[cfg(feature = "obvhs")]
+            BvhBackend::ObvhsBvh2 => {
+                let obvhs_bvh2_cache = self.obvhs_bvh2_caches.get(target.mesh_handle);
+                if let Some(obvhs_bvh2_cache) = obvhs_bvh2_cache {
+                    ray_intersection_over_mesh_using_obvhs_bvh2_cache(
+                        transform,
+                        ray,
+                        backfaces,
+                        obvhs_bvh2_cache,
+                        max_distance,
+                    )
+                } else {
+                    ray_intersection_over_mesh(mesh?, transform, ray, backfaces, max_distance)
+                }
+            }
+        }
+    }
+
+    /// Casts the `ray` on the mesh of the `target`, and returns all the intersections before
+    /// `max_distance`, nearest first. The `backfaces` of the target are overridden.
+    pub(crate) fn ray_intersections(
+        &self,
+        target: &MeshTarget,
+        ray: Ray3d,
+        backfaces: Backfaces,
+        max_distance: f32,
+    ) -> Vec<RayMeshHit> {
+        #[cfg(feature = "obvhs")]
+        if let Some((cache, transform)) = self.deformed_mesh_bvh(target) {
+            return ray_intersections_over_mesh_using_obvhs_bvh2_cache(
+                &transform,
+                ray,
+                backfaces,
+                cache,
+                max_distance,
+            );
+        }
+
+        // The meshes only used in the render world are cast with their cache alone
+        let mesh = self.meshes.get(target.mesh_handle);
+        let transform = &target.transform;
+        let intersections_over_mesh = |mesh: Option<&Mesh>| {
+            mesh.map(|mesh| {
+                ray_intersections_over_mesh(mesh, transform, ray, backfaces, max_distance)
+            })
+            .unwrap_or_default()
+        };
+
+        if let Some(hits) = self.line_and_point_intersections(target, ray, max_distance) {
+            return hits;
+        }
+
+        match target.backend {
+            BvhBackend::None => intersections_over_mesh(mesh),
+            #[cfg(feature = "bvh")]
+            BvhBackend::Bvh => {
+                let bvh_cache = self.bvh_caches.get(target.mesh_handle);
+                if let Some(bvh_cache) = bvh_cache {
+                    ray_intersections_over_mesh_using_bvh_cache(
+                        transform,
+                        ray,
+                        backfaces,
+                        bvh_cache,
+                        max_distance,
+                    )
+                } else {
+                    intersections_over_mesh(mesh)
+                }
+            }
+            #[cfg(feature = "obvhs")]
+            BvhBackend::ObvhsBvh2 => {
+                let obvhs_bvh2_cache = self.obvhs_bvh2_caches.get(target.mesh_handle);
+                if let Some(obvhs_bvh2_cache) = obvhs_bvh2_cache {
+                    ray_intersections_over_mesh_using_obvhs_bvh2_cache(
+                        transform,
+                        ray,
+                        backfaces,
+                        obvhs_bvh2_cache,
+                        max_distance,
+                    )
+                } else {
+                    intersections_over_mesh(mesh)
+                }
+            }
+        }
+    }
+
+    /// Returns `true` if the `ray` hits any triangle of the mesh of the `target` before `max_distance`.
+    pub(crate) fn ray_any_hit(&self, target: &MeshTarget, ray: Ray3d, max_distance: f32) -> bool {
+        if let Some(alpha_mask) = self.alpha_mask(target) {
+            return self
+                .ray_intersections(target, ray, target.backfaces, max_distance)
+                .iter()
+                .any(|hit| self.is_hit_opaque(target, &alpha_mask, hit));
+        }
+
+        #[cfg(feature = "obvhs")]
+        if let Some((cache, transform)) = self.deformed_mesh_bvh(target) {
+            return ray_any_hit_over_mesh_using_obvhs_bvh2_cache(
+                &transform,
+                ray,
+                target.backfaces,
+                cache,
+                max_distance,
+            );
+        }
+
+        // The meshes only used in the render world are cast with their cache alone
+        let mesh = self.meshes.get(target.mesh_handle);
+        let transform = &target.transform;
+        let backfaces = target.backfaces;
+        let any_hit_over_mesh = |mesh: Option<&Mesh>| {
+            mesh.and_then(|mesh| {
+                ray_intersection_over_mesh(mesh, transform, ray, backfaces, max_distance)
+            })
+            .is_some()
+        };
+
+        if let Some(hits) = self.line_and_point_intersections(target, ray, max_distance) {
+            return !hits.is_empty();
+        }
+
+        match target.backend {
+            BvhBackend::None => any_hit_over_mesh(mesh),
+            #[cfg(feature = "bvh")]
+            BvhBackend::Bvh => {
+                let bvh_cache = self.bvh_caches.get(target.mesh_handle);
+                if let Some(bvh_cache) = bvh_cache {
+                    ray_any_hit_over_mesh_using_bvh_cache(
+                        transform,
+                        ray,
+                        backfaces,
+                        bvh_cache,
+                        max_distance,
+                    )
+                } else {
+                    any_hit_over_mesh(mesh)
+                }
+            }
+            #[cfg(feature = "obvhs")]
+            BvhBackend::ObvhsBvh2 => {
+                let obvhs_bvh2_cache = self.obvhs_bvh2_caches.get(target.mesh_handle);
+                if let Some(obvhs_bvh2_cache) = obvhs_bvh2_cache {
+                    ray_any_hit_over_mesh_using_obvhs_bvh2_cache(
+                        transform,
+                        ray,
+                        backfaces,
+                        obvhs_bvh2_cache,
+                        max_distance,
+                    )
+                } else {
+                    any_hit_over_mesh(mesh)
+                }
+            }
+        }
+    }
+
+    /// Returns the point of the mesh of the `target` nearest to the `ray`, within the `tolerance`
+    /// and before `max_distance`. Only the meshes with a point list topology have points to pick.
+    pub(crate) fn ray_nearest_point(
+        &self,
+        target: &MeshTarget,
+        ray: Ray3d,
+        tolerance: &PickTolerance,
+        max_distance: f32,
+    ) -> Option<RayPointHit> {
+        #[cfg(feature = "obvhs")]
+        if target.backend == BvhBackend::ObvhsBvh2 {
+            if let Some(cache) = self.point_cloud_caches.get(target.mesh_handle) {
+                return ray_nearest_point_using_point_cloud_cache(
+                    &target.transform,
+                    ray,
+                    tolerance,
+                    cache,
+                    max_distance,
+                );
+            }
+        }
+
+        let mesh = self.meshes.get(target.mesh_handle)?;
+        ray_nearest_point_over_mesh(mesh, &target.transform, ray, tolerance, max_distance)
+    }
+
+    /// Returns the segment of the mesh of the `target` nearest to the `ray`, within the `tolerance`
+    /// and before `max_distance`. Only the meshes with a line list or line strip topology have
+    /// segments to pick.
+    pub(crate) fn ray_nearest_segment(
+        &self,
+        target: &MeshTarget,
+        ray: Ray3d,
+        tolerance: &PickTolerance,
+        max_distance: f32,
+    ) -> Option<RaySegmentHit> {
+        #[cfg(feature = "obvhs")]
+        if target.backend == BvhBackend::ObvhsBvh2 {
+            if let Some(cache) = self.line_caches.get(target.mesh_handle) {
+                return ray_nearest_segment_using_line_cache(
+                    &target.transform,
+                    ray,
+                    tolerance,
+                    cache,
+                    max_distance,
+                );
+            }
+        }
+
+        let mesh = self.meshes.get(target.mesh_handle)?;
+        ray_nearest_segment_over_mesh(mesh, &target.transform, ray, tolerance, max_distance)
+    }
+
+    /// Traverses the triangles of the mesh of the `target` best first with a query, built by
+    /// `new_query` with the transform from the space of the triangles to world space.
+    ///
+    /// Returns `None` for the meshes of lines or points, or if neither the mesh nor its cache is
+    /// available. The triangles of the meshes without a cache are all tested.
+    pub(crate) fn query_triangles<Q: TriangleQuery>(
+        &self,
+        target: &MeshTarget,
+        new_query: impl FnOnce(&Mat4) -> Q,
+    ) -> Option<Q> {
+        #[cfg(feature = "obvhs")]
+        if let Some((cache, transform)) = self.deformed_mesh_bvh(target) {
+            let mut query = new_query(&transform);
+            query_triangles_using_obvhs_bvh2_cache(cache, &mut query);
+            return Some(query);
+        }
+
+        let mesh = self.meshes.get(target.mesh_handle);
+        if mesh.is_some_and(is_line_or_point_mesh) {
+            return None;
+        }
+
+        match target.backend {
+            BvhBackend::None => {}
+            #[cfg(feature = "bvh")]
+            BvhBackend::Bvh => {
+                if let Some(bvh_cache) = self.bvh_caches.get(target.mesh_handle) {
+                    let mut query = new_query(&target.transform);
+                    query_triangles_using_bvh_cache(bvh_cache, &mut query);
+                    return Some(query);
+                }
+            }
+            #[cfg(feature = "obvhs")]
+            BvhBackend::ObvhsBvh2 => {
+                if let Some(obvhs_bvh2_cache) = self.obvhs_bvh2_caches.get(target.mesh_handle) {
+                    let mut query = new_query(&target.transform);
+                    query_triangles_using_obvhs_bvh2_cache(obvhs_bvh2_cache, &mut query);
+                    return Some(query);
+                }
+            }
+        }
+
+        let triangles = mesh_triangles(mesh?).ok()?;
+        let mut query = new_query(&target.transform);
+        for triangle in triangles {
+            let [a, b, c] = triangle.positions;
+            if query.node_cost(a.min(b).min(c), a.max(b).max(c)).is_some() {
+                query.visit(triangle.positions, triangle.face.triangle_index);
+            }
+        }
+        Some(query)
+    }
+
+    /// Casts the coherent `rays` on the mesh of the `target`, and returns the intersection of each
+    /// ray. The `ObvhsBvh2` backend traverses its cache with packets of rays.
+    #[cfg(feature = "obvhs")]
+    pub(crate) fn ray_packet_intersections(
+        &self,
+        target: &MeshTarget,
+        rays: &[Ray3d],
+    ) -> Vec<Option<RayMeshHit>> {
+        // The alpha tested hits are cast ray by ray
+        let packet_cache = self.deformed_mesh_bvh(target).or_else(|| {
+            if target.backend != BvhBackend::ObvhsBvh2 {
+                return None;
+            }
+            self.obvhs_bvh2_caches
+                .get(target.mesh_handle)
+                .map(|cache| (cache, target.transform))
+        });
+        if let Some((cache, transform)) = packet_cache.filter(|_| self.alpha_mask(target).is_none())
+        {
+            return rays
+                .chunks(RAY_PACKET_SIZE)
+                .flat_map(|rays| {
+                    let hits = ray_packet_intersections_over_mesh_using_obvhs_bvh2_cache::<
+                        RAY_PACKET_SIZE,
+                    >(
+                        &transform, rays, target.backfaces, cache, f32::INFINITY
+                    );
+                    hits.into_iter().take(rays.len())
+                })
+                .collect();
+        }
+
+        rays.iter()
+            .map(|ray| self.ray_intersection(target, *ray, f32::INFINITY))
+            .collect()
+    }
+}
+
+/// Returns `true` if the mesh is made of lines or points, which have no triangle cache and are hit
+/// within the pick radius of the target.
+fn is_line_or_point_mesh(mesh: &Mesh) -> bool {
+    matches!(
+        mesh.primitive_topology(),
+        PrimitiveTopology::PointList | PrimitiveTopology::LineList | PrimitiveTopology::LineStrip
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy_math::prelude::*;
+    use bevy_render::mesh::Meshable;
+
+    use super::*;
+    use crate::ray_cast::{
+        closest_point::ClosestPointQuery,
+        shape_cast::{ShapeSweep, SweptShape},
+    };
+
+    /// Counts the triangles visited by a query.
+    struct CountVisits<Q> {
+        query: Q,
+        visits: usize,
+    }
+
+    impl<Q: TriangleQuery> TriangleQuery for CountVisits<Q> {
+        fn node_cost(&self, min: Vec3, max: Vec3) -> Option<f32> {
+            self.query.node_cost(min, max)
+        }
+
+        fn visit(&mut self, positions: [Vec3; 3], triangle_index: usize) -> f32 {
+            self.visits += 1;
+            self.query.visit(positions, triangle_index)
+        }
+    }
+
+    /// Runs the queries built by `new_query` on every triangle of the `mesh`, then through its
+    /// cache with each backend, and returns them with the number of triangles they visited.
+    fn query_mesh_and_caches<Q: TriangleQuery>(
+        mesh: &Mesh,
+        new_query: impl Fn() -> Q,
+    ) -> Vec<(Q, usize)> {
+        let mut brute_force = CountVisits {
+            query: new_query(),
+            visits: 0,
+        };
+        for triangle in mesh_triangles(mesh).unwrap() {
+            brute_force.visit(triangle.positions, triangle.face.triangle_index);
+        }
+        let mut queries = vec![(brute_force.query, brute_force.visits)];
+
+        #[cfg(feature = "bvh")]
+        {
+            let cache = crate::bvh::build_bvh_cache(mesh, &Default::default()).unwrap();
+            let mut query = CountVisits {
+                query: new_query(),
+                visits: 0,
+            };
+            query_triangles_using_bvh_cache(&cache, &mut query);
+            queries.push((query.query, query.visits));
+        }
+        #[cfg(feature = "obvhs")]
+        {
+            let cache = crate::obvhs::build_bvh2_cache(mesh, &Default::default()).unwrap();
+            let mut query = CountVisits {
+                query: new_query(),
+                visits: 0,
+            };
+            query_triangles_using_obvhs_bvh2_cache(&cache, &mut query);
+            queries.push((query.query, query.visits));
+        }
+        queries
+    }
+
+    #[test]
+    fn shape_sweeps_prune_the_triangles() {
+        let mesh = Sphere::new(1.0).mesh().ico(3).unwrap();
+        let transform = Mat4::from_scale_rotation_translation(
+            Vec3::new(1.2, 1.0, 1.0),
+            Quat::from_rotation_y(0.3),
+            Vec3::new(0.5, 0.0, 0.0),
+        );
+        let shape = SweptShape {
+            segment: [Vec3::new(0.2, 0.1, 5.0), Vec3::new(0.3, 0.1, 5.0)],
+            radius: 0.05,
+        };
+        let queries = query_mesh_and_caches(&mesh, || {
+            ShapeSweep::new(shape, Dir3::NEG_Z, f32::INFINITY, &transform)
+        });
+
+        let (brute_force, triangle_count) = &queries[0];
+        let expected = brute_force.nearest_hit.as_ref().unwrap();
+        for (query, visits) in &queries[1..] {
+            let hit = query.nearest_hit.as_ref().unwrap();
+            assert!((hit.distance - expected.distance).abs() < 1e-5);
+            assert!(hit.point.distance(expected.point) < 1e-4);
+            // Only the triangles near the front of the sphere are tested
+            assert!(*visits < triangle_count / 4);
+        }
+    }
+
+    #[test]
+    fn closest_point_queries_prune_the_triangles() {
+        let mesh = Sphere::new(1.0).mesh().ico(3).unwrap();
+        let transform = Mat4::from_rotation_x(0.7);
+        let point = Vec3::new(0.4, 1.2, -0.3);
+        let queries = query_mesh_and_caches(&mesh, || {
+            ClosestPointQuery::new(point, f32::INFINITY, &transform)
+        });
+
+        let (brute_force, triangle_count) = &queries[0];
+        let expected = brute_force.nearest_hit.as_ref().unwrap();
+        for (query, visits) in &queries[1..] {
+            let hit = query.nearest_hit.as_ref().unwrap();
+            assert!((hit.distance - expected.distance).abs() < 1e-5);
+            assert!(hit.point.distance(expected.point) < 1e-4);
+            // Only the triangles near the point are tested
+            assert!(*visits < triangle_count / 4);
+        }
+    }
+}
@@ -2,34 +2,148 @@
 //!
 //! See the [`MeshRayCast`] system parameter for more information.
 
+pub mod alpha_mask;
+pub mod closest_point;
 pub mod intersections;
+pub mod mesh_caches;
+pub mod shape_cast;
 
-use bevy_math::{bounding::Aabb3d, Ray3d};
+use bevy_math::{
+    bounding::{Aabb3d, BoundingVolume},
+    primitives::Capsule3d,
+    Mat4, Quat, Ray3d, Vec2, Vec3, Vec3A, Vec4,
+};
 use bevy_picking_more_hitinfo::mesh_picking::ray_cast::{
     ray_aabb_intersection_3d, Backfaces, RayCastBackfaces, RayCastSettings, RayCastVisibility,
     RayMeshHit, SimplifiedMesh,
 };
-use bevy_render::mesh::Mesh;
+use bevy_render::mesh::{Mesh, MeshVertexAttributeId};
 
-use bevy_asset::Assets;
+use bevy_asset::Handle;
 use bevy_ecs::{prelude::*, system::lifetimeless::Read, system::SystemParam};
 use bevy_math::FloatOrd;
+use bevy_pbr::{MeshMaterial3d, StandardMaterial};
 use bevy_render::{prelude::*, primitives::Aabb};
+use bevy_tasks::ComputeTaskPool;
 use bevy_transform::components::GlobalTransform;
 use bevy_utils::tracing::*;
-
-#[cfg(feature = "bvh")]
-use crate::bvh::{ray_cast::ray_intersection_over_mesh_using_bvh_cache, BvhCache};
+#[cfg(feature = "obvhs")]
+use bevy_utils::HashMap;
 
 #[cfg(feature = "obvhs")]
-use crate::obvhs::{ray_cast::ray_intersection_over_mesh_using_obvhs_bvh2_cache, ObvhsBvh2Cache};
+use crate::obvhs::{packet::RAY_PACKET_SIZE, tlas::MeshEntitiesTlas};
+
+use crate::{
+    common::{attribute::interpolate_vertex_attribute, mesh_triangle_face, triangle::TriangleFace},
+    settings::BvhBuildHint,
+    BvhBackend, PickingBvhBackend,
+};
+
+use closest_point::{ClosestPointHit, ClosestPointQuery};
+use intersections::{ray_sphere_point_metric, PickTolerance, RayPointHit, RaySegmentHit};
+use mesh_caches::{MeshBvhCaches, TriangleQuery};
+use shape_cast::{max_stretch, ShapeCastHit, ShapeSweep, SweptShape};
+
+/// Limits of the hits returned by [`BvhMeshRayCast::cast_ray_all_hits`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RayHitsSettings {
+    /// Maximum number of hits, the nearest are kept.
+    pub max_count: Option<usize>,
+    /// Hits farther than this distance from the ray origin are ignored.
+    pub max_distance: Option<f32>,
+}
+
+impl RayHitsSettings {
+    pub fn with_max_count(mut self, max_count: usize) -> Self {
+        self.max_count = Some(max_count);
+        self
+    }
+
+    pub fn with_max_distance(mut self, max_distance: f32) -> Self {
+        self.max_distance = Some(max_distance);
+        self
+    }
+}
+
+pub(crate) type MeshFilter = Or<(With<Mesh3d>, With<Mesh2d>, With<SimplifiedMesh>)>;
+
+#[doc(hidden)]
+pub type MeshQueryData = (
+    Option<Read<Mesh2d>>,
+    Option<Read<Mesh3d>>,
+    Option<Read<SimplifiedMesh>>,
+    Has<RayCastBackfaces>,
+    Read<GlobalTransform>,
+    Option<Read<BvhBuildHint>>,
+    Option<Read<MeshMaterial3d<StandardMaterial>>>,
+);
+
+/// The mesh of an entity to ray cast, with its transform, backfaces and backend.
+pub(crate) struct MeshTarget<'a> {
+    pub(crate) entity: Entity,
+    pub(crate) mesh_handle: &'a Handle<Mesh>,
+    pub(crate) transform: Mat4,
+    pub(crate) backfaces: Backfaces,
+    pub(crate) backend: BvhBackend,
+    /// See [`PickingBvhBackend::pick_radius`].
+    pub(crate) pick_radius: f32,
+    /// The material of the entity, if its hits are alpha tested, see
+    /// [`PickingBvhBackend::alpha_test`].
+    pub(crate) material: Option<&'a Handle<StandardMaterial>>,
+}
+
+/// Returns the mesh to ray cast for the `entity`, given the settings of the `picking_bvh_backend`.
+fn mesh_target<'a>(
+    mesh_query: &'a Query<MeshQueryData, MeshFilter>,
+    picking_bvh_backend: &PickingBvhBackend,
+    entity: Entity,
+) -> Option<MeshTarget<'a>> {
+    // Get the mesh components and transform.
+    let (mesh2d, mesh3d, simplified_mesh, has_backfaces, transform, build_hint, material) =
+        mesh_query.get(entity).ok()?;
 
-#[cfg(any(feature = "obvhs", feature = "bvh"))]
-use crate::storage::AssetsBvhCaches;
+    // Get the underlying mesh handle. One of these will always be `Some` because of the query filters.
+    let mesh_handle = simplified_mesh
+        .map(|m| &m.0)
+        .or(mesh3d.map(|m| &m.0).or(mesh2d.map(|m| &m.0)))?;
 
-use crate::{ray_cast::intersections::ray_intersection_over_mesh, PickingBvhBackend};
+    // Backfaces of 2d meshes are never culled, unlike 3d mehses.
+    let backfaces = match (has_backfaces, mesh2d.is_some()) {
+        (false, false) => Backfaces::Cull,
+        _ => Backfaces::Include,
+    };
 
-type MeshFilter = Or<(With<Mesh3d>, With<Mesh2d>, With<SimplifiedMesh>)>;
+    // The build hint of the entity can override the backend used for its mesh
+    let default_backend = &picking_bvh_backend.backend;
+    let backend = match build_hint {
+        Some(build_hint) => build_hint.backend(default_backend),
+        None => default_backend.clone(),
+    };
+
+    Some(MeshTarget {
+        entity,
+        mesh_handle,
+        transform: transform.compute_matrix(),
+        backfaces,
+        backend,
+        pick_radius: picking_bvh_backend.pick_radius,
+        material: material
+            .filter(|_| picking_bvh_backend.alpha_test)
+            .map(|material| &material.0),
+    })
+}
+
+/// Returns the local `aabb` of an entity grown by the world-space `pick_radius`, so that the broad
+/// phase keeps the lines and points near the ray.
+pub(crate) fn broad_phase_aabb(aabb: &Aabb, transform: &Mat4, pick_radius: f32) -> Aabb3d {
+    let scale = Vec3A::new(
+        transform.x_axis.truncate().length(),
+        transform.y_axis.truncate().length(),
+        transform.z_axis.truncate().length(),
+    )
+    .max(Vec3A::splat(f32::EPSILON));
+    Aabb3d::new(aabb.center, aabb.half_extents + pick_radius / scale)
+}
 
 /// Add this ray casting [`SystemParam`] to your system to cast rays into the world with an
 /// immediate-mode API. Call `cast_ray` to immediately perform a ray cast and get a result.
@@ -87,13 +201,10 @@ type MeshFilter = Or<(With<Mesh3d>, With<Mesh2d>, With<SimplifiedMesh>)>;
 #[derive(SystemParam)]
 pub struct BvhMeshRayCast<'w, 's> {
     #[doc(hidden)]
-    pub meshes: Res<'w, Assets<Mesh>>,
-    #[cfg(feature = "bvh")]
-    #[doc(hidden)]
-    pub bvh_caches: Res<'w, AssetsBvhCaches<Mesh, BvhCache>>,
+    pub caches: MeshBvhCaches<'w>,
     #[cfg(feature = "obvhs")]
     #[doc(hidden)]
-    pub obvhs_bvh2_caches: Res<'w, AssetsBvhCaches<Mesh, ObvhsBvh2Cache>>,
+    pub tlas: Res<'w, MeshEntitiesTlas>,
     #[doc(hidden)]
     pub picking_bvh_backend: Res<'w, PickingBvhBackend>,
     #[doc(hidden)]
@@ -116,55 +227,41 @@ pub struct BvhMeshRayCast<'w, 's> {
         MeshFilter,
     >,
     #[doc(hidden)]
-    pub mesh_query: Query<
-        'w,
-        's,
-        (
-            Option<Read<Mesh2d>>,
-            Option<Read<Mesh3d>>,
-            Option<Read<SimplifiedMesh>>,
-            Has<RayCastBackfaces>,
-            Read<GlobalTransform>,
-        ),
-        MeshFilter,
-    >,
+    pub mesh_query: Query<'w, 's, MeshQueryData, MeshFilter>,
 }
 
 impl<'w, 's> BvhMeshRayCast<'w, 's> {
     /// Casts the `ray` into the world and returns a sorted list of intersections, nearest first.
     pub fn cast_ray(&mut self, ray: Ray3d, settings: &RayCastSettings) -> &[(Entity, RayMeshHit)] {
+        self.cast_ray_bounded(ray, 0.0, f32::INFINITY, settings)
+    }
+
+    /// Casts the segment of the `ray` between `min_distance` and `max_distance` from its origin into
+    /// the world and returns a sorted list of intersections, nearest first. The distances of the
+    /// hits are still measured from the origin of the `ray`.
+    ///
+    /// The entities and the nodes of the BVH caches outside of the segment are never visited.
+    pub fn cast_ray_bounded(
+        &mut self,
+        ray: Ray3d,
+        min_distance: f32,
+        max_distance: f32,
+        settings: &RayCastSettings,
+    ) -> &[(Entity, RayMeshHit)] {
         let ray_cull = info_span!("ray culling");
         let ray_cull_guard = ray_cull.enter();
 
         self.hits.clear();
-        self.culled_list.clear();
         self.output.clear();
 
-        // TODO: create a BVH cache for meshes also, useful if there is many meshes, but would need updating if they move/rotate
-
-        // Check all entities to see if the ray intersects the AABB. Use this to build a short list
-        // of entities that are in the path of the ray.
-        let (aabb_hits_tx, aabb_hits_rx) = crossbeam_channel::unbounded::<(FloatOrd, Entity)>();
-        let visibility_setting = settings.visibility;
-        self.culling_query.par_iter().for_each(
-            |(inherited_visibility, view_visibility, aabb, transform, entity)| {
-                let should_ray_cast = match visibility_setting {
-                    RayCastVisibility::Any => true,
-                    RayCastVisibility::Visible => inherited_visibility.get(),
-                    RayCastVisibility::VisibleInView => view_visibility.get(),
-                };
-                if should_ray_cast {
-                    if let Some(distance) = ray_aabb_intersection_3d(
-                        ray,
-                        &Aabb3d::new(aabb.center, aabb.half_extents),
-                        &transform.compute_matrix(),
-                    ) {
-                        aabb_hits_tx.send((FloatOrd(distance), entity)).ok();
-                    }
-                }
-            },
-        );
-        *self.culled_list = aabb_hits_rx.try_iter().collect();
+        // The segment is cast as a ray from its start, so that everything before it is skipped
+        let min_distance = min_distance.max(0.0);
+        let ray = Ray3d {
+            origin: ray.get_point(min_distance),
+            ..ray
+        };
+        let max_distance = max_distance - min_distance;
+        self.cull_entities(ray, 0.0, max_distance, settings);
 
         // Sort by the distance along the ray.
         self.culled_list.sort_by_key(|(aabb_near, _)| *aabb_near);
@@ -178,17 +275,8 @@ impl<'w, 's> BvhMeshRayCast<'w, 's> {
             .iter()
             .filter(|(_, entity)| (settings.filter)(*entity))
             .for_each(|(aabb_near, entity)| {
-                // Get the mesh components and transform.
-                let Ok((mesh2d, mesh3d, simplified_mesh, has_backfaces, transform)) =
-                    self.mesh_query.get(*entity)
-                else {
-                    return;
-                };
-
-                // Get the underlying mesh handle. One of these will always be `Some` because of the query filters.
-                let Some(mesh_handle) = simplified_mesh
-                    .map(|m| &m.0)
-                    .or(mesh3d.map(|m| &m.0).or(mesh2d.map(|m| &m.0)))
+                let Some(target) =
+                    mesh_target(&self.mesh_query, &self.picking_bvh_backend, *entity)
                 else {
                     return;
                 };
@@ -198,51 +286,9 @@ impl<'w, 's> BvhMeshRayCast<'w, 's> {
                     return;
                 }
 
-                // Does the mesh handle resolve?
-                let Some(mesh) = self.meshes.get(mesh_handle) else {
-                    return;
-                };
-
-                // Backfaces of 2d meshes are never culled, unlike 3d mehses.
-                let backfaces = match (has_backfaces, mesh2d.is_some()) {
-                    (false, false) => Backfaces::Cull,
-                    _ => Backfaces::Include,
-                };
-
                 // Perform the actual ray cast.
                 let _ray_cast_guard = ray_cast_guard.enter();
-                let transform = transform.compute_matrix();
-
-                let intersection = match self.picking_bvh_backend.backend {
-                    crate::BvhBackend::None => {
-                        ray_intersection_over_mesh(mesh, &transform, ray, backfaces)
-                    }
-                    #[cfg(feature = "bvh")]
-                    crate::BvhBackend::Bvh => {
-                        let bvh_cache = self.bvh_caches.get(mesh_handle);
-                        if let Some(bvh_cache) = bvh_cache {
-                            ray_intersection_over_mesh_using_bvh_cache(
-                                &transform, ray, backfaces, bvh_cache,
-                            )
-                        } else {
-                            ray_intersection_over_mesh(mesh, &transform, ray, backfaces)
-                        }
-                    }
-                    #[cfg(feature = "obvhs")]
-                    crate::BvhBackend::ObvhsBvh2 => {
-                        let obvhs_bvh2_cache = self.obvhs_bvh2_caches.get(mesh_handle);
-                        if let Some(obvhs_bvh2_cache) = obvhs_bvh2_cache {
-                            ray_intersection_over_mesh_using_obvhs_bvh2_cache(
-                                &transform,
-                                ray,
-                                backfaces,
-                                obvhs_bvh2_cache,
-                            )
-                        } else {
-                            ray_intersection_over_mesh(mesh, &transform, ray, backfaces)
-                        }
-                    }
-                };
+                let intersection = self.caches.ray_intersection(&target, ray, max_distance);
 
                 if let Some(intersection) = intersection {
                     let distance = FloatOrd(intersection.distance);
@@ -260,8 +306,833 @@ impl<'w, 's> BvhMeshRayCast<'w, 's> {
 
         self.hits.retain(|(dist, _)| *dist <= nearest_blocking_hit);
         self.hits.sort_by_key(|(k, _)| *k);
-        let hits = self.hits.iter().map(|(_, (e, i))| (*e, i.to_owned()));
+        let hits = self.hits.iter().map(|(_, (e, i))| {
+            let mut hit = i.to_owned();
+            hit.distance += min_distance;
+            (*e, hit)
+        });
         self.output.extend(hits);
         self.output.as_ref()
     }
+
+    /// Casts the `rays` into the world and returns, for each ray, a sorted list of intersections,
+    /// nearest first, as [`Self::cast_ray`] would.
+    ///
+    /// The filter, the early exit test and the visibility of the `settings` are evaluated once per
+    /// entity for all the rays, then the rays are cast in parallel on the [`ComputeTaskPool`].
+    pub fn cast_rays(
+        &mut self,
+        rays: &[Ray3d],
+        settings: &RayCastSettings,
+    ) -> Vec<Vec<(Entity, RayMeshHit)>> {
+        if rays.is_empty() {
+            return Vec::new();
+        }
+
+        let batch_cull = info_span!("batch culling");
+        let batch_cull_guard = batch_cull.enter();
+
+        let targets = self.batch_targets(settings);
+        #[cfg(feature = "obvhs")]
+        let tlas = self.batch_tlas(&targets);
+
+        drop(batch_cull_guard);
+
+        let _ray_cast_guard = debug_span!("cast_rays").entered();
+        let caches = &self.caches;
+        let targets = &targets;
+        #[cfg(feature = "obvhs")]
+        let tlas = &tlas;
+
+        let task_pool = ComputeTaskPool::get();
+        let chunk_size = rays.len().div_ceil(task_pool.thread_num().max(1));
+        task_pool
+            .scope(|scope| {
+                for rays in rays.chunks(chunk_size) {
+                    scope.spawn(async move {
+                        let mut culled = Vec::new();
+                        rays.iter()
+                            .map(|ray| {
+                                culled.clear();
+                                #[cfg(feature = "obvhs")]
+                                if let Some((tlas, indices)) = tlas {
+                                    tlas.traverse_ray(*ray, f32::INFINITY, |entity| {
+                                        if let Some(&index) = indices.get(&entity) {
+                                            culled.push(index);
+                                        }
+                                    });
+                                    return cast_batch_ray(*ray, caches, targets, &culled);
+                                }
+                                culled.extend(0..targets.len());
+                                cast_batch_ray(*ray, caches, targets, &culled)
+                            })
+                            .collect::<Vec<_>>()
+                    });
+                }
+            })
+            .into_iter()
+            .flatten()
+            .collect()
+    }
+
+    /// Casts the coherent `rays` into the world and returns, for each ray, a sorted list of
+    /// intersections, nearest first, as [`Self::cast_rays`] would.
+    ///
+    /// The rays are traversed in packets of consecutive rays, which is faster when neighbouring
+    /// rays are close to each other, like the rays through the pixels of a camera. The packets are
+    /// cast in parallel on the [`ComputeTaskPool`].
+    #[cfg(feature = "obvhs")]
+    pub fn cast_coherent_rays(
+        &mut self,
+        rays: &[Ray3d],
+        settings: &RayCastSettings,
+    ) -> Vec<Vec<(Entity, RayMeshHit)>> {
+        if rays.is_empty() {
+            return Vec::new();
+        }
+
+        let batch_cull = info_span!("batch culling");
+        let batch_cull_guard = batch_cull.enter();
+
+        let targets = self.batch_targets(settings);
+        let tlas = self.batch_tlas(&targets);
+
+        drop(batch_cull_guard);
+
+        let _ray_cast_guard = debug_span!("cast_coherent_rays").entered();
+        let caches = &self.caches;
+        let targets = &targets;
+        let tlas = &tlas;
+
+        let task_pool = ComputeTaskPool::get();
+        let packet_count = rays.len().div_ceil(RAY_PACKET_SIZE);
+        let chunk_size = packet_count.div_ceil(task_pool.thread_num().max(1)) * RAY_PACKET_SIZE;
+        task_pool
+            .scope(|scope| {
+                for rays in rays.chunks(chunk_size) {
+                    scope.spawn(async move {
+                        rays.chunks(RAY_PACKET_SIZE)
+                            .flat_map(|packet| {
+                                let mut culled = Vec::new();
+                                match tlas {
+                                    Some((tlas, indices)) => {
+                                        for ray in packet {
+                                            tlas.traverse_ray(*ray, f32::INFINITY, |entity| {
+                                                if let Some(&index) = indices.get(&entity) {
+                                                    culled.push(index);
+                                                }
+                                            });
+                                        }
+                                        culled.sort_unstable();
+                                        culled.dedup();
+                                    }
+                                    None => culled.extend(0..targets.len()),
+                                }
+                                cast_batch_packet(packet, caches, targets, &culled)
+                            })
+                            .collect::<Vec<_>>()
+                    });
+                }
+            })
+            .into_iter()
+            .flatten()
+            .collect()
+    }
+
+    /// Returns the entities that can be hit by the rays of a batch, whatever the ray: the filter,
+    /// the early exit test and the visibility of the `settings` are evaluated once per entity.
+    fn batch_targets(&self, settings: &RayCastSettings) -> Vec<BatchTarget<'_>> {
+        self.culling_query
+            .iter()
+            .filter(
+                |(inherited_visibility, view_visibility, ..)| match settings.visibility {
+                    RayCastVisibility::Any => true,
+                    RayCastVisibility::Visible => inherited_visibility.get(),
+                    RayCastVisibility::VisibleInView => view_visibility.get(),
+                },
+            )
+            .filter(|(.., entity)| (settings.filter)(*entity))
+            .filter_map(|(_, _, aabb, _, entity)| {
+                let target = mesh_target(&self.mesh_query, &self.picking_bvh_backend, entity)?;
+                let (aabb, aabb_transform) =
+                    self.caches
+                        .broad_phase_bounds(entity, aabb, target.transform);
+                Some(BatchTarget {
+                    entity,
+                    aabb: broad_phase_aabb(&aabb, &aabb_transform, target.pick_radius),
+                    aabb_transform,
+                    blocks: (settings.early_exit_test)(entity),
+                    target,
+                })
+            })
+            .collect()
+    }
+
+    /// Returns the TLAS to cull the `targets` of a batch, with the index of each target entity.
+    #[cfg(feature = "obvhs")]
+    fn batch_tlas(
+        &self,
+        targets: &[BatchTarget],
+    ) -> Option<(&MeshEntitiesTlas, HashMap<Entity, usize>)> {
+        (self.picking_bvh_backend.backend != BvhBackend::None && self.tlas.is_ready()).then(|| {
+            let indices = targets
+                .iter()
+                .enumerate()
+                .map(|(index, target)| (target.entity, index))
+                .collect::<HashMap<_, _>>();
+            (self.tlas.as_ref(), indices)
+        })
+    }
+
+    /// Casts the `ray` into the world and returns all the intersections with the meshes, nearest
+    /// first, including the backfaces so that both the entry and the exit of each mesh are returned.
+    ///
+    /// Unlike [`Self::cast_ray`], the early exit test of the `settings` is ignored: every mesh along
+    /// the ray is hit. Use [`RayHitsSettings`] to limit the number and the distance of the hits.
+    pub fn cast_ray_all_hits(
+        &mut self,
+        ray: Ray3d,
+        settings: &RayCastSettings,
+        hits_settings: &RayHitsSettings,
+    ) -> &[(Entity, RayMeshHit)] {
+        let ray_cull = info_span!("ray culling");
+        let ray_cull_guard = ray_cull.enter();
+
+        self.hits.clear();
+        self.output.clear();
+
+        let max_distance = hits_settings.max_distance.unwrap_or(f32::INFINITY);
+        self.cull_entities(ray, 0.0, max_distance, settings);
+
+        drop(ray_cull_guard);
+
+        let ray_cast_guard = debug_span!("ray_cast");
+        for (_, entity) in self.culled_list.iter() {
+            if !(settings.filter)(*entity) {
+                continue;
+            }
+            let Some(target) = mesh_target(&self.mesh_query, &self.picking_bvh_backend, *entity)
+            else {
+                continue;
+            };
+
+            let _ray_cast_guard = ray_cast_guard.enter();
+            // The exit hits are on backfaces
+            let intersections =
+                self.caches
+                    .ray_intersections(&target, ray, Backfaces::Include, max_distance);
+
+            self.hits.extend(
+                intersections
+                    .into_iter()
+                    .map(|intersection| (FloatOrd(intersection.distance), (*entity, intersection))),
+            );
+        }
+
+        self.hits.sort_by_key(|(k, _)| *k);
+        if let Some(max_count) = hits_settings.max_count {
+            self.hits.truncate(max_count);
+        }
+        let hits = self.hits.drain(..).map(|(_, hit)| hit);
+        self.output.extend(hits);
+        self.output.as_ref()
+    }
+
+    /// Returns the face of the triangle of a `hit` on the mesh of `entity`: its index, its vertex
+    /// indices and its offset in the index buffer. The hits on lines or points have no face.
+    ///
+    /// The `triangle_index` of the hits is the same with every backend, so it can be used to look
+    /// up per-face data.
+    pub fn triangle_face(&self, entity: Entity, hit: &RayMeshHit) -> Option<TriangleFace> {
+        let target = mesh_target(&self.mesh_query, &self.picking_bvh_backend, entity)?;
+        let mesh = self.caches.meshes.get(target.mesh_handle)?;
+        mesh_triangle_face(mesh, hit.triangle_index?)
+    }
+
+    /// Interpolates the `attribute` of the mesh of `entity` at a `hit` on it, as the
+    /// [`Mesh::ATTRIBUTE_UV_0`] to place a decal or the [`Mesh::ATTRIBUTE_COLOR`]. See
+    /// [`interpolate_vertex_attribute`] for the supported formats.
+    pub fn hit_attribute(
+        &self,
+        entity: Entity,
+        hit: &RayMeshHit,
+        attribute: impl Into<MeshVertexAttributeId>,
+    ) -> Option<Vec4> {
+        let target = mesh_target(&self.mesh_query, &self.picking_bvh_backend, entity)?;
+        let mesh = self.caches.meshes.get(target.mesh_handle)?;
+        let face = mesh_triangle_face(mesh, hit.triangle_index?)?;
+        interpolate_vertex_attribute(mesh, attribute, &face, hit.barycentric_coords)
+    }
+
+    /// Returns the UV of a `hit` on the mesh of `entity`, interpolated from its
+    /// [`Mesh::ATTRIBUTE_UV_0`], or from its cache if it was built with `store_uvs` and the mesh
+    /// is not available.
+    pub fn hit_uv(&self, entity: Entity, hit: &RayMeshHit) -> Option<Vec2> {
+        let target = mesh_target(&self.mesh_query, &self.picking_bvh_backend, entity)?;
+        self.caches.hit_uv(&target, hit, Mesh::ATTRIBUTE_UV_0)
+    }
+
+    /// Casts the segment of the `ray` from its origin to `max_distance` into the world and returns
+    /// the first entity found to be hit, which is not necessarily the nearest.
+    ///
+    /// This is faster than [`Self::cast_ray_bounded`] when only visibility matters: the entities are
+    /// not sorted, no hit data is computed and the traversals stop at the first triangle hit. The
+    /// early exit test of the `settings` is ignored, as any hit ends the query.
+    pub fn any_hit(
+        &mut self,
+        ray: Ray3d,
+        max_distance: f32,
+        settings: &RayCastSettings,
+    ) -> Option<Entity> {
+        let ray_cull = info_span!("ray culling");
+        let ray_cull_guard = ray_cull.enter();
+
+        self.cull_entities(ray, 0.0, max_distance, settings);
+
+        drop(ray_cull_guard);
+
+        let _ray_cast_guard = debug_span!("any_hit").entered();
+        self.culled_list
+            .iter()
+            .filter(|(_, entity)| (settings.filter)(*entity))
+            .find(|(_, entity)| {
+                mesh_target(&self.mesh_query, &self.picking_bvh_backend, *entity)
+                    .is_some_and(|target| self.caches.ray_any_hit(&target, ray, max_distance))
+            })
+            .map(|(_, entity)| *entity)
+    }
+
+    /// Returns `true` if any mesh is hit between the origin of the `ray` and `max_distance`.
+    ///
+    /// To test if `b` is visible from `a`, cast a ray from `a` towards `b` with the distance between
+    /// them as `max_distance`, and filter out the entity at `b` if any. See [`Self::any_hit`].
+    pub fn is_occluded(
+        &mut self,
+        ray: Ray3d,
+        max_distance: f32,
+        settings: &RayCastSettings,
+    ) -> bool {
+        self.any_hit(ray, max_distance, settings).is_some()
+    }
+
+    /// Sweeps a sphere of `radius` centered at the origin of the `ray` along it, up to
+    /// `max_distance`, and returns the first entity touched with the contact.
+    ///
+    /// The backfaces are always included, the early exit test of the `settings` is ignored and the
+    /// hits are not alpha tested. Lines and points are not touched.
+    pub fn cast_sphere(
+        &mut self,
+        ray: Ray3d,
+        radius: f32,
+        max_distance: f32,
+        settings: &RayCastSettings,
+    ) -> Option<(Entity, ShapeCastHit)> {
+        let shape = SweptShape {
+            segment: [ray.origin; 2],
+            radius,
+        };
+        self.cast_swept_shape(ray, shape, max_distance, settings)
+    }
+
+    /// Sweeps the `capsule` centered at the origin of the `ray` along it, up to `max_distance`, and
+    /// returns the first entity touched with the contact.
+    ///
+    /// The `rotation` orients the capsule, whose segment is along the Y axis. As with
+    /// [`Self::cast_sphere`], the backfaces are always included, the early exit test of the
+    /// `settings` is ignored and the hits are not alpha tested.
+    pub fn cast_capsule(
+        &mut self,
+        ray: Ray3d,
+        capsule: Capsule3d,
+        rotation: Quat,
+        max_distance: f32,
+        settings: &RayCastSettings,
+    ) -> Option<(Entity, ShapeCastHit)> {
+        let half_segment = rotation * Vec3::Y * capsule.half_length;
+        let shape = SweptShape {
+            segment: [ray.origin - half_segment, ray.origin + half_segment],
+            radius: capsule.radius,
+        };
+        self.cast_swept_shape(ray, shape, max_distance, settings)
+    }
+
+    /// Sweeps the `shape` along the `ray` up to `max_distance`, and returns the first entity
+    /// touched with the contact.
+    fn cast_swept_shape(
+        &mut self,
+        ray: Ray3d,
+        shape: SweptShape,
+        max_distance: f32,
+        settings: &RayCastSettings,
+    ) -> Option<(Entity, ShapeCastHit)> {
+        let shape_cull = info_span!("shape culling");
+        let shape_cull_guard = shape_cull.enter();
+
+        let (_, bounding_radius) = shape.bounding_sphere();
+        self.cull_entities(ray, bounding_radius, max_distance, settings);
+
+        // Sort by the distance along the ray.
+        self.culled_list.sort_by_key(|(aabb_near, _)| *aabb_near);
+
+        drop(shape_cull_guard);
+
+        let _shape_cast_guard = debug_span!("shape_cast").entered();
+        self.nearest_triangle_hit(
+            max_distance,
+            settings,
+            |best, transform| ShapeSweep::new(shape, ray.direction, best, transform),
+            |sweep| sweep.nearest_hit.map(|hit| (hit.distance, hit)),
+        )
+    }
+
+    /// Returns the point of the meshes nearest to `point`, within `max_distance`, with its entity,
+    /// its normal, its triangle and its barycentric coordinates.
+    ///
+    /// The entities are visited by increasing distance to their AABB and the triangles of each mesh
+    /// best first, so that the search stops once no triangle can be nearer. The backfaces are
+    /// included, the early exit test of the `settings` is ignored and lines and points are not
+    /// found.
+    pub fn closest_point(
+        &mut self,
+        point: Vec3,
+        max_distance: f32,
+        settings: &RayCastSettings,
+    ) -> Option<(Entity, ClosestPointHit)> {
+        let point_cull = info_span!("point culling");
+        let point_cull_guard = point_cull.enter();
+
+        self.cull_entities_near(point, max_distance, settings);
+
+        // Sort by the distance to their AABB.
+        self.culled_list
+            .sort_by_key(|(aabb_distance, _)| *aabb_distance);
+
+        drop(point_cull_guard);
+
+        let _closest_point_guard = debug_span!("closest_point").entered();
+        self.nearest_triangle_hit(
+            max_distance,
+            settings,
+            |best, transform| ClosestPointQuery::new(point, best, transform),
+            |query| query.nearest_hit.map(|hit| (hit.distance, hit)),
+        )
+    }
+
+    /// Queries the triangles of the meshes of `culled_list`, sorted by a lower bound of the distance
+    /// of their hits, and returns the nearest hit within `max_distance`.
+    ///
+    /// The query of each mesh is built by `new_query` with the distance of the nearest hit found so
+    /// far and the transform of its triangles, and its hit is taken with its distance by `into_hit`.
+    /// The meshes whose lower bound is beyond the nearest hit are not queried.
+    fn nearest_triangle_hit<Q: TriangleQuery, H>(
+        &self,
+        max_distance: f32,
+        settings: &RayCastSettings,
+        new_query: impl Fn(f32, &Mat4) -> Q,
+        into_hit: impl Fn(Q) -> Option<(f32, H)>,
+    ) -> Option<(Entity, H)> {
+        let mut nearest_hit: Option<(f32, Entity, H)> = None;
+        for (lower_bound, entity) in self.culled_list.iter() {
+            let best = nearest_hit
+                .as_ref()
+                .map_or(max_distance, |(distance, ..)| *distance);
+            // Is it even possible the mesh could be closer than the current best?
+            if lower_bound.0 > best {
+                break;
+            }
+            if !(settings.filter)(*entity) {
+                continue;
+            }
+            let Some(target) = mesh_target(&self.mesh_query, &self.picking_bvh_backend, *entity)
+            else {
+                continue;
+            };
+
+            let hit = self
+                .caches
+                .query_triangles(&target, |transform| new_query(best, transform))
+                .and_then(&into_hit);
+            if let Some((distance, hit)) = hit {
+                if nearest_hit
+                    .as_ref()
+                    .is_none_or(|(nearest_distance, ..)| distance < *nearest_distance)
+                {
+                    nearest_hit = Some((distance, *entity, hit));
+                }
+            }
+        }
+        nearest_hit.map(|(_, entity, hit)| (entity, hit))
+    }
+
+    /// Returns the point nearest to the `ray` among the meshes with a point list topology (point
+    /// clouds), within the `tolerance` and before `max_distance`, with its vertex index.
+    ///
+    /// The points are not hidden by the other meshes, and the early exit test of the `settings` is
+    /// ignored. Use [`PickTolerance::from_pixels`] for a tolerance in pixels on the screen.
+    pub fn pick_point(
+        &mut self,
+        ray: Ray3d,
+        tolerance: PickTolerance,
+        max_distance: f32,
+        settings: &RayCastSettings,
+    ) -> Option<(Entity, RayPointHit)> {
+        self.cull_tolerance_targets(ray, &tolerance, max_distance, settings);
+
+        let _pick_point_guard = debug_span!("pick_point").entered();
+        let mut nearest_hit: Option<(Entity, RayPointHit)> = None;
+        for (metric, entity) in self.culled_list.iter() {
+            // Can any point of the entity be nearer than the current best?
+            if nearest_hit
+                .as_ref()
+                .is_some_and(|(_, nearest_hit)| metric.0 > nearest_hit.metric)
+            {
+                break;
+            }
+            if !(settings.filter)(*entity) {
+                continue;
+            }
+            let Some(target) = mesh_target(&self.mesh_query, &self.picking_bvh_backend, *entity)
+            else {
+                continue;
+            };
+            let Some(hit) = self
+                .caches
+                .ray_nearest_point(&target, ray, &tolerance, max_distance)
+            else {
+                continue;
+            };
+            if nearest_hit
+                .as_ref()
+                .is_none_or(|(_, nearest_hit)| hit.is_nearer_than(nearest_hit))
+            {
+                nearest_hit = Some((*entity, hit));
+            }
+        }
+        nearest_hit
+    }
+
+    /// Returns the segment nearest to the `ray` among the meshes with a line list or line strip
+    /// topology, within the `tolerance` and before `max_distance`, with its index and the point
+    /// picked along it.
+    ///
+    /// The lines are not hidden by the other meshes, and the early exit test of the `settings` is
+    /// ignored. Use [`PickTolerance::from_pixels`] for a tolerance in pixels on the screen.
+    pub fn pick_line(
+        &mut self,
+        ray: Ray3d,
+        tolerance: PickTolerance,
+        max_distance: f32,
+        settings: &RayCastSettings,
+    ) -> Option<(Entity, RaySegmentHit)> {
+        self.cull_tolerance_targets(ray, &tolerance, max_distance, settings);
+
+        let _pick_line_guard = debug_span!("pick_line").entered();
+        let mut nearest_hit: Option<(Entity, RaySegmentHit)> = None;
+        for (metric, entity) in self.culled_list.iter() {
+            // Can any segment of the entity be nearer than the current best?
+            if nearest_hit
+                .as_ref()
+                .is_some_and(|(_, nearest_hit)| metric.0 > nearest_hit.metric)
+            {
+                break;
+            }
+            if !(settings.filter)(*entity) {
+                continue;
+            }
+            let Some(target) = mesh_target(&self.mesh_query, &self.picking_bvh_backend, *entity)
+            else {
+                continue;
+            };
+            let Some(hit) = self
+                .caches
+                .ray_nearest_segment(&target, ray, &tolerance, max_distance)
+            else {
+                continue;
+            };
+            if nearest_hit
+                .as_ref()
+                .is_none_or(|(_, nearest_hit)| hit.is_nearer_than(nearest_hit))
+            {
+                nearest_hit = Some((*entity, hit));
+            }
+        }
+        nearest_hit
+    }
+
+    /// Fills `culled_list` with the entities that may have a point or a segment within the
+    /// `tolerance` of the `ray` before `max_distance`, sorted by the lower bound of their metric.
+    fn cull_tolerance_targets(
+        &mut self,
+        ray: Ray3d,
+        tolerance: &PickTolerance,
+        max_distance: f32,
+        settings: &RayCastSettings,
+    ) {
+        let _tolerance_cull_guard = info_span!("tolerance culling").entered();
+
+        // The TLAS is traversed with the ray grown by the tolerance at `max_distance`
+        let radius = match tolerance {
+            PickTolerance::World(distance) => *distance,
+            PickTolerance::Angular(_) if tolerance.limit() > 0.0 => {
+                tolerance.limit() * max_distance
+            }
+            PickTolerance::Angular(_) => 0.0,
+        };
+        let volume = CullingVolume::Ray {
+            ray,
+            radius,
+            max_distance,
+        };
+        let pick_radius = self.picking_bvh_backend.pick_radius;
+        // The bounding sphere of each entity gives a lower bound of the metric of its primitives
+        self.cull_entities_with(volume, settings, |aabb, transform| {
+            let aabb = broad_phase_aabb(aabb, transform, pick_radius);
+            let max_scale = transform
+                .x_axis
+                .truncate()
+                .length()
+                .max(transform.y_axis.truncate().length())
+                .max(transform.z_axis.truncate().length());
+            let center = transform.transform_point3(aabb.center().into());
+            let radius = Vec3::from(aabb.half_size()).length() * max_scale;
+            ray_sphere_point_metric(&ray, tolerance, max_distance, center, radius)
+        });
+        self.culled_list.sort_by_key(|(metric, _)| *metric);
+    }
+
+    /// Fills `culled_list` with the entities whose AABB is hit by the `ray` before `max_distance`,
+    /// with the distance along the ray to their AABB. The list is not sorted.
+    ///
+    /// The AABBs are grown by the world-space `radius` of a shape swept along the ray.
+    fn cull_entities(
+        &mut self,
+        ray: Ray3d,
+        radius: f32,
+        max_distance: f32,
+        settings: &RayCastSettings,
+    ) {
+        let pick_radius = self.picking_bvh_backend.pick_radius;
+        let volume = CullingVolume::Ray {
+            ray,
+            radius,
+            max_distance,
+        };
+        self.cull_entities_with(volume, settings, |aabb, transform| {
+            ray_aabb_intersection_3d(
+                ray,
+                &broad_phase_aabb(aabb, transform, pick_radius + radius),
+                transform,
+            )
+            .filter(|distance| *distance <= max_distance)
+        });
+    }
+
+    /// Fills `culled_list` with the visible entities in the `volume` for which `aabb_distance`,
+    /// given their AABB and its transform, returns a distance. The list is not sorted.
+    ///
+    /// The AABBs of the skinned mesh entities enclose their tree, see
+    /// [`MeshBvhCaches::broad_phase_bounds`].
+    fn cull_entities_with(
+        &mut self,
+        volume: CullingVolume,
+        settings: &RayCastSettings,
+        aabb_distance: impl Fn(&Aabb, &Mat4) -> Option<f32> + Sync,
+    ) {
+        self.culled_list.clear();
+
+        let visibility_setting = settings.visibility;
+        let caches = &self.caches;
+        let entity_distance =
+            |(inherited_visibility, view_visibility, aabb, transform, entity): (
+                &InheritedVisibility,
+                &ViewVisibility,
+                &Aabb,
+                &GlobalTransform,
+                Entity,
+            )| {
+                let should_ray_cast = match visibility_setting {
+                    RayCastVisibility::Any => true,
+                    RayCastVisibility::Visible => inherited_visibility.get(),
+                    RayCastVisibility::VisibleInView => view_visibility.get(),
+                };
+                if !should_ray_cast {
+                    return None;
+                }
+                let (aabb, transform) =
+                    caches.broad_phase_bounds(entity, aabb, transform.compute_matrix());
+                aabb_distance(&aabb, &transform)
+            };
+
+        // Use the TLAS to only test the entities whose world-space AABB is in the volume
+        #[cfg(feature = "obvhs")]
+        if self.picking_bvh_backend.backend != BvhBackend::None && self.tlas.is_ready() {
+            let culling_query = &self.culling_query;
+            let culled_list = &mut self.culled_list;
+            let f = |entity| {
+                let Ok(item) = culling_query.get(entity) else {
+                    return;
+                };
+                if let Some(distance) = entity_distance(item) {
+                    culled_list.push((FloatOrd(distance), entity));
+                }
+            };
+            match volume {
+                CullingVolume::Ray {
+                    ray,
+                    radius,
+                    max_distance,
+                } => {
+                    if radius > 0.0 {
+                        self.tlas
+                            .traverse_swept_sphere(ray, radius, max_distance, f);
+                    } else {
+                        self.tlas.traverse_ray(ray, max_distance, f);
+                    }
+                }
+                CullingVolume::Sphere { center, radius } => {
+                    self.tlas.traverse_sphere(center.into(), radius, f);
+                }
+            }
+            return;
+        }
+        // Only the TLAS uses the volume
+        #[cfg(not(feature = "obvhs"))]
+        let _ = volume;
+
+        // Check all entities to see if their AABB is in the volume. Use this to build a short list
+        // of entities that may be hit.
+        let (aabb_hits_tx, aabb_hits_rx) = crossbeam_channel::unbounded::<(FloatOrd, Entity)>();
+        self.culling_query.par_iter().for_each(|item| {
+            let entity = item.4;
+            if let Some(distance) = entity_distance(item) {
+                aabb_hits_tx.send((FloatOrd(distance), entity)).ok();
+            }
+        });
+        self.culled_list.extend(aabb_hits_rx.try_iter());
+    }
+
+    /// Fills `culled_list` with the entities whose AABB is within `max_distance` of `point`, with a
+    /// lower bound of the distance to their AABB. The list is not sorted.
+    fn cull_entities_near(&mut self, point: Vec3, max_distance: f32, settings: &RayCastSettings) {
+        let volume = CullingVolume::Sphere {
+            center: point,
+            radius: max_distance,
+        };
+        self.cull_entities_with(volume, settings, |aabb, transform| {
+            let world_to_local = transform.inverse();
+            let local_point = Vec3A::from(world_to_local.transform_point3(point));
+            let offset = ((aabb.center - local_point).abs() - aabb.half_extents).max(Vec3A::ZERO);
+            Some(offset.length() / max_stretch(&world_to_local))
+                .filter(|distance| *distance <= max_distance)
+        });
+    }
+}
+
+/// The region of the world where [`BvhMeshRayCast::cull_entities_with`] looks for entities with
+/// the TLAS.
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(not(feature = "obvhs"), allow(dead_code))]
+enum CullingVolume {
+    /// The segment of a ray from its origin to `max_distance`, grown by `radius`.
+    Ray {
+        ray: Ray3d,
+        radius: f32,
+        max_distance: f32,
+    },
+    /// The sphere of `radius` around `center`.
+    Sphere { center: Vec3, radius: f32 },
+}
+
+/// An entity that may be hit by the rays of [`BvhMeshRayCast::cast_rays`].
+struct BatchTarget<'a> {
+    entity: Entity,
+    aabb: Aabb3d,
+    /// The transform of the `aabb`, the identity for the skinned mesh entities.
+    aabb_transform: Mat4,
+    /// The result of the early exit test of the entity.
+    blocks: bool,
+    target: MeshTarget<'a>,
+}
+
+/// Casts the `ray` against the `culled` targets, and returns a sorted list of intersections,
+/// nearest first, up to the nearest blocking hit.
+fn cast_batch_ray(
+    ray: Ray3d,
+    caches: &MeshBvhCaches,
+    targets: &[BatchTarget],
+    culled: &[usize],
+) -> Vec<(Entity, RayMeshHit)> {
+    let mut aabb_hits = culled
+        .iter()
+        .filter_map(|index| {
+            let target = &targets[*index];
+            let distance = ray_aabb_intersection_3d(ray, &target.aabb, &target.aabb_transform)?;
+            Some((FloatOrd(distance), target))
+        })
+        .collect::<Vec<_>>();
+    aabb_hits.sort_by_key(|(aabb_near, _)| *aabb_near);
+
+    let mut nearest_blocking_hit = FloatOrd(f32::INFINITY);
+    let mut hits = Vec::new();
+    for (aabb_near, target) in aabb_hits {
+        // Is it even possible the mesh could be closer than the current best?
+        if aabb_near > nearest_blocking_hit {
+            break;
+        }
+        let Some(intersection) = caches.ray_intersection(&target.target, ray, f32::INFINITY) else {
+            continue;
+        };
+        let distance = FloatOrd(intersection.distance);
+        if target.blocks && distance < nearest_blocking_hit {
+            nearest_blocking_hit = distance;
+        }
+        hits.push((distance, (target.entity, intersection)));
+    }
+
+    hits.retain(|(distance, _)| *distance <= nearest_blocking_hit);
+    hits.sort_by_key(|(distance, _)| *distance);
+    hits.into_iter().map(|(_, hit)| hit).collect()
+}
+
+/// Casts the coherent rays of a `packet` against the `culled` targets, and returns for each ray a
+/// sorted list of intersections, nearest first, up to the nearest blocking hit.
+#[cfg(feature = "obvhs")]
+fn cast_batch_packet(
+    packet: &[Ray3d],
+    caches: &MeshBvhCaches,
+    targets: &[BatchTarget],
+    culled: &[usize],
+) -> Vec<Vec<(Entity, RayMeshHit)>> {
+    let mut nearest_blocking_hits = vec![FloatOrd(f32::INFINITY); packet.len()];
+    let mut hits = vec![Vec::new(); packet.len()];
+    for index in culled {
+        let target = &targets[*index];
+        let aabb_hit = packet.iter().any(|ray| {
+            ray_aabb_intersection_3d(*ray, &target.aabb, &target.aabb_transform).is_some()
+        });
+        if !aabb_hit {
+            continue;
+        }
+
+        let intersections = caches.ray_packet_intersections(&target.target, packet);
+        for (i, intersection) in intersections.into_iter().enumerate() {
+            let Some(intersection) = intersection else {
+                continue;
+            };
+            let distance = FloatOrd(intersection.distance);
+            if target.blocks {
+                nearest_blocking_hits[i] = nearest_blocking_hits[i].min(distance);
+            }
+            hits[i].push((distance, (target.entity, intersection)));
+        }
+    }
+
+    hits.into_iter()
+        .zip(nearest_blocking_hits)
+        .map(|(mut hits, nearest_blocking_hit)| {
+            hits.retain(|(distance, _)| *distance <= nearest_blocking_hit);
+            hits.sort_by_key(|(distance, _)| *distance);
+            hits.into_iter().map(|(_, hit)| hit).collect()
+        })
+        .collect()
 }
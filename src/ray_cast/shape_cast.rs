@@ -0,0 +1,475 @@
+//! Sweeps of spheres and capsules along a ray against the triangles of the meshes, see
+//! [`BvhMeshRayCast::cast_sphere`](super::BvhMeshRayCast::cast_sphere) and
+//! [`BvhMeshRayCast::cast_capsule`](super::BvhMeshRayCast::cast_capsule).
+//!
+//! A swept shape is the set of points within its radius of a segment, which is a single point for
+//! a sphere. It touches a triangle when the distance between its segment and the triangle reaches
+//! its radius, that is when the segment, moving along the ray, enters the triangle grown by the
+//! radius.
+
+use bevy_math::{Dir3, Mat4, Vec3};
+
+use super::mesh_caches::TriangleQuery;
+
+/// The first contact of a shape swept along a ray with a mesh.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ShapeCastHit {
+    /// The contact point on the mesh, in world space.
+    pub point: Vec3,
+    /// The normal of the contact, from the mesh towards the shape, in world space.
+    pub normal: Vec3,
+    /// The barycentric coordinates of the contact point in its triangle, as in the hits of the ray
+    /// casts.
+    pub barycentric_coords: Vec3,
+    /// The distance travelled by the shape along the ray before the contact (its time of impact).
+    /// It is zero if the shape already overlaps the mesh at the origin of the ray.
+    pub distance: f32,
+    /// The vertices of the triangle hit, in world space.
+    pub triangle: [Vec3; 3],
+    /// The index of the triangle hit in its mesh.
+    pub triangle_index: usize,
+}
+
+/// A sphere or a capsule: the points within `radius` of the `segment`, in world space.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct SweptShape {
+    pub segment: [Vec3; 2],
+    pub radius: f32,
+}
+
+impl SweptShape {
+    /// Returns the center of the shape and the radius of its bounding sphere.
+    pub fn bounding_sphere(&self) -> (Vec3, f32) {
+        let [a, b] = self.segment;
+        ((a + b) * 0.5, self.radius + a.distance(b) * 0.5)
+    }
+}
+
+/// Sweeps a [`SweptShape`] along a direction against the triangles of a mesh, keeping the first
+/// contact.
+pub(crate) struct ShapeSweep {
+    shape: SweptShape,
+    direction: Dir3,
+    max_distance: f32,
+    /// The transform from the space of the triangles to world space.
+    transform: Mat4,
+    /// The bounding sphere of the shape in the space of the triangles: its center and direction,
+    /// parameterized by the world space distance, and a radius enclosing the transformed sphere.
+    mesh_center: Vec3,
+    mesh_inv_direction: Vec3,
+    mesh_radius: f32,
+    pub nearest_hit: Option<ShapeCastHit>,
+}
+
+impl ShapeSweep {
+    pub fn new(shape: SweptShape, direction: Dir3, max_distance: f32, transform: &Mat4) -> Self {
+        let world_to_mesh = transform.inverse();
+        let (center, radius) = shape.bounding_sphere();
+        Self {
+            shape,
+            direction,
+            max_distance,
+            transform: *transform,
+            mesh_center: world_to_mesh.transform_point3(center),
+            mesh_inv_direction: world_to_mesh.transform_vector3(*direction).recip(),
+            mesh_radius: radius * max_stretch(&world_to_mesh),
+            nearest_hit: None,
+        }
+    }
+
+    /// Returns the distance travelled by the shape before its first contact found so far.
+    fn nearest_distance(&self) -> f32 {
+        self.nearest_hit
+            .as_ref()
+            .map_or(self.max_distance, |hit| hit.distance)
+    }
+}
+
+impl TriangleQuery for ShapeSweep {
+    fn node_cost(&self, min: Vec3, max: Vec3) -> Option<f32> {
+        // Slab test of the center of the bounding sphere against the grown AABB
+        let t1 = (min - self.mesh_radius - self.mesh_center) * self.mesh_inv_direction;
+        let t2 = (max + self.mesh_radius - self.mesh_center) * self.mesh_inv_direction;
+        let t_near = t1.min(t2).max_element().max(0.0);
+        let t_far = t1.max(t2).min_element().min(self.nearest_distance());
+        (t_near <= t_far).then_some(t_near)
+    }
+
+    fn visit(&mut self, positions: [Vec3; 3], triangle_index: usize) -> f32 {
+        let triangle = positions.map(|position| self.transform.transform_point3(position));
+        let max_distance = self.nearest_distance();
+        if let Some(distance) =
+            swept_shape_triangle_distance(&self.shape, self.direction, &triangle, max_distance)
+        {
+            if self.nearest_hit.is_none() || distance < max_distance {
+                self.nearest_hit = Some(shape_cast_hit(
+                    &self.shape,
+                    self.direction,
+                    distance,
+                    triangle,
+                    triangle_index,
+                ));
+            }
+        }
+        self.nearest_distance()
+    }
+}
+
+/// Returns an upper bound of the factor by which the linear part of the `matrix` can stretch a
+/// distance: its Frobenius norm.
+pub(crate) fn max_stretch(matrix: &Mat4) -> f32 {
+    (matrix.x_axis.truncate().length_squared()
+        + matrix.y_axis.truncate().length_squared()
+        + matrix.z_axis.truncate().length_squared())
+    .sqrt()
+}
+
+/// Returns the distance travelled by the `shape` along the `direction` before it touches the
+/// `triangle`, if it does before `max_distance`.
+pub(crate) fn swept_shape_triangle_distance(
+    shape: &SweptShape,
+    direction: Dir3,
+    triangle: &[Vec3; 3],
+    max_distance: f32,
+) -> Option<f32> {
+    let [a, b] = shape.segment;
+    let radius = shape.radius;
+    if a.distance_squared(b) <= f32::EPSILON * f32::EPSILON {
+        return ray_grown_triangle_distance(a, direction, triangle, radius, max_distance);
+    }
+    if segment_crosses_triangle(a, b, triangle) {
+        return Some(0.0);
+    }
+
+    // The nearest points are an end of the segment and the triangle, or the segment and an edge of
+    // the triangle. The points of an edge relative to the segment form a parallelogram.
+    let mut nearest = None::<f32>;
+    let mut test = |origin: Vec3, triangle: &[Vec3; 3]| {
+        let max_distance = nearest.unwrap_or(max_distance);
+        if let Some(distance) =
+            ray_grown_triangle_distance(origin, direction, triangle, radius, max_distance)
+        {
+            nearest = Some(distance);
+        }
+    };
+    test(a, triangle);
+    test(b, triangle);
+    for i in 0..3 {
+        let (e0, e1) = (triangle[i], triangle[(i + 1) % 3]);
+        test(Vec3::ZERO, &[e0 - a, e1 - a, e1 - b]);
+        test(Vec3::ZERO, &[e0 - a, e1 - b, e0 - b]);
+    }
+    nearest
+}
+
+/// Returns the distance along the ray from `origin` in the `direction` to the points within
+/// `radius` of the `triangle`, if it is reached before `max_distance`. It is zero if the origin is
+/// already within the radius.
+fn ray_grown_triangle_distance(
+    origin: Vec3,
+    direction: Dir3,
+    triangle: &[Vec3; 3],
+    radius: f32,
+    max_distance: f32,
+) -> Option<f32> {
+    let (closest, _) = closest_point_on_triangle(origin, triangle);
+    if origin.distance_squared(closest) <= radius * radius {
+        return Some(0.0);
+    }
+
+    // The grown triangle is made of the triangle moved by the radius along its normal on both
+    // sides, and of the capsules around its edges
+    let mut nearest = None::<f32>;
+    let normal = (triangle[1] - triangle[0])
+        .cross(triangle[2] - triangle[0])
+        .normalize_or_zero();
+    let denominator = normal.dot(*direction);
+    if normal != Vec3::ZERO && denominator.abs() > f32::EPSILON {
+        for side in [radius, -radius] {
+            let distance = (normal.dot(triangle[0] - origin) + side) / denominator;
+            if !(0.0..=max_distance).contains(&distance) {
+                continue;
+            }
+            let point = origin + direction * distance - normal * side;
+            let (_, weights) = closest_point_on_triangle(point, triangle);
+            if weights.iter().all(|weight| *weight > 0.0) {
+                nearest = Some(nearest.map_or(distance, |nearest| nearest.min(distance)));
+            }
+        }
+    }
+    for i in 0..3 {
+        let edge = [triangle[i], triangle[(i + 1) % 3]];
+        let max_distance = nearest.unwrap_or(max_distance);
+        if let Some(distance) = ray_capsule_distance(origin, direction, &edge, radius, max_distance)
+        {
+            nearest = Some(distance);
+        }
+    }
+    nearest
+}
+
+/// Returns the distance along the ray from `origin` in the `direction` to the points within
+/// `radius` of the `segment`, if it is reached before `max_distance`. The origin must be outside.
+fn ray_capsule_distance(
+    origin: Vec3,
+    direction: Dir3,
+    segment: &[Vec3; 2],
+    radius: f32,
+    max_distance: f32,
+) -> Option<f32> {
+    let mut nearest = None::<f32>;
+    let mut keep = |distance: f32| {
+        if (0.0..=nearest.unwrap_or(max_distance)).contains(&distance) {
+            nearest = Some(distance);
+        }
+    };
+
+    // The cylinder around the segment, between its ends
+    let axis = segment[1] - segment[0];
+    let axis_length_squared = axis.length_squared();
+    if axis_length_squared > f32::EPSILON * f32::EPSILON {
+        let offset = origin - segment[0];
+        let direction_along = direction.dot(axis) / axis_length_squared;
+        let offset_along = offset.dot(axis) / axis_length_squared;
+        let direction_across = *direction - axis * direction_along;
+        let offset_across = offset - axis * offset_along;
+        let a = direction_across.length_squared();
+        let b = direction_across.dot(offset_across);
+        let c = offset_across.length_squared() - radius * radius;
+        let discriminant = b * b - a * c;
+        if a > f32::EPSILON && discriminant >= 0.0 {
+            let distance = (-b - discriminant.sqrt()) / a;
+            let along = offset_along + direction_along * distance;
+            if (0.0..=1.0).contains(&along) {
+                keep(distance);
+            }
+        }
+    }
+
+    // The spheres at its ends
+    for center in segment {
+        let offset = origin - *center;
+        let b = offset.dot(*direction);
+        let c = offset.length_squared() - radius * radius;
+        let discriminant = b * b - c;
+        if discriminant >= 0.0 {
+            keep(-b - discriminant.sqrt());
+        }
+    }
+    nearest
+}
+
+/// Returns `true` if the segment from `a` to `b` crosses the `triangle`.
+fn segment_crosses_triangle(a: Vec3, b: Vec3, triangle: &[Vec3; 3]) -> bool {
+    let edge1 = triangle[1] - triangle[0];
+    let edge2 = triangle[2] - triangle[0];
+    let direction = b - a;
+    let p = direction.cross(edge2);
+    let determinant = edge1.dot(p);
+    if determinant.abs() < f32::EPSILON {
+        return false;
+    }
+    let inverse = 1.0 / determinant;
+    let offset = a - triangle[0];
+    let u = offset.dot(p) * inverse;
+    let q = offset.cross(edge1);
+    let v = direction.dot(q) * inverse;
+    let t = edge2.dot(q) * inverse;
+    u >= 0.0 && v >= 0.0 && u + v <= 1.0 && (0.0..=1.0).contains(&t)
+}
+
+/// Returns the hit of the `shape` swept along the `direction` on the `triangle` at `distance`.
+fn shape_cast_hit(
+    shape: &SweptShape,
+    direction: Dir3,
+    distance: f32,
+    triangle: [Vec3; 3],
+    triangle_index: usize,
+) -> ShapeCastHit {
+    let [a, b] = shape.segment.map(|end| end + direction * distance);
+
+    // The nearest points of the moved segment and of the triangle
+    let mut nearest = [a, b]
+        .map(|end| (end, closest_point_on_triangle(end, &triangle).0))
+        .into_iter()
+        .min_by(|(a, p), (b, q)| a.distance_squared(*p).total_cmp(&b.distance_squared(*q)))
+        .unwrap();
+    for i in 0..3 {
+        let edge = [triangle[i], triangle[(i + 1) % 3]];
+        let (on_segment, on_edge) = closest_points_on_segments(&[a, b], &edge);
+        if on_segment.distance_squared(on_edge) < nearest.0.distance_squared(nearest.1) {
+            nearest = (on_segment, on_edge);
+        }
+    }
+    let (on_shape, point) = nearest;
+
+    let face_normal = (triangle[1] - triangle[0])
+        .cross(triangle[2] - triangle[0])
+        .normalize_or_zero();
+    let face_normal = if face_normal.dot(*direction) > 0.0 {
+        -face_normal
+    } else {
+        face_normal
+    };
+    let (_, weights) = closest_point_on_triangle(point, &triangle);
+    ShapeCastHit {
+        point,
+        normal: (on_shape - point).try_normalize().unwrap_or(face_normal),
+        barycentric_coords: Vec3::new(weights[1], weights[2], weights[0]),
+        distance,
+        triangle,
+        triangle_index,
+    }
+}
+
+/// Returns the point of the `triangle` nearest to `point`, with its weights in the order of the
+/// vertices.
+pub fn closest_point_on_triangle(point: Vec3, triangle: &[Vec3; 3]) -> (Vec3, [f32; 3]) {
+    // Source: Real-Time Collision Detection, Christer Ericson, 5.1.5
+    let [a, b, c] = *triangle;
+    let ab = b - a;
+    let ac = c - a;
+    let ap = point - a;
+    let d1 = ab.dot(ap);
+    let d2 = ac.dot(ap);
+    if d1 <= 0.0 && d2 <= 0.0 {
+        return (a, [1.0, 0.0, 0.0]);
+    }
+
+    let bp = point - b;
+    let d3 = ab.dot(bp);
+    let d4 = ac.dot(bp);
+    if d3 >= 0.0 && d4 <= d3 {
+        return (b, [0.0, 1.0, 0.0]);
+    }
+
+    let vc = d1 * d4 - d3 * d2;
+    if vc <= 0.0 && d1 >= 0.0 && d3 <= 0.0 {
+        let v = d1 / (d1 - d3);
+        return (a + ab * v, [1.0 - v, v, 0.0]);
+    }
+
+    let cp = point - c;
+    let d5 = ab.dot(cp);
+    let d6 = ac.dot(cp);
+    if d6 >= 0.0 && d5 <= d6 {
+        return (c, [0.0, 0.0, 1.0]);
+    }
+
+    let vb = d5 * d2 - d1 * d6;
+    if vb <= 0.0 && d2 >= 0.0 && d6 <= 0.0 {
+        let w = d2 / (d2 - d6);
+        return (a + ac * w, [1.0 - w, 0.0, w]);
+    }
+
+    let va = d3 * d6 - d5 * d4;
+    if va <= 0.0 && (d4 - d3) >= 0.0 && (d5 - d6) >= 0.0 {
+        let w = (d4 - d3) / ((d4 - d3) + (d5 - d6));
+        return (b + (c - b) * w, [0.0, 1.0 - w, w]);
+    }
+
+    let denominator = 1.0 / (va + vb + vc);
+    let v = vb * denominator;
+    let w = vc * denominator;
+    (a + ab * v + ac * w, [1.0 - v - w, v, w])
+}
+
+/// Returns the nearest points of the segments `s1` and `s2`, on `s1` then on `s2`.
+fn closest_points_on_segments(s1: &[Vec3; 2], s2: &[Vec3; 2]) -> (Vec3, Vec3) {
+    // Source: Real-Time Collision Detection, Christer Ericson, 5.1.9
+    let d1 = s1[1] - s1[0];
+    let d2 = s2[1] - s2[0];
+    let r = s1[0] - s2[0];
+    let a = d1.length_squared();
+    let e = d2.length_squared();
+    let f = d2.dot(r);
+    let (s, t) = if a <= f32::EPSILON && e <= f32::EPSILON {
+        (0.0, 0.0)
+    } else if a <= f32::EPSILON {
+        (0.0, (f / e).clamp(0.0, 1.0))
+    } else {
+        let c = d1.dot(r);
+        if e <= f32::EPSILON {
+            ((-c / a).clamp(0.0, 1.0), 0.0)
+        } else {
+            let b = d1.dot(d2);
+            let denominator = a * e - b * b;
+            let s = if denominator > 0.0 {
+                ((b * f - c * e) / denominator).clamp(0.0, 1.0)
+            } else {
+                0.0
+            };
+            let t = (b * s + f) / e;
+            if t < 0.0 {
+                ((-c / a).clamp(0.0, 1.0), 0.0)
+            } else if t > 1.0 {
+                (((b - c) / a).clamp(0.0, 1.0), 1.0)
+            } else {
+                (s, t)
+            }
+        }
+    };
+    (s1[0] + d1 * s, s2[0] + d2 * t)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// A triangle in the XZ plane, also used by the tests of the closest point queries.
+    pub(crate) const TRIANGLE: [Vec3; 3] = [
+        Vec3::new(-1.0, 0.0, -1.0),
+        Vec3::new(1.0, 0.0, -1.0),
+        Vec3::new(0.0, 0.0, 1.0),
+    ];
+
+    #[test]
+    fn sphere_sweep() {
+        let sphere = SweptShape {
+            segment: [Vec3::new(0.0, 2.0, 0.0); 2],
+            radius: 0.5,
+        };
+        // Onto the face
+        let distance =
+            swept_shape_triangle_distance(&sphere, Dir3::NEG_Y, &TRIANGLE, f32::INFINITY);
+        assert!((distance.unwrap() - 1.5).abs() < 1e-5);
+        // Past the triangle, within the radius of its edge
+        let sphere = SweptShape {
+            segment: [Vec3::new(0.0, 2.0, -1.25); 2],
+            radius: 0.5,
+        };
+        let distance =
+            swept_shape_triangle_distance(&sphere, Dir3::NEG_Y, &TRIANGLE, f32::INFINITY).unwrap();
+        // The sphere touches the edge at z = -1 when its center is 0.5 away from it
+        assert!((distance - (2.0 - 0.1875_f32.sqrt())).abs() < 1e-4);
+        let hit = shape_cast_hit(&sphere, Dir3::NEG_Y, distance, TRIANGLE, 0);
+        assert!(hit.point.distance(Vec3::new(0.0, 0.0, -1.0)) < 1e-4);
+        // Too far
+        assert!(swept_shape_triangle_distance(&sphere, Dir3::NEG_Y, &TRIANGLE, 1.0).is_none());
+    }
+
+    #[test]
+    fn capsule_sweep() {
+        // A capsule lying across the triangle, whose ends are beyond it
+        let capsule = SweptShape {
+            segment: [Vec3::new(-3.0, 2.0, 0.0), Vec3::new(3.0, 2.0, 0.0)],
+            radius: 0.25,
+        };
+        let distance =
+            swept_shape_triangle_distance(&capsule, Dir3::NEG_Y, &TRIANGLE, f32::INFINITY).unwrap();
+        assert!((distance - 1.75).abs() < 1e-5);
+        let hit = shape_cast_hit(&capsule, Dir3::NEG_Y, distance, TRIANGLE, 0);
+        assert!(hit.normal.distance(Vec3::Y) < 1e-4);
+        assert!(hit.point.y.abs() < 1e-5);
+
+        // Already crossing the triangle
+        let capsule = SweptShape {
+            segment: [Vec3::new(0.0, 1.0, 0.0), Vec3::new(0.0, -1.0, 0.0)],
+            radius: 0.1,
+        };
+        assert_eq!(
+            swept_shape_triangle_distance(&capsule, Dir3::X, &TRIANGLE, f32::INFINITY),
+            Some(0.0)
+        );
+    }
+}
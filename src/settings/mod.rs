@@ -0,0 +1,373 @@
+//! Settings used to build the BVH caches of mesh assets.
+
+#[cfg(any(feature = "bvh", feature = "obvhs"))]
+use std::path::PathBuf;
+
+use bevy_asset::AssetId;
+use bevy_ecs::{
+    prelude::*,
+    system::{lifetimeless::Read, SystemParam},
+};
+use bevy_picking_more_hitinfo::mesh_picking::ray_cast::SimplifiedMesh;
+use bevy_reflect::prelude::*;
+use bevy_render::prelude::*;
+use bevy_utils::HashMap;
+#[cfg(feature = "obvhs")]
+use obvhs::BvhBuildParams;
+
+use crate::BvhBackend;
+
+/// Settings used to build the BVH caches, for each backend.
+///
+/// They are read from [`PickingBvhBackend`](crate::PickingBvhBackend) each time a cache is built,
+/// so changing them only affects meshes that are added or modified afterwards.
+#[derive(Clone, Debug, Default, Reflect)]
+#[reflect(Default, Debug)]
+pub struct BvhBuildSettings {
+    /// Build settings of the [`BvhBackend::ObvhsBvh2`](crate::BvhBackend::ObvhsBvh2) backend.
+    #[cfg(feature = "obvhs")]
+    pub obvhs: ObvhsBuildSettings,
+    /// Build settings of the [`BvhBackend::Bvh`](crate::BvhBackend::Bvh) backend.
+    #[cfg(feature = "bvh")]
+    pub bvh: BvhCrateBuildSettings,
+    /// Directory where the built caches are persisted between runs, for the meshes without a baked
+    /// cache. Disabled by default, and ignored on the web, which has no file system.
+    #[cfg(any(feature = "bvh", feature = "obvhs"))]
+    pub persistent_cache: Option<PersistentCacheSettings>,
+}
+
+/// Settings of the persistent directory of the BVH caches, see [`crate::storage::cache_dir`].
+#[cfg(any(feature = "bvh", feature = "obvhs"))]
+#[derive(Clone, Debug, Reflect)]
+#[reflect(Debug)]
+pub struct PersistentCacheSettings {
+    /// The directory, created when the first cache is written.
+    pub path: PathBuf,
+    /// Maximum size of the files of the directory, in bytes. The least recently used files are
+    /// removed above it.
+    pub max_size: u64,
+}
+
+#[cfg(any(feature = "bvh", feature = "obvhs"))]
+impl PersistentCacheSettings {
+    /// Persists the caches in the directory at `path`, up to 1 GiB.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            max_size: 1 << 30,
+        }
+    }
+
+    pub fn with_max_size(mut self, max_size: u64) -> Self {
+        self.max_size = max_size;
+        self
+    }
+}
+
+/// Build settings of the [`BvhBackend::ObvhsBvh2`](crate::BvhBackend::ObvhsBvh2) backend.
+#[cfg(feature = "obvhs")]
+#[derive(Clone, Debug, Reflect)]
+#[reflect(Default, Debug)]
+pub struct ObvhsBuildSettings {
+    /// Quality of the built tree. Higher quality trees are slower to build but faster to query.
+    pub quality: ObvhsBuildQuality,
+    /// No cache is built for meshes with less triangles than this, except for the meshes only used
+    /// in the render world, which are ray cast with their cache alone.
+    pub min_triangles: usize,
+    /// Store the UVs of the triangles in the cache, to get the UV of a hit without the mesh.
+    pub store_uvs: bool,
+    /// How the triangles are stored in the cache. The trees of the skinned and morphed mesh entities
+    /// always use the default layout, as their triangles are stored again at each refit.
+    pub layout: ObvhsCacheLayout,
+    /// Load the cache of the meshes loaded from a file from their baked `.bvh` file, when it was
+    /// built from the same mesh, instead of building it. See [`crate::storage::file`].
+    pub load_baked_caches: bool,
+    /// When the trees of the skinned mesh entities are refitted to their current pose.
+    pub skinned_meshes: DeformedBvhUpdate,
+    /// When the trees of the mesh entities with morph targets are refitted to their current weights.
+    pub morphed_meshes: DeformedBvhUpdate,
+}
+
+#[cfg(feature = "obvhs")]
+impl Default for ObvhsBuildSettings {
+    fn default() -> Self {
+        Self {
+            quality: ObvhsBuildQuality::default(),
+            min_triangles: 64,
+            store_uvs: false,
+            layout: ObvhsCacheLayout::default(),
+            load_baked_caches: false,
+            skinned_meshes: DeformedBvhUpdate::default(),
+            morphed_meshes: DeformedBvhUpdate::default(),
+        }
+    }
+}
+
+/// How the triangles of an obvhs tree are stored, trading the precision of the hits for memory.
+///
+/// The triangles are always stored in the order of the leaves of the tree, see
+/// [`CacheTriangles`](crate::obvhs::triangles::CacheTriangles).
+#[cfg(feature = "obvhs")]
+#[derive(Clone, Debug, PartialEq, Reflect)]
+#[reflect(Default, Debug)]
+pub struct ObvhsCacheLayout {
+    /// Share the vertices between the triangles, which index them, instead of storing the three
+    /// vertices of each triangle. Most meshes use each vertex in about six triangles.
+    pub indexed: bool,
+    /// Precision of the stored positions.
+    pub precision: PositionPrecision,
+    /// Store the vertex normals, to interpolate the normal of the hits. Without them, the hits have
+    /// the normal of their triangle.
+    pub normals: bool,
+}
+
+#[cfg(feature = "obvhs")]
+impl Default for ObvhsCacheLayout {
+    fn default() -> Self {
+        Self {
+            indexed: false,
+            precision: PositionPrecision::default(),
+            normals: true,
+        }
+    }
+}
+
+/// Precision of the positions stored in an obvhs tree.
+#[cfg(feature = "obvhs")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Reflect)]
+pub enum PositionPrecision {
+    /// 32-bit floats, as in the mesh.
+    #[default]
+    Full,
+    /// 16-bit integers within the bounds of the mesh, half the size. The positions are rounded to
+    /// 1/65535 of the size of the mesh on each axis, and the hits are computed on the rounded
+    /// triangles.
+    Quantized,
+}
+
+/// When the trees of the deformed mesh entities are refitted, see
+/// [`SkinnedMeshBvhs`](crate::obvhs::skinned::SkinnedMeshBvhs) for the skinned meshes and
+/// [`MorphedMeshBvhs`](crate::obvhs::morph::MorphedMeshBvhs) for the meshes with morph targets.
+#[cfg(feature = "obvhs")]
+#[derive(Clone, Debug, Default, PartialEq, Reflect)]
+pub enum DeformedBvhUpdate {
+    /// Deformed meshes are ray cast in their rest pose, as regular meshes.
+    Disabled,
+    /// The trees are refitted each frame. The trees of the morphed meshes are only refitted when
+    /// their weights change.
+    #[default]
+    EveryFrame,
+    /// The trees are only refitted when requested with
+    /// [`SkinnedMeshBvhs::request_refit`](crate::obvhs::skinned::SkinnedMeshBvhs::request_refit) or
+    /// [`MorphedMeshBvhs::request_refit`](crate::obvhs::morph::MorphedMeshBvhs::request_refit).
+    OnDemand,
+}
+
+/// The former name of [`DeformedBvhUpdate`], from when it only applied to the skinned meshes.
+#[cfg(feature = "obvhs")]
+pub type SkinnedBvhUpdate = DeformedBvhUpdate;
+
+/// Build quality preset of the obvhs `Bvh2` tree.
+#[cfg(feature = "obvhs")]
+#[derive(Clone, Debug, Default, PartialEq, Reflect)]
+pub enum ObvhsBuildQuality {
+    Fastest,
+    Fast,
+    #[default]
+    Medium,
+    Slow,
+    VerySlow,
+    /// Custom SAH parameters, applied on top of the `Medium` preset.
+    Custom(ObvhsSahParams),
+}
+
+#[cfg(feature = "obvhs")]
+impl ObvhsBuildQuality {
+    /// Returns the obvhs build parameters of this preset.
+    pub fn build_params(&self) -> BvhBuildParams {
+        match self {
+            ObvhsBuildQuality::Fastest => BvhBuildParams::fastest_build(),
+            ObvhsBuildQuality::Fast => BvhBuildParams::fast_build(),
+            ObvhsBuildQuality::Medium => BvhBuildParams::medium_build(),
+            ObvhsBuildQuality::Slow => BvhBuildParams::slow_build(),
+            ObvhsBuildQuality::VerySlow => BvhBuildParams::very_slow_build(),
+            ObvhsBuildQuality::Custom(params) => BvhBuildParams {
+                pre_split: params.pre_split,
+                search_depth_threshold: params.search_depth_threshold,
+                reinsertion_batch_ratio: params.reinsertion_batch_ratio,
+                collapse_traversal_cost: params.collapse_traversal_cost,
+                max_prims_per_leaf: params.max_prims_per_leaf,
+                ..BvhBuildParams::medium_build()
+            },
+        }
+    }
+}
+
+/// Custom SAH parameters of the obvhs `Bvh2` builder, see `obvhs::BvhBuildParams`.
+#[cfg(feature = "obvhs")]
+#[derive(Clone, Debug, PartialEq, Reflect)]
+#[reflect(Default, Debug)]
+pub struct ObvhsSahParams {
+    /// Split large triangles before building the tree.
+    pub pre_split: bool,
+    /// Below this depth, the PLOC search distance is reduced.
+    pub search_depth_threshold: usize,
+    /// Ratio of the nodes that are reinserted to optimize the tree, from `0.0` to `1.0`.
+    pub reinsertion_batch_ratio: f32,
+    /// Traversal cost used by the SAH when collapsing leaves.
+    pub collapse_traversal_cost: f32,
+    /// Maximum number of triangles in a leaf.
+    pub max_prims_per_leaf: u32,
+}
+
+#[cfg(feature = "obvhs")]
+impl Default for ObvhsSahParams {
+    fn default() -> Self {
+        let params = BvhBuildParams::medium_build();
+        Self {
+            pre_split: params.pre_split,
+            search_depth_threshold: params.search_depth_threshold,
+            reinsertion_batch_ratio: params.reinsertion_batch_ratio,
+            collapse_traversal_cost: params.collapse_traversal_cost,
+            max_prims_per_leaf: params.max_prims_per_leaf,
+        }
+    }
+}
+
+/// Build settings of the [`BvhBackend::Bvh`](crate::BvhBackend::Bvh) backend.
+///
+/// The `bvh` crate has no build quality preset, its trees always have one triangle per leaf.
+#[cfg(feature = "bvh")]
+#[derive(Clone, Debug, Default, Reflect)]
+#[reflect(Default, Debug)]
+pub struct BvhCrateBuildSettings {
+    /// No cache is built for meshes with less triangles than this, except for the meshes only used
+    /// in the render world, which are ray cast with their cache alone.
+    pub min_triangles: usize,
+    /// Store the UVs of the triangles in the cache, to get the UV of a hit without the mesh.
+    pub store_uvs: bool,
+}
+
+/// Overrides the BVH build settings for the mesh of an entity.
+///
+/// The hint applies to the mesh used for picking, that is the [`SimplifiedMesh`] if any, or else
+/// the [`Mesh3d`] or [`Mesh2d`]. When several entities share the same mesh, the hint of the first
+/// entity found is used. Adding, changing or removing a hint rebuilds the cache of its mesh.
+#[derive(Component, Clone, Debug, Default, PartialEq, Reflect)]
+#[reflect(Component, Default, Debug)]
+pub struct BvhBuildHint {
+    /// Never build a BVH cache for this mesh, it is ray cast without acceleration.
+    pub opt_out: bool,
+    /// Backend used for this mesh instead of [`PickingBvhBackend::backend`](crate::PickingBvhBackend::backend).
+    /// Only the cache of this backend is built.
+    pub backend: Option<BvhBackend>,
+    /// Build quality used for the obvhs tree of this mesh.
+    #[cfg(feature = "obvhs")]
+    pub obvhs_quality: Option<ObvhsBuildQuality>,
+}
+
+impl BvhBuildHint {
+    /// A hint to never build a BVH cache for the mesh.
+    pub fn opt_out() -> Self {
+        Self {
+            opt_out: true,
+            ..Default::default()
+        }
+    }
+
+    pub fn with_backend(mut self, backend: BvhBackend) -> Self {
+        self.backend = Some(backend);
+        self
+    }
+
+    #[cfg(feature = "obvhs")]
+    pub fn with_obvhs_quality(mut self, quality: ObvhsBuildQuality) -> Self {
+        self.obvhs_quality = Some(quality);
+        self
+    }
+
+    /// Returns `true` if the cache of the given `backend` should be built for the mesh.
+    pub fn builds(&self, backend: &BvhBackend) -> bool {
+        !self.opt_out && self.backend.as_ref().is_none_or(|b| b == backend)
+    }
+
+    /// Returns the backend to use for the mesh, given the default `backend`.
+    pub fn backend(&self, backend: &BvhBackend) -> BvhBackend {
+        if self.opt_out {
+            BvhBackend::None
+        } else {
+            self.backend.clone().unwrap_or_else(|| backend.clone())
+        }
+    }
+}
+
+/// Finds the [`BvhBuildHint`]s of the mesh assets.
+#[derive(SystemParam)]
+pub struct BvhBuildHints<'w, 's> {
+    hints: Query<
+        'w,
+        's,
+        (
+            Ref<'static, BvhBuildHint>,
+            Option<Read<Mesh2d>>,
+            Option<Read<Mesh3d>>,
+            Option<Read<SimplifiedMesh>>,
+        ),
+    >,
+    removed_hints: RemovedComponents<'w, 's, BvhBuildHint>,
+    meshes: Query<
+        'w,
+        's,
+        (
+            Option<Read<Mesh2d>>,
+            Option<Read<Mesh3d>>,
+            Option<Read<SimplifiedMesh>>,
+        ),
+    >,
+}
+
+impl<'w, 's> BvhBuildHints<'w, 's> {
+    /// Returns the hint of each mesh asset that has one.
+    ///
+    /// Build it once per run of the system, rather than looking up the hint of each mesh.
+    pub fn by_mesh(&self) -> HashMap<AssetId<Mesh>, &BvhBuildHint> {
+        let mut hints = HashMap::default();
+        for (hint, mesh2d, mesh3d, simplified_mesh) in &self.hints {
+            if let Some(id) = picked_mesh_id(mesh2d, mesh3d, simplified_mesh) {
+                hints.entry(id).or_insert(hint.into_inner());
+            }
+        }
+        hints
+    }
+
+    /// Returns the mesh assets whose hint was added, changed or removed since the last run of the
+    /// system. The meshes whose hint was removed are built with the global settings again.
+    pub fn changed(&mut self) -> Vec<AssetId<Mesh>> {
+        let mut changed = self
+            .hints
+            .iter()
+            .filter(|(hint, ..)| hint.is_changed())
+            .filter_map(|(_, mesh2d, mesh3d, simplified_mesh)| {
+                picked_mesh_id(mesh2d, mesh3d, simplified_mesh)
+            })
+            .collect::<Vec<_>>();
+        // The despawned entities have no mesh anymore
+        for entity in self.removed_hints.read() {
+            if let Ok((mesh2d, mesh3d, simplified_mesh)) = self.meshes.get(entity) {
+                changed.extend(picked_mesh_id(mesh2d, mesh3d, simplified_mesh));
+            }
+        }
+        changed
+    }
+}
+
+/// Returns the mesh used for picking, in the same order of precedence as the ray cast.
+fn picked_mesh_id(
+    mesh2d: Option<&Mesh2d>,
+    mesh3d: Option<&Mesh3d>,
+    simplified_mesh: Option<&SimplifiedMesh>,
+) -> Option<AssetId<Mesh>> {
+    simplified_mesh
+        .map(|m| m.0.id())
+        .or(mesh3d.map(|m| m.0.id()).or(mesh2d.map(|m| m.0.id())))
+}
@@ -0,0 +1,379 @@
+//! A persistent directory of BVH cache files, for the meshes that have no baked cache, as the meshes
+//! generated at runtime.
+//!
+//! Once built, the cache of a mesh is written to the directory, in a file named after the hash of
+//! the mesh and of the build settings, see [`mesh_hash`](super::file::mesh_hash). The next time a
+//! mesh with the same content is built with the same settings, even in another run, its cache is
+//! read from the file instead. The files of other versions of the format are ignored, and removed
+//! first when the directory is too large. Then the least recently used files are removed.
+//!
+//! The directory is enabled with
+//! [`BvhBuildSettings::persistent_cache`](crate::settings::BvhBuildSettings::persistent_cache). It
+//! is only available on native platforms: there is no file system on the web.
+
+use std::{
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+    process,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime},
+};
+
+use bevy_ecs::prelude::*;
+use bevy_log::prelude::*;
+
+use super::file::{
+    cache_key, BvhCacheFile, CacheBuildSettings, FileBvhCache, BVH_CACHE_FORMAT_VERSION,
+};
+use crate::settings::PersistentCacheSettings;
+
+/// Extension of the cache files, after their format version.
+const EXTENSION: &str = "bvh";
+
+/// Extension of the files being written, after the id of the writing process.
+const TEMPORARY_EXTENSION: &str = "tmp";
+
+/// Age after which a file being written is considered left over by a process that stopped.
+const STALE_TEMPORARY_FILE_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// The directory is evicted each time this fraction of its maximum size is written.
+const EVICTION_FRACTION: u64 = 16;
+
+/// A directory of BVH cache files, persisted between runs.
+///
+/// The clones of a directory share the bytes written since its last eviction, see
+/// [`PersistentBvhCacheDir`].
+#[derive(Clone, Debug)]
+pub struct BvhCacheDir {
+    path: PathBuf,
+    max_size: u64,
+    /// Bytes written since the last eviction. The directory is evicted when they reach a fraction
+    /// of its maximum size, rather than after each write.
+    written: Arc<AtomicU64>,
+}
+
+impl BvhCacheDir {
+    pub fn new(settings: &PersistentCacheSettings) -> Self {
+        Self {
+            path: settings.path.clone(),
+            max_size: settings.max_size,
+            // The first write evicts the files left by the previous runs
+            written: Arc::new(AtomicU64::new(settings.max_size)),
+        }
+    }
+
+    /// Returns the path of the file of the cache with the `key`.
+    fn file_path(&self, key: u64) -> PathBuf {
+        self.path.join(format!(
+            "{key:016x}.v{BVH_CACHE_FORMAT_VERSION}.{EXTENSION}"
+        ))
+    }
+
+    /// Returns the cache of the mesh with the `mesh_hash` built with the `build_settings`, if it is
+    /// in the directory, or else builds it with `build` and writes it to the directory.
+    pub(crate) fn load_or_build<B: FileBvhCache, E>(
+        &self,
+        mesh_hash: u64,
+        build_settings: &impl CacheBuildSettings,
+        build: impl FnOnce() -> Result<B, E>,
+    ) -> Result<B, E> {
+        let key = cache_key(mesh_hash, build_settings);
+        if let Some(cache) = self.read(key, mesh_hash, build_settings) {
+            return Ok(cache);
+        }
+        let cache = build()?;
+        let bytes = cache.to_file_bytes(mesh_hash, build_settings.settings_key());
+        match self.write(key, &bytes) {
+            Ok(()) => self.evict_if_needed(bytes.len() as u64),
+            Err(error) => warn!(
+                "Failed to write the BVH cache file {}: {}",
+                self.file_path(key).display(),
+                error
+            ),
+        }
+        Ok(cache)
+    }
+
+    /// Reads the cache with the `key`, if its file exists and was built from the mesh with the
+    /// `mesh_hash` with the `build_settings`.
+    fn read<B: FileBvhCache>(
+        &self,
+        key: u64,
+        mesh_hash: u64,
+        build_settings: &impl CacheBuildSettings,
+    ) -> Option<B> {
+        let path = self.file_path(key);
+        let bytes = fs::read(&path).ok()?;
+        let file = BvhCacheFile::from_bytes(&bytes)
+            .inspect_err(|error| warn!("Ignoring the BVH cache file {}: {}", path.display(), error))
+            .ok()?;
+        if !file.matches(mesh_hash, build_settings) {
+            return None;
+        }
+        // The modification time of the files orders them for the eviction
+        if let Err(error) = touch(&path) {
+            debug!(
+                "Failed to touch the BVH cache file {}: {}",
+                path.display(),
+                error
+            );
+        }
+        B::from_file(file)
+    }
+
+    /// Writes the file of the cache with the `key`.
+    fn write(&self, key: u64, bytes: &[u8]) -> io::Result<()> {
+        fs::create_dir_all(&self.path)?;
+        // The file is written aside then moved, so that other processes never read a partial file
+        let path = self.file_path(key);
+        let temporary_path =
+            path.with_extension(format!("{}.{TEMPORARY_EXTENSION}", process::id()));
+        let result =
+            fs::write(&temporary_path, bytes).and_then(|()| fs::rename(&temporary_path, &path));
+        if result.is_err() {
+            let _ = fs::remove_file(&temporary_path);
+        }
+        result
+    }
+
+    /// Counts the `written` bytes, and evicts the directory once enough bytes were written since
+    /// its last eviction.
+    fn evict_if_needed(&self, written: u64) {
+        let threshold = self.max_size / EVICTION_FRACTION;
+        let previous = self
+            .written
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |total| {
+                Some(match total.saturating_add(written) {
+                    total if total >= threshold => 0,
+                    total => total,
+                })
+            })
+            .unwrap_or_default();
+        if previous.saturating_add(written) < threshold {
+            return;
+        }
+        if let Err(error) = self.evict() {
+            warn!(
+                "Failed to evict the BVH cache directory {}: {}",
+                self.path.display(),
+                error
+            );
+        }
+    }
+
+    /// Removes the files of other format versions, then the least recently used files, until the
+    /// files of the directory fit in its maximum size. The files being written by a process that
+    /// stopped are removed too.
+    ///
+    /// The files removed meanwhile, as by another process, are skipped. Fails only if the directory
+    /// can't be read, the files that can't be removed are logged.
+    pub fn evict(&self) -> io::Result<()> {
+        let now = SystemTime::now();
+        let mut files = Vec::new();
+        for entry in fs::read_dir(&self.path)? {
+            let Ok(entry) = entry else {
+                continue;
+            };
+            let path = entry.path();
+            let Ok(metadata) = entry.metadata() else {
+                continue;
+            };
+            let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+            match path.extension() {
+                Some(extension) if extension == EXTENSION => {
+                    files.push((is_current_version(&path), modified, metadata.len(), path));
+                }
+                Some(extension) if extension == TEMPORARY_EXTENSION => {
+                    let age = now.duration_since(modified).unwrap_or_default();
+                    if age > STALE_TEMPORARY_FILE_AGE {
+                        remove_file(&path);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        // The stale files first, then the least recently used
+        files.sort_by_key(|(current, modified, ..)| (*current, *modified));
+        let mut size = files.iter().map(|(_, _, len, _)| len).sum::<u64>();
+        for (current, _, len, path) in files {
+            if current && size <= self.max_size {
+                break;
+            }
+            if remove_file(&path) {
+                size -= len;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The persistent directory of the BVH caches of the
+/// [`BvhBuildSettings::persistent_cache`](crate::settings::BvhBuildSettings::persistent_cache)
+/// settings, shared by the builds of all the backends.
+#[derive(Resource, Default)]
+pub struct PersistentBvhCacheDir(Option<BvhCacheDir>);
+
+impl PersistentBvhCacheDir {
+    /// Returns the directory of the `settings`, reopened when they change. Returns `None` on the
+    /// web, which has no file system.
+    pub fn get(&mut self, settings: Option<&PersistentCacheSettings>) -> Option<BvhCacheDir> {
+        let Some(settings) = settings.filter(|_| cfg!(not(target_arch = "wasm32"))) else {
+            self.0 = None;
+            return None;
+        };
+        if !self.0.as_ref().is_some_and(|cache_dir| {
+            cache_dir.path == settings.path && cache_dir.max_size == settings.max_size
+        }) {
+            self.0 = Some(BvhCacheDir::new(settings));
+        }
+        self.0.clone()
+    }
+}
+
+/// Returns `true` if the file at `path` is of the current format version, from its name.
+fn is_current_version(path: &Path) -> bool {
+    path.file_stem()
+        .and_then(|stem| Path::new(stem).extension())
+        .is_some_and(|version| version == format!("v{BVH_CACHE_FORMAT_VERSION}").as_str())
+}
+
+/// Removes the file at `path`, and returns `true` if it does not exist anymore.
+fn remove_file(path: &Path) -> bool {
+    match fs::remove_file(path) {
+        Ok(()) => true,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => true,
+        Err(error) => {
+            warn!(
+                "Failed to remove the BVH cache file {}: {}",
+                path.display(),
+                error
+            );
+            false
+        }
+    }
+}
+
+/// Sets the modification time of the file at `path` to now.
+fn touch(path: &Path) -> io::Result<()> {
+    File::options()
+        .write(true)
+        .open(path)?
+        .set_modified(SystemTime::now())
+}
+
+#[cfg(test)]
+#[cfg(feature = "obvhs")]
+mod tests {
+    use bevy_math::prelude::*;
+
+    use super::*;
+    use crate::{
+        obvhs::{build_bvh2_cache, ObvhsBvh2Cache},
+        settings::ObvhsBuildSettings,
+        storage::{file::mesh_hash, AssetBvhCache},
+        BvhCacheBuildError,
+    };
+
+    #[test]
+    fn persistent_caches() {
+        let path = std::env::temp_dir().join(format!("bvh_cache_dir_test_{}", process::id()));
+        let _ = fs::remove_dir_all(&path);
+        let cache_dir = BvhCacheDir::new(&PersistentCacheSettings::new(&path));
+
+        let mesh = Sphere::new(1.0).mesh().ico(3).unwrap();
+        let build_settings = ObvhsBuildSettings::default();
+        let build = || build_bvh2_cache(&mesh, &build_settings);
+        let built: ObvhsBvh2Cache = cache_dir
+            .load_or_build(mesh_hash(&mesh), &build_settings, build)
+            .unwrap();
+        let loaded: ObvhsBvh2Cache = cache_dir
+            .load_or_build(mesh_hash(&mesh), &build_settings, || {
+                Err::<_, BvhCacheBuildError>(BvhCacheBuildError::NotEnoughTriangles(0))
+            })
+            .unwrap();
+        assert_eq!(loaded.triangle_count(), built.triangle_count());
+
+        // The directory is not evicted again after a small write
+        let stale_path = path.join(format!("0.v0.{EXTENSION}"));
+        fs::write(&stale_path, b"stale").unwrap();
+        let other_mesh = Sphere::new(1.0).mesh().ico(2).unwrap();
+        let _: ObvhsBvh2Cache = cache_dir
+            .load_or_build(mesh_hash(&other_mesh), &build_settings, || {
+                build_bvh2_cache(&other_mesh, &build_settings)
+            })
+            .unwrap();
+        assert!(stale_path.exists());
+
+        // The files being written are only removed once they are left over
+        let written_path = path.join(format!(
+            "1.v{BVH_CACHE_FORMAT_VERSION}.1.{TEMPORARY_EXTENSION}"
+        ));
+        let left_over_path = path.join(format!(
+            "2.v{BVH_CACHE_FORMAT_VERSION}.2.{TEMPORARY_EXTENSION}"
+        ));
+        fs::write(&written_path, b"written").unwrap();
+        fs::write(&left_over_path, b"left over").unwrap();
+        File::options()
+            .write(true)
+            .open(&left_over_path)
+            .unwrap()
+            .set_modified(SystemTime::now() - STALE_TEMPORARY_FILE_AGE * 2)
+            .unwrap();
+        cache_dir.evict().unwrap();
+        assert!(written_path.exists());
+        assert!(!left_over_path.exists());
+        fs::remove_file(&written_path).unwrap();
+
+        // A stale file is evicted first, then the oldest ones
+        let small_dir = BvhCacheDir {
+            max_size: 0,
+            ..cache_dir.clone()
+        };
+        small_dir.evict().unwrap();
+        assert!(!stale_path.exists());
+        assert_eq!(fs::read_dir(&path).unwrap().count(), 0);
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn cyclic_tree_files_are_rebuilt() {
+        let path = std::env::temp_dir().join(format!("bvh_cache_dir_cyclic_{}", process::id()));
+        let _ = fs::remove_dir_all(&path);
+        let cache_dir = BvhCacheDir::new(&PersistentCacheSettings::new(&path));
+
+        // A file whose root is its own child
+        let mesh = Sphere::new(1.0).mesh().ico(3).unwrap();
+        let build_settings = ObvhsBuildSettings::default();
+        let mut cyclic = build_bvh2_cache(&mesh, &build_settings).unwrap();
+        cyclic.bvh.nodes[0].first_index = 0;
+        let key = cache_key(mesh_hash(&mesh), &build_settings);
+        fs::create_dir_all(&path).unwrap();
+        fs::write(
+            cache_dir.file_path(key),
+            cyclic.to_file_bytes(mesh_hash(&mesh), build_settings.settings_key()),
+        )
+        .unwrap();
+
+        let mut built = false;
+        let cache: ObvhsBvh2Cache = cache_dir
+            .load_or_build(mesh_hash(&mesh), &build_settings, || {
+                built = true;
+                build_bvh2_cache(&mesh, &build_settings)
+            })
+            .unwrap();
+        assert!(built);
+        assert_ne!(cache.bvh.nodes[0].first_index, 0);
+
+        // The file is replaced by the built cache
+        let bytes = fs::read(cache_dir.file_path(key)).unwrap();
+        assert!(BvhCacheFile::from_bytes(&bytes).is_ok());
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+}
@@ -0,0 +1,892 @@
+//! BVH caches baked to `.bvh` files, so that they are loaded instead of being rebuilt.
+//!
+//! A file stores one cache, with the hash of the mesh it was built from and of its build settings: a
+//! cache is only used for a mesh with the same hash, built with the same settings, see [`mesh_hash`]
+//! and [`BvhCacheFile::settings_key`].
+//!
+//! The baked file of a mesh is next to the file it is loaded from, see [`baked_bvh_cache_path`].
+//! [`compute_obvhs_bvh2_cache_assets`](crate::obvhs::compute_obvhs_bvh2_cache_assets) reads it when
+//! [`ObvhsBuildSettings::load_baked_caches`](crate::settings::ObvhsBuildSettings::load_baked_caches)
+//! is set.
+//!
+//! The files are baked ahead of time, either:
+//! - by the asset processor, with the `asset_processor` feature: the plugin registers
+//!   [`BakeBvhCacheFile`] as the default processor of the `.bvh` files. A source `.bvh` file at the
+//!   [`baked_bvh_cache_path`] of a mesh contains the asset path of the mesh, and is processed into
+//!   its baked cache, see [`BvhBakeRequestLoader`].
+//! - by a tool that loads the meshes, builds their caches with [`BvhCacheFile::bake_obvhs_bvh2`] or
+//!   [`BvhCacheFile::bake_bvh`], and writes them with [`BvhCacheFile::to_bytes`] at their
+//!   [`baked_bvh_cache_path`], as in the `bake_bvh_caches` example.
+//!
+//! The [`BvhCacheFileLoader`] is registered, to load the baked files as assets.
+
+use core::fmt;
+use std::{error::Error, path::PathBuf};
+
+use bevy_asset::{
+    io::{Reader, Writer},
+    saver::{AssetSaver, SavedAsset},
+    Asset, AssetLoader, AssetPath, AssetServer, AssetServerMode, LoadContext,
+};
+#[cfg(all(feature = "asset_processor", feature = "obvhs"))]
+use bevy_asset::{processor::LoadAndSave, LoadDirectError};
+use bevy_log::prelude::*;
+use bevy_math::{Vec2, Vec3};
+use bevy_reflect::TypePath;
+use bevy_render::mesh::{Mesh, VertexAttributeValues};
+use futures_lite::AsyncWriteExt;
+
+use crate::BvhCacheBuildError;
+#[cfg(feature = "bvh")]
+use crate::{bvh::BvhCache, settings::BvhCrateBuildSettings};
+#[cfg(feature = "obvhs")]
+use crate::{
+    obvhs::ObvhsBvh2Cache,
+    settings::{ObvhsBuildQuality, ObvhsBuildSettings},
+};
+
+/// First bytes of the BVH cache files.
+const MAGIC: [u8; 4] = *b"BVHC";
+
+/// Version of the format of the BVH cache files, incremented when it changes. The files of other
+/// versions are not loaded.
+pub const BVH_CACHE_FORMAT_VERSION: u32 = 1;
+
+/// A BVH cache baked to a file, with the hash of the mesh it was built from.
+#[derive(Asset, TypePath)]
+pub struct BvhCacheFile {
+    /// Hash of the mesh the cache was built from, see [`mesh_hash`].
+    pub mesh_hash: u64,
+    /// Hash of the settings the cache was built with, see [`CacheBuildSettings::settings_key`].
+    pub settings_key: u64,
+    pub cache: BakedBvhCache,
+}
+
+/// The cache of a [`BvhCacheFile`], for one of the backends.
+pub enum BakedBvhCache {
+    #[cfg(feature = "obvhs")]
+    ObvhsBvh2(ObvhsBvh2Cache),
+    #[cfg(feature = "bvh")]
+    Bvh(BvhCache),
+}
+
+impl BvhCacheFile {
+    /// Builds the [`BvhBackend::ObvhsBvh2`](crate::BvhBackend::ObvhsBvh2) cache of a `mesh`, to
+    /// bake it.
+    #[cfg(feature = "obvhs")]
+    pub fn bake_obvhs_bvh2(
+        mesh: &Mesh,
+        build_settings: &ObvhsBuildSettings,
+    ) -> Result<Self, BvhCacheBuildError> {
+        Ok(Self {
+            mesh_hash: mesh_hash(mesh),
+            settings_key: build_settings.settings_key(),
+            cache: BakedBvhCache::ObvhsBvh2(crate::obvhs::build_bvh2_cache(mesh, build_settings)?),
+        })
+    }
+
+    /// Builds the [`BvhBackend::Bvh`](crate::BvhBackend::Bvh) cache of a `mesh`, to bake it.
+    #[cfg(feature = "bvh")]
+    pub fn bake_bvh(
+        mesh: &Mesh,
+        build_settings: &BvhCrateBuildSettings,
+    ) -> Result<Self, BvhCacheBuildError> {
+        Ok(Self {
+            mesh_hash: mesh_hash(mesh),
+            settings_key: build_settings.settings_key(),
+            cache: BakedBvhCache::Bvh(crate::bvh::build_bvh_cache(mesh, build_settings)?),
+        })
+    }
+
+    /// Writes the file.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match &self.cache {
+            #[cfg(feature = "obvhs")]
+            BakedBvhCache::ObvhsBvh2(cache) => {
+                cache.to_file_bytes(self.mesh_hash, self.settings_key)
+            }
+            #[cfg(feature = "bvh")]
+            BakedBvhCache::Bvh(cache) => cache.to_file_bytes(self.mesh_hash, self.settings_key),
+        }
+    }
+
+    /// Reads a file written by [`BvhCacheFile::to_bytes`].
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, BvhCacheFileError> {
+        let mut reader = CacheReader { bytes };
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err(BvhCacheFileError::InvalidHeader);
+        }
+        let version = reader.u32()?;
+        if version != BVH_CACHE_FORMAT_VERSION {
+            return Err(BvhCacheFileError::UnsupportedVersion(version));
+        }
+        let backend_tag = reader.u8()?;
+        let mesh_hash = reader.u64()?;
+        let settings_key = reader.u64()?;
+        let cache = match backend_tag {
+            #[cfg(feature = "obvhs")]
+            ObvhsBvh2Cache::BACKEND_TAG => {
+                BakedBvhCache::ObvhsBvh2(ObvhsBvh2Cache::read(&mut reader)?)
+            }
+            #[cfg(feature = "bvh")]
+            BvhCache::BACKEND_TAG => BakedBvhCache::Bvh(BvhCache::read(&mut reader)?),
+            _ => return Err(BvhCacheFileError::UnsupportedBackend(backend_tag)),
+        };
+        if !reader.bytes.is_empty() {
+            return Err(BvhCacheFileError::Corrupted);
+        }
+        Ok(Self {
+            mesh_hash,
+            settings_key,
+            cache,
+        })
+    }
+
+    /// Returns `true` if the cache was built from the mesh with the `mesh_hash`, with the
+    /// `build_settings`.
+    pub(crate) fn matches(&self, mesh_hash: u64, build_settings: &impl CacheBuildSettings) -> bool {
+        self.mesh_hash == mesh_hash && self.settings_key == build_settings.settings_key()
+    }
+}
+
+/// The build settings of the caches stored in the files.
+pub trait CacheBuildSettings {
+    /// Returns a hash of the settings that change the built cache, stable across runs and
+    /// platforms. The files of the caches built with other settings are not used.
+    fn settings_key(&self) -> u64;
+}
+
+#[cfg(feature = "obvhs")]
+impl CacheBuildSettings for ObvhsBuildSettings {
+    fn settings_key(&self) -> u64 {
+        // The minimum number of triangles only decides if a cache is built, and the other settings
+        // are not about the cache of the mesh
+        let mut hasher = StableHasher::default();
+        match &self.quality {
+            ObvhsBuildQuality::Fastest => hasher.write(&[0]),
+            ObvhsBuildQuality::Fast => hasher.write(&[1]),
+            ObvhsBuildQuality::Medium => hasher.write(&[2]),
+            ObvhsBuildQuality::Slow => hasher.write(&[3]),
+            ObvhsBuildQuality::VerySlow => hasher.write(&[4]),
+            ObvhsBuildQuality::Custom(params) => {
+                hasher.write(&[5, params.pre_split as u8]);
+                hasher.write(&(params.search_depth_threshold as u64).to_le_bytes());
+                hasher.write(&params.reinsertion_batch_ratio.to_le_bytes());
+                hasher.write(&params.collapse_traversal_cost.to_le_bytes());
+                hasher.write(&params.max_prims_per_leaf.to_le_bytes());
+            }
+        }
+        hasher.write(&[
+            self.store_uvs as u8,
+            self.layout.indexed as u8,
+            self.layout.precision as u8,
+            self.layout.normals as u8,
+        ]);
+        hasher.0
+    }
+}
+
+#[cfg(feature = "bvh")]
+impl CacheBuildSettings for BvhCrateBuildSettings {
+    fn settings_key(&self) -> u64 {
+        let mut hasher = StableHasher::default();
+        hasher.write(&[self.store_uvs as u8]);
+        hasher.0
+    }
+}
+
+/// The caches that can be stored in a [`BvhCacheFile`].
+pub(crate) trait FileBvhCache: Sized {
+    /// Tag of the backend of the cache in the files.
+    const BACKEND_TAG: u8;
+
+    fn write(&self, writer: &mut CacheWriter);
+
+    /// Returns the cache of a `file`, if it is of this type.
+    fn from_file(file: BvhCacheFile) -> Option<Self>;
+
+    /// Writes the file of the cache, built from the mesh with the `mesh_hash` with the settings
+    /// with the `settings_key`.
+    fn to_file_bytes(&self, mesh_hash: u64, settings_key: u64) -> Vec<u8> {
+        let mut writer = CacheWriter::default();
+        writer.bytes(&MAGIC);
+        writer.u32(BVH_CACHE_FORMAT_VERSION);
+        writer.u8(Self::BACKEND_TAG);
+        writer.u64(mesh_hash);
+        writer.u64(settings_key);
+        self.write(&mut writer);
+        writer.bytes
+    }
+}
+
+#[cfg(feature = "obvhs")]
+impl FileBvhCache for ObvhsBvh2Cache {
+    const BACKEND_TAG: u8 = 0;
+
+    fn write(&self, writer: &mut CacheWriter) {
+        ObvhsBvh2Cache::write(self, writer);
+    }
+
+    fn from_file(file: BvhCacheFile) -> Option<Self> {
+        match file.cache {
+            BakedBvhCache::ObvhsBvh2(cache) => Some(cache),
+            #[allow(unreachable_patterns)]
+            _ => None,
+        }
+    }
+}
+
+#[cfg(feature = "bvh")]
+impl FileBvhCache for BvhCache {
+    const BACKEND_TAG: u8 = 1;
+
+    fn write(&self, writer: &mut CacheWriter) {
+        BvhCache::write(self, writer);
+    }
+
+    fn from_file(file: BvhCacheFile) -> Option<Self> {
+        match file.cache {
+            BakedBvhCache::Bvh(cache) => Some(cache),
+            #[allow(unreachable_patterns)]
+            _ => None,
+        }
+    }
+}
+
+/// Reason why a BVH cache file could not be read.
+#[derive(Debug)]
+pub enum BvhCacheFileError {
+    Io(std::io::Error),
+    /// The file is not a BVH cache file.
+    InvalidHeader,
+    /// The file was written with another version of the format, see [`BVH_CACHE_FORMAT_VERSION`].
+    UnsupportedVersion(u32),
+    /// The cache is for a backend whose feature is not enabled.
+    UnsupportedBackend(u8),
+    /// The file is truncated, or its content is inconsistent.
+    Corrupted,
+}
+
+impl fmt::Display for BvhCacheFileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BvhCacheFileError::Io(error) => write!(f, "failed to read the BVH cache file: {error}"),
+            BvhCacheFileError::InvalidHeader => write!(f, "not a BVH cache file"),
+            BvhCacheFileError::UnsupportedVersion(version) => write!(
+                f,
+                "unsupported BVH cache file version {version}, expected {BVH_CACHE_FORMAT_VERSION}"
+            ),
+            BvhCacheFileError::UnsupportedBackend(tag) => {
+                write!(f, "unsupported backend {tag} in BVH cache file")
+            }
+            BvhCacheFileError::Corrupted => write!(f, "corrupted BVH cache file"),
+        }
+    }
+}
+
+impl Error for BvhCacheFileError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            BvhCacheFileError::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for BvhCacheFileError {
+    fn from(error: std::io::Error) -> Self {
+        BvhCacheFileError::Io(error)
+    }
+}
+
+/// Loads the `.bvh` files as [`BvhCacheFile`] assets.
+#[derive(Default)]
+pub struct BvhCacheFileLoader;
+
+impl AssetLoader for BvhCacheFileLoader {
+    type Asset = BvhCacheFile;
+    type Settings = ();
+    type Error = BvhCacheFileError;
+
+    async fn load(
+        &self,
+        reader: &mut dyn Reader,
+        _settings: &(),
+        _load_context: &mut LoadContext<'_>,
+    ) -> Result<BvhCacheFile, BvhCacheFileError> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).await?;
+        BvhCacheFile::from_bytes(&bytes)
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["bvh"]
+    }
+}
+
+/// Saves the [`BvhCacheFile`] assets to `.bvh` files, for the asset processor.
+#[derive(Default)]
+pub struct BvhCacheFileSaver;
+
+impl AssetSaver for BvhCacheFileSaver {
+    type Asset = BvhCacheFile;
+    type Settings = ();
+    type OutputLoader = BvhCacheFileLoader;
+    type Error = BvhCacheFileError;
+
+    async fn save(
+        &self,
+        writer: &mut Writer,
+        asset: SavedAsset<'_, BvhCacheFile>,
+        _settings: &(),
+    ) -> Result<(), BvhCacheFileError> {
+        writer.write_all(&asset.to_bytes()).await?;
+        Ok(())
+    }
+}
+
+/// Bakes the `.bvh` source files into the [`BvhBackend::ObvhsBvh2`](crate::BvhBackend::ObvhsBvh2)
+/// caches of their meshes, with the asset processor.
+///
+/// The plugin registers it as the default processor of the `.bvh` files, with the build settings of
+/// the plugin, so that the baked caches are used by the processed app.
+#[cfg(all(feature = "asset_processor", feature = "obvhs"))]
+pub type BakeBvhCacheFile = LoadAndSave<BvhBakeRequestLoader, BvhCacheFileSaver>;
+
+/// Loads the `.bvh` source files of the asset processor as the [`BvhCacheFile`] of the mesh they
+/// request, see [`BakeBvhCacheFile`].
+///
+/// A request contains the asset path of the mesh, as `models/plant.glb#Mesh0/Primitive0`, and is
+/// placed at the [`baked_bvh_cache_path`] of the mesh. The files that are already baked are loaded
+/// as they are.
+#[cfg(all(feature = "asset_processor", feature = "obvhs"))]
+pub struct BvhBakeRequestLoader {
+    /// The settings of the baked caches, which must be those of the app for its caches to be used.
+    pub build_settings: ObvhsBuildSettings,
+}
+
+#[cfg(all(feature = "asset_processor", feature = "obvhs"))]
+impl AssetLoader for BvhBakeRequestLoader {
+    type Asset = BvhCacheFile;
+    type Settings = ();
+    type Error = BvhCacheBakeError;
+
+    async fn load(
+        &self,
+        reader: &mut dyn Reader,
+        _settings: &(),
+        load_context: &mut LoadContext<'_>,
+    ) -> Result<BvhCacheFile, BvhCacheBakeError> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).await?;
+        if bytes.starts_with(&MAGIC) {
+            return BvhCacheFile::from_bytes(&bytes).map_err(BvhCacheBakeError::File);
+        }
+
+        let mesh_path =
+            std::str::from_utf8(&bytes).map_err(|_| BvhCacheBakeError::InvalidRequest)?;
+        let mesh_path = AssetPath::try_parse(mesh_path.trim())
+            .map_err(|_| BvhCacheBakeError::InvalidRequest)?
+            .into_owned();
+        // The meshes are sub-assets of their file, which is loaded whole
+        let loaded_file = load_context
+            .loader()
+            .immediate()
+            .with_unknown_type()
+            .load(mesh_path.without_label())
+            .await
+            .map_err(BvhCacheBakeError::LoadMesh)?;
+        let mesh = match mesh_path.label() {
+            Some(label) => loaded_file
+                .get_labeled(label.to_owned())
+                .and_then(|loaded_mesh| loaded_mesh.get::<Mesh>()),
+            None => loaded_file.get::<Mesh>(),
+        }
+        .ok_or_else(|| BvhCacheBakeError::MissingMesh(mesh_path.clone()))?;
+        BvhCacheFile::bake_obvhs_bvh2(mesh, &self.build_settings).map_err(BvhCacheBakeError::Build)
+    }
+
+    fn extensions(&self) -> &[&str] {
+        // Only used by the processor, the `.bvh` files are loaded by the `BvhCacheFileLoader`
+        &[]
+    }
+}
+
+/// Reason why a `.bvh` source file could not be baked by [`BakeBvhCacheFile`].
+#[cfg(all(feature = "asset_processor", feature = "obvhs"))]
+#[derive(Debug)]
+pub enum BvhCacheBakeError {
+    Io(std::io::Error),
+    /// The file is not the asset path of a mesh.
+    InvalidRequest,
+    /// The file of the requested mesh could not be loaded.
+    LoadMesh(LoadDirectError),
+    /// The file of the requested mesh has no mesh at its label.
+    MissingMesh(AssetPath<'static>),
+    /// The cache of the requested mesh could not be built.
+    Build(BvhCacheBuildError),
+    /// The file is already baked, but can't be read.
+    File(BvhCacheFileError),
+}
+
+#[cfg(all(feature = "asset_processor", feature = "obvhs"))]
+impl fmt::Display for BvhCacheBakeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BvhCacheBakeError::Io(error) => write!(f, "failed to read the bake request: {error}"),
+            BvhCacheBakeError::InvalidRequest => {
+                write!(f, "the bake request is not the asset path of a mesh")
+            }
+            BvhCacheBakeError::LoadMesh(error) => write!(f, "failed to load the mesh: {error}"),
+            BvhCacheBakeError::MissingMesh(path) => write!(f, "no mesh at {path}"),
+            BvhCacheBakeError::Build(error) => {
+                write!(f, "failed to build the cache of the mesh: {error:?}")
+            }
+            BvhCacheBakeError::File(error) => write!(f, "{error}"),
+        }
+    }
+}
+
+#[cfg(all(feature = "asset_processor", feature = "obvhs"))]
+impl Error for BvhCacheBakeError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            BvhCacheBakeError::Io(error) => Some(error),
+            BvhCacheBakeError::LoadMesh(error) => Some(error),
+            BvhCacheBakeError::File(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(all(feature = "asset_processor", feature = "obvhs"))]
+impl From<std::io::Error> for BvhCacheBakeError {
+    fn from(error: std::io::Error) -> Self {
+        BvhCacheBakeError::Io(error)
+    }
+}
+
+/// Returns the path of the baked BVH cache of the mesh at `mesh_path`, next to the file of the mesh.
+///
+/// The label of the mesh in its file is part of the name, so that each mesh of a glTF file has its
+/// own cache: the cache of `models/plant.glb#Mesh0/Primitive0` is
+/// `models/plant.glb.Mesh0.Primitive0.bvh`.
+pub fn baked_bvh_cache_path(mesh_path: &AssetPath) -> AssetPath<'static> {
+    let mut file_name = mesh_path
+        .path()
+        .file_name()
+        .map(|file_name| file_name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    if let Some(label) = mesh_path.label() {
+        file_name.push('.');
+        file_name.push_str(&label.replace('/', "."));
+    }
+    file_name.push_str(".bvh");
+    let path: PathBuf = mesh_path.path().with_file_name(file_name);
+    AssetPath::from(path).with_source(mesh_path.source().clone_owned())
+}
+
+/// Reads the baked BVH cache file at `path` from the asset sources, without loading it as an
+/// asset. Returns `None` if there is no such file, if it can't be read, or if it was not built from
+/// the mesh with the `mesh_hash` with the `build_settings`.
+pub(crate) async fn read_baked_bvh_cache(
+    asset_server: &AssetServer,
+    path: &AssetPath<'_>,
+    mesh_hash: u64,
+    build_settings: &impl CacheBuildSettings,
+) -> Option<BvhCacheFile> {
+    let source = asset_server.get_source(path.source().clone_owned()).ok()?;
+    let asset_reader = match asset_server.mode() {
+        AssetServerMode::Processed => source.processed_reader().ok()?,
+        AssetServerMode::Unprocessed => source.reader(),
+    };
+    let mut reader = asset_reader.read(path.path()).await.ok()?;
+    let mut bytes = Vec::new();
+    reader.read_to_end(&mut bytes).await.ok()?;
+    let file = BvhCacheFile::from_bytes(&bytes)
+        .inspect_err(|error| warn!("Ignoring the BVH cache file {}: {}", path, error))
+        .ok()?;
+    if !file.matches(mesh_hash, build_settings) {
+        warn!(
+            "Ignoring the BVH cache file {}: it was built from another mesh or with other settings",
+            path
+        );
+        return None;
+    }
+    Some(file)
+}
+
+/// Returns a hash of the data of a mesh used by its BVH caches: its topology, positions, normals,
+/// UVs and indices.
+///
+/// The hash is stable across runs and platforms, so that it can be stored in the baked files.
+pub fn mesh_hash(mesh: &Mesh) -> u64 {
+    let mut hasher = StableHasher::default();
+    hasher.write(&[mesh.primitive_topology() as u8]);
+    for attribute in [
+        Mesh::ATTRIBUTE_POSITION,
+        Mesh::ATTRIBUTE_NORMAL,
+        Mesh::ATTRIBUTE_UV_0,
+    ] {
+        match mesh.attribute(attribute) {
+            Some(values) => {
+                hasher.write(&[1]);
+                hasher.write(&(values.len() as u64).to_le_bytes());
+                hash_vertex_attribute(&mut hasher, values);
+            }
+            None => hasher.write(&[0]),
+        }
+    }
+    match mesh.indices() {
+        Some(indices) => {
+            hasher.write(&[1]);
+            hasher.write(&(indices.len() as u64).to_le_bytes());
+            for index in indices.iter() {
+                hasher.write(&(index as u32).to_le_bytes());
+            }
+        }
+        None => hasher.write(&[0]),
+    }
+    hasher.0
+}
+
+/// Returns a hash of the mesh with the `mesh_hash` and of the `build_settings` of its cache,
+/// stable across runs, to name the files of the caches built with these settings.
+pub(crate) fn cache_key(mesh_hash: u64, build_settings: &impl CacheBuildSettings) -> u64 {
+    let mut hasher = StableHasher::default();
+    hasher.write(&mesh_hash.to_le_bytes());
+    hasher.write(&build_settings.settings_key().to_le_bytes());
+    hasher.0
+}
+
+fn hash_vertex_attribute(hasher: &mut StableHasher, values: &VertexAttributeValues) {
+    // Each component is hashed in little endian, as in the baked files, whatever the platform
+    macro_rules! hash_components {
+        ([$($scalar:ident),*], [$($array:ident),*]) => {
+            match values {
+                $(VertexAttributeValues::$scalar(values) => {
+                    for value in values {
+                        hasher.write(&value.to_le_bytes());
+                    }
+                })*
+                $(VertexAttributeValues::$array(values) => {
+                    for value in values.iter().flatten() {
+                        hasher.write(&value.to_le_bytes());
+                    }
+                })*
+            }
+        };
+    }
+    hash_components!(
+        [Float32, Sint32, Uint32],
+        [
+            Float32x2, Sint32x2, Uint32x2, Float32x3, Sint32x3, Uint32x3, Float32x4, Sint32x4,
+            Uint32x4, Sint16x2, Snorm16x2, Uint16x2, Unorm16x2, Sint16x4, Snorm16x4, Uint16x4,
+            Unorm16x4, Sint8x2, Snorm8x2, Uint8x2, Unorm8x2, Sint8x4, Snorm8x4, Uint8x4, Unorm8x4
+        ]
+    );
+}
+
+/// FNV-1a, whose result does not depend on the platform or on the version of Rust.
+struct StableHasher(u64);
+
+impl Default for StableHasher {
+    fn default() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl StableHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 ^ *byte as u64).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+}
+
+/// Writes the little endian binary data of the BVH cache files.
+#[derive(Default)]
+pub(crate) struct CacheWriter {
+    bytes: Vec<u8>,
+}
+
+impl CacheWriter {
+    pub fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.bytes.extend(value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.bytes.extend(value.to_le_bytes());
+    }
+
+    pub fn f32(&mut self, value: f32) {
+        self.bytes.extend(value.to_le_bytes());
+    }
+
+    pub fn vec2(&mut self, value: Vec2) {
+        self.f32(value.x);
+        self.f32(value.y);
+    }
+
+    pub fn vec3(&mut self, value: Vec3) {
+        self.f32(value.x);
+        self.f32(value.y);
+        self.f32(value.z);
+    }
+
+    /// Writes the length of a sequence before its items, or an index.
+    pub fn len(&mut self, len: usize) {
+        self.u64(len as u64);
+    }
+
+    /// Writes the `items`, after their length.
+    pub fn slice<T>(&mut self, items: &[T], mut write: impl FnMut(&mut Self, &T)) {
+        self.len(items.len());
+        for item in items {
+            write(self, item);
+        }
+    }
+
+    /// Writes an optional value, after a flag telling if it is present.
+    pub fn option<T>(&mut self, value: Option<&T>, write: impl FnOnce(&mut Self, &T)) {
+        self.bool(value.is_some());
+        if let Some(value) = value {
+            write(self, value);
+        }
+    }
+}
+
+/// Reads the data written by a [`CacheWriter`].
+pub(crate) struct CacheReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl CacheReader<'_> {
+    fn take(&mut self, len: usize) -> Result<&[u8], BvhCacheFileError> {
+        if len > self.bytes.len() {
+            return Err(BvhCacheFileError::Corrupted);
+        }
+        let (bytes, remaining) = self.bytes.split_at(len);
+        self.bytes = remaining;
+        Ok(bytes)
+    }
+
+    pub fn array<const N: usize>(&mut self) -> Result<[u8; N], BvhCacheFileError> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    pub fn u8(&mut self) -> Result<u8, BvhCacheFileError> {
+        Ok(self.array::<1>()?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, BvhCacheFileError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(BvhCacheFileError::Corrupted),
+        }
+    }
+
+    pub fn u32(&mut self) -> Result<u32, BvhCacheFileError> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, BvhCacheFileError> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    pub fn f32(&mut self) -> Result<f32, BvhCacheFileError> {
+        Ok(f32::from_le_bytes(self.array()?))
+    }
+
+    pub fn vec2(&mut self) -> Result<Vec2, BvhCacheFileError> {
+        Ok(Vec2::new(self.f32()?, self.f32()?))
+    }
+
+    pub fn vec3(&mut self) -> Result<Vec3, BvhCacheFileError> {
+        Ok(Vec3::new(self.f32()?, self.f32()?, self.f32()?))
+    }
+
+    /// Reads the length of a sequence. Every item takes at least a byte, so a length longer than
+    /// the remaining bytes is corrupted, and never allocated.
+    pub fn len(&mut self) -> Result<usize, BvhCacheFileError> {
+        let len = self.u64()?;
+        if len > self.bytes.len() as u64 {
+            return Err(BvhCacheFileError::Corrupted);
+        }
+        Ok(len as usize)
+    }
+
+    /// Reads an index or a length written by [`CacheWriter::len`], which must fit in a `usize`.
+    pub fn index(&mut self) -> Result<usize, BvhCacheFileError> {
+        usize::try_from(self.u64()?).map_err(|_| BvhCacheFileError::Corrupted)
+    }
+
+    /// Reads the items written by [`CacheWriter::slice`].
+    pub fn vec<T>(
+        &mut self,
+        mut read: impl FnMut(&mut Self) -> Result<T, BvhCacheFileError>,
+    ) -> Result<Vec<T>, BvhCacheFileError> {
+        let len = self.len()?;
+        let mut items = Vec::with_capacity(len);
+        for _ in 0..len {
+            items.push(read(self)?);
+        }
+        Ok(items)
+    }
+
+    /// Reads a value written by [`CacheWriter::option`].
+    pub fn option<T>(
+        &mut self,
+        read: impl FnOnce(&mut Self) -> Result<T, BvhCacheFileError>,
+    ) -> Result<Option<T>, BvhCacheFileError> {
+        if self.bool()? {
+            read(self).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy_math::prelude::*;
+
+    use super::*;
+
+    #[test]
+    fn baked_caches_round_trip() {
+        let mesh = Sphere::new(1.0).mesh().ico(3).unwrap();
+        let other_mesh = Sphere::new(1.0).mesh().ico(2).unwrap();
+        assert_eq!(mesh_hash(&mesh), mesh_hash(&mesh.clone()));
+        assert_ne!(mesh_hash(&mesh), mesh_hash(&other_mesh));
+
+        #[cfg(feature = "obvhs")]
+        {
+            use crate::{
+                obvhs::ray_cast::ray_intersection_over_mesh_using_obvhs_bvh2_cache,
+                settings::{ObvhsCacheLayout, PositionPrecision},
+            };
+            use bevy_picking_more_hitinfo::mesh_picking::ray_cast::Backfaces;
+
+            let build_settings = ObvhsBuildSettings {
+                store_uvs: true,
+                layout: ObvhsCacheLayout {
+                    indexed: true,
+                    precision: PositionPrecision::Quantized,
+                    normals: true,
+                },
+                ..Default::default()
+            };
+            let file = BvhCacheFile::bake_obvhs_bvh2(&mesh, &build_settings).unwrap();
+            let bytes = file.to_bytes();
+            let loaded = BvhCacheFile::from_bytes(&bytes).unwrap();
+            assert!(loaded.matches(mesh_hash(&mesh), &build_settings));
+            assert_eq!(loaded.to_bytes(), bytes);
+
+            // The caches built with other settings are not used
+            let other_settings = ObvhsBuildSettings {
+                store_uvs: false,
+                ..build_settings.clone()
+            };
+            assert!(!loaded.matches(mesh_hash(&mesh), &other_settings));
+            let unrelated_settings = ObvhsBuildSettings {
+                min_triangles: 0,
+                load_baked_caches: true,
+                ..build_settings.clone()
+            };
+            assert!(loaded.matches(mesh_hash(&mesh), &unrelated_settings));
+
+            let (baked, loaded) = match (&file.cache, &loaded.cache) {
+                (BakedBvhCache::ObvhsBvh2(baked), BakedBvhCache::ObvhsBvh2(loaded)) => {
+                    (baked, loaded)
+                }
+                #[allow(unreachable_patterns)]
+                _ => panic!("The baked cache is not an obvhs cache"),
+            };
+            let ray = Ray3d::new(Vec3::new(0.3, 0.2, 5.0), Dir3::NEG_Z);
+            let cast = |cache| {
+                ray_intersection_over_mesh_using_obvhs_bvh2_cache(
+                    &Mat4::IDENTITY,
+                    ray,
+                    Backfaces::Cull,
+                    cache,
+                    f32::INFINITY,
+                )
+                .map(|hit| (hit.point, hit.triangle_index))
+            };
+            assert!(cast(baked).is_some());
+            assert_eq!(cast(baked), cast(loaded));
+
+            // Truncated files and files of another version are rejected
+            assert!(matches!(
+                BvhCacheFile::from_bytes(&bytes[..bytes.len() - 1]),
+                Err(BvhCacheFileError::Corrupted)
+            ));
+            let mut other_version = bytes.clone();
+            other_version[4] += 1;
+            assert!(matches!(
+                BvhCacheFile::from_bytes(&other_version),
+                Err(BvhCacheFileError::UnsupportedVersion(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn cyclic_trees_are_rejected() {
+        let mesh = Sphere::new(1.0).mesh().ico(2).unwrap();
+
+        #[cfg(feature = "obvhs")]
+        {
+            let mut cache = crate::obvhs::build_bvh2_cache(&mesh, &Default::default()).unwrap();
+            // The root is its own child
+            cache.bvh.nodes[0].first_index = 0;
+            let bytes = cache.to_file_bytes(mesh_hash(&mesh), 0);
+            assert!(matches!(
+                BvhCacheFile::from_bytes(&bytes),
+                Err(BvhCacheFileError::Corrupted)
+            ));
+        }
+
+        #[cfg(feature = "bvh")]
+        {
+            use ::bvh::bvh::BvhNode;
+
+            let mut cache = crate::bvh::build_bvh_cache(&mesh, &Default::default()).unwrap();
+            // The root is its own child
+            let BvhNode::Node { child_l_index, .. } = &mut cache.bvh.nodes[0] else {
+                panic!("The root is a leaf");
+            };
+            *child_l_index = 0;
+            let bytes = cache.to_file_bytes(mesh_hash(&mesh), 0);
+            assert!(matches!(
+                BvhCacheFile::from_bytes(&bytes),
+                Err(BvhCacheFileError::Corrupted)
+            ));
+        }
+    }
+
+    #[test]
+    fn baked_cache_path() {
+        assert_eq!(
+            baked_bvh_cache_path(&AssetPath::parse("models/plant.glb#Mesh0/Primitive0")),
+            AssetPath::parse("models/plant.glb.Mesh0.Primitive0.bvh")
+        );
+        assert_eq!(
+            baked_bvh_cache_path(&AssetPath::parse("rock.obj")),
+            AssetPath::parse("rock.obj.bvh")
+        );
+    }
+}
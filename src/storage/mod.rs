@@ -1,17 +1,61 @@
-use std::marker::PhantomData;
+use std::{
+    fmt::Debug,
+    hash::{DefaultHasher, Hash, Hasher},
+    marker::PhantomData,
+    sync::{Arc, Mutex, PoisonError, Weak},
+};
 
-use bevy_asset::{Asset, AssetId, AssetIndex};
-use bevy_ecs::system::Resource;
+use bevy_asset::{prelude::*, Asset, AssetId, AssetIndex, RenderAssetUsages};
+use bevy_ecs::prelude::*;
 use bevy_reflect::Reflect;
-use bevy_utils::HashMap;
+use bevy_render::mesh::Mesh;
+use bevy_utils::{HashMap, HashSet};
 use uuid::Uuid;
 
-pub trait AssetBvhCache: Send + Sync + 'static {}
+use crate::BvhCacheBuildError;
 
+#[cfg(any(feature = "bvh", feature = "obvhs"))]
+pub mod cache_dir;
+#[cfg(any(feature = "bvh", feature = "obvhs"))]
+pub mod file;
+
+pub trait AssetBvhCache: Send + Sync + 'static {
+    /// Returns the number of triangles in the cache.
+    fn triangle_count(&self) -> usize;
+
+    /// Returns the approximate number of bytes used by the cache on the heap.
+    fn memory_footprint(&self) -> usize;
+}
+
+/// Build status of the BVH cache of an asset.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Reflect)]
+pub enum BvhCacheAssetStatus {
+    /// The asset is known, but is not available yet to build its cache.
+    Pending,
+    /// The cache is being built.
+    Building,
+    /// The cache is built and used for ray casts.
+    Ready,
+    /// The cache could not be built, see [`BvhCacheFailed`](crate::BvhCacheFailed).
+    Failed,
+    /// No cache is built for this asset, because it has not enough triangles, is made of lines or
+    /// points, or opted out.
+    Skipped,
+}
+
+/// The caches of the assets, shared between the assets with the same content.
 #[derive(Resource, Reflect)]
 pub struct AssetsBvhCaches<A: Asset, B: AssetBvhCache> {
-    dense_storage: HashMap<u64, B>,
-    hash_map: HashMap<Uuid, B>,
+    dense_storage: HashMap<u64, Arc<B>>,
+    hash_map: HashMap<Uuid, Arc<B>>,
+    statuses: HashMap<AssetId<A>, BvhCacheAssetStatus>,
+    content_hashes: HashMap<AssetId<A>, u64>,
+    shared: SharedBvhCaches<B>,
+    /// The assets waiting for each in-flight build, by key of the built cache, with the hash of
+    /// their content.
+    pending_builds: HashMap<u64, (u64, Vec<AssetId<A>>)>,
+    /// The key of the in-flight build each waiting asset is attached to.
+    pending_keys: HashMap<AssetId<A>, u64>,
     marker: PhantomData<fn() -> A>,
 }
 
@@ -20,6 +64,11 @@ impl<A: Asset, B: AssetBvhCache> Default for AssetsBvhCaches<A, B> {
         Self {
             dense_storage: Default::default(),
             hash_map: Default::default(),
+            statuses: Default::default(),
+            content_hashes: Default::default(),
+            shared: Default::default(),
+            pending_builds: Default::default(),
+            pending_keys: Default::default(),
             marker: Default::default(),
         }
     }
@@ -30,13 +79,21 @@ impl<A: Asset, B: AssetBvhCache> AssetsBvhCaches<A, B> {
     /// Note that this supports anything that implements `Into<AssetId<A>>`, which includes [`Handle`] and [`AssetId`].
     #[inline]
     pub fn get(&self, id: impl Into<AssetId<A>>) -> Option<&B> {
+        self.get_shared(id).map(Arc::as_ref)
+    }
+
+    /// Retrieves the shared [`BvhCache`] of the asset with the given `id`, if it exists.
+    /// Note that this supports anything that implements `Into<AssetId<A>>`, which includes [`Handle`] and [`AssetId`].
+    #[inline]
+    pub fn get_shared(&self, id: impl Into<AssetId<A>>) -> Option<&Arc<B>> {
         match id.into() {
             AssetId::Index { index, .. } => self.dense_storage.get(&index.to_bits()),
             AssetId::Uuid { uuid } => self.hash_map.get(&uuid),
         }
     }
 
-    /// Retrieves a mutable reference to the [`BvhCache`] of the asset with the given `id`, if it exists.
+    /// Retrieves a mutable reference to the [`BvhCache`] of the asset with the given `id`, if it exists
+    /// and is not shared with other assets.
     /// Note that this supports anything that implements `Into<AssetId<A>>`, which includes [`Handle`] and [`AssetId`].
     #[inline]
     pub fn get_mut(&mut self, id: impl Into<AssetId<A>>) -> Option<&mut B> {
@@ -45,13 +102,14 @@ impl<A: Asset, B: AssetBvhCache> AssetsBvhCaches<A, B> {
             AssetId::Index { index, .. } => self.dense_storage.get_mut(&index.to_bits()),
             AssetId::Uuid { uuid } => self.hash_map.get_mut(&uuid),
         };
-        result
+        result.and_then(Arc::get_mut)
     }
 
     /// Removes (and returns) the [`Asset`] with the given `id`, if it exists.
     /// Note that this supports anything that implements `Into<AssetId<A>>`, which includes [`Handle`] and [`AssetId`].
-    pub fn remove(&mut self, id: impl Into<AssetId<A>>) -> Option<B> {
+    pub fn remove(&mut self, id: impl Into<AssetId<A>>) -> Option<Arc<B>> {
         let id: AssetId<A> = id.into();
+        self.content_hashes.remove(&id);
         match id {
             AssetId::Index { index, .. } => self.dense_storage.remove(&index.to_bits()),
             AssetId::Uuid { uuid } => self.hash_map.remove(&uuid),
@@ -59,8 +117,19 @@ impl<A: Asset, B: AssetBvhCache> AssetsBvhCaches<A, B> {
     }
 
     /// Inserts the given `bvh cache`, identified by the given `id` of the asset. If a `bvh cache` already exists for `id`, it will be replaced.
+    /// The status of the asset becomes [`BvhCacheAssetStatus::Ready`].
     pub fn insert(&mut self, id: impl Into<AssetId<A>>, bvh_cache: B) {
-        match id.into() {
+        self.insert_shared(id, Arc::new(bvh_cache));
+    }
+
+    /// Inserts the given `bvh cache`, shared with other assets, identified by the given `id` of the
+    /// asset. If a `bvh cache` already exists for `id`, it will be replaced.
+    /// The status of the asset becomes [`BvhCacheAssetStatus::Ready`].
+    pub fn insert_shared(&mut self, id: impl Into<AssetId<A>>, bvh_cache: Arc<B>) {
+        let id: AssetId<A> = id.into();
+        self.statuses.insert(id, BvhCacheAssetStatus::Ready);
+        self.content_hashes.remove(&id);
+        match id {
             AssetId::Index { index, .. } => {
                 self.insert_with_index(index, bvh_cache);
             }
@@ -70,13 +139,324 @@ impl<A: Asset, B: AssetBvhCache> AssetsBvhCaches<A, B> {
         }
     }
 
-    pub(crate) fn insert_with_uuid(&mut self, uuid: Uuid, bvh_cache: B) -> Option<B> {
+    /// Returns the build status of the [`BvhCache`] of the asset with the given `id`, or `None` if the asset is unknown.
+    #[inline]
+    pub fn status(&self, id: impl Into<AssetId<A>>) -> Option<BvhCacheAssetStatus> {
+        self.statuses.get(&id.into()).copied()
+    }
+
+    /// Returns `true` if the [`BvhCache`] of the asset with the given `id` is built.
+    #[inline]
+    pub fn is_ready(&self, id: impl Into<AssetId<A>>) -> bool {
+        self.status(id) == Some(BvhCacheAssetStatus::Ready)
+    }
+
+    /// Iterates over the assets with a known build status.
+    pub fn statuses(&self) -> impl Iterator<Item = (AssetId<A>, BvhCacheAssetStatus)> + '_ {
+        self.statuses.iter().map(|(id, status)| (*id, *status))
+    }
+
+    /// Returns the hash of the content of the asset with the given `id` its cache was built from,
+    /// see [`mesh_hash`](file::mesh_hash), if the cache was built by the plugin.
+    #[inline]
+    pub fn content_hash(&self, id: impl Into<AssetId<A>>) -> Option<u64> {
+        self.content_hashes.get(&id.into()).copied()
+    }
+
+    /// Returns the caches shared between the assets with the same content.
+    pub fn shared(&self) -> &SharedBvhCaches<B> {
+        &self.shared
+    }
+
+    /// Returns the approximate number of bytes used by all the caches on the heap, counting the
+    /// shared caches once.
+    pub fn memory_footprint(&self) -> usize {
+        let mut counted = HashSet::new();
+        self.dense_storage
+            .values()
+            .chain(self.hash_map.values())
+            .filter(|bvh_cache| counted.insert(Arc::as_ptr(bvh_cache)))
+            .map(|bvh_cache| bvh_cache.memory_footprint())
+            .sum()
+    }
+
+    /// Inserts the `bvh_cache` built by the plugin for the content with the `content_hash`.
+    pub(crate) fn insert_built(
+        &mut self,
+        id: impl Into<AssetId<A>>,
+        content_hash: u64,
+        bvh_cache: Arc<B>,
+    ) {
+        let id: AssetId<A> = id.into();
+        self.insert_shared(id, bvh_cache);
+        self.content_hashes.insert(id, content_hash);
+    }
+
+    /// Starts updating the cache of the asset with the given `id` to the one of its new content,
+    /// with the `content_hash`, built with the settings of the `key`, see [`SharedBvhCaches::key`].
+    ///
+    /// The asset gets the cache of another asset with the same key, or waits for the in-flight build
+    /// with the same key, so that the assets with the same content are only built once, even when
+    /// they arrive in the same frame. Returns `true` if no cache has the key: the caller must start a
+    /// build, whose result is given to [`AssetsBvhCaches::finish_build`].
+    ///
+    /// The asset must first be detached from its previous build, see
+    /// [`AssetsBvhCaches::detach_build`]. Its `previous_cache`, removed so that picking falls back to
+    /// the asset meanwhile, is kept alive until the shared caches are looked up, so that it is reused
+    /// if the asset was modified but not its geometry.
+    pub(crate) fn start_build(
+        &mut self,
+        id: impl Into<AssetId<A>>,
+        content_hash: u64,
+        key: u64,
+        previous_cache: Option<Arc<B>>,
+    ) -> bool {
+        let id: AssetId<A> = id.into();
+        if let Some(bvh_cache) = self.shared.get(key) {
+            self.insert_built(id, content_hash, bvh_cache);
+            return false;
+        }
+        drop(previous_cache);
+
+        self.set_status(id, BvhCacheAssetStatus::Building);
+        self.pending_keys.insert(id, key);
+        let (_, waiting) = self
+            .pending_builds
+            .entry(key)
+            .or_insert_with(|| (content_hash, Vec::new()));
+        waiting.push(id);
+        waiting.len() == 1
+    }
+
+    /// Detaches the asset with the given `id` from the in-flight build it waits for, if any. Returns
+    /// the key of the build if no other asset waits for it anymore, so that it is cancelled.
+    pub(crate) fn detach_build(&mut self, id: impl Into<AssetId<A>>) -> Option<u64> {
+        let id: AssetId<A> = id.into();
+        let key = self.pending_keys.remove(&id)?;
+        let (_, waiting) = self.pending_builds.get_mut(&key)?;
+        waiting.retain(|waiting_id| *waiting_id != id);
+        if !waiting.is_empty() {
+            return None;
+        }
+        self.pending_builds.remove(&key);
+        Some(key)
+    }
+
+    /// Gives the result of the build with the `key` to the assets waiting for it, and returns them.
+    pub(crate) fn finish_build(
+        &mut self,
+        key: u64,
+        result: &Result<Arc<B>, BvhCacheBuildError>,
+    ) -> Vec<AssetId<A>> {
+        let Some((content_hash, waiting)) = self.pending_builds.remove(&key) else {
+            return Vec::new();
+        };
+        for id in &waiting {
+            self.pending_keys.remove(id);
+            match result {
+                Ok(bvh_cache) => self.insert_built(*id, content_hash, bvh_cache.clone()),
+                Err(error) => self.set_status(*id, error.status()),
+            }
+        }
+        waiting
+    }
+
+    pub(crate) fn set_status(&mut self, id: impl Into<AssetId<A>>, status: BvhCacheAssetStatus) {
+        self.statuses.insert(id.into(), status);
+    }
+
+    pub(crate) fn remove_status(&mut self, id: impl Into<AssetId<A>>) {
+        self.statuses.remove(&id.into());
+    }
+
+    pub(crate) fn insert_with_uuid(&mut self, uuid: Uuid, bvh_cache: Arc<B>) -> Option<Arc<B>> {
         let result = self.hash_map.insert(uuid, bvh_cache);
         result
     }
 
-    pub(crate) fn insert_with_index(&mut self, index: AssetIndex, bvh_cache: B) -> Option<B> {
+    pub(crate) fn insert_with_index(
+        &mut self,
+        index: AssetIndex,
+        bvh_cache: Arc<B>,
+    ) -> Option<Arc<B>> {
         let result = self.dense_storage.insert(index.to_bits(), bvh_cache);
         result
     }
 }
+
+/// The caches built by the plugin, keyed by the content of their assets and their build settings,
+/// see [`SharedBvhCaches::key`].
+///
+/// The assets with the same geometry, as the instances of a model loaded several times, share a
+/// single cache instead of building their own. The caches are only referenced weakly: they are
+/// dropped with the last asset using them. The map is shared with the build tasks, that add the
+/// caches they build, see [`AssetsBvhCaches::start_build`].
+pub struct SharedBvhCaches<B>(Arc<Mutex<HashMap<u64, Weak<B>>>>);
+
+impl<B> Default for SharedBvhCaches<B> {
+    fn default() -> Self {
+        Self(Default::default())
+    }
+}
+
+impl<B> Clone for SharedBvhCaches<B> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<B> SharedBvhCaches<B> {
+    /// Returns the key of the cache of the content with the `content_hash`, built with the
+    /// `build_settings`: the assets with the same content but built differently don't share their
+    /// caches.
+    pub fn key(content_hash: u64, build_settings: &impl Debug) -> u64 {
+        // The keys are never stored, the settings are hashed with their debug representation
+        let mut hasher = DefaultHasher::new();
+        content_hash.hash(&mut hasher);
+        format!("{build_settings:?}").hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Returns the cache with the `key`, if an asset still uses it.
+    pub fn get(&self, key: u64) -> Option<Arc<B>> {
+        let caches = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        caches.get(&key).and_then(Weak::upgrade)
+    }
+
+    /// Shares the `bvh_cache` with the `key`, replacing the previous one.
+    pub(crate) fn insert(&self, key: u64, bvh_cache: B) -> Arc<B> {
+        let bvh_cache = Arc::new(bvh_cache);
+        let mut caches = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        caches.retain(|_, bvh_cache| bvh_cache.strong_count() > 0);
+        caches.insert(key, Arc::downgrade(&bvh_cache));
+        bvh_cache
+    }
+}
+
+/// The meshes with [`RenderAssetUsages::RENDER_WORLD`] only, which are removed from
+/// [`Assets<Mesh>`] once extracted to the render world.
+///
+/// They are captured before their extraction, so that their caches can be built the next frame,
+/// whatever their number of primitives, then released: afterwards, they are ray cast with their
+/// cache alone. Their caches are kept when they are removed by the extraction, and dropped when
+/// they are unused.
+#[derive(Resource, Default)]
+pub struct RenderWorldMeshes {
+    captured: HashMap<AssetId<Mesh>, Mesh>,
+    extracted: HashSet<AssetId<Mesh>>,
+}
+
+impl RenderWorldMeshes {
+    /// Returns the captured mesh with the given `id`, until its caches are built.
+    pub fn get(&self, id: impl Into<AssetId<Mesh>>) -> Option<&Mesh> {
+        self.captured.get(&id.into())
+    }
+
+    /// Returns `true` if the mesh with the given `id` is only in the render world.
+    pub fn is_extracted(&self, id: impl Into<AssetId<Mesh>>) -> bool {
+        self.extracted.contains(&id.into())
+    }
+}
+
+/// Captures the new and modified meshes that are only used in the render world, before they are
+/// extracted away.
+pub fn capture_render_world_meshes(
+    mut render_world_meshes: ResMut<RenderWorldMeshes>,
+    mut asset_events: EventReader<AssetEvent<Mesh>>,
+    meshes: Res<Assets<Mesh>>,
+) {
+    // The caches of the meshes captured last frame have been built since
+    render_world_meshes.captured.clear();
+
+    for ev in asset_events.read() {
+        match ev {
+            AssetEvent::Added { id } | AssetEvent::Modified { id } => {
+                let Some(mesh) = meshes.get(*id) else {
+                    continue;
+                };
+                if mesh.asset_usage.contains(RenderAssetUsages::MAIN_WORLD) {
+                    render_world_meshes.extracted.remove(id);
+                    continue;
+                }
+                render_world_meshes.captured.insert(*id, mesh.clone());
+                render_world_meshes.extracted.insert(*id);
+            }
+            AssetEvent::Unused { id } => {
+                render_world_meshes.extracted.remove(id);
+            }
+            AssetEvent::Removed { .. } | AssetEvent::LoadedWithDependencies { .. } => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TestCache(Vec<u8>);
+
+    impl AssetBvhCache for TestCache {
+        fn triangle_count(&self) -> usize {
+            0
+        }
+
+        fn memory_footprint(&self) -> usize {
+            self.0.len()
+        }
+    }
+
+    #[test]
+    fn shared_caches() {
+        let mut bvh_caches = AssetsBvhCaches::<Mesh, TestCache>::default();
+        let shared = bvh_caches.shared().clone();
+        let key = SharedBvhCaches::<TestCache>::key(1, &"settings");
+        assert_ne!(key, SharedBvhCaches::<TestCache>::key(1, &"other settings"));
+
+        let [a, b] = [1, 2].map(|uuid| AssetId::<Mesh>::Uuid {
+            uuid: Uuid::from_u128(uuid),
+        });
+        let bvh_cache = shared.insert(key, TestCache(vec![0; 16]));
+        bvh_caches.insert_built(a, 1, bvh_cache);
+        bvh_caches.insert_built(b, 1, shared.get(key).unwrap());
+        assert_eq!(bvh_caches.content_hash(b), Some(1));
+        assert_eq!(bvh_caches.memory_footprint(), 16);
+        assert!(bvh_caches.get_mut(a).is_none());
+
+        // The cache is dropped with the last asset using it
+        bvh_caches.remove(a);
+        assert!(shared.get(key).is_some());
+        bvh_caches.remove(b);
+        assert!(shared.get(key).is_none());
+    }
+
+    #[test]
+    fn pending_builds() {
+        let mut bvh_caches = AssetsBvhCaches::<Mesh, TestCache>::default();
+        let [a, b, c] = [1, 2, 3].map(|uuid| AssetId::<Mesh>::Uuid {
+            uuid: Uuid::from_u128(uuid),
+        });
+
+        // Only the first asset with the key starts a build, the others wait for it
+        assert!(bvh_caches.start_build(a, 1, 10, None));
+        assert!(!bvh_caches.start_build(b, 1, 10, None));
+        assert!(bvh_caches.start_build(c, 2, 20, None));
+        assert_eq!(bvh_caches.status(b), Some(BvhCacheAssetStatus::Building));
+
+        // The build is only cancelled once no asset waits for it
+        assert_eq!(bvh_caches.detach_build(a), None);
+        assert_eq!(bvh_caches.detach_build(c), Some(20));
+
+        let result = Ok(bvh_caches.shared().insert(10, TestCache(vec![0; 16])));
+        assert_eq!(bvh_caches.finish_build(10, &result), vec![b]);
+        assert!(bvh_caches.is_ready(b));
+        assert_eq!(bvh_caches.content_hash(b), Some(1));
+        assert!(bvh_caches.get(a).is_none());
+
+        // A later asset with the same key gets the built cache
+        assert!(!bvh_caches.start_build(a, 1, 10, None));
+        assert!(Arc::ptr_eq(
+            bvh_caches.get_shared(a).unwrap(),
+            bvh_caches.get_shared(b).unwrap()
+        ));
+    }
+}
@@ -0,0 +1,691 @@
+//! Ray casts on meshes spawned in a headless app, once their caches are built.
+
+use std::time::Duration;
+
+use bevy_asset::RenderAssetUsages;
+use bevy_ecs::system::RunSystemOnce;
+use bevy_internal::prelude::*;
+use bevy_picking_bvh_backend::{
+    ray_cast::{BvhMeshRayCast, RayHitsSettings},
+    storage::{AssetBvhCache, AssetsBvhCaches, BvhCacheAssetStatus},
+    BvhBackend, PickingBvhBackend,
+};
+use bevy_picking_more_hitinfo::mesh_picking::ray_cast::{
+    RayCastSettings, RayCastVisibility, RayMeshHit,
+};
+use bevy_render::mesh::{skinning::SkinnedMeshInverseBindposes, PrimitiveTopology};
+
+/// Creates a headless app with the plugin, without rendering: the meshes are never visible, so the
+/// ray casts use [`RayCastVisibility::Any`].
+fn test_app(picking_bvh_backend: PickingBvhBackend) -> App {
+    let mut app = App::new();
+    app.add_plugins((
+        MinimalPlugins,
+        AssetPlugin::default(),
+        TransformPlugin,
+        HierarchyPlugin,
+    ));
+    app.init_asset::<Mesh>()
+        .init_asset::<Image>()
+        .init_asset::<StandardMaterial>()
+        .init_asset::<SkinnedMeshInverseBindposes>();
+    app.add_plugins(picking_bvh_backend);
+    app
+}
+
+/// Spawns an entity with the `mesh`, and returns it with the id of the mesh.
+fn spawn_mesh(app: &mut App, mesh: Mesh, transform: Transform) -> (Entity, AssetId<Mesh>) {
+    let aabb = mesh.compute_aabb().unwrap_or_default();
+    let handle = app.world_mut().resource_mut::<Assets<Mesh>>().add(mesh);
+    let id = handle.id();
+    let entity = app
+        .world_mut()
+        .spawn((Mesh3d(handle), transform, aabb))
+        .id();
+    (entity, id)
+}
+
+/// Updates the app until the `B` cache of the mesh with the given `id` is built, and returns its
+/// status.
+fn wait_for_cache<B: AssetBvhCache>(app: &mut App, id: AssetId<Mesh>) -> BvhCacheAssetStatus {
+    for _ in 0..1000 {
+        app.update();
+        let status = app
+            .world()
+            .resource::<AssetsBvhCaches<Mesh, B>>()
+            .status(id);
+        match status {
+            None | Some(BvhCacheAssetStatus::Pending | BvhCacheAssetStatus::Building) => {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            Some(status) => return status,
+        }
+    }
+    panic!("The cache of {id} was not built");
+}
+
+/// Replaces the mesh asset with the given `id`, which sends an [`AssetEvent::Modified`].
+fn set_mesh(app: &mut App, id: AssetId<Mesh>, mesh: Mesh) {
+    let mut meshes = app.world_mut().resource_mut::<Assets<Mesh>>();
+    *meshes.get_mut(id).unwrap() = mesh;
+}
+
+/// Returns the events `E` sent by the app since they were last drained.
+fn drain_events<E: Event>(app: &mut App) -> Vec<E> {
+    app.world_mut()
+        .resource_mut::<Events<E>>()
+        .drain()
+        .collect()
+}
+
+/// Casts the `ray` in the world of the app, and returns its hits.
+fn cast_ray(app: &mut App, ray: Ray3d) -> Vec<(Entity, RayMeshHit)> {
+    app.world_mut()
+        .run_system_once(move |mut ray_cast: BvhMeshRayCast| {
+            let settings = RayCastSettings {
+                visibility: RayCastVisibility::Any,
+                ..Default::default()
+            };
+            ray_cast.cast_ray(ray, &settings).to_vec()
+        })
+        .unwrap()
+}
+
+/// Returns the backends enabled by the features.
+fn backends() -> Vec<BvhBackend> {
+    let mut backends = vec![BvhBackend::None];
+    #[cfg(feature = "bvh")]
+    backends.push(BvhBackend::Bvh);
+    #[cfg(feature = "obvhs")]
+    backends.push(BvhBackend::ObvhsBvh2);
+    backends
+}
+
+/// Spawns the `meshes` with the `backend`, and returns the app once their caches are built, with
+/// the entities.
+fn meshes_app<const N: usize>(
+    backend: BvhBackend,
+    meshes: [(Mesh, Transform); N],
+) -> (App, [Entity; N]) {
+    let mut app = test_app(PickingBvhBackend::with_backend(backend.clone()));
+    let spawned = meshes.map(|(mesh, transform)| spawn_mesh(&mut app, mesh, transform));
+    for (_, id) in spawned {
+        let status = match backend {
+            BvhBackend::None => None,
+            #[cfg(feature = "bvh")]
+            BvhBackend::Bvh => Some(wait_for_cache::<bevy_picking_bvh_backend::bvh::BvhCache>(
+                &mut app, id,
+            )),
+            #[cfg(feature = "obvhs")]
+            BvhBackend::ObvhsBvh2 => Some(wait_for_cache::<
+                bevy_picking_bvh_backend::obvhs::ObvhsBvh2Cache,
+            >(&mut app, id)),
+        };
+        assert!(status.is_none_or(|status| status == BvhCacheAssetStatus::Ready));
+    }
+    // Propagate the transforms of the meshes without a cache
+    app.update();
+    (app, spawned.map(|(entity, _)| entity))
+}
+
+/// Spawns a unit sphere at the origin and a smaller one along the X axis, with the `backend`, and
+/// returns the app once their caches are built, with the entities.
+fn spheres_app(backend: BvhBackend) -> (App, [Entity; 2]) {
+    meshes_app(
+        backend,
+        [
+            (
+                Sphere::new(1.0).mesh().ico(3).unwrap(),
+                Transform::default(),
+            ),
+            (
+                Sphere::new(0.5).mesh().ico(2).unwrap(),
+                Transform::from_xyz(3.0, 0.0, 0.0),
+            ),
+        ],
+    )
+}
+
+/// Spawns three disks of radius 1 and thickness 0.5 facing the Z axis, stacked at `z = 4, 0, -4`,
+/// with the `backend`, and returns the app once their caches are built, with the entities.
+///
+/// A ray from `z = 10` towards `-Z` near the axis enters and exits them at the distances
+/// `5.75, 6.25`, `9.75, 10.25` and `13.75, 14.25`.
+fn disks_app(backend: BvhBackend) -> (App, [Entity; 3]) {
+    let disk = Mesh::from(Cylinder::new(1.0, 0.5).mesh().resolution(32));
+    let facing_z = Quat::from_rotation_x(std::f32::consts::FRAC_PI_2);
+    meshes_app(
+        backend,
+        [4.0, 0.0, -4.0].map(|z| {
+            let transform = Transform::from_xyz(0.0, 0.0, z).with_rotation(facing_z);
+            (disk.clone(), transform)
+        }),
+    )
+}
+
+/// Asserts that the `hits` are on the `expected` entities, given by their index in `entities`, at
+/// the expected distances.
+fn assert_hits(entities: &[Entity], hits: &[(Entity, RayMeshHit)], expected: &[(usize, f32)]) {
+    let hits = hits
+        .iter()
+        .map(|(entity, hit)| {
+            let index = entities.iter().position(|e| e == entity).unwrap();
+            (index, hit.distance)
+        })
+        .collect::<Vec<_>>();
+    let same = hits.len() == expected.len()
+        && hits
+            .iter()
+            .zip(expected)
+            .all(|(hit, expected)| hit.0 == expected.0 && (hit.1 - expected.1).abs() < 1e-4);
+    assert!(same, "{hits:?} != {expected:?}");
+}
+
+/// Returns a mesh of a few `topology` primitives along the X axis, only used in the render world.
+fn render_world_mesh(topology: PrimitiveTopology) -> Mesh {
+    let mut mesh = match topology {
+        PrimitiveTopology::TriangleList => Mesh::from(Cuboid::new(4.0, 1.0, 1.0)),
+        _ => Mesh::new(topology, RenderAssetUsages::default()).with_inserted_attribute(
+            Mesh::ATTRIBUTE_POSITION,
+            vec![[-2.0, 0.0, 0.0], [0.0, 0.0, 0.0], [2.0, 0.0, 0.0]],
+        ),
+    };
+    mesh.asset_usage = RenderAssetUsages::RENDER_WORLD;
+    mesh
+}
+
+#[cfg(feature = "obvhs")]
+#[test]
+fn render_world_meshes_are_hit_with_their_cache() {
+    use bevy_picking_bvh_backend::obvhs::{
+        lines::LineCache, point_cloud::PointCloudCache, ObvhsBvh2Cache,
+    };
+
+    let picking_bvh_backend = PickingBvhBackend::with_backend(BvhBackend::ObvhsBvh2);
+    let mut app = test_app(picking_bvh_backend.with_pick_radius(0.1));
+    let transform = Transform::from_xyz(0.0, 0.0, -5.0);
+    let (cuboid, cuboid_id) = spawn_mesh(
+        &mut app,
+        render_world_mesh(PrimitiveTopology::TriangleList),
+        transform.with_translation(Vec3::new(0.0, 2.0, -5.0)),
+    );
+    let (lines, lines_id) = spawn_mesh(
+        &mut app,
+        render_world_mesh(PrimitiveTopology::LineStrip),
+        transform,
+    );
+    let (points, points_id) = spawn_mesh(
+        &mut app,
+        render_world_mesh(PrimitiveTopology::PointList),
+        transform.with_translation(Vec3::new(0.0, -2.0, -5.0)),
+    );
+
+    // The meshes have less primitives than the minimum of the build settings
+    let ready = BvhCacheAssetStatus::Ready;
+    assert_eq!(wait_for_cache::<ObvhsBvh2Cache>(&mut app, cuboid_id), ready);
+    assert_eq!(wait_for_cache::<LineCache>(&mut app, lines_id), ready);
+    assert_eq!(
+        wait_for_cache::<PointCloudCache>(&mut app, points_id),
+        ready
+    );
+
+    // The extraction to the render world removes the meshes from the main world
+    let mut meshes = app.world_mut().resource_mut::<Assets<Mesh>>();
+    for id in [cuboid_id, lines_id, points_id] {
+        meshes.remove(id);
+    }
+    app.update();
+    app.update();
+
+    // Between the vertices of the lines and points, only the lines are hit
+    for (entity, y) in [(cuboid, 2.0), (lines, 0.0), (points, -2.0)] {
+        let ray = Ray3d::new(Vec3::new(1.0, y, 0.0), Dir3::NEG_Z);
+        let hit_entity = cast_ray(&mut app, ray).first().map(|(entity, _)| *entity);
+        assert_eq!(hit_entity, (entity != points).then_some(entity));
+    }
+    let ray = Ray3d::new(Vec3::new(2.0, -2.0, 0.0), Dir3::NEG_Z);
+    let hit_entity = cast_ray(&mut app, ray).first().map(|(entity, _)| *entity);
+    assert_eq!(hit_entity, Some(points));
+}
+
+#[cfg(feature = "obvhs")]
+#[test]
+fn identical_meshes_added_in_the_same_frame_share_one_build() {
+    use bevy_picking_bvh_backend::obvhs::ObvhsBvh2Cache;
+
+    let mut app = test_app(PickingBvhBackend::with_backend(BvhBackend::ObvhsBvh2));
+    let mesh = Sphere::new(1.0).mesh().ico(2).unwrap();
+    let (_, a) = spawn_mesh(&mut app, mesh.clone(), Transform::default());
+    let (_, b) = spawn_mesh(&mut app, mesh, Transform::from_xyz(3.0, 0.0, 0.0));
+
+    let ready = BvhCacheAssetStatus::Ready;
+    assert_eq!(wait_for_cache::<ObvhsBvh2Cache>(&mut app, a), ready);
+    assert_eq!(wait_for_cache::<ObvhsBvh2Cache>(&mut app, b), ready);
+    let bvh_caches = app
+        .world()
+        .resource::<AssetsBvhCaches<Mesh, ObvhsBvh2Cache>>();
+    assert!(std::sync::Arc::ptr_eq(
+        bvh_caches.get_shared(a).unwrap(),
+        bvh_caches.get_shared(b).unwrap()
+    ));
+}
+
+#[test]
+fn shape_casts_agree_with_every_backend() {
+    let capsule = Capsule3d::new(0.2, 1.0);
+    let horizontal = Quat::from_rotation_z(std::f32::consts::FRAC_PI_2);
+    let mut casts = Vec::new();
+    for backend in backends() {
+        let (mut app, spheres) = spheres_app(backend);
+        let cast = app
+            .world_mut()
+            .run_system_once(move |mut ray_cast: BvhMeshRayCast| {
+                let settings = RayCastSettings {
+                    visibility: RayCastVisibility::Any,
+                    ..Default::default()
+                };
+                let towards = Ray3d::new(Vec3::new(0.3, 0.2, 5.0), Dir3::NEG_Z);
+                let away = Ray3d::new(Vec3::new(0.3, 0.2, 5.0), Dir3::Z);
+                let capsule_ray = Ray3d::new(Vec3::new(3.0, 0.0, 5.0), Dir3::NEG_Z);
+                [
+                    ray_cast.cast_sphere(towards, 0.25, f32::INFINITY, &settings),
+                    ray_cast.cast_sphere(towards, 0.25, 3.0, &settings),
+                    ray_cast.cast_sphere(away, 0.25, f32::INFINITY, &settings),
+                    ray_cast.cast_capsule(capsule_ray, capsule, horizontal, 10.0, &settings),
+                ]
+            })
+            .unwrap();
+        // The index of the sphere hit, instead of its entity in this app
+        let cast = cast.map(|hit| {
+            hit.map(|(entity, hit)| (spheres.iter().position(|e| *e == entity).unwrap(), hit))
+        });
+        casts.push(cast);
+    }
+
+    let [sphere, too_far, away, capsule] = &casts[0];
+    let sphere = sphere.as_ref().unwrap();
+    assert_eq!(sphere.0, 0);
+    assert!((3.79..3.82).contains(&sphere.1.distance));
+    assert!(too_far.is_none());
+    assert!(away.is_none());
+    // The capsule lies across the small sphere, so its side touches it first
+    let capsule = capsule.as_ref().unwrap();
+    assert_eq!(capsule.0, 1);
+    assert!((4.29..4.35).contains(&capsule.1.distance));
+
+    for cast in &casts[1..] {
+        for (hit, expected) in cast.iter().zip(&casts[0]) {
+            let same = match (hit, expected) {
+                (Some((sphere, hit)), Some((expected_sphere, expected))) => {
+                    sphere == expected_sphere
+                        && (hit.distance - expected.distance).abs() < 1e-4
+                        && hit.point.distance(expected.point) < 1e-3
+                }
+                (hit, expected) => hit.is_none() && expected.is_none(),
+            };
+            assert!(same, "{hit:?} != {expected:?}");
+        }
+    }
+}
+
+#[test]
+fn closest_points_agree_with_every_backend() {
+    let mut closest_points = Vec::new();
+    for backend in backends() {
+        let (mut app, spheres) = spheres_app(backend);
+        let closest_point = app
+            .world_mut()
+            .run_system_once(move |mut ray_cast: BvhMeshRayCast| {
+                let settings = RayCastSettings {
+                    visibility: RayCastVisibility::Any,
+                    ..Default::default()
+                };
+                [
+                    ray_cast.closest_point(Vec3::new(0.2, 2.0, 0.3), f32::INFINITY, &settings),
+                    ray_cast.closest_point(Vec3::new(2.0, 0.1, 0.0), f32::INFINITY, &settings),
+                    ray_cast.closest_point(Vec3::new(0.0, 5.0, 0.0), 1.0, &settings),
+                ]
+            })
+            .unwrap();
+        // The index of the sphere, instead of its entity in this app
+        let closest_point = closest_point.map(|hit| {
+            hit.map(|(entity, hit)| (spheres.iter().position(|e| *e == entity).unwrap(), hit))
+        });
+        closest_points.push(closest_point);
+    }
+
+    // The faces of the spheres are slightly inside them
+    let [large, small, too_far] = &closest_points[0];
+    let large = large.as_ref().unwrap();
+    assert_eq!(large.0, 0);
+    assert!((1.03..1.04).contains(&large.1.distance));
+    let small = small.as_ref().unwrap();
+    assert_eq!(small.0, 1);
+    assert!((0.5..0.52).contains(&small.1.distance));
+    assert!(too_far.is_none());
+
+    for closest_point in &closest_points[1..] {
+        for (hit, expected) in closest_point.iter().zip(&closest_points[0]) {
+            let same = match (hit, expected) {
+                (Some((sphere, hit)), Some((expected_sphere, expected))) => {
+                    sphere == expected_sphere
+                        && (hit.distance - expected.distance).abs() < 1e-4
+                        && hit.point.distance(expected.point) < 1e-4
+                }
+                (hit, expected) => hit.is_none() && expected.is_none(),
+            };
+            assert!(same, "{hit:?} != {expected:?}");
+        }
+    }
+}
+
+#[test]
+fn bounded_casts_skip_the_hits_outside_of_the_segment_with_every_backend() {
+    let ray = Ray3d::new(Vec3::new(0.1, 0.2, 10.0), Dir3::NEG_Z);
+    for backend in backends() {
+        let (mut app, disks) = disks_app(backend);
+        let casts = app
+            .world_mut()
+            .run_system_once(move |mut ray_cast: BvhMeshRayCast| {
+                let settings = RayCastSettings {
+                    visibility: RayCastVisibility::Any,
+                    ..Default::default()
+                };
+                [(0.0, 5.0), (0.0, f32::INFINITY), (6.0, 20.0), (6.0, 9.0)].map(
+                    |(min_distance, max_distance)| {
+                        ray_cast
+                            .cast_ray_bounded(ray, min_distance, max_distance, &settings)
+                            .to_vec()
+                    },
+                )
+            })
+            .unwrap();
+
+        let [before, unbounded, after_first, between] = casts;
+        assert_hits(&disks, &before, &[]);
+        assert_hits(&disks, &unbounded, &[(0, 5.75)]);
+        // The segment starts inside the first disk, whose exit is a backface, so the second disk is
+        // hit, still at its distance from the origin of the ray
+        assert_hits(&disks, &after_first, &[(1, 9.75)]);
+        assert_hits(&disks, &between, &[]);
+    }
+}
+
+#[test]
+fn all_hits_are_sorted_with_the_backfaces_with_every_backend() {
+    let ray = Ray3d::new(Vec3::new(0.1, 0.2, 10.0), Dir3::NEG_Z);
+    for backend in backends() {
+        let (mut app, disks) = disks_app(backend);
+        let hits = app
+            .world_mut()
+            .run_system_once(move |mut ray_cast: BvhMeshRayCast| {
+                let settings = RayCastSettings {
+                    visibility: RayCastVisibility::Any,
+                    ..Default::default()
+                };
+                let hits_settings = RayHitsSettings::default();
+                [
+                    hits_settings,
+                    hits_settings.with_max_count(3),
+                    hits_settings.with_max_distance(10.0),
+                    hits_settings.with_max_count(2).with_max_distance(10.0),
+                ]
+                .map(|hits_settings| {
+                    ray_cast
+                        .cast_ray_all_hits(ray, &settings, &hits_settings)
+                        .to_vec()
+                })
+            })
+            .unwrap();
+
+        // The early exit test of the settings is ignored, every disk is entered and exited
+        let [all, nearest, near, nearest_and_near] = hits;
+        let expected = [
+            (0, 5.75),
+            (0, 6.25),
+            (1, 9.75),
+            (1, 10.25),
+            (2, 13.75),
+            (2, 14.25),
+        ];
+        assert_hits(&disks, &all, &expected);
+        // The exits are backfaces
+        for (index, (_, hit)) in all.iter().enumerate() {
+            assert_eq!(hit.normal.dot(*ray.direction) > 0.0, index % 2 == 1);
+        }
+        assert_hits(&disks, &nearest, &expected[..3]);
+        assert_hits(&disks, &near, &expected[..3]);
+        assert_hits(&disks, &nearest_and_near, &expected[..2]);
+    }
+}
+
+#[test]
+fn occlusion_agrees_with_bounded_casts_with_every_backend() {
+    let towards = Ray3d::new(Vec3::new(0.1, 0.2, 10.0), Dir3::NEG_Z);
+    let inside = Ray3d::new(Vec3::new(0.1, 0.2, 4.0), Dir3::NEG_Z);
+    let beside = Ray3d::new(Vec3::new(3.0, 0.0, 10.0), Dir3::NEG_Z);
+    // The first hits are at 5.75 from `towards`, and at 3.75 from `inside` as it leaves the first
+    // disk through a backface
+    let segments = [
+        (towards, 5.74, false),
+        (towards, 5.76, true),
+        (towards, f32::INFINITY, true),
+        (inside, 3.74, false),
+        (inside, 3.76, true),
+        (beside, f32::INFINITY, false),
+    ];
+    for backend in backends() {
+        let (mut app, disks) = disks_app(backend.clone());
+        let queries = app
+            .world_mut()
+            .run_system_once(move |mut ray_cast: BvhMeshRayCast| {
+                let settings = RayCastSettings {
+                    visibility: RayCastVisibility::Any,
+                    ..Default::default()
+                };
+                segments.map(|(ray, max_distance, _)| {
+                    let any_hit = ray_cast.any_hit(ray, max_distance, &settings);
+                    let is_occluded = ray_cast.is_occluded(ray, max_distance, &settings);
+                    let nearest_hit = ray_cast
+                        .cast_ray_bounded(ray, 0.0, max_distance, &settings)
+                        .first()
+                        .map(|(entity, _)| *entity);
+                    (any_hit, is_occluded, nearest_hit)
+                })
+            })
+            .unwrap();
+
+        for ((any_hit, is_occluded, nearest_hit), (ray, max_distance, occluded)) in
+            queries.into_iter().zip(segments)
+        {
+            let segment = format!("{backend:?} {ray:?} {max_distance}");
+            assert_eq!(is_occluded, occluded, "{segment}");
+            assert_eq!(any_hit.is_some(), occluded, "{segment}");
+            assert_eq!(nearest_hit.is_some(), occluded, "{segment}");
+            assert!(any_hit.is_none_or(|entity| disks.contains(&entity)));
+        }
+    }
+}
+
+/// Casts the `rays` in the world of the app as a batch and one by one, and asserts that they hit the
+/// same `entities` at the same distances, both with the nearest hit and with all of them.
+fn assert_batch_agrees(app: &mut App, entities: &[Entity], rays: &[Ray3d]) {
+    let rays = rays.to_vec();
+    let casts = app
+        .world_mut()
+        .run_system_once(move |mut ray_cast: BvhMeshRayCast| {
+            let nearest = RayCastSettings {
+                visibility: RayCastVisibility::Any,
+                ..Default::default()
+            };
+            let all = RayCastSettings {
+                early_exit_test: &|_| false,
+                ..nearest
+            };
+            [nearest, all].map(|settings| {
+                let batch = ray_cast.cast_rays(&rays, &settings);
+                let single = rays
+                    .iter()
+                    .map(|ray| ray_cast.cast_ray(*ray, &settings).to_vec())
+                    .collect::<Vec<_>>();
+                (batch, single)
+            })
+        })
+        .unwrap();
+
+    for (batch, single) in casts {
+        assert_eq!(batch.len(), single.len());
+        for (hits, expected) in batch.iter().zip(&single) {
+            let expected = expected
+                .iter()
+                .map(|(entity, hit)| {
+                    let index = entities.iter().position(|e| e == entity).unwrap();
+                    (index, hit.distance)
+                })
+                .collect::<Vec<_>>();
+            assert_hits(entities, hits, &expected);
+        }
+    }
+}
+
+#[test]
+fn batches_agree_with_single_ray_casts_with_every_backend() {
+    // Coherent rays fanning out through the disks, the outer ones miss the farther disks
+    let origin = Vec3::new(0.0, 0.0, 10.0);
+    let rays = (0..64)
+        .map(|i| {
+            let target = Vec3::new(
+                (i % 8) as f32 * 0.3 - 1.05,
+                (i / 8) as f32 * 0.3 - 1.05,
+                0.0,
+            );
+            Ray3d::new(origin, Dir3::new(target - origin).unwrap())
+        })
+        .collect::<Vec<_>>();
+    for backend in backends() {
+        let (mut app, disks) = disks_app(backend);
+        assert_batch_agrees(&mut app, &disks, &rays);
+
+        // Without the TLAS, the entities are culled one by one
+        #[cfg(feature = "obvhs")]
+        {
+            use bevy_picking_bvh_backend::obvhs::tlas::MeshEntitiesTlas;
+
+            app.world_mut().insert_resource(MeshEntitiesTlas::default());
+            assert_batch_agrees(&mut app, &disks, &rays);
+        }
+    }
+}
+
+#[cfg(feature = "obvhs")]
+#[test]
+fn modified_meshes_are_rebuilt_and_removed_meshes_evicted() {
+    use bevy_picking_bvh_backend::{
+        obvhs::ObvhsBvh2Cache, BvhCacheBuildError, BvhCacheBuilt, BvhCacheFailed,
+    };
+
+    fn caches(app: &App) -> &AssetsBvhCaches<Mesh, ObvhsBvh2Cache> {
+        app.world().resource()
+    }
+
+    let mut app = test_app(PickingBvhBackend::with_backend(BvhBackend::ObvhsBvh2));
+    let (_, id) = spawn_mesh(
+        &mut app,
+        Sphere::new(1.0).mesh().ico(2).unwrap(),
+        Transform::default(),
+    );
+    assert_eq!(caches(&app).status(id), None);
+
+    let ready = BvhCacheAssetStatus::Ready;
+    assert_eq!(wait_for_cache::<ObvhsBvh2Cache>(&mut app, id), ready);
+    let built = drain_events::<BvhCacheBuilt>(&mut app);
+    assert_eq!(built.len(), 1);
+    assert_eq!(built[0].asset_id, id);
+    assert_eq!(built[0].backend, BvhBackend::ObvhsBvh2);
+    assert_eq!(built[0].triangle_count, 320);
+
+    // Too few triangles to build a cache, the previous one is dropped
+    set_mesh(&mut app, id, Mesh::from(Cuboid::default()));
+    let skipped = BvhCacheAssetStatus::Skipped;
+    assert_eq!(wait_for_cache::<ObvhsBvh2Cache>(&mut app, id), skipped);
+    assert!(caches(&app).get(id).is_none());
+    assert!(drain_events::<BvhCacheBuilt>(&mut app).is_empty());
+    assert!(drain_events::<BvhCacheFailed>(&mut app).is_empty());
+
+    let no_positions = Mesh::new(
+        PrimitiveTopology::TriangleList,
+        RenderAssetUsages::default(),
+    );
+    set_mesh(&mut app, id, no_positions);
+    let failed = BvhCacheAssetStatus::Failed;
+    assert_eq!(wait_for_cache::<ObvhsBvh2Cache>(&mut app, id), failed);
+    let failures = drain_events::<BvhCacheFailed>(&mut app);
+    assert_eq!(failures.len(), 1);
+    assert_eq!(failures[0].asset_id, id);
+    assert_eq!(failures[0].error, BvhCacheBuildError::MissingPositions);
+
+    set_mesh(&mut app, id, Sphere::new(1.0).mesh().ico(3).unwrap());
+    assert_eq!(wait_for_cache::<ObvhsBvh2Cache>(&mut app, id), ready);
+    assert_eq!(caches(&app).get(id).unwrap().triangle_count(), 1280);
+    let built = drain_events::<BvhCacheBuilt>(&mut app);
+    assert_eq!(built.len(), 1);
+    assert_eq!(built[0].triangle_count, 1280);
+
+    // The removed meshes are evicted
+    app.world_mut().resource_mut::<Assets<Mesh>>().remove(id);
+    for _ in 0..3 {
+        app.update();
+    }
+    assert_eq!(caches(&app).status(id), None);
+    assert!(caches(&app).get(id).is_none());
+
+    // So are the unused ones, once their last entity is despawned
+    let (entity, id) = spawn_mesh(
+        &mut app,
+        Sphere::new(1.0).mesh().ico(2).unwrap(),
+        Transform::default(),
+    );
+    assert_eq!(wait_for_cache::<ObvhsBvh2Cache>(&mut app, id), ready);
+    app.world_mut().despawn(entity);
+    for _ in 0..3 {
+        app.update();
+    }
+    assert_eq!(caches(&app).status(id), None);
+    assert!(caches(&app).get(id).is_none());
+}
+
+#[cfg(feature = "obvhs")]
+#[test]
+fn modified_meshes_cancel_their_outdated_build() {
+    use bevy_picking_bvh_backend::{obvhs::ObvhsBvh2Cache, BvhCacheBuilt};
+
+    let mut app = test_app(PickingBvhBackend::with_backend(BvhBackend::ObvhsBvh2));
+    let (_, id) = spawn_mesh(
+        &mut app,
+        Sphere::new(1.0).mesh().ico(6).unwrap(),
+        Transform::default(),
+    );
+    // The build of the large sphere starts, and may even finish within this frame
+    app.update();
+    drain_events::<BvhCacheBuilt>(&mut app);
+
+    set_mesh(&mut app, id, Sphere::new(1.0).mesh().ico(2).unwrap());
+    let ready = BvhCacheAssetStatus::Ready;
+    assert_eq!(wait_for_cache::<ObvhsBvh2Cache>(&mut app, id), ready);
+    let mut built = drain_events::<BvhCacheBuilt>(&mut app);
+    // Let the outdated build finish, if it was not cancelled
+    for _ in 0..10 {
+        app.update();
+        built.extend(drain_events::<BvhCacheBuilt>(&mut app));
+        std::thread::sleep(Duration::from_millis(10));
+    }
+
+    // Only the cache of the new version of the mesh is built
+    let bvh_caches = app
+        .world()
+        .resource::<AssetsBvhCaches<Mesh, ObvhsBvh2Cache>>();
+    assert_eq!(bvh_caches.status(id), Some(ready));
+    assert_eq!(bvh_caches.get(id).unwrap().triangle_count(), 320);
+    assert_eq!(built.len(), 1);
+    assert_eq!(built[0].triangle_count, 320);
+}